@@ -0,0 +1,575 @@
+//! Animation for UI and games.
+//!
+//! A `FrameScheduler` wakes up at a target frame rate using embassy time, so the app has
+//! to have called `Wrapper::start_scheduler`, and keeps an FPS / frame time counter.
+//! Things that want to move register tweens or springs with an `Animator`, call `tick`
+//! once per frame and read back the current values when drawing.
+
+use alloc::vec::Vec;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use micromath::F32Ext;
+
+// ---------- easing ----------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps linear progress from 0 to 1 onto the eased progress.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => {
+                let u = 1.0 - t;
+                1.0 - u * u * u
+            }
+            Easing::EaseInOut => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    let u = -2.0 * t + 2.0;
+                    1.0 - u * u * u / 2.0
+                }
+            }
+        }
+    }
+}
+
+// ---------- values ----------
+
+/// Something that can be blended between two values.
+pub trait Lerp: Copy {
+    fn lerp(from: Self, to: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+impl Lerp for i32 {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + ((to - from) as f32 * t).round() as i32
+    }
+}
+
+impl Lerp for Point {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        Point::new(i32::lerp(from.x, to.x, t), i32::lerp(from.y, to.y, t))
+    }
+}
+
+impl Lerp for Rgb565 {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        let channel = |a: u8, b: u8| i32::lerp(a as i32, b as i32, t) as u8;
+        Rgb565::new(
+            channel(from.r(), to.r()),
+            channel(from.g(), to.g()),
+            channel(from.b(), to.b()),
+        )
+    }
+}
+
+/// The kinds of values the `Animator` can hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Number(f32),
+    Point(Point),
+    Color(Rgb565),
+}
+
+impl Lerp for Value {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        match (from, to) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(f32::lerp(a, b, t)),
+            (Value::Point(a), Value::Point(b)) => Value::Point(Point::lerp(a, b, t)),
+            (Value::Color(a), Value::Color(b)) => Value::Color(Rgb565::lerp(a, b, t)),
+            // mismatched kinds just snap to the end
+            (_, to) => to,
+        }
+    }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self {
+        Value::Number(v)
+    }
+}
+
+impl From<Point> for Value {
+    fn from(v: Point) -> Self {
+        Value::Point(v)
+    }
+}
+
+impl From<Rgb565> for Value {
+    fn from(v: Rgb565) -> Self {
+        Value::Color(v)
+    }
+}
+
+// ---------- tweens ----------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    Once,
+    Loop,
+    /// go to the end and back again, forever
+    PingPong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tween<T: Lerp> {
+    pub from: T,
+    pub to: T,
+    pub duration_ms: u64,
+    pub easing: Easing,
+    pub repeat: Repeat,
+    start_ms: u64,
+}
+
+impl<T: Lerp> Tween<T> {
+    pub fn new(from: T, to: T, duration_ms: u64, easing: Easing) -> Self {
+        Tween {
+            from,
+            to,
+            duration_ms: duration_ms.max(1),
+            easing,
+            repeat: Repeat::Once,
+            start_ms: 0,
+        }
+    }
+
+    pub fn repeat(mut self, repeat: Repeat) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    pub fn start_at(mut self, now_ms: u64) -> Self {
+        self.start_ms = now_ms;
+        self
+    }
+
+    /// Linear progress from 0 to 1, taking repeats into account.
+    pub fn progress(&self, now_ms: u64) -> f32 {
+        let elapsed = now_ms.saturating_sub(self.start_ms);
+        match self.repeat {
+            Repeat::Once => (elapsed as f32 / self.duration_ms as f32).min(1.0),
+            Repeat::Loop => (elapsed % self.duration_ms) as f32 / self.duration_ms as f32,
+            Repeat::PingPong => {
+                let cycle = elapsed % (self.duration_ms * 2);
+                if cycle < self.duration_ms {
+                    cycle as f32 / self.duration_ms as f32
+                } else {
+                    (self.duration_ms * 2 - cycle) as f32 / self.duration_ms as f32
+                }
+            }
+        }
+    }
+
+    pub fn value_at(&self, now_ms: u64) -> T {
+        T::lerp(self.from, self.to, self.easing.apply(self.progress(now_ms)))
+    }
+
+    pub fn is_done(&self, now_ms: u64) -> bool {
+        self.repeat == Repeat::Once && now_ms >= self.start_ms + self.duration_ms
+    }
+}
+
+// ---------- springs ----------
+
+/// A damped spring pulling a number towards a target. Good for things that follow
+/// input, since changing the target mid-flight stays smooth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spring {
+    pub value: f32,
+    pub velocity: f32,
+    pub target: f32,
+    pub stiffness: f32,
+    pub damping: f32,
+}
+
+impl Spring {
+    pub fn new(value: f32, target: f32) -> Self {
+        Spring {
+            value,
+            velocity: 0.0,
+            target,
+            stiffness: 170.0,
+            damping: 26.0,
+        }
+    }
+
+    pub fn with_params(mut self, stiffness: f32, damping: f32) -> Self {
+        self.stiffness = stiffness;
+        self.damping = damping;
+        self
+    }
+
+    pub fn step(&mut self, dt_ms: u64) {
+        // small fixed sub steps keep the integration stable at low frame rates
+        let mut remaining = dt_ms as f32 / 1000.0;
+        while remaining > 0.0 {
+            let dt = remaining.min(0.004);
+            let force = -self.stiffness * (self.value - self.target) - self.damping * self.velocity;
+            self.velocity += force * dt;
+            self.value += self.velocity * dt;
+            remaining -= dt;
+        }
+    }
+
+    pub fn is_settled(&self) -> bool {
+        (self.value - self.target).abs() < 0.01 && self.velocity.abs() < 0.01
+    }
+}
+
+// ---------- animator ----------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimId(usize);
+
+enum Anim {
+    Tween(Tween<Value>),
+    Spring(Spring),
+}
+
+struct Entry {
+    anim: Anim,
+    current: Value,
+    running: bool,
+}
+
+/// Holds every running animation. Register tweens or springs, call `tick` once per
+/// frame, then read the values when drawing.
+pub struct Animator {
+    entries: Vec<Option<Entry>>,
+    now_ms: u64,
+    started: bool,
+}
+
+impl Default for Animator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Animator {
+            entries: Vec::new(),
+            now_ms: 0,
+            started: false,
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) -> AnimId {
+        if let Some(i) = self.entries.iter().position(|e| e.is_none()) {
+            self.entries[i] = Some(entry);
+            AnimId(i)
+        } else {
+            self.entries.push(Some(entry));
+            AnimId(self.entries.len() - 1)
+        }
+    }
+
+    /// Starts a tween now. Any kind of value which converts into `Value` works.
+    pub fn tween<T: Into<Value>>(&mut self, from: T, to: T, duration_ms: u64, easing: Easing, repeat: Repeat) -> AnimId {
+        let tween = Tween::new(from.into(), to.into(), duration_ms, easing)
+            .repeat(repeat)
+            .start_at(self.now_ms);
+        self.insert(Entry {
+            current: tween.from,
+            anim: Anim::Tween(tween),
+            running: true,
+        })
+    }
+
+    pub fn spring(&mut self, spring: Spring) -> AnimId {
+        self.insert(Entry {
+            current: Value::Number(spring.value),
+            anim: Anim::Spring(spring),
+            running: true,
+        })
+    }
+
+    /// Points a spring somewhere new without restarting it.
+    pub fn set_target(&mut self, id: AnimId, target: f32) {
+        if let Some(Some(entry)) = self.entries.get_mut(id.0) {
+            if let Anim::Spring(spring) = &mut entry.anim {
+                spring.target = target;
+                entry.running = true;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: AnimId) {
+        if let Some(slot) = self.entries.get_mut(id.0) {
+            *slot = None;
+        }
+    }
+
+    /// Advances every animation to the given time.
+    pub fn tick(&mut self, now_ms: u64) {
+        let first = !self.started;
+        self.started = true;
+        // a long pause shouldn't fling the springs
+        let dt = if first { 0 } else { now_ms.saturating_sub(self.now_ms).min(100) };
+        self.now_ms = now_ms;
+        for entry in self.entries.iter_mut().flatten() {
+            if !entry.running {
+                continue;
+            }
+            match &mut entry.anim {
+                // tweens added before the first tick start now
+                Anim::Tween(tween) if first => {
+                    *tween = tween.start_at(now_ms);
+                    entry.current = tween.value_at(now_ms);
+                }
+                Anim::Tween(tween) => {
+                    entry.current = tween.value_at(now_ms);
+                    entry.running = !tween.is_done(now_ms);
+                }
+                Anim::Spring(spring) => {
+                    spring.step(dt);
+                    if spring.is_settled() {
+                        spring.value = spring.target;
+                        spring.velocity = 0.0;
+                        entry.running = false;
+                    }
+                    entry.current = Value::Number(spring.value);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: AnimId) -> Option<Value> {
+        self.entries.get(id.0)?.as_ref().map(|e| e.current)
+    }
+
+    pub fn number(&self, id: AnimId) -> f32 {
+        match self.get(id) {
+            Some(Value::Number(v)) => v,
+            _ => 0.0,
+        }
+    }
+
+    pub fn point(&self, id: AnimId) -> Point {
+        match self.get(id) {
+            Some(Value::Point(v)) => v,
+            _ => Point::zero(),
+        }
+    }
+
+    pub fn color(&self, id: AnimId) -> Rgb565 {
+        match self.get(id) {
+            Some(Value::Color(v)) => v,
+            _ => Rgb565::BLACK,
+        }
+    }
+
+    pub fn is_running(&self, id: AnimId) -> bool {
+        matches!(self.entries.get(id.0), Some(Some(e)) if e.running)
+    }
+
+    /// True if anything is still moving, so callers can skip redrawing when idle.
+    pub fn any_running(&self) -> bool {
+        self.entries.iter().flatten().any(|e| e.running)
+    }
+}
+
+// ---------- frame timing ----------
+
+/// Frame rate and frame time counter. Feed it the length of each frame and it keeps
+/// the FPS over the last second plus the average and worst frame time.
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    pub fps: u32,
+    pub avg_frame_us: u32,
+    pub max_frame_us: u32,
+    /// time spent doing work, as opposed to waiting for the next frame
+    pub avg_busy_us: u32,
+    window_us: u64,
+    window_frames: u32,
+    window_busy_us: u64,
+    window_max_us: u32,
+}
+
+impl FrameStats {
+    pub fn record(&mut self, frame_us: u64, busy_us: u64) {
+        self.window_us += frame_us;
+        self.window_busy_us += busy_us;
+        self.window_frames += 1;
+        self.window_max_us = self.window_max_us.max(frame_us as u32);
+        if self.window_us >= 1_000_000 {
+            self.fps = (self.window_frames as u64 * 1_000_000 / self.window_us) as u32;
+            self.avg_frame_us = (self.window_us / self.window_frames as u64) as u32;
+            self.avg_busy_us = (self.window_busy_us / self.window_frames as u64) as u32;
+            self.max_frame_us = self.window_max_us;
+            self.window_us = 0;
+            self.window_busy_us = 0;
+            self.window_frames = 0;
+            self.window_max_us = 0;
+        }
+    }
+}
+
+pub struct FrameInfo {
+    pub now_ms: u64,
+    /// time since the previous frame
+    pub dt_ms: u64,
+    pub frame: u64,
+}
+
+/// Paces a render loop at a target frame rate. If frames run long the schedule skips
+/// ahead instead of trying to catch up.
+pub struct FrameScheduler {
+    period: Duration,
+    deadline: Instant,
+    last: Instant,
+    work_start: Instant,
+    frame: u64,
+    pub stats: FrameStats,
+}
+
+impl FrameScheduler {
+    pub fn new(target_fps: u32) -> Self {
+        let now = Instant::now();
+        FrameScheduler {
+            period: Duration::from_hz(target_fps.max(1) as u64),
+            deadline: now,
+            last: now,
+            work_start: now,
+            frame: 0,
+            stats: FrameStats::default(),
+        }
+    }
+
+    /// Waits for the next frame and returns its timing.
+    pub async fn next_frame(&mut self) -> FrameInfo {
+        let busy = Instant::now().saturating_duration_since(self.work_start);
+        self.deadline += self.period;
+        Timer::at(self.deadline).await;
+        let now = Instant::now();
+        if self.deadline + self.period < now {
+            // too far behind, start counting from now
+            self.deadline = now;
+        }
+        let dt = now.saturating_duration_since(self.last);
+        self.stats.record(dt.as_micros(), busy.as_micros());
+        self.last = now;
+        self.work_start = now;
+        self.frame += 1;
+        FrameInfo {
+            now_ms: now.as_millis(),
+            dt_ms: dt.as_millis(),
+            frame: self.frame,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EASINGS: [Easing; 4] = [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut];
+
+    #[test]
+    fn easing_endpoints() {
+        for easing in EASINGS {
+            assert_eq!(easing.apply(0.0), 0.0, "{easing:?}");
+            assert_eq!(easing.apply(1.0), 1.0, "{easing:?}");
+            // progress outside 0 to 1 is clamped
+            assert_eq!(easing.apply(-0.5), 0.0, "{easing:?}");
+            assert_eq!(easing.apply(2.0), 1.0, "{easing:?}");
+            let mut last = 0.0;
+            for i in 1..=100 {
+                let eased = easing.apply(i as f32 / 100.0);
+                assert!(eased >= last, "{easing:?} goes back at {i}");
+                last = eased;
+            }
+        }
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert!(Easing::EaseIn.apply(0.5) < 0.5);
+        assert!(Easing::EaseOut.apply(0.5) > 0.5);
+    }
+
+    #[test]
+    fn tweens_finish_at_their_end() {
+        let tween = Tween::new(Point::new(0, 0), Point::new(100, -50), 200, Easing::EaseOut).start_at(1000);
+        assert_eq!(tween.value_at(0), Point::new(0, 0));
+        assert_eq!(tween.value_at(1000), Point::new(0, 0));
+        assert!(!tween.is_done(1199));
+        assert!(tween.is_done(1200));
+        assert_eq!(tween.value_at(1200), Point::new(100, -50));
+        assert_eq!(tween.value_at(5000), Point::new(100, -50));
+
+        let looping = Tween::new(0.0, 10.0, 100, Easing::Linear).repeat(Repeat::Loop);
+        assert_eq!(looping.value_at(150), 5.0);
+        assert!(!looping.is_done(1000));
+        let ping_pong = Tween::new(0.0, 10.0, 100, Easing::Linear).repeat(Repeat::PingPong);
+        assert_eq!(ping_pong.value_at(100), 10.0);
+        assert_eq!(ping_pong.value_at(150), 5.0);
+        assert_eq!(ping_pong.value_at(200), 0.0);
+    }
+
+    #[test]
+    fn animated_tweens_stop_running() {
+        let mut animator = Animator::new();
+        let id = animator.tween(Rgb565::BLACK, Rgb565::WHITE, 100, Easing::EaseInOut, Repeat::Once);
+        // the first tick starts it, whatever the time
+        animator.tick(5000);
+        assert_eq!(animator.color(id), Rgb565::BLACK);
+        animator.tick(5050);
+        assert!(animator.is_running(id));
+        animator.tick(5100);
+        assert_eq!(animator.color(id), Rgb565::WHITE);
+        assert!(!animator.is_running(id));
+        assert!(!animator.any_running());
+
+        animator.remove(id);
+        assert_eq!(animator.get(id), None);
+    }
+
+    #[test]
+    fn springs_settle_on_their_target() {
+        let mut spring = Spring::new(0.0, 100.0);
+        let mut steps = 0;
+        while !spring.is_settled() {
+            spring.step(16);
+            steps += 1;
+            // the default is just short of critically damped, so it barely goes past
+            assert!(spring.value <= 100.01, "{}", spring.value);
+            assert!(steps < 100, "still at {} after {steps} frames", spring.value);
+        }
+
+        let mut animator = Animator::new();
+        let id = animator.spring(Spring::new(0.0, 100.0));
+        let mut now = 0;
+        while animator.is_running(id) {
+            animator.tick(now);
+            now += 16;
+            assert!(now < 2000);
+        }
+        // it snaps exactly onto the target once settled
+        assert_eq!(animator.number(id), 100.0);
+
+        // a new target sets it going again from where it is
+        animator.set_target(id, -20.0);
+        assert!(animator.is_running(id));
+        animator.tick(now);
+        assert!(animator.number(id) < 100.0);
+        // and a long pause doesn't fling it past the target
+        animator.tick(now + 60_000);
+        assert!(animator.number(id) > -20.0);
+    }
+}
@@ -0,0 +1,390 @@
+//! Sound out of the speaker.
+//!
+//! `AudioSink::new` sets up I2S0 with a circular DMA buffer and splits it in two. The
+//! `AudioWriter` goes to whatever makes the sound and takes interleaved i16 samples.
+//! The `AudioPlayer` runs in its own task and keeps the DMA buffer topped up. They
+//! share a lock free queue, so a decoder can work ahead of the speaker and doesn't have
+//! to keep up with the DMA a chunk at a time.
+//!
+//! When the queue runs dry the player fills in silence and counts an underrun, unless
+//! the writer said it was finished. Samples written after that wait behind the silence,
+//! so the latency is at most the queue plus the DMA buffer, about 160ms.
+//!
+//! The player scales everything by the master volume as it goes to the DMA buffer. The
+//! gain ramps rather than jumps, fades in when sound starts after silence, and fades out
+//! when the writer calls `stop`, which then throws away whatever was still queued.
+//! `clear` throws it away without the fade, for seeking, and what's written next fades
+//! in.
+//!
+//! Before the volume, everything goes through the `Dsp` from the dsp module, an equalizer
+//! and limiter set up from the `Preset` the writer picks. Flat, the default, skips it.
+//! What comes out of that also goes to `SINK_TAP` for the visualizer.
+
+use crate::dsp::{Dsp, Preset};
+use crate::visualizer::Tap;
+use crate::volume::{Ramp, Volume, DEFAULT_STEP, GAINS, RAMP_MS};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use embassy_time::Timer;
+use esp_hal::dma_circular_buffers;
+use esp_hal::i2s::master::asynch::I2sWriteDmaTransferAsync;
+use esp_hal::i2s::master::{Config, ConfigError, DataFormat, Error, I2s};
+use esp_hal::peripherals::{DMA_CH0, GPIO5, GPIO6, GPIO7, I2S0};
+use esp_hal::time::Rate;
+use heapless::spsc::{Consumer, Producer, Queue};
+use log::info;
+use static_cell::StaticCell;
+
+/// frames queued between the writer and the player, about 90ms at 44.1kHz
+pub const QUEUE_FRAMES: usize = 4096;
+// three DMA descriptors, each one is a little under 1024 frames
+const DMA_BYTES: usize = 3 * 4092;
+const BYTES_PER_FRAME: usize = 4;
+
+/// The peripherals the speaker is wired to. `Wrapper::init` keeps these for
+/// `AudioSink::new`.
+pub struct AudioPeripherals {
+    pub i2s: I2S0<'static>,
+    pub dma: DMA_CH0<'static>,
+    pub bclk: GPIO7<'static>,
+    pub ws: GPIO5<'static>,
+    pub dout: GPIO6<'static>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    /// channels in the samples given to the writer. Mono is played on both sides,
+    /// anything past the second channel is dropped.
+    pub channels: u8,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: 44100,
+            channels: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioError {
+    Config(ConfigError),
+    I2s(Error),
+}
+
+impl From<ConfigError> for AudioError {
+    fn from(e: ConfigError) -> Self {
+        AudioError::Config(e)
+    }
+}
+
+impl From<Error> for AudioError {
+    fn from(e: Error) -> Self {
+        AudioError::I2s(e)
+    }
+}
+
+/// Counters shared by the writer and the player.
+struct Counters {
+    frames_played: AtomicU32,
+    underruns: AtomicU32,
+    late: AtomicU32,
+    finished: AtomicBool,
+}
+
+static COUNTERS: Counters = Counters {
+    frames_played: AtomicU32::new(0),
+    underruns: AtomicU32::new(0),
+    late: AtomicU32::new(0),
+    finished: AtomicBool::new(true),
+};
+
+/// The master volume, set by the writer and followed by the player.
+struct Master {
+    /// Q16 gain to ramp to
+    gain: AtomicU32,
+    /// fading out to stop, cleared by the player once it's done
+    stopping: AtomicBool,
+    /// throwing away the queue without a fade, cleared by the player once it's empty
+    clearing: AtomicBool,
+    /// the `Preset` the player should be using, as its number
+    preset: AtomicU8,
+}
+
+static MASTER: Master = Master {
+    gain: AtomicU32::new(GAINS[DEFAULT_STEP as usize]),
+    stopping: AtomicBool::new(false),
+    clearing: AtomicBool::new(false),
+    preset: AtomicU8::new(Preset::Flat as u8),
+};
+
+/// The last samples played, mixed to mono.
+pub static SINK_TAP: Tap = Tap::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SinkStats {
+    /// frames sent to the DMA buffer, not counting silence
+    pub frames_played: u32,
+    /// times the queue ran dry in the middle of playing
+    pub underruns: u32,
+    /// times the player itself fell behind and the DMA played the buffer over again
+    pub late: u32,
+    pub queued_frames: u32,
+    /// roughly how long a sample written now takes to come out of the speaker
+    pub latency_ms: u32,
+}
+
+pub struct AudioSink;
+
+impl AudioSink {
+    /// Starts I2S output and returns the two halves. This can only be done once, the
+    /// peripherals can't be given back.
+    pub fn new(
+        peripherals: AudioPeripherals,
+        config: AudioConfig,
+    ) -> Result<(AudioWriter, AudioPlayer), AudioError> {
+        let (_, _, tx_buffer, tx_descriptors) = dma_circular_buffers!(0, DMA_BYTES);
+        // start with silence
+        tx_buffer.fill(0);
+
+        let i2s = I2s::new(
+            peripherals.i2s,
+            peripherals.dma,
+            Config::new_tdm_philips()
+                .with_data_format(DataFormat::Data16Channel16)
+                .with_sample_rate(Rate::from_hz(config.sample_rate)),
+        )?
+        .into_async();
+        let i2s_tx = i2s
+            .i2s_tx
+            .with_bclk(peripherals.bclk)
+            .with_ws(peripherals.ws)
+            .with_dout(peripherals.dout)
+            .build(tx_descriptors);
+        let transfer = i2s_tx.write_dma_circular_async(tx_buffer)?;
+        info!("audio started at {}Hz, {} channels", config.sample_rate, config.channels);
+        SINK_TAP.set_sample_rate(config.sample_rate);
+
+        static QUEUE: StaticCell<Queue<[i16; 2], QUEUE_FRAMES>> = StaticCell::new();
+        let (producer, consumer) = QUEUE.init(Queue::new()).split();
+        Ok((
+            AudioWriter { producer, config },
+            AudioPlayer {
+                transfer,
+                consumer,
+                was_playing: false,
+                ramp: Ramp::new(config.sample_rate, RAMP_MS),
+                dsp: Dsp::new(Preset::Flat, config.sample_rate),
+                sample_rate: config.sample_rate,
+            },
+        ))
+    }
+}
+
+/// Takes samples from whatever is making sound.
+pub struct AudioWriter {
+    producer: Producer<'static, [i16; 2], QUEUE_FRAMES>,
+    config: AudioConfig,
+}
+
+impl AudioWriter {
+    pub fn config(&self) -> AudioConfig {
+        self.config
+    }
+
+    /// Frames that can be written without waiting.
+    pub fn space(&self) -> usize {
+        self.producer.capacity() - self.producer.len()
+    }
+
+    /// Queues as many whole frames as fit and returns how many samples were taken.
+    pub fn write(&mut self, samples: &[i16]) -> usize {
+        let channels = self.config.channels.max(1) as usize;
+        let mut taken = 0;
+        for frame in samples.chunks_exact(channels) {
+            let stereo = if channels == 1 {
+                [frame[0], frame[0]]
+            } else {
+                [frame[0], frame[1]]
+            };
+            if self.producer.enqueue(stereo).is_err() {
+                break;
+            }
+            taken += channels;
+        }
+        if taken > 0 {
+            COUNTERS.finished.store(false, Ordering::Relaxed);
+        }
+        taken
+    }
+
+    /// Queues all the samples, waiting for the player to make room.
+    pub async fn write_all(&mut self, samples: &[i16]) {
+        let mut samples = samples;
+        loop {
+            let taken = self.write(samples);
+            samples = &samples[taken..];
+            if samples.len() < self.config.channels.max(1) as usize {
+                break;
+            }
+            // a DMA descriptor takes about 20ms to play, no point checking much sooner
+            Timer::after_millis(5).await;
+        }
+    }
+
+    /// Sets the master volume. The player gets there over a few milliseconds.
+    pub fn set_volume(&mut self, volume: Volume) {
+        MASTER.gain.store(volume.gain(), Ordering::Relaxed);
+    }
+
+    /// Sets the equalizer and limiter. The player changes over at its next refill.
+    pub fn set_preset(&mut self, preset: Preset) {
+        MASTER.preset.store(preset as u8, Ordering::Relaxed);
+    }
+
+    /// Fades out and throws away everything queued, for stopping or pausing without a
+    /// click. Returns once it's quiet.
+    pub async fn stop(&mut self) {
+        MASTER.stopping.store(true, Ordering::Relaxed);
+        while MASTER.stopping.load(Ordering::Relaxed) {
+            Timer::after_millis(2).await;
+        }
+    }
+
+    /// Throws away everything queued straight away, for seeking. What's already gone to
+    /// the DMA buffer still plays, then the next samples written fade in. Returns once
+    /// the queue is empty.
+    pub async fn clear(&mut self) {
+        MASTER.clearing.store(true, Ordering::Relaxed);
+        while MASTER.clearing.load(Ordering::Relaxed) {
+            Timer::after_millis(2).await;
+        }
+    }
+
+    /// Says there's nothing more to play for now, so running dry isn't an underrun.
+    pub fn finish(&mut self) {
+        COUNTERS.finished.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits until everything queued has gone to the DMA buffer.
+    pub async fn drain(&mut self) {
+        while self.producer.len() > 0 {
+            Timer::after_millis(5).await;
+        }
+    }
+
+    pub fn stats(&self) -> SinkStats {
+        let queued = self.producer.len();
+        let frames = queued + DMA_BYTES / BYTES_PER_FRAME;
+        SinkStats {
+            frames_played: COUNTERS.frames_played.load(Ordering::Relaxed),
+            underruns: COUNTERS.underruns.load(Ordering::Relaxed),
+            late: COUNTERS.late.load(Ordering::Relaxed),
+            queued_frames: queued as u32,
+            latency_ms: (frames as u64 * 1000 / self.config.sample_rate as u64) as u32,
+        }
+    }
+}
+
+/// Moves queued frames into the DMA buffer. Give it its own task and call `run`.
+pub struct AudioPlayer {
+    transfer: I2sWriteDmaTransferAsync<'static, &'static mut [u8]>,
+    consumer: Consumer<'static, [i16; 2], QUEUE_FRAMES>,
+    was_playing: bool,
+    ramp: Ramp,
+    dsp: Dsp,
+    sample_rate: u32,
+}
+
+impl AudioPlayer {
+    pub async fn run(mut self) -> ! {
+        loop {
+            self.pump().await;
+        }
+    }
+
+    /// Waits for the DMA to free up some of the buffer, then refills it.
+    pub async fn pump(&mut self) {
+        if let Err(e) = self.transfer.available().await {
+            // the DMA went all the way round the buffer before we got to it
+            COUNTERS.late.fetch_add(1, Ordering::Relaxed);
+            info!("audio fell behind {:?}", e);
+            // this doesn't wait, so give the other tasks a turn
+            Timer::after_millis(1).await;
+        }
+        let preset = Preset::from_index(MASTER.preset.load(Ordering::Relaxed));
+        if preset != self.dsp.preset {
+            self.dsp = Dsp::new(preset, self.sample_rate);
+        }
+        let consumer = &mut self.consumer;
+        let was_playing = &mut self.was_playing;
+        let ramp = &mut self.ramp;
+        let dsp = &mut self.dsp;
+        if let Err(e) = self
+            .transfer
+            .push_with(|buf| fill(consumer, was_playing, ramp, dsp, buf))
+            .await
+        {
+            info!("audio push failed {:?}", e);
+        }
+    }
+}
+
+// copies frames through the dsp and at the master volume as 16 bit little endian, left
+// then right, and fills the rest with silence. whatever isn't written would be played
+// again when the DMA comes round.
+fn fill(
+    consumer: &mut Consumer<'static, [i16; 2], QUEUE_FRAMES>,
+    was_playing: &mut bool,
+    ramp: &mut Ramp,
+    dsp: &mut Dsp,
+    buf: &mut [u8],
+) -> usize {
+    let frames = buf.len() / BYTES_PER_FRAME;
+    if MASTER.clearing.load(Ordering::Relaxed) {
+        while consumer.dequeue().is_some() {}
+        // whatever comes next doesn't follow on from what was playing
+        *was_playing = false;
+        MASTER.clearing.store(false, Ordering::Relaxed);
+    }
+    let stopping = MASTER.stopping.load(Ordering::Relaxed);
+    let target = if stopping { 0 } else { MASTER.gain.load(Ordering::Relaxed) };
+    if !*was_playing {
+        // coming out of silence, so fade in
+        ramp.set(0);
+    }
+    let mut played = 0;
+    for out in buf.chunks_exact_mut(BYTES_PER_FRAME) {
+        if stopping && ramp.gain() == 0 {
+            break;
+        }
+        let Some(frame) = consumer.dequeue() else {
+            break;
+        };
+        let frame = dsp.process(frame);
+        SINK_TAP.push(((frame[0] as i32 + frame[1] as i32) / 2) as i16);
+        let [left, right] = ramp.apply(frame, target);
+        out[..2].copy_from_slice(&left.to_le_bytes());
+        out[2..].copy_from_slice(&right.to_le_bytes());
+        played += 1;
+    }
+    COUNTERS.frames_played.fetch_add(played as u32, Ordering::Relaxed);
+    if stopping && (played < frames || ramp.gain() == 0) {
+        // faded out, or ran out before getting there
+        while consumer.dequeue().is_some() {}
+        COUNTERS.finished.store(true, Ordering::Relaxed);
+        MASTER.stopping.store(false, Ordering::Relaxed);
+    }
+    if played < frames {
+        if (played > 0 || *was_playing) && !COUNTERS.finished.load(Ordering::Relaxed) {
+            COUNTERS.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        buf[played * BYTES_PER_FRAME..frames * BYTES_PER_FRAME].fill(0);
+        for _ in played..frames {
+            SINK_TAP.push(0);
+        }
+    }
+    *was_playing = played == frames;
+    frames * BYTES_PER_FRAME
+}
@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use embassy_executor::Spawner;
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::Text;
+use esp_hal::clock::CpuClock;
+use log::info;
+use rust_tdeck_experiments::anim::{AnimId, Animator, Easing, FrameScheduler, FrameStats, Repeat, Spring};
+use rust_tdeck_experiments::game::{render, screen_bounds, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+const BOX_SIZE: Size = Size::new(30, 30);
+const CURSOR_SIZE: Size = Size::new(12, 12);
+const STATS_AREA: Rectangle = Rectangle::new(Point::new(0, 226), Size::new(320, 14));
+
+struct Demo {
+    animator: Animator,
+    slider: AnimId,
+    fade: AnimId,
+    cursor_x: AnimId,
+    cursor_y: AnimId,
+    stats: FrameStats,
+}
+
+impl Demo {
+    fn box_bounds(&self) -> Rectangle {
+        Rectangle::new(self.animator.point(self.slider), BOX_SIZE)
+    }
+
+    fn cursor_bounds(&self) -> Rectangle {
+        let x = self.animator.number(self.cursor_x) as i32;
+        let y = self.animator.number(self.cursor_y) as i32;
+        Rectangle::new(Point::new(x, y), CURSOR_SIZE)
+    }
+}
+
+impl Scene for Demo {
+    fn draw(&self, target: &mut FrameBuffer) {
+        // a panel that fades between two colors
+        Rectangle::new(Point::new(20, 120), Size::new(280, 90))
+            .into_styled(PrimitiveStyle::with_fill(self.animator.color(self.fade)))
+            .draw(target)
+            .unwrap();
+        self.box_bounds()
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::YELLOW))
+            .draw(target)
+            .unwrap();
+        self.cursor_bounds()
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+            .draw(target)
+            .unwrap();
+
+        let style = MonoTextStyle::new(&FONT_6X10, Rgb565::GREEN);
+        let text = format!(
+            "fps {}  frame {}.{}ms  max {}ms  busy {}.{}ms",
+            self.stats.fps,
+            self.stats.avg_frame_us / 1000,
+            self.stats.avg_frame_us / 100 % 10,
+            self.stats.max_frame_us / 1000,
+            self.stats.avg_busy_us / 1000,
+            self.stats.avg_busy_us / 100 % 10,
+        );
+        Text::new(&text, Point::new(4, 236), style).draw(target).unwrap();
+    }
+}
+
+#[esp_rtos::main]
+async fn main(_spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    info!("running");
+
+    let mut animator = Animator::new();
+    let slider = animator.tween(
+        Point::new(20, 40),
+        Point::new(270, 40),
+        1500,
+        Easing::EaseInOut,
+        Repeat::PingPong,
+    );
+    let fade = animator.tween(
+        Rgb565::CSS_DARK_BLUE,
+        Rgb565::CSS_DARK_RED,
+        3000,
+        Easing::Linear,
+        Repeat::PingPong,
+    );
+    let cursor_x = animator.spring(Spring::new(160.0, 160.0));
+    let cursor_y = animator.spring(Spring::new(160.0, 160.0).with_params(120.0, 14.0));
+    let mut demo = Demo {
+        animator,
+        slider,
+        fade,
+        cursor_x,
+        cursor_y,
+        stats: FrameStats::default(),
+    };
+
+    let mut scheduler = FrameScheduler::new(30);
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(screen_bounds());
+    let mut target = Point::new(160, 160);
+
+    loop {
+        let frame = scheduler.next_frame().await;
+
+        // the trackball moves the target and the cursor springs after it
+        wrapper.poll_trackball();
+        let [left, right, up, down, _] = wrapper.trackball_changes();
+        target.x = (target.x + (right as i32 - left as i32) * 20).clamp(0, 300);
+        target.y = (target.y + (down as i32 - up as i32) * 20).clamp(0, 210);
+        demo.animator.set_target(demo.cursor_x, target.x as f32);
+        demo.animator.set_target(demo.cursor_y, target.y as f32);
+
+        let old_box = demo.box_bounds();
+        let old_cursor = demo.cursor_bounds();
+        demo.animator.tick(frame.now_ms);
+        demo.stats = scheduler.stats.clone();
+
+        dirty.add_moved(old_box, demo.box_bounds());
+        dirty.add_moved(old_cursor, demo.cursor_bounds());
+        dirty.add(Rectangle::new(Point::new(20, 120), Size::new(280, 90)));
+        if frame.frame % 30 == 0 {
+            dirty.add(STATS_AREA);
+        }
+        render(&demo, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+    }
+}
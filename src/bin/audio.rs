@@ -1,43 +1,35 @@
+//! Quacks, then plays a sawtooth until it's reset, through the [audio](../audio.rs)
+//! module's `AudioSink`.
+
 #![no_std]
 #![no_main]
-#![deny(
-    clippy::mem_forget,
-    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
-    holding buffers for the duration of a data transfer."
-)]
+extern crate alloc;
 
+use embassy_executor::Spawner;
 use esp_hal::clock::CpuClock;
 use esp_hal::delay::Delay;
 use esp_hal::gpio::Level::High;
 use esp_hal::gpio::{Output, OutputConfig};
-use esp_hal::i2s::master::{Config, DataFormat, I2s};
-use esp_hal::time::Rate;
-use esp_hal::{dma_buffers, main};
 use esp_hal::timer::timg::TimerGroup;
-use log::info;
+use log::{error, info};
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPeripherals, AudioPlayer, AudioSink};
+use rust_tdeck_experiments::mixer::Clip;
 
 #[panic_handler]
-fn panic(_: &core::panic::PanicInfo) -> ! {
+fn panic(nfo: &core::panic::PanicInfo) -> ! {
+    error!("PANIC: {:?}", nfo);
     loop {}
 }
 
-const SINE: [i16; 64] = [
-    0, 3211, 6392, 9511, 12539, 15446, 18204, 20787, 23169, 25329, 27244, 28897, 30272, 31356,
-    32137, 32609, 32767, 32609, 32137, 31356, 30272, 28897, 27244, 25329, 23169, 20787, 18204,
-    15446, 12539, 9511, 6392, 3211, 0, -3211, -6392, -9511, -12539, -15446, -18204, -20787, -23169,
-    -25329, -27244, -28897, -30272, -31356, -32137, -32609, -32767, -32609, -32137, -31356, -30272,
-    -28897, -27244, -25329, -23169, -20787, -18204, -15446, -12539, -9511, -6392, -3211,
-];
-
 const QUACK: &[u8] = include_bytes!("quack.wav");
+const SAMPLE_RATE: u32 = 44_100;
+
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
-extern crate alloc;
-
-#[main]
-fn main() -> ! {
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
     esp_println::logger::init_logger_from_env();
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
@@ -52,83 +44,41 @@ fn main() -> ! {
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_rtos::start(timg0.timer0);
 
-    //     if #[cfg(any(feature = "esp32", feature = "esp32s2"))] {
-    //         let dma_channel = peripherals.DMA_I2S0;
-    //     } else {
-    //         let dma_channel = peripherals.DMA_CH0;
-    //     }
-    // }
-
-    // let dma_channel = peripherals.DMA_CH0;
-    // info!("peripherals {:?}",peripherals);
-
-    let (_, _, tx_buffer, tx_descriptors) = dma_buffers!(0, 32000 * 2);
+    let audio = AudioPeripherals {
+        i2s: peripherals.I2S0,
+        dma: peripherals.DMA_CH0,
+        bclk: peripherals.GPIO7,
+        ws: peripherals.GPIO5,
+        dout: peripherals.GPIO6,
+    };
+    // quack.wav is mono at 48kHz, the clip comes out at the sink's rate
+    let quack = Clip::from_wav(&mut &QUACK[..], SAMPLE_RATE).unwrap();
+    let config = AudioConfig {
+        sample_rate: SAMPLE_RATE,
+        channels: quack.channels as u8,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
 
-    let i2s = I2s::new(
-        peripherals.I2S0,
-        peripherals.DMA_CH0,
-        Config::new_tdm_philips()
-            .with_data_format(DataFormat::Data16Channel16)
-            .with_sample_rate(Rate::from_hz(44100))
-    );
-    
-    let mut i2s_tx = i2s.unwrap()
-        .i2s_tx
-        .with_bclk(peripherals.GPIO7)
-        .with_ws(peripherals.GPIO5)
-        .with_dout(peripherals.GPIO6)
-        .build(tx_descriptors);
-
-    let mut SAW: [i16; 256] = [0; 256];
-    for i in 0..256 {
-        SAW[i] = (i as i16) * 128;
-    }
+    info!("Start");
+    writer.write_all(&quack.samples).await;
 
     let mut saw_buffer = [0i16; 1024];
     generate_sawtooth(&mut saw_buffer, 10_000); // amplitude up to +/- 10k
-
-    // create unsafe data from the sine wave
-    let data = unsafe { core::slice::from_raw_parts(&SAW as *const _ as *const u8, SAW.len() * 2) };
-
-    // fill the buffer with the sine wave
-    let buffer = tx_buffer;
-    let mut idx = 0;
-    for i in 0..buffer.len() {
-        buffer[i] = QUACK[i % QUACK.len()];
-    }
-    // for i in 0..usize::max(data.len(), buffer.len()) {
-    //     buffer[i] = data[idx];
-    //
-    //     idx += 1;
-    //
-    //     if idx >= data.len() {
-    //         idx = 0;
-    //     }
-    // }
-
-    let mut filler = [0u8; 10000];
-    let mut idx = 32000 % data.len();
-
-    info!("Start");
-    let mut transaction = i2s_tx.write_dma_circular(buffer).unwrap();
     loop {
-        for i in 0..filler.len() {
-            filler[i] = data[(idx + i) % data.len()];
-        }
-        info!("Next");
-        info!("can push {:?}", transaction.available());
-
-        let written = transaction.push(&filler).unwrap();
-        idx = (idx + written) % data.len();
-        info!("written {}", written);
+        writer.write_all(&saw_buffer).await;
     }
 }
 
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
+
 fn generate_sawtooth(buffer: &mut [i16], amplitude: i16) {
-    let len = buffer.len() as i16;
-    for i in 0..buffer.len() {
+    let len = buffer.len() as i32;
+    for (i, sample) in buffer.iter_mut().enumerate() {
         // Linearly ramp from -amplitude to +amplitude
-        let value = ((i as i32 * 2 * amplitude as i32) / len as i32) - amplitude as i32;
-        buffer[i] = value as i16;
+        *sample = ((i as i32 * 2 * amplitude as i32) / len - amplitude as i32) as i16;
     }
 }
@@ -31,27 +31,14 @@
 #![no_main]
 extern crate alloc;
 
-use alloc::vec;
-use alloc::vec::Vec;
-use core::error::Error;
-use core::f32::consts::TAU;
-use core::time::Duration;
 use embassy_executor::Spawner;
-// use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
 use esp_hal::delay::Delay;
 use esp_hal::gpio::Level::High;
 use esp_hal::gpio::{Output, OutputConfig};
-use esp_hal::{
-    dma_buffers,
-    // i2s::master::{DataFormat, I2s, Standard},
-    time::Rate,
-    timer::timg::TimerGroup,
-};
-use esp_hal::i2s::master::{Config, DataFormat, I2s};
-use esp_rtos::main;
+use esp_hal::timer::timg::TimerGroup;
 use log::{error, info};
-use micromath::F32Ext;
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPeripherals, AudioPlayer, AudioSink};
 
 #[panic_handler]
 fn panic(nfo: &core::panic::PanicInfo) -> ! {
@@ -71,45 +58,8 @@ const SINE: [i16; 64] = [
     -28897, -27244, -25329, -23169, -20787, -18204, -15446, -12539, -9511, -6392, -3211,
 ];
 
-const TIMEOUT: Duration = Duration::from_millis(100);
 const SAMPLE_RATE_HZ: u32 = 44100;
 
-fn make_sawtooth(freq: f32, vol: f32) -> Vec<u8> {
-    let buffer_size = (SAMPLE_RATE_HZ as f32 / freq) as usize;
-    let mut buffer = vec![0; buffer_size * 4];
-    let mut value: f32 = 0.0;
-    let mut value_inc = 0.1 / (buffer_size as f32);
-
-    for i in (0..buffer.len()).step_by(4) {
-        let i_value = (value * vol * (i16::MAX as f32)) as i16 as u16;
-
-        buffer[i] = (i_value & 0x00ff) as u8;
-        buffer[i + 1] = ((i_value & 0xff00) >> 8) as u8;
-        buffer[i + 2] = (i_value & 0x00ff) as u8;
-        buffer[i + 3] = ((i_value & 0xff00) >> 8) as u8;
-        value += value_inc;
-
-        if value_inc > 0.0 && value > 1.0 {
-            value = 2.0 - value;
-            value_inc = -value_inc;
-        } else if value_inc < 0.0 && value < 1.0 {
-            value = -2.0 - value;
-            value_inc = -value_inc;
-        }
-    }
-
-    buffer
-}
-
-fn make_sawtooth_sample(freq: f32, vol: f32, i: usize) -> u16 {
-    let buffer_size = (SAMPLE_RATE_HZ as f32 / freq) as usize;
-    let mut value: f32 = 0.0;
-    let mut value_inc = 0.1 / (buffer_size as f32);
-    let value = value_inc * (i as f32);
-    let i_value = (value * vol * (i16::MAX as f32)) as i16 as u16;
-    return i_value;
-}
-
 #[esp_rtos::main]
 async fn main(spawner: Spawner) {
     esp_println::logger::init_logger_from_env();
@@ -129,68 +79,31 @@ async fn main(spawner: Spawner) {
     let delay = Delay::new();
     delay.delay_millis(1000);
 
-    let (_, _, tx_buffer, tx_descriptors) = dma_buffers!(0, 32000);
-
-    let i2s = I2s::new(
-        peripherals.I2S0,
-        peripherals.DMA_CH0,
-        Config::new_tdm_philips()
-            .with_data_format(DataFormat::Data16Channel16)
-            .with_sample_rate(Rate::from_hz(44100))
-    ).unwrap().into_async();
-
-    let i2s_tx = i2s.i2s_tx
-        .with_bclk(peripherals.GPIO7)
-        .with_ws(peripherals.GPIO5)
-        .with_dout(peripherals.GPIO6)
-        .build(tx_descriptors);
-
-    let data =
-        unsafe { core::slice::from_raw_parts(&SINE as *const _ as *const u8, SINE.len() * 2) };
-    // let data = make_sawtooth(240.0, 0.2);
-
-    let buffer = tx_buffer;
-    let mut idx = 0;
-    for i in 0..usize::min(data.len(), buffer.len()) {
-        buffer[i] = data[idx];
-
-        idx += 1;
-
-        if idx >= data.len() {
-            idx = 0;
-        }
-    }
-
-    let mut filler = [0u8; 10000];
-    let mut idx = 32000 % data.len();
+    let audio = AudioPeripherals {
+        i2s: peripherals.I2S0,
+        dma: peripherals.DMA_CH0,
+        bclk: peripherals.GPIO7,
+        ws: peripherals.GPIO5,
+        dout: peripherals.GPIO6,
+    };
+    let config = AudioConfig {
+        sample_rate: SAMPLE_RATE_HZ,
+        channels: 1,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
 
     info!("Start");
-    let mut transaction = i2s_tx.write_dma_circular_async(buffer).unwrap();
-    let freq = 120.0 * 2.0;
-    const OMEGA_INC: f32 = TAU / SAMPLE_RATE_HZ as f32;
-    let mut omega: f32 = 0.0;
-    let mut count = 0;
-    let mut vol: f32 = 0.1;
-    loop {
-        for i in (0..filler.len()).step_by(2) {
-            filler[i] = data[(idx + i) % data.len()];
-            filler[i + 1] = data[(idx + i + 1) % data.len()];
-            // let sample = ((omega * freq).sin() * vol * (i16::MAX as f32)) as u16;
-            // filler[i] = (sample & 0x00ff) as u8;
-            // filler[i + 1] = ((sample & 0xff00) >> 8) as u8;
-            // omega += OMEGA_INC;
-            // if omega >= TAU {
-            //     omega -= TAU;
-            // }
-        }
-        info!("Next");
-
-        let written = transaction.push(&filler).await.unwrap();
-        idx = (idx + written) % data.len();
-        info!("written {}", written);
-        count += 1;
-        if count >= 20 {
-            break;
-        }
+    // the table is one cycle, about 690Hz, and this plays it for about two seconds
+    for _ in 0..2 * SAMPLE_RATE_HZ as usize / SINE.len() {
+        writer.write_all(&SINE).await;
     }
+    writer.finish();
+    writer.drain().await;
+    info!("done {:?}", writer.stats());
+}
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
 }
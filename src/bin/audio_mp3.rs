@@ -1,35 +1,24 @@
 #![no_std]
 #![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
 
-use core::mem::MaybeUninit;
+use alloc::vec::Vec;
 use embassy_executor::Spawner;
-use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
-use embedded_sdmmc::{BlockDevice, File, SdCard, Timestamp, VolumeIdx, VolumeManager};
-// use critical_section as cs;
-// use esp_hal::{
-// clock::ClockControl,
-// dma::{Dma, DmaPriority},
-// entry,
-// gpio::Io,
-// i2s::{Channel as I2sChannel, DataFormat as I2sDataFmt, I2s, I2sTx, PinsBclkWsDout, Standard as I2sStd},
-// peripherals::Peripherals,
-// prelude::*,
-// spi::{Spi, SpiMode},
-// timer::TimerGroup,
-// };
 use esp_hal::clock::CpuClock;
-use esp_hal::delay::Delay;
-use esp_hal::dma::DmaTransferTx;
-use esp_hal::gpio::Level::High;
-use esp_hal::gpio::{Input, InputConfig, Output, OutputConfig, Pull};
-use esp_hal::i2s::master::{Config, DataFormat, Error, I2s, I2sTx};
-use esp_hal::spi::master::{Config as SpiConfig, Spi};
-use esp_hal::time::Rate;
-use esp_hal::timer::timg::TimerGroup;
-use esp_hal::{dma_buffers, dma_circular_buffers, main, Blocking};
-use heapless::spsc::Queue;
-// use panic_halt as _;
 use log::{error, info};
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPlayer, AudioSink};
+use rust_tdeck_experiments::mp3::{self, Mp3Stream};
+use rust_tdeck_experiments::reader::TextSource;
+use rust_tdeck_experiments::resample::{Quality, Resampler};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::volume::Volume;
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
 
 #[panic_handler]
 fn panic(nfo: &core::panic::PanicInfo) -> ! {
@@ -40,293 +29,104 @@ fn panic(nfo: &core::panic::PanicInfo) -> ! {
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
-extern crate alloc;
-
-// ---------- Config ----------
-// const CHUNK_BYTES: usize = 8192; // increase if you get underruns
-const CHUNK_BYTES: usize = 32000; // increase if you get underruns
-const WAV_HEADER_LEN: usize = 44;
 
-// T-Deck pins
-const SD_SCK: i32 = 39;
-const SD_MOSI: i32 = 40;
-const SD_MISO: i32 = 38;
-const SD_CS: i32 = 41;
+/*
+Plays an MP3 file from the SD card over and over, logging how far through it is. Constant
+and variable bitrate files both work, mono or stereo, at any sample rate. The speaker stays
+at 44.1kHz and the resample module converts the file to match.
+ */
 
-const I2S_BCLK: i32 = 42;
-const I2S_WS: i32 = 1;
-const I2S_DOUT: i32 = 2;
+const FILE_NAME: &str = "U2NONAME.MP3";
+const SINK_RATE: u32 = 44_100;
 
-// ---------- DMA buffers ----------
-#[repr(align(4))]
-struct Aligned<T>(T);
-static mut DMA_BUF_A: Aligned<[u32; CHUNK_BYTES / 4]> = Aligned([0; CHUNK_BYTES / 4]);
-static mut DMA_BUF_B: Aligned<[u32; CHUNK_BYTES / 4]> = Aligned([0; CHUNK_BYTES / 4]);
-
-// Queue to track which buffer is currently playing (0 or 1)
-static mut Q_STORAGE: MaybeUninit<Queue<u8, 4>> = MaybeUninit::uninit();
-
-// ---------- WAV info ----------
-#[derive(Clone, Copy, Debug)]
-struct WavInfo {
-    audio_format: u16, // 1 = PCM
-    num_channels: u16, // 1 or 2
-    sample_rate: u32,
-    bits_per_sample: u16, // 16
-    data_size: u32,       // bytes
-    block_align: u16,     // bytes per frame (channels * bits/8)
+struct SdFile<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+    size: u32,
 }
 
-// Minimal parser for 16-bit PCM, finds the "fmt " & "data" chunks in the 44B header
-fn parse_wav_header(h: &[u8]) -> Option<WavInfo> {
-    use byteorder::{ByteOrder, LittleEndian as LE};
-    if h.len() < 44 || &h[0..4] != b"RIFF" || &h[8..12] != b"WAVE" {
-        return None;
-    }
-
-    let mut p = 12usize;
-    let mut fmt_found = false;
-    let mut audio_format = 0u16;
-    let mut num_channels = 0u16;
-    let mut sample_rate = 0u32;
-    let mut bits_per_sample = 0u16;
-    let mut block_align = 0u16;
-    let mut data_size = 0u32;
-
-    while p + 8 <= h.len() {
-        let id = &h[p..p + 4];
-        let sz = LE::read_u32(&h[p + 4..p + 8]) as usize;
-        let body = p + 8;
-        let next = body + sz;
-
-        if id == b"fmt " && body + 16 <= h.len() {
-            audio_format = LE::read_u16(&h[body..body + 2]);
-            num_channels = LE::read_u16(&h[body + 2..body + 4]);
-            sample_rate = LE::read_u32(&h[body + 4..body + 8]);
-            let _byte_rate = LE::read_u32(&h[body + 8..body + 12]);
-            block_align = LE::read_u16(&h[body + 12..body + 14]);
-            bits_per_sample = LE::read_u16(&h[body + 14..body + 16]);
-            fmt_found = true;
-        } else if id == b"data" {
-            data_size = (sz as u32);
-            break;
-        }
-        // chunks are word-aligned
-        p = next + (next & 1);
-    }
-
-    if !fmt_found || data_size == 0 {
-        return None;
+impl TextSource for SdFile<'_> {
+    fn size(&self) -> u32 {
+        self.size
     }
-    Some(WavInfo {
-        audio_format,
-        num_channels,
-        sample_rate,
-        bits_per_sample,
-        data_size,
-        block_align,
-    })
-}
-
-// Read PCM bytes from SD and pack to u32 I2S frames (L: high 16, R: low 16)
-fn fill_frames_from_sd(
-    file: &mut File<
-        SdCard<ExclusiveDevice<Spi<Blocking>, Output, NoDelay>, Delay>,
-        DummyTime,
-        4,
-        4,
-        1,
-    >,
-    out_frames: &mut [u32],
-    w: &WavInfo,
-) -> usize {
-    // Temp byte buffer: size in bytes equals out_frames*4 (16b stereo = 4 bytes per frame)
-    let mut tmp = [0u8; CHUNK_BYTES];
-
-    let bytes_per_sample = (w.bits_per_sample / 8) as usize; // 2
-    let ch = w.num_channels as usize; // 1 or 2
-
-    // Compute how many PCM bytes we'd like to fill
-    let want_frames = out_frames.len();
-    let want_pcm_bytes = want_frames * bytes_per_sample * ch;
-    let to_read = core::cmp::min(tmp.len(), want_pcm_bytes);
-
-    if file.is_eof() {
-        // info!("end of file");
-        file.seek_from_start(WAV_HEADER_LEN as u32).unwrap();
-    }
-    let n = file.read(&mut tmp[..to_read]).unwrap_or(0);
-    if n == 0 {
-        return 0;
-    }
-
-    let mut produced = 0usize;
-    let mut i = 0usize;
-    while i + bytes_per_sample * ch <= n && produced < out_frames.len() {
-        // 16-bit little-endian
-        let l = i16::from_le_bytes([tmp[i], tmp[i + 1]]) as i32;
-        let r = if ch == 2 {
-            let j = i + 2;
-            i16::from_le_bytes([tmp[j], tmp[j + 1]]) as i32
-        } else {
-            l
-        };
-        let lu = (l as u32) & 0xFFFF;
-        let ru = (r as u32) & 0xFFFF;
-
-        out_frames[produced] = (lu << 16) | ru;
-        produced += 1;
-        i += bytes_per_sample * ch; // advance by one sample frame in input
-    }
-
-    produced
-}
 
-// Dummy time source required by embedded-sdmmc
-#[derive(Default)]
-struct DummyTime;
-impl embedded_sdmmc::TimeSource for DummyTime {
-    fn get_timestamp(&self) -> Timestamp {
-        Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
-        }
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        self.wrapper.read_file_at(self.name, offset, buf).unwrap_or_else(|e| {
+            info!("couldn't read {} {:?}", self.name, e);
+            0
+        })
     }
 }
 
-// ----------------- MAIN -----------------
 #[esp_rtos::main]
-async fn main(spawnerr: Spawner) -> ! {
+async fn main(spawner: Spawner) {
     esp_println::logger::init_logger_from_env();
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
-    let timer_g1 = TimerGroup::new(peripherals.TIMG1);
-    esp_rtos::start(timer_g1.timer0);
-    esp_alloc::heap_allocator!(size: 96 * 1024);
-    let delay = Delay::new();
-
-    info!("powering on");
-
-    let BOARD_POWERON = peripherals.GPIO10;
-    let BOARD_SDCARD_CS = peripherals.GPIO39;
-
-    let mut board_power = Output::new(BOARD_POWERON, High, OutputConfig::default());
-    board_power.set_high();
-    delay.delay_millis(1000);
-
-    info!("setting up SPI");
-
-    // --- SPI2 for SD card (T-Deck pins) ---
-    let BOARD_SPI_SCK = peripherals.GPIO40;
-    let BOARD_SPI_MOSI = peripherals.GPIO41;
-    let BOARD_SPI_MISO = peripherals.GPIO38;
-    let RADIO_CS_PIN = peripherals.GPIO9;
-    let BOARD_TFT_CS = peripherals.GPIO12;
-    // let cs   = peripherals.gpio41;
-    let sdmmc_cs = Output::new(BOARD_SDCARD_CS, High, OutputConfig::default());
-    let BOARD_SPI_MISO = Input::new(BOARD_SPI_MISO, InputConfig::default().with_pull(Pull::Up));
-    // I don't know why we need these set, but we do.
-    let radio_cs = Output::new(RADIO_CS_PIN, High, OutputConfig::default());
-    let board_tft = Output::new(BOARD_TFT_CS, High, OutputConfig::default());
-
-    let sdmmc_spi_bus = Spi::new(
-        peripherals.SPI2,
-        SpiConfig::default().with_frequency(Rate::from_mhz(40)),
-    )
-    .unwrap()
-    .with_sck(BOARD_SPI_SCK)
-    .with_mosi(BOARD_SPI_MOSI)
-    .with_miso(BOARD_SPI_MISO);
-    let sdmmc_spi =
-        ExclusiveDevice::new_no_delay(sdmmc_spi_bus, sdmmc_cs).expect("Failed to create SpiDevice");
-
-    info!("setting up SD CARD");
-
-    let card = SdCard::new(sdmmc_spi, delay);
-    info!("size of card in bytes: {}", card.num_bytes().unwrap());
-    info!("type of card: {:?}", card.get_card_type());
-    info!("opening volume manager");
-    let mut volume_mgr = VolumeManager::new(card, DummyTime {});
-    info!("opening volume");
-    let mut volume = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
-    info!("opening root dir");
-    let root_dir = volume.open_root_dir().unwrap();
-
-    root_dir
-        .iterate_dir(|de| {
-            info!("dir entry {:?} is {} bytes", de.name, de.size);
-        })
-        .unwrap();
-
-    // Open your WAV file (8.3 name unless you enable long names)
-    let file = root_dir
-        .open_file_in_dir("U2NONAME.MP3", embedded_sdmmc::Mode::ReadOnly)
-        .unwrap();
-
-    info!("opened the mp3 file {:?}", file);
-
-    let mut decoder = nanomp3::Decoder::new();
-    let (_, _, tx_buffer, tx_descriptors) = dma_circular_buffers!(0, CHUNK_BYTES);
-
-    // --- I2S0 TX to built-in speaker pins ---
-    let i2s = I2s::new(
-        peripherals.I2S0,
-        peripherals.DMA_CH0,
-        Config::new_tdm_philips()
-            .with_data_format(DataFormat::Data16Channel16)
-            .with_sample_rate(Rate::from_hz(44100))
-    ).unwrap().into_async();
-
-    let mut i2s_tx = i2s.i2s_tx
-        .with_bclk(peripherals.GPIO7)
-        .with_ws(peripherals.GPIO5)
-        .with_dout(peripherals.GPIO6)
-        .build(tx_descriptors);
-
-    let mut pcm_buffer = [0f32; nanomp3::MAX_SAMPLES_PER_FRAME];
-    let mut file_data = [0u8; CHUNK_BYTES];
-
-    // read a chunk of bytes from SD card
-    let n = file.read(&mut file_data).unwrap_or(0);
-    info!("read file bytes {}, total len {}", n, file_data.len());
-    // decode mp3 data into the pcm buffer
-    let (consumed, nfo) = decoder.decode(&file_data, &mut pcm_buffer);
-    info!("read out {} bytes {:?}", consumed, nfo);
-    let mut start = 0;
-    let mut end = 0;
-    if let Some(nfo) = nfo {
-        info!(
-            "bitrate {} channels {} sample rate {} samples_produced {}",
-            nfo.bitrate,
-            nfo.channels.num(),
-            nfo.sample_rate,
-            nfo.samples_produced
-        );
-        let sample_len = nfo.samples_produced * (nfo.channels.num() as usize);
-        end = sample_len;
-    } else {
-        end = 0;
-    }
-    let mut transaction = i2s_tx.write_dma_circular_async(tx_buffer).unwrap();
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    info!("running");
+
+    let files = wrapper.list_files().unwrap();
+    let Some((_, size)) = files.iter().find(|(name, _)| name.as_str() == FILE_NAME) else {
+        error!("couldn't find {} on the SD card", FILE_NAME);
+        loop {}
+    };
+    let audio = wrapper.audio.take().unwrap();
+    let mut file = SdFile {
+        wrapper: &mut wrapper,
+        name: FILE_NAME,
+        size: *size,
+    };
+    let info = mp3::parse(&mut file).expect("not an MP3 file we can play");
+    info!(
+        "{} channels at {}Hz, {}kbps{}, {}ms",
+        info.channels,
+        info.sample_rate,
+        info.bitrate_kbps,
+        if info.vbr { " VBR" } else { "" },
+        info.duration_ms()
+    );
+
+    let config = AudioConfig {
+        sample_rate: SINK_RATE,
+        channels: info.channels as u8,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
+    // at the volume set in the music player or piano
+    let volume: Volume = settings::load(&mut wrapper.flash, Slot::Volume).unwrap_or_default();
+    writer.set_volume(volume);
+
+    let mut resampler = Resampler::new(info.sample_rate, SINK_RATE, info.channels, Quality::Sinc);
+    let mut stream = Mp3Stream::new(info);
+    let mut samples = Vec::new();
+    let mut resampled = Vec::new();
+    let mut last_second = 0;
     loop {
-        transaction
-            .push_with(|dma_buf| {
-                let mut end = end;
-                if dma_buf.len() < end / 4 {
-                    end = dma_buf.len() * 4;
-                }
-                let slice = &pcm_buffer[start..end];
-                info!("copying slice {} to dma_buf {}", slice.len(), dma_buf.len());
-                for (dest_byte, source_f32) in dma_buf.chunks_exact_mut(4).zip(slice.iter()) {
-                    dest_byte.copy_from_slice(&source_f32.to_le_bytes())
-                }
-                dma_buf.len()
-            })
-            .await
-            .unwrap();
+        samples.clear();
+        if !stream.next_frame(&mut file, &mut samples) {
+            info!("starting over {:?}", writer.stats());
+            stream.seek(0);
+            continue;
+        }
+        resampled.clear();
+        resampler.process(&samples, &mut resampled);
+        writer.write_all(&resampled).await;
+
+        let second = stream.position_ms() / 1000;
+        if second != last_second {
+            last_second = second;
+            let total = stream.info.duration_ms() / 1000;
+            info!("{}:{:02} / {}:{:02}", second / 60, second % 60, total / 60, total % 60);
+        }
     }
 }
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
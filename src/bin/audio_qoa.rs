@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::vec::Vec;
+use embassy_executor::Spawner;
+use esp_hal::clock::CpuClock;
+use log::{error, info};
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPlayer, AudioSink, AudioWriter};
+use rust_tdeck_experiments::music::to_stereo;
+use rust_tdeck_experiments::qoa::{self, QoaStream};
+use rust_tdeck_experiments::reader::TextSource;
+use rust_tdeck_experiments::resample::{Quality, Resampler};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::volume::Volume;
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(nfo: &core::panic::PanicInfo) -> ! {
+    error!("PANIC: {:?}", nfo);
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+Quacks from a QOA file built into the app, then plays a QOA file from the SD card over and
+over. QOA is a fifth of the size of a 16 bit WAV, so it takes a fifth of the card's time to
+read, and it decodes a frame of about 5000 samples at a time with a few adds and multiplies
+each. The speaker stays at 44.1kHz and the resample module converts the file to match.
+
+Make QOA files, or IMA ADPCM WAVs for audio_wavfile, with the audioconv tool:
+
+cd tools/audioconv
+cargo run --release -- U2MYST.WAV U2MYST.QOA
+ */
+
+const FILE_NAME: &str = "U2MYST.QOA";
+const SINK_RATE: u32 = 44_100;
+
+struct SdFile<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+    size: u32,
+}
+
+impl TextSource for SdFile<'_> {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        self.wrapper.read_file_at(self.name, offset, buf).unwrap_or_else(|e| {
+            info!("couldn't read {} {:?}", self.name, e);
+            0
+        })
+    }
+}
+
+// decodes the whole file once, stereo at the sink's rate. Returns false if it isn't QOA.
+async fn play_file(src: &mut impl TextSource, writer: &mut AudioWriter) -> bool {
+    let info = match qoa::parse(src) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("not a QOA file we can play {:?}", e);
+            return false;
+        }
+    };
+    info!("{} channels at {}Hz, {}ms", info.channels, info.sample_rate, info.duration_ms());
+    let mut resampler = Resampler::new(info.sample_rate, SINK_RATE, info.channels, Quality::Sinc);
+    let mut stream = QoaStream::new(info);
+    let mut samples = Vec::new();
+    let mut resampled = Vec::new();
+    let mut stereo = Vec::new();
+    loop {
+        samples.clear();
+        resampled.clear();
+        let more = stream.next_chunk(src, &mut samples);
+        resampler.process(&samples, &mut resampled);
+        if !more {
+            resampler.flush(&mut resampled);
+        }
+        stereo.clear();
+        to_stereo(&resampled, info.channels, &mut stereo);
+        writer.write_all(&stereo).await;
+        if !more {
+            return true;
+        }
+    }
+}
+
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    // a stereo frame of QOA decodes to 20KB
+    esp_alloc::heap_allocator!(size: 96 * 1024);
+
+    info!("running");
+
+    let audio = wrapper.audio.take().unwrap();
+    let config = AudioConfig {
+        sample_rate: SINK_RATE,
+        channels: 2,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
+    // at the volume set in the music player or piano
+    let volume: Volume = settings::load(&mut wrapper.flash, Slot::Volume).unwrap_or_default();
+    writer.set_volume(volume);
+
+    // from flash, the same way as from the card
+    let mut quack: &[u8] = include_bytes!("quack.qoa");
+    play_file(&mut quack, &mut writer).await;
+
+    let files = wrapper.list_files().unwrap();
+    let Some((_, size)) = files.iter().find(|(name, _)| name.as_str() == FILE_NAME) else {
+        error!("couldn't find {} on the SD card", FILE_NAME);
+        loop {}
+    };
+    let mut file = SdFile {
+        wrapper: &mut wrapper,
+        name: FILE_NAME,
+        size: *size,
+    };
+    while play_file(&mut file, &mut writer).await {
+        info!("starting over {:?}", writer.stats());
+    }
+    loop {}
+}
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
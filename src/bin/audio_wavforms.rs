@@ -42,16 +42,12 @@ use esp_hal::clock::CpuClock;
 use esp_hal::delay::Delay;
 use esp_hal::gpio::Level::High;
 use esp_hal::gpio::{Output, OutputConfig};
-use esp_hal::{
-    dma_buffers,
-    i2s::master::{DataFormat, I2s},
-    time::Rate,
-    timer::timg::TimerGroup,
-};
-use esp_hal::i2s::master::Config;
+use esp_hal::timer::timg::TimerGroup;
 use esp_rtos::main;
 use log::{error, info};
 use micromath::F32Ext;
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPeripherals, AudioPlayer, AudioSink};
+use rust_tdeck_experiments::volume::Volume;
 
 #[panic_handler]
 fn panic(nfo: &core::panic::PanicInfo) -> ! {
@@ -127,47 +123,39 @@ async fn main(spawner: Spawner) {
     let delay = Delay::new();
     delay.delay_millis(1000);
 
-    let (_, _, tx_buffer, tx_descriptors) = dma_buffers!(0, 32000);
+    // the speaker plays mono samples on both sides
+    let audio = AudioPeripherals {
+        i2s: peripherals.I2S0,
+        dma: peripherals.DMA_CH0,
+        bclk: peripherals.GPIO7,
+        ws: peripherals.GPIO5,
+        dout: peripherals.GPIO6,
+    };
+    let config = AudioConfig {
+        sample_rate: 44_100,
+        channels: 1,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
 
-    let i2s = I2s::new(
-        peripherals.I2S0,
-        peripherals.DMA_CH0,
-        Config::new_tdm_philips()
-            .with_data_format(DataFormat::Data16Channel16)
-            .with_sample_rate(Rate::from_hz(44100))
-    ).unwrap().into_async();
-
-    let i2s_tx = i2s
-        .i2s_tx
-        .with_bclk(peripherals.GPIO7)
-        .with_ws(peripherals.GPIO5)
-        .with_dout(peripherals.GPIO6)
-        .build(tx_descriptors);
-
-    let buffer = tx_buffer;
-    let mut transaction = i2s_tx.write_dma_circular_async(buffer).unwrap();
-    let mut count = 0;
     // let mut samples = SineWaveSource::new();
-    let mut samples = SawtoothWaveSource::new(262, 44_100, i16::MAX / 4); // 440Hz, half volume
-    loop {
-        let written = transaction
-            .push_with(|buf| {
-                for i in (0..buf.len()).step_by(4) {
-                    let samp = samples.next().unwrap();
-                    let isamp = samp as u16;
-                    buf[i + 0] = (isamp & 0x00ff) as u8;
-                    buf[i + 1] = ((isamp & 0xff00) >> 8) as u8;
-                    buf[i + 2] = (isamp & 0x00ff) as u8;
-                    buf[i + 3] = ((isamp & 0xff00) >> 8) as u8;
-                }
-                buf.len()
-            })
-            .await
-            .unwrap();
-        info!("written {}", written);
-        count += 1;
-        if count >= 50 {
-            break;
+    // a full scale wave, played at a quarter of full scale by the master volume
+    writer.set_volume(Volume::default());
+    let mut samples = SawtoothWaveSource::new(262, 44_100, i16::MAX); // 262Hz
+    let mut chunk = [0i16; 512];
+    // about five seconds
+    for _ in 0..430 {
+        for s in chunk.iter_mut() {
+            *s = samples.next().unwrap();
         }
+        writer.write_all(&chunk).await;
     }
+    writer.finish();
+    writer.drain().await;
+    info!("done {:?}", writer.stats());
+}
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
 }
@@ -6,16 +6,26 @@
     holding buffers for the duration of a data transfer."
 )]
 
-use alloc::vec;
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
-use core::default::Default;
-use embedded_graphics::pixelcolor::Rgb565;
-use embedded_graphics::prelude::*;
-use embedded_graphics::primitives::*;
-use embedded_graphics::*;
+use embassy_executor::Spawner;
+use embassy_time::Timer;
 use esp_hal::clock::CpuClock;
-use esp_hal::{main, Config};
+use esp_hal::time::Instant;
+use esp_hal::Config;
 use log::info;
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPlayer, AudioSink, AudioWriter, QUEUE_FRAMES};
+use rust_tdeck_experiments::breakout::{Breakout, BreakoutView, HighScores, BRICK_KINDS, BRICK_SIZE};
+use rust_tdeck_experiments::game::{
+    render, screen_bounds, DirtyRegions, FrameBuffer, GameClock, InputMap, SpriteSheet,
+};
+use rust_tdeck_experiments::mixer::{Clip, Mixer, VoiceId};
+use rust_tdeck_experiments::music::{to_stereo, TrackDecoder, TrackKind};
+use rust_tdeck_experiments::reader::TextSource;
+use rust_tdeck_experiments::resample::{Quality, Resampler};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::volume::Volume;
 use rust_tdeck_experiments::Wrapper;
 
 extern crate alloc;
@@ -29,208 +39,232 @@ fn panic(_: &core::panic::PanicInfo) -> ! {
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
-pub struct Brick {
-    pub bounds: Rectangle,
-    pub color: Rgb565,
-    pub active: bool,
+/*
+Quacks when the ball hits a brick or the paddle, from the side of the screen it happened on.
+If there's a MUSIC.MP3, MUSIC.WAV or MUSIC.QOA in the root of the SD card it plays over and
+over behind the game. The mixer module adds the two together.
+ */
+
+const SINK_RATE: u32 = 44_100;
+// how far ahead of the speaker to mix. more is safer against underruns but makes the
+// quacks later.
+const AHEAD_FRAMES: usize = 2048;
+const MIX_FRAMES: usize = 256;
+const MUSIC_VOLUME: f32 = 0.5;
+const HIT_VOLUME: f32 = 0.7;
+
+struct SdFile<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+    size: u32,
 }
-pub struct GameView {
-    pub bounds: Rectangle,
-    pub paddle: Rectangle,
-    pub old_paddle: Rectangle,
-    pub visible: bool,
-    pub count: i32,
-    pub ball_bounds: Rectangle,
-    pub ball_velocity: Point,
-    pub bricks: Vec<Brick>,
+
+impl TextSource for SdFile<'_> {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        self.wrapper.read_file_at(self.name, offset, buf).unwrap_or_else(|e| {
+            info!("couldn't read {} {:?}", self.name, e);
+            0
+        })
+    }
 }
-impl GameView {
-    pub fn new() -> Self {
-        let colors = [
-            Rgb565::GREEN,
-            Rgb565::YELLOW,
-            Rgb565::CSS_ORANGE,
-            Rgb565::RED,
-        ];
-        let mut bricks: Vec<Brick> = Vec::new();
-        for i in 0..6 {
-            for j in 0..4 {
-                bricks.push(Brick {
-                    bounds: Rectangle::new(
-                        Point::new(40 + i * 40 as i32, 20 + j * 20 as i32),
-                        Size::new(35, 15),
-                    ),
-                    color: colors[(j as usize) % colors.len()],
-                    active: true,
-                })
-            }
-        }
-        GameView {
-            bounds: Rectangle::new(Point::new(0, 0), Size::new(200, 200)),
-            paddle: Rectangle::new(Point::new(100, 220), Size::new(50, 10)),
-            old_paddle: Rectangle::new(Point::new(100, 220), Size::new(50, 10)),
-            visible: true,
-            count: 0,
-            ball_bounds: Rectangle::new(Point::new(100, 120), Size::new(10, 10)),
-            ball_velocity: Point::new(2, 1),
-            bricks,
+
+/// Background music, decoded into a stream voice of the mixer.
+struct Music {
+    name: String,
+    size: u32,
+    decoder: TrackDecoder,
+    resampler: Resampler,
+    voice: VoiceId,
+    samples: Vec<i16>,
+    resampled: Vec<i16>,
+    stereo: Vec<i16>,
+}
+
+impl Music {
+    fn open(wrapper: &mut Wrapper, mixer: &mut Mixer) -> Option<Music> {
+        let files = wrapper.list_files().ok()?;
+        let (name, size, kind) = files.into_iter().find_map(|(name, size)| {
+            let kind = TrackKind::from_name(&name)?;
+            name.starts_with("MUSIC.").then_some((name, size, kind))
+        })?;
+        let mut src = SdFile {
+            wrapper,
+            name: &name,
+            size,
+        };
+        let decoder = TrackDecoder::open(kind, &mut src)?;
+        info!("playing {}", name);
+        let resampler = Resampler::new(decoder.sample_rate(), SINK_RATE, decoder.channels(), Quality::Sinc);
+        let voice = mixer.play_stream();
+        if let Some(v) = mixer.voice_mut(voice) {
+            v.volume = MUSIC_VOLUME;
         }
+        Some(Music {
+            name,
+            size,
+            decoder,
+            resampler,
+            voice,
+            samples: Vec::new(),
+            resampled: Vec::new(),
+            stereo: Vec::new(),
+        })
     }
-}
 
-impl GameView {
-    pub(crate) fn handle_collisions(&mut self) {
-        let old_ball_bounds = self.ball_bounds.clone();
-        self.ball_bounds = self.ball_bounds.translate(self.ball_velocity);
-
-        // collide with bricks
-        for brick in &mut self.bricks {
-            if brick.active && !self.ball_bounds.intersection(&brick.bounds).is_zero_sized() {
-                brick.active = false;
-                // from the bottom
-                if old_ball_bounds.top_left.y
-                    > brick.bounds.top_left.y + brick.bounds.size.height as i32
-                {
-                    info!("from the bottom");
-                    self.ball_velocity.y = -self.ball_velocity.y
-                }
-                // from the top
-                if (old_ball_bounds.top_left.y + old_ball_bounds.size.height as i32)
-                    < brick.bounds.top_left.y
-                {
-                    info!("from the top");
-                    self.ball_velocity.y = -self.ball_velocity.y
-                }
-                // from the right
-                if old_ball_bounds.top_left.x
-                    > brick.bounds.top_left.x + brick.bounds.size.width as i32
-                {
-                    info!("from the right");
-                    self.ball_velocity.x = -self.ball_velocity.x
-                }
-                // from the left
-                if (old_ball_bounds.top_left.x + old_ball_bounds.size.width as i32)
-                    < brick.bounds.top_left.x
-                {
-                    info!("from the left");
-                    self.ball_velocity.x = -self.ball_velocity.x
+    /// Decodes until the mixer has `frames` frames of music waiting.
+    fn fill(&mut self, wrapper: &mut Wrapper, mixer: &mut Mixer, frames: usize) {
+        while mixer.queued(self.voice) < frames {
+            let mut src = SdFile {
+                wrapper: &mut *wrapper,
+                name: &self.name,
+                size: self.size,
+            };
+            self.samples.clear();
+            if !self.decoder.next_chunk(&mut src, &mut self.samples) {
+                // round again from the start
+                self.decoder.seek(0);
+                self.resampler.reset();
+                if !self.decoder.next_chunk(&mut src, &mut self.samples) {
+                    return;
                 }
             }
+            self.resampled.clear();
+            self.resampler.process(&self.samples, &mut self.resampled);
+            self.stereo.clear();
+            to_stereo(&self.resampled, self.decoder.channels(), &mut self.stereo);
+            mixer.push(self.voice, &self.stereo);
         }
+    }
+}
 
-        // collide with the screen edges
-        if self.ball_bounds.top_left.y >= 240 - 20 {
-            self.ball_velocity = Point::new(self.ball_velocity.x, -self.ball_velocity.y);
-        }
-        if self.ball_bounds.top_left.y <= 0 {
-            self.ball_velocity = Point::new(self.ball_velocity.x, -self.ball_velocity.y);
-        }
-        if self.ball_bounds.top_left.x >= 320 - 20 {
-            self.ball_velocity = Point::new(-self.ball_velocity.x, self.ball_velocity.y);
-        }
-        if self.ball_bounds.top_left.x <= 0 {
-            self.ball_velocity = Point::new(-self.ball_velocity.x, self.ball_velocity.y);
+// keeps the sink about AHEAD_FRAMES ahead of the speaker
+fn mix_ahead(
+    wrapper: &mut Wrapper,
+    mixer: &mut Mixer,
+    music: &mut Option<Music>,
+    writer: &mut AudioWriter,
+    mixed: &mut Vec<i16>,
+) {
+    while QUEUE_FRAMES - writer.space() < AHEAD_FRAMES {
+        if let Some(music) = music.as_mut() {
+            music.fill(wrapper, mixer, MIX_FRAMES);
         }
+        mixed.clear();
+        mixer.render(MIX_FRAMES, mixed);
+        writer.write(mixed);
+    }
+}
 
-        // collide with the paddle
-        let inter = self.ball_bounds.intersection(&self.paddle);
-        if !inter.is_zero_sized() {
-            self.ball_velocity = Point::new(self.ball_velocity.x, -self.ball_velocity.y);
+// levels are LEVEL1.TXT, LEVEL2.TXT, ... in the root of the SD card
+fn load_levels(wrapper: &mut Wrapper) -> Vec<String> {
+    let mut levels = Vec::new();
+    for n in 1..=9 {
+        let name = format!("LEVEL{}.TXT", n);
+        match wrapper.read_file(&name) {
+            Ok(data) => levels.push(String::from_utf8_lossy(&data).into_owned()),
+            Err(_) => break,
         }
     }
+    info!("loaded {} levels from the SD card", levels.len());
+    if levels.is_empty() {
+        levels = Breakout::builtin_levels();
+    }
+    levels
 }
 
-#[main]
-fn main() -> ! {
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
     esp_println::logger::init_logger_from_env();
     let config = Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
     let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
 
     esp_alloc::heap_allocator!(size: 72 * 1024);
 
     info!("running");
 
-    let mut game = GameView::new();
+    let audio = wrapper.audio.take().unwrap();
+    let config = AudioConfig {
+        sample_rate: SINK_RATE,
+        channels: 2,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
+    // at the volume set in the music player or piano
+    let volume: Volume = settings::load(&mut wrapper.flash, Slot::Volume).unwrap_or_default();
+    writer.set_volume(volume);
+    let mut mixer = Mixer::new(SINK_RATE, 8);
+    // a fifth of the size of quack.wav in flash
+    let quack = Clip::from_qoa(&mut &include_bytes!("quack.qoa")[..], SINK_RATE).unwrap();
+    let mut music = Music::open(&mut wrapper, &mut mixer);
+    let mut mixed = Vec::new();
 
-    loop {
-        wrapper.poll_keyboard();
-        wrapper.poll_trackball();
-        game.handle_input(&mut wrapper);
-        game.draw(&mut wrapper);
-        wrapper.delay.delay_millis(10);
-    }
-}
+    // use brick art from the SD card if there is some
+    let brick_sheet = match wrapper.read_file("BRICKS.BMP") {
+        Ok(data) => match SpriteSheet::from_bmp(&data, BRICK_SIZE) {
+            Ok(sheet) if sheet.frame_count() >= BRICK_KINDS => sheet,
+            Ok(sheet) => {
+                info!(
+                    "BRICKS.BMP has {} frames of {}x{}, it needs {}",
+                    sheet.frame_count(),
+                    BRICK_SIZE.width,
+                    BRICK_SIZE.height,
+                    BRICK_KINDS
+                );
+                BreakoutView::default_bricks()
+            }
+            Err(e) => {
+                info!("couldn't load BRICKS.BMP {:?}", e);
+                BreakoutView::default_bricks()
+            }
+        },
+        Err(_) => BreakoutView::default_bricks(),
+    };
+    let levels = load_levels(&mut wrapper);
+    let high_scores: HighScores =
+        settings::load(&mut wrapper.flash, Slot::HighScores).unwrap_or_default();
+    let mut game = BreakoutView::new(Breakout::new(levels, high_scores), brick_sheet);
 
-impl GameView {
-    fn draw(&mut self, wrapper: &mut Wrapper) {
-        self.count = self.count + 1;
+    let mut clock = GameClock::new(100);
+    let mut input = InputMap::default();
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(screen_bounds());
 
-        let old_ball_bounds = self.ball_bounds;
-        self.handle_collisions();
+    loop {
+        wrapper.poll_trackball();
+        let key = wrapper.read_key();
+        input.update(key, wrapper.trackball_changes());
+        game.state.handle_input(&input, key, &mut dirty);
 
-        // draw background
-        if self.count < 10 {
-            let screen = Rectangle::new(Point::new(0, 0), Size::new(320, 240));
-            screen
-                .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
-                .draw(&mut wrapper.display)
-                .unwrap();
+        let steps = clock.tick(Instant::now().duration_since_epoch().as_millis());
+        for _ in 0..steps {
+            game.state.update(&mut dirty);
         }
-
-        for brick in &self.bricks {
-            if brick.active {
-                brick
-                    .bounds
-                    .into_styled(PrimitiveStyle::with_fill(brick.color))
-                    .draw(&mut wrapper.display)
-                    .unwrap();
-            } else {
-                brick
-                    .bounds
-                    .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
-                    .draw(&mut wrapper.display)
-                    .unwrap();
+        for pan in game.state.take_hits() {
+            let voice = mixer.play_clip(&quack);
+            if let Some(v) = mixer.voice_mut(voice) {
+                v.volume = HIT_VOLUME;
+                v.pan = pan;
             }
         }
-
-        // draw the ball
-        old_ball_bounds
-            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
-            .draw(&mut wrapper.display)
-            .unwrap();
-        self.ball_bounds
-            .into_styled(PrimitiveStyle::with_fill(Rgb565::MAGENTA))
-            .draw(&mut wrapper.display)
-            .unwrap();
-
-        // draw the paddle
-        self.old_paddle
-            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
-            .draw(&mut wrapper.display)
-            .unwrap();
-        self.paddle
-            .into_styled(PrimitiveStyle::with_fill(Rgb565::RED))
-            .draw(&mut wrapper.display)
-            .unwrap();
-    }
-
-    fn handle_input(&mut self, wrapper: &mut Wrapper) {
-        self.old_paddle = self.paddle;
-        let mut x = 0;
-        if wrapper.left.changed {
-            x = -1;
-        }
-        if wrapper.right.changed {
-            x = 1;
-        }
-        self.paddle = self.paddle.translate(Point::new(x * 20, 0));
-        if self.paddle.top_left.x < 0 {
-            self.paddle.top_left.x = 0;
-        }
-        if self.paddle.top_left.x + (self.paddle.size.width as i32) > 320 {
-            self.paddle.top_left.x = 320 - self.paddle.size.width as i32;
+        if game.state.take_scores_changed() {
+            if let Err(e) = settings::save(&mut wrapper.flash, Slot::HighScores, &game.state.high_scores) {
+                info!("couldn't save the high scores {:?}", e);
+            }
         }
-        self.paddle.top_left.y = 200;
+        render(&game, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        mix_ahead(&mut wrapper, &mut mixer, &mut music, &mut writer, &mut mixed);
+        Timer::after_millis(clock.remaining_ms()).await;
     }
 }
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
@@ -0,0 +1,207 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use core::sync::atomic::{AtomicI32, Ordering};
+use embassy_executor::Spawner;
+use embassy_time::{Duration, Timer};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::text::{Baseline, Text};
+use esp_hal::clock::CpuClock;
+use esp_radio::wifi::{ClientConfig, ModeConfig, ScanConfig, WifiController};
+use esp_radio::Controller;
+use log::info;
+use rust_tdeck_experiments::chart::{Change, Chart, Series, Sparkline};
+use rust_tdeck_experiments::game::{render, screen_bounds, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::theme::Theme;
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+// When you are okay with using a nightly compiler it's better to use https://docs.rs/static_cell/2.1.0/static_cell/macro.make_static.html
+macro_rules! mk_static {
+    ($t:ty,$val:expr) => {{
+        static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
+        #[deny(unused_attributes)]
+        let x = STATIC_CELL.uninit().write(($val));
+        x
+    }};
+}
+
+/*
+Charts heap usage, battery voltage and wifi signal strength once a second.
+The signal strength comes from scanning, so no password is needed. Set SSID to follow
+one network, otherwise the strongest one is shown.
+ */
+
+const SSID: Option<&str> = option_env!("SSID");
+const SAMPLE_MS: u64 = 1000;
+// no reading yet
+const NO_RSSI: i32 = i32::MIN;
+
+static RSSI: AtomicI32 = AtomicI32::new(NO_RSSI);
+
+struct Dashboard {
+    heap: Series,
+    battery: Series,
+    rssi: Series,
+    heap_chart: Chart,
+    battery_chart: Chart,
+    rssi_chart: Chart,
+    sparklines: [Sparkline; 3],
+    theme: Theme,
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        let theme = Theme::dark();
+        let spark = |y: i32, color: Rgb565| {
+            let mut line = Sparkline::new(Rectangle::new(Point::new(226, y + 12), Size::new(92, 36)), color);
+            line.background = theme.background;
+            line
+        };
+        Dashboard {
+            heap: Series::new(60),
+            battery: Series::new(60),
+            rssi: Series::new(40),
+            heap_chart: Chart::line(
+                Rectangle::new(Point::new(0, 0), Size::new(320, 80)),
+                "heap used",
+                "B",
+                Rgb565::CSS_ORANGE,
+            )
+            .with_sample_ms(SAMPLE_MS as u32),
+            battery_chart: Chart::line(
+                Rectangle::new(Point::new(0, 80), Size::new(222, 80)),
+                "battery",
+                "V",
+                Rgb565::GREEN,
+            )
+            .with_range(3.0, 4.3),
+            rssi_chart: Chart::bar(
+                Rectangle::new(Point::new(0, 160), Size::new(222, 80)),
+                "wifi signal",
+                "dBm",
+                Rgb565::CSS_DEEP_SKY_BLUE,
+            ),
+            sparklines: [
+                spark(80, Rgb565::CSS_ORANGE),
+                spark(133, Rgb565::GREEN),
+                spark(186, Rgb565::CSS_DEEP_SKY_BLUE),
+            ],
+            theme,
+        }
+    }
+
+    fn series(&self) -> [&Series; 3] {
+        [&self.heap, &self.battery, &self.rssi]
+    }
+
+    // rescale everything and mark what changed
+    fn update(&mut self, dirty: &mut DirtyRegions) {
+        let changes = [
+            (self.heap_chart.update(&self.heap), &self.heap_chart),
+            (self.battery_chart.update(&self.battery), &self.battery_chart),
+            (self.rssi_chart.update(&self.rssi), &self.rssi_chart),
+        ];
+        for (change, chart) in changes {
+            if let Some(area) = chart.dirty_area(change) {
+                dirty.add(area);
+            }
+        }
+        let series = [&self.heap, &self.battery, &self.rssi];
+        for (line, series) in self.sparklines.iter_mut().zip(series) {
+            if line.update(series) != Change::None {
+                dirty.add(line.bounds);
+            }
+        }
+    }
+}
+
+impl Scene for Dashboard {
+    fn draw(&self, target: &mut FrameBuffer) {
+        self.heap_chart.draw(&self.heap, target).unwrap();
+        self.battery_chart.draw(&self.battery, target).unwrap();
+        self.rssi_chart.draw(&self.rssi, target).unwrap();
+        let label = MonoTextStyle::new(self.theme.small, self.theme.quote);
+        for ((line, series), name) in self.sparklines.iter().zip(self.series()).zip(["heap", "battery", "signal"]) {
+            let p = line.bounds.top_left - Point::new(0, 11);
+            Text::with_baseline(name, p, label, Baseline::Top).draw(target).unwrap();
+            line.draw(series, target).unwrap();
+        }
+    }
+}
+
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    info!("running");
+
+    // wifi only scans, it never connects
+    let esp_radio_ctrl = &*mk_static!(Controller<'static>, esp_radio::init().unwrap());
+    let (controller, _interfaces) =
+        esp_radio::wifi::new(esp_radio_ctrl, wrapper.wifi.take().unwrap(), Default::default()).unwrap();
+    spawner.spawn(scan(controller)).ok();
+
+    let mut dashboard = Dashboard::new();
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(screen_bounds());
+
+    loop {
+        dashboard.heap.push(esp_alloc::HEAP.used() as f32);
+        dashboard.battery.push(wrapper.read_battery_millivolts() as f32 / 1000.0);
+        let rssi = RSSI.load(Ordering::Relaxed);
+        // a gap in the chart until the first scan is done
+        dashboard.rssi.push(if rssi == NO_RSSI { f32::NAN } else { rssi as f32 });
+
+        dashboard.update(&mut dirty);
+        render(&dashboard, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        Timer::after(Duration::from_millis(SAMPLE_MS)).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn scan(mut controller: WifiController<'static>) {
+    controller
+        .set_config(&ModeConfig::Client(ClientConfig::default()))
+        .unwrap();
+    controller.start_async().await.unwrap();
+    loop {
+        match controller.scan_with_config_async(ScanConfig::default().with_max(10)).await {
+            Ok(result) => {
+                let best = result
+                    .iter()
+                    .filter(|ap| SSID.is_none_or(|ssid| ap.ssid.as_str() == ssid))
+                    .map(|ap| ap.signal_strength as i32)
+                    .max();
+                RSSI.store(best.unwrap_or(NO_RSSI), Ordering::Relaxed);
+            }
+            Err(e) => info!("scan failed {:?}", e),
+        }
+        Timer::after(Duration::from_millis(2000)).await;
+    }
+}
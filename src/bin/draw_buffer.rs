@@ -15,6 +15,7 @@ use esp_hal::spi::master::{Config as SpiConfig, Spi};
 use esp_hal::time::{Duration, Instant, Rate};
 use esp_hal::{main, Blocking};
 use log::info;
+use rust_tdeck_experiments::color::to_rgb888;
 
 use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
 use embedded_graphics::{
@@ -124,9 +125,10 @@ impl DrawTarget for ExampleDisplay {
             let x = coord.x as u32;
             let y = coord.y as u32;
             let index: u32 = (x + y * width) * 3;
-            self.framebuffer[(index + 0) as usize] = color.r() << 3;
-            self.framebuffer[(index + 1) as usize] = color.g() << 2;
-            self.framebuffer[(index + 2) as usize] = color.b() << 3;
+            let color = to_rgb888(color);
+            self.framebuffer[(index + 0) as usize] = color.r();
+            self.framebuffer[(index + 1) as usize] = color.g();
+            self.framebuffer[(index + 2) as usize] = color.b();
         }
         Ok(())
     }
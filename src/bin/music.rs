@@ -0,0 +1,655 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embassy_executor::Spawner;
+use embassy_time::{Instant, Timer};
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10, FONT_7X13};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use esp_hal::clock::CpuClock;
+use log::info;
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPlayer, AudioSink, AudioWriter, SINK_TAP};
+use rust_tdeck_experiments::dsp::Preset;
+use rust_tdeck_experiments::game::{render, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::music::{
+    format_time, playlist_tracks, to_stereo, tracks_playlist, Queue, Repeat, Resume, Track, TrackDecoder,
+};
+use rust_tdeck_experiments::playlist::{Playlist, PlaylistFormat};
+use rust_tdeck_experiments::reader::TextSource;
+use rust_tdeck_experiments::resample::{Quality, Resampler};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::visualizer::{Mode, Visualizer};
+use rust_tdeck_experiments::volume::{Volume, VolumeControl};
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+A music player for the WAV, MP3, QOA, MIDI and MOD files in the root of the SD card. The library shows the
+title and artist from the tags, or the file name when there aren't any. MOD files are played
+from memory, so ones over 96KB aren't listed.
+
+trackball up/down picks a track and click plays it. Trackball left/right or a/d seek back
+and forward 10 seconds. space pauses, n and p go to the next and previous track, s turns
+shuffle on and off and r cycles repeat between off, all and one.
+l goes through the .M3U, .M3U8 and .PLS playlists on the card and back to all the tracks,
+and w saves the queue as QUEUE.M3U. + and - change the volume, and so do trackball up and
+down while it's showing, and 0 mutes.
+
+v swaps the library for a picture of what's playing, and goes through spectrum bars, a
+waterfall, the waveform and back to the library.
+
+e goes through the equalizer presets from the dsp module: flat, speaker, which takes out
+the bass the speaker can't play and softens the harsh part, voice, bass and night, which
+evens out loud and quiet parts. The preset is saved to flash.
+
+What's playing and where is saved to flash, so it carries on after a reboot.
+ */
+
+const SINK_RATE: u32 = 44_100;
+const SEEK_MS: u32 = 10_000;
+// going back within this far into a track goes to the previous one instead of the start
+const RESTART_MS: u32 = 3_000;
+// how often to save the position while playing, flash doesn't like being written a lot
+const SAVE_EVERY_MS: u64 = 30_000;
+const QUEUE_FILE: &str = "QUEUE.M3U";
+
+const NOW_PLAYING: Rectangle = Rectangle::new(Point::new(0, 0), Size::new(320, 84));
+const PROGRESS: Rectangle = Rectangle::new(Point::new(0, 56), Size::new(320, 28));
+const BAR: Rectangle = Rectangle::new(Point::new(8, 58), Size::new(304, 6));
+const LIST_TOP: i32 = 90;
+const ROW_HEIGHT: i32 = 13;
+const STATUS: Rectangle = Rectangle::new(Point::new(0, 228), Size::new(320, 12));
+const VISUAL: Rectangle = Rectangle::new(Point::new(0, 86), Size::new(320, 142));
+// the visualizer is redrawn at 30fps
+const FRAME_MS: u64 = 33;
+
+struct SdFile<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+    size: u32,
+}
+
+impl TextSource for SdFile<'_> {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        self.wrapper.read_file_at(self.name, offset, buf).unwrap_or_else(|e| {
+            info!("couldn't read {} {:?}", self.name, e);
+            0
+        })
+    }
+}
+
+struct App {
+    library: Vec<Track>,
+    /// the tracks shown, in the order of the playlist they came from
+    list: Vec<usize>,
+    /// the playlist name, or `None` for all the tracks
+    playlist: Option<String>,
+    /// an index into `list`
+    selected: usize,
+    current: Option<usize>,
+    position_ms: u32,
+    duration_ms: u32,
+    playing: bool,
+    shuffle: bool,
+    repeat: Repeat,
+    volume: VolumeControl,
+    preset: Preset,
+    /// shown instead of the library
+    visual: Option<Visualizer>,
+}
+
+impl Scene for App {
+    fn draw(&self, target: &mut FrameBuffer) {
+        let small = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_LIGHT_GRAY);
+        NOW_PLAYING
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_MIDNIGHT_BLUE))
+            .draw(target)
+            .unwrap();
+
+        // what's playing
+        let track = self.current.map(|i| &self.library[i]);
+        let title = track.map(|t| t.title()).unwrap_or("nothing playing");
+        let big = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+        Text::with_baseline(&clip(title, 30), Point::new(8, 6), big, Baseline::Top)
+            .draw(target)
+            .unwrap();
+        let style = MonoTextStyle::new(&FONT_7X13, Rgb565::CSS_LIGHT_SKY_BLUE);
+        if let Some(artist) = track.and_then(|t| t.artist.as_deref()) {
+            Text::with_baseline(&clip(artist, 28), Point::new(8, 32), style, Baseline::Top)
+                .draw(target)
+                .unwrap();
+        }
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Top).build();
+        let source = self.playlist.as_deref().unwrap_or("all tracks");
+        Text::with_text_style(&clip(source, 14), Point::new(312, 32), style, right)
+            .draw(target)
+            .unwrap();
+
+        // progress
+        BAR.into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_GRAY))
+            .draw(target)
+            .unwrap();
+        if self.duration_ms > 0 {
+            let done = (BAR.size.width as u64 * self.position_ms.min(self.duration_ms) as u64
+                / self.duration_ms as u64) as u32;
+            Rectangle::new(BAR.top_left, Size::new(done, BAR.size.height))
+                .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_ORANGE))
+                .draw(target)
+                .unwrap();
+        }
+        let times = format!("{} / {}", format_time(self.position_ms), format_time(self.duration_ms));
+        Text::with_baseline(&times, Point::new(8, 68), small, Baseline::Top)
+            .draw(target)
+            .unwrap();
+        let repeat = match self.repeat {
+            Repeat::Off => "",
+            Repeat::All => "  repeat",
+            Repeat::One => "  repeat one",
+        };
+        let flags = format!(
+            "{}{}{}  eq {}",
+            if self.playing { "playing" } else { "paused" },
+            if self.shuffle { "  shuffle" } else { "" },
+            repeat,
+            self.preset.name()
+        );
+        Text::with_text_style(&flags, Point::new(312, 68), small, right)
+            .draw(target)
+            .unwrap();
+
+        match &self.visual {
+            Some(visual) => visual.draw(target).unwrap(),
+            None => self.draw_library(target),
+        }
+
+        STATUS
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_GRAY))
+            .draw(target)
+            .unwrap();
+        Text::with_baseline(
+            "space pause n/p track a/d seek s shuffle r repeat l list",
+            STATUS.top_left + Point::new(2, 1),
+            small,
+            Baseline::Top,
+        )
+        .draw(target)
+        .unwrap();
+        self.volume.draw(target).unwrap();
+    }
+}
+
+impl App {
+    // the library, scrolled so the selection stays visible
+    fn draw_library(&self, target: &mut FrameBuffer) {
+        let list = MonoTextStyle::new(&FONT_7X13, Rgb565::WHITE);
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Top).build();
+        let rows = ((STATUS.top_left.y - LIST_TOP) / ROW_HEIGHT) as usize;
+        let first = self.selected.saturating_sub(rows - 1);
+        for (i, index) in self.list.iter().enumerate().skip(first).take(rows) {
+            let track = &self.library[*index];
+            let marker = if Some(*index) == self.current { ">" } else { " " };
+            let line = match &track.artist {
+                Some(artist) => format!("{}{} - {}", marker, track.title(), artist),
+                None => format!("{}{}", marker, track.title()),
+            };
+            let color = if i == self.selected { Rgb565::YELLOW } else { Rgb565::WHITE };
+            let p = Point::new(4, LIST_TOP + (i - first) as i32 * ROW_HEIGHT);
+            Text::with_baseline(&clip(&line, 38), p, MonoTextStyle::new(&FONT_7X13, color), Baseline::Top)
+                .draw(target)
+                .unwrap();
+            let time = format_time(track.duration_ms);
+            Text::with_text_style(&time, Point::new(316, p.y), list, right)
+                .draw(target)
+                .unwrap();
+        }
+        if self.list.is_empty() {
+            Text::with_baseline("no WAV, MP3, QOA, MIDI or MOD files", Point::new(4, LIST_TOP), list, Baseline::Top)
+                .draw(target)
+                .unwrap();
+        }
+    }
+}
+
+// cuts text to fit, marking that it was cut
+fn clip(text: &str, chars: usize) -> String {
+    if text.chars().count() <= chars {
+        return text.into();
+    }
+    let mut clipped: String = text.chars().take(chars - 1).collect();
+    clipped.push('~');
+    clipped
+}
+
+fn playlist_files(wrapper: &mut Wrapper) -> Vec<(String, u32)> {
+    let mut files = wrapper.list_files().unwrap_or_default();
+    files.retain(|(name, _)| PlaylistFormat::from_name(name).is_some());
+    files.sort();
+    files
+}
+
+// the library tracks in a playlist on the card
+fn load_playlist(wrapper: &mut Wrapper, library: &[Track], name: &str) -> Option<Vec<usize>> {
+    let data = wrapper
+        .read_file(name)
+        .inspect_err(|e| info!("couldn't read {} {:?}", name, e))
+        .ok()?;
+    let playlist = Playlist::parse(name, &data)?;
+    let tracks = playlist_tracks(library, &playlist);
+    info!("{} has {} entries, {} of them in the library", name, playlist.entries.len(), tracks.len());
+    Some(tracks)
+}
+
+fn save_queue(wrapper: &mut Wrapper, library: &[Track], queue: &Queue) {
+    let Some(text) = tracks_playlist(library, &queue.order).write(QUEUE_FILE) else {
+        return;
+    };
+    match wrapper.write_file(QUEUE_FILE, text.as_bytes()) {
+        Ok(()) => info!("saved {} tracks to {}", queue.order.len(), QUEUE_FILE),
+        Err(e) => info!("couldn't save {} {:?}", QUEUE_FILE, e),
+    }
+}
+
+fn scan_library(wrapper: &mut Wrapper) -> Vec<Track> {
+    let mut files = wrapper.list_files().unwrap_or_default();
+    files.sort();
+    let mut library = Vec::new();
+    for (name, size) in files {
+        let mut src = SdFile {
+            wrapper: &mut *wrapper,
+            name: &name,
+            size,
+        };
+        if let Some(track) = Track::scan(&name, &mut src) {
+            library.push(track);
+        }
+    }
+    info!("found {} tracks", library.len());
+    library
+}
+
+/// The track being played and everything between it and the speaker.
+struct Playing {
+    index: usize,
+    decoder: TrackDecoder,
+    resampler: Resampler,
+    samples: Vec<i16>,
+    resampled: Vec<i16>,
+    // stereo samples at the sink rate waiting for room in the queue
+    pending: Vec<i16>,
+    written: usize,
+}
+
+impl Playing {
+    fn open(wrapper: &mut Wrapper, library: &[Track], index: usize, position_ms: u32) -> Option<Playing> {
+        let track = &library[index];
+        let mut src = SdFile {
+            wrapper,
+            name: &track.name,
+            size: track.size,
+        };
+        let Some(mut decoder) = TrackDecoder::open(track.kind, &mut src) else {
+            info!("couldn't open {}", track.name);
+            return None;
+        };
+        if position_ms > 0 {
+            decoder.seek(position_ms);
+        }
+        info!("playing {} from {}ms", track.name, position_ms);
+        Some(Playing {
+            index,
+            resampler: Resampler::new(decoder.sample_rate(), SINK_RATE, decoder.channels(), Quality::Sinc),
+            decoder,
+            samples: Vec::new(),
+            resampled: Vec::new(),
+            pending: Vec::new(),
+            written: 0,
+        })
+    }
+
+    fn seek(&mut self, ms: u32) {
+        self.decoder.seek(ms);
+        self.resampler.reset();
+        self.pending.clear();
+        self.written = 0;
+    }
+
+    /// Decodes into the queue until it's full. Returns false when the track has finished.
+    fn fill(&mut self, wrapper: &mut Wrapper, library: &[Track], writer: &mut AudioWriter) -> bool {
+        let track = &library[self.index];
+        loop {
+            if self.written < self.pending.len() {
+                self.written += writer.write(&self.pending[self.written..]);
+                if self.written < self.pending.len() {
+                    return true;
+                }
+            }
+            let mut src = SdFile {
+                wrapper: &mut *wrapper,
+                name: &track.name,
+                size: track.size,
+            };
+            self.samples.clear();
+            if !self.decoder.next_chunk(&mut src, &mut self.samples) {
+                self.resampled.clear();
+                self.resampler.flush(&mut self.resampled);
+                self.pending.clear();
+                to_stereo(&self.resampled, self.decoder.channels(), &mut self.pending);
+                self.written = writer.write(&self.pending);
+                return false;
+            }
+            self.resampled.clear();
+            self.resampler.process(&self.samples, &mut self.resampled);
+            self.pending.clear();
+            to_stereo(&self.resampled, self.decoder.channels(), &mut self.pending);
+            self.written = 0;
+        }
+    }
+}
+
+fn save_resume(wrapper: &mut Wrapper, resume: &Resume) {
+    if let Err(e) = settings::save(&mut wrapper.flash, Slot::Music, resume) {
+        info!("couldn't save where we were {:?}", e);
+    }
+}
+
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    // room for a MOD file loaded whole and the visualizer
+    esp_alloc::heap_allocator!(size: 192 * 1024);
+
+    info!("running");
+
+    let audio = wrapper.audio.take().unwrap();
+    let config = AudioConfig {
+        sample_rate: SINK_RATE,
+        channels: 2,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
+    let volume: Volume = settings::load(&mut wrapper.flash, Slot::Volume).unwrap_or_default();
+    writer.set_volume(volume);
+    let preset: Preset = settings::load(&mut wrapper.flash, Slot::Equalizer).unwrap_or_default();
+    writer.set_preset(preset);
+
+    let library = scan_library(&mut wrapper);
+    let playlists = playlist_files(&mut wrapper);
+    let seed = Instant::now().as_ticks() as u32;
+    let mut queue = Queue::new(library.len(), seed);
+    let mut app = App {
+        list: (0..library.len()).collect(),
+        library,
+        playlist: None,
+        selected: 0,
+        current: None,
+        position_ms: 0,
+        duration_ms: 0,
+        playing: false,
+        shuffle: false,
+        repeat: Repeat::Off,
+        volume: VolumeControl::new(volume),
+        preset,
+        visual: None,
+    };
+
+    // carry on from before the reboot
+    let resume: Resume = settings::load(&mut wrapper.flash, Slot::Music).unwrap_or_default();
+    let mut playing = None;
+    if let Some(name) = &resume.playlist {
+        if let Some(list) = load_playlist(&mut wrapper, &app.library, name) {
+            queue = Queue::from_order(list.clone(), seed);
+            app.list = list;
+            app.playlist = Some(name.clone());
+        }
+    }
+    if let Some(index) = app.library.iter().position(|t| t.name == resume.name) {
+        queue.repeat = resume.repeat;
+        queue.play(index);
+        queue.set_shuffle(resume.shuffle);
+        playing = Playing::open(&mut wrapper, &app.library, index, resume.position_ms);
+        app.selected = app.list.iter().position(|t| *t == index).unwrap_or(0);
+        app.playing = playing.is_some();
+    }
+    let mut last_saved = Instant::now().as_millis();
+    let mut last_frame = last_saved;
+
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(wrapper.screen_bounds());
+
+    loop {
+        wrapper.poll_trackball();
+        let [left, right, mut up, mut down, click] = wrapper.trackball_changes();
+        let mut key = wrapper.read_key();
+        if app.volume.handle(key, up, down, Instant::now().as_millis()) {
+            writer.set_volume(app.volume.volume);
+            (key, up, down) = (None, false, false);
+        }
+        let before = (app.current, app.selected, app.playing, queue.shuffle, queue.repeat);
+        let mut playlist_changed = false;
+        // what to play next, and from where
+        let mut open: Option<(usize, u32)> = None;
+
+        if down && app.selected + 1 < app.list.len() {
+            app.selected += 1;
+        }
+        if up && app.selected > 0 {
+            app.selected -= 1;
+        }
+        if (click || key == Some(b'\r')) && !app.list.is_empty() {
+            let index = app.list[app.selected];
+            queue.play(index);
+            open = Some((index, 0));
+        }
+        match key {
+            Some(b' ') => {
+                if let Some(p) = playing.as_mut() {
+                    app.playing = !app.playing;
+                    if !app.playing {
+                        // fade out now rather than play out the queue, and carry on from
+                        // what was heard
+                        writer.stop().await;
+                        p.seek(app.position_ms);
+                    }
+                } else if let Some(index) = queue.current() {
+                    open = Some((index, 0));
+                }
+            }
+            Some(b'n') => open = queue.next(true).map(|i| (i, 0)),
+            Some(b'p') => {
+                if app.position_ms > RESTART_MS {
+                    open = queue.current().map(|i| (i, 0));
+                } else {
+                    open = queue.previous().map(|i| (i, 0));
+                }
+            }
+            Some(b's') => queue.set_shuffle(!queue.shuffle),
+            Some(b'r') => queue.repeat = queue.repeat.next(),
+            Some(b'l') => {
+                // the next playlist, or all the tracks after the last one
+                let at = app.playlist.as_ref().and_then(|p| playlists.iter().position(|(n, _)| n == p));
+                let next = match at {
+                    Some(at) => playlists.get(at + 1),
+                    None => playlists.first(),
+                };
+                let (name, list) = match next {
+                    Some((name, _)) => match load_playlist(&mut wrapper, &app.library, name) {
+                        Some(list) => (Some(name.clone()), list),
+                        None => (None, (0..app.library.len()).collect()),
+                    },
+                    None => (None, (0..app.library.len()).collect()),
+                };
+                let (shuffle, repeat) = (queue.shuffle, queue.repeat);
+                queue = Queue::from_order(list.clone(), seed);
+                queue.repeat = repeat;
+                // carry on with what's playing if it's in there
+                if let Some(current) = app.current {
+                    queue.play(current);
+                }
+                queue.set_shuffle(shuffle);
+                app.list = list;
+                app.playlist = name;
+                app.selected = 0;
+                playlist_changed = true;
+            }
+            Some(b'w') => save_queue(&mut wrapper, &app.library, &queue),
+            Some(b'v') => {
+                match app.visual.as_mut() {
+                    None => app.visual = Some(Visualizer::new(VISUAL, Mode::Bars)),
+                    Some(visual) if visual.mode == Mode::Waveform => app.visual = None,
+                    Some(visual) => visual.set_mode(visual.mode.next()),
+                }
+                dirty.add(VISUAL);
+            }
+            Some(b'e') => {
+                app.preset = app.preset.next();
+                writer.set_preset(app.preset);
+                if let Err(e) = settings::save(&mut wrapper.flash, Slot::Equalizer, &app.preset) {
+                    info!("couldn't save the equalizer {:?}", e);
+                }
+                dirty.add(PROGRESS);
+            }
+            _ => {}
+        }
+        if let Some(p) = playing.as_mut() {
+            let position = p.decoder.position_ms();
+            let mut seek = None;
+            if left || key == Some(b'a') {
+                seek = Some(position.saturating_sub(SEEK_MS));
+            }
+            if right || key == Some(b'd') {
+                seek = Some((position + SEEK_MS).min(p.decoder.duration_ms()));
+            }
+            if let Some(ms) = seek {
+                // what's queued is from before the seek, don't make the user sit through it
+                if app.playing {
+                    writer.clear().await;
+                }
+                p.seek(ms);
+            }
+        }
+
+        // the user changed track, so cut off what's queued rather than play it out
+        let cut = open.is_some();
+
+        // keep the queue topped up
+        if app.playing && open.is_none() {
+            if let Some(p) = playing.as_mut() {
+                if !p.fill(&mut wrapper, &app.library, &mut writer) {
+                    match queue.next(false) {
+                        Some(index) => open = Some((index, 0)),
+                        None => {
+                            info!("end of the queue");
+                            writer.finish();
+                            app.playing = false;
+                            p.seek(0);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some((index, position)) = open {
+            if cut && playing.is_some() {
+                writer.stop().await;
+            }
+            // the old track's memory is needed for the new one
+            drop(playing.take());
+            playing = Playing::open(&mut wrapper, &app.library, index, position);
+            app.playing = playing.is_some();
+            if let Some(at) = app.list.iter().position(|t| *t == index) {
+                app.selected = at;
+            }
+        }
+
+        app.current = playing.as_ref().map(|p| p.index);
+        app.shuffle = queue.shuffle;
+        app.repeat = queue.repeat;
+        if playlist_changed || before != (app.current, app.selected, app.playing, queue.shuffle, queue.repeat) {
+            dirty.add(wrapper.screen_bounds());
+        }
+        if let Some(p) = &playing {
+            // what's coming out of the speaker is behind what's been decoded
+            let latency = if app.playing { writer.stats().latency_ms } else { 0 };
+            let position = p.decoder.position_ms().saturating_sub(latency);
+            if position / 1000 != app.position_ms / 1000 || app.duration_ms != p.decoder.duration_ms() {
+                dirty.add(PROGRESS);
+            }
+            app.position_ms = position;
+            app.duration_ms = p.decoder.duration_ms();
+        }
+
+        // save where we are when something changes, and now and then while playing
+        let now = Instant::now().as_millis();
+        let changed =
+            playlist_changed || before.0 != app.current || before.2 != app.playing || before.3 != queue.shuffle;
+        if changed || before.4 != queue.repeat || (app.playing && now - last_saved > SAVE_EVERY_MS) {
+            if let Some(index) = app.current {
+                let resume = Resume {
+                    name: app.library[index].name.clone(),
+                    position_ms: app.position_ms,
+                    shuffle: queue.shuffle,
+                    repeat: queue.repeat,
+                    playlist: app.playlist.clone(),
+                };
+                save_resume(&mut wrapper, &resume);
+            }
+            last_saved = now;
+        }
+
+        if let Some(visual) = app.visual.as_mut() {
+            if now >= last_frame + FRAME_MS {
+                visual.update(&SINK_TAP, now - last_frame);
+                dirty.add(VISUAL);
+                last_frame = now;
+            }
+        }
+
+        if app.volume.poll(now) {
+            dirty.add(VolumeControl::AREA);
+        }
+        if let Some(volume) = app.volume.take_unsaved() {
+            if let Err(e) = settings::save(&mut wrapper.flash, Slot::Volume, &volume) {
+                info!("couldn't save the volume {:?}", e);
+            }
+        }
+
+        render(&app, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        // an MP3 frame is about 26ms, so this keeps well ahead of the speaker
+        Timer::after_millis(10).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
@@ -0,0 +1,299 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use embassy_executor::Spawner;
+use embassy_time::{Instant, Timer};
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10, FONT_7X13};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use esp_hal::clock::CpuClock;
+use log::info;
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPlayer, AudioSink, AudioWriter, QUEUE_FRAMES};
+use rust_tdeck_experiments::game::{render, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::synth::{Synth, PRESETS};
+use rust_tdeck_experiments::volume::{Volume, VolumeControl};
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+Plays the keyboard like a piano, through the synth module.
+
+The middle row, a s d f g h j k l, are the white keys from C, and w e t y u o above them
+are the black keys. z and x move down and up an octave, as do trackball up and down.
+c and v, or trackball left and right, go through the preset sounds. + and - change the
+volume, as do trackball up and down while it's showing, and 0 mutes.
+
+The keyboard only says when a key is pressed, not when it's let go, so each note is held
+for a moment and then released.
+ */
+
+const SINK_RATE: u32 = 44_100;
+const POLYPHONY: usize = 10;
+const VELOCITY: u8 = 100;
+// how long a note is held before it's released
+const GATE_MS: u64 = 300;
+// kept small so a note sounds soon after its key is pressed, about 23ms
+const AHEAD_FRAMES: usize = 1024;
+const MIX_FRAMES: usize = 256;
+
+// semitones above C for each key
+const WHITE_KEYS: [(u8, u8); 9] = [
+    (b'a', 0),
+    (b's', 2),
+    (b'd', 4),
+    (b'f', 5),
+    (b'g', 7),
+    (b'h', 9),
+    (b'j', 11),
+    (b'k', 12),
+    (b'l', 14),
+];
+const BLACK_KEYS: [(u8, u8); 6] = [
+    (b'w', 1),
+    (b'e', 3),
+    (b't', 6),
+    (b'y', 8),
+    (b'u', 10),
+    (b'o', 13),
+];
+
+const HEADER: Rectangle = Rectangle::new(Point::new(0, 0), Size::new(320, 60));
+const KEYBOARD: Rectangle = Rectangle::new(Point::new(0, 70), Size::new(320, 150));
+const WHITE_WIDTH: i32 = 34;
+const BLACK_WIDTH: i32 = 22;
+const BLACK_HEIGHT: u32 = 90;
+const LEFT: i32 = 7;
+
+struct App {
+    preset: usize,
+    octave: u8,
+    /// semitones above the C of `octave` that are sounding
+    held: Vec<u8>,
+    voices: usize,
+    volume: VolumeControl,
+}
+
+impl App {
+    fn base_note(&self) -> u8 {
+        12 * (self.octave + 1)
+    }
+}
+
+impl Scene for App {
+    fn draw(&self, target: &mut FrameBuffer) {
+        let big = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+        let small = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_LIGHT_GRAY);
+        HEADER
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_MIDNIGHT_BLUE))
+            .draw(target)
+            .unwrap();
+        Text::with_baseline(PRESETS[self.preset].0, Point::new(8, 6), big, Baseline::Top)
+            .draw(target)
+            .unwrap();
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Top).build();
+        let status = format!("octave {}  voices {}/{}", self.octave, self.voices, POLYPHONY);
+        Text::with_text_style(&status, Point::new(312, 10), small, right)
+            .draw(target)
+            .unwrap();
+        Text::with_baseline("z/x octave  c/v sound", Point::new(8, 36), small, Baseline::Top)
+            .draw(target)
+            .unwrap();
+
+        let label = MonoTextStyle::new(&FONT_7X13, Rgb565::CSS_DIM_GRAY);
+        let outline = PrimitiveStyleBuilder::new()
+            .stroke_color(Rgb565::CSS_DIM_GRAY)
+            .stroke_width(1);
+        let top = KEYBOARD.top_left.y;
+        for (i, (key, semis)) in WHITE_KEYS.iter().enumerate() {
+            let fill = if self.held.contains(semis) {
+                Rgb565::CSS_ORANGE
+            } else {
+                Rgb565::WHITE
+            };
+            let x = LEFT + i as i32 * WHITE_WIDTH;
+            Rectangle::new(Point::new(x, top), Size::new(WHITE_WIDTH as u32, KEYBOARD.size.height))
+                .into_styled(outline.fill_color(fill).build())
+                .draw(target)
+                .unwrap();
+            let text = [key.to_ascii_uppercase()];
+            let text = core::str::from_utf8(&text).unwrap();
+            Text::with_baseline(text, Point::new(x + 13, top + 130), label, Baseline::Top)
+                .draw(target)
+                .unwrap();
+        }
+        let label = MonoTextStyle::new(&FONT_7X13, Rgb565::CSS_LIGHT_GRAY);
+        for (key, semis) in BLACK_KEYS.iter() {
+            // sits over the line between the white keys either side
+            let white = WHITE_KEYS.iter().position(|(_, s)| *s == semis + 1).unwrap_or(0) as i32;
+            let x = LEFT + white * WHITE_WIDTH - BLACK_WIDTH / 2;
+            let fill = if self.held.contains(semis) {
+                Rgb565::CSS_ORANGE
+            } else {
+                Rgb565::BLACK
+            };
+            Rectangle::new(Point::new(x, top), Size::new(BLACK_WIDTH as u32, BLACK_HEIGHT))
+                .into_styled(outline.fill_color(fill).build())
+                .draw(target)
+                .unwrap();
+            let text = [key.to_ascii_uppercase()];
+            let text = core::str::from_utf8(&text).unwrap();
+            Text::with_baseline(text, Point::new(x + 8, top + 70), label, Baseline::Top)
+                .draw(target)
+                .unwrap();
+        }
+        self.volume.draw(target).unwrap();
+    }
+}
+
+fn key_semitones(key: u8) -> Option<u8> {
+    WHITE_KEYS
+        .iter()
+        .chain(BLACK_KEYS.iter())
+        .find(|(k, _)| *k == key)
+        .map(|(_, s)| *s)
+}
+
+// keeps the sink about AHEAD_FRAMES ahead of the speaker
+fn mix_ahead(synth: &mut Synth, writer: &mut AudioWriter, samples: &mut [i16]) {
+    while QUEUE_FRAMES - writer.space() < AHEAD_FRAMES {
+        synth.render(samples);
+        writer.write(samples);
+    }
+}
+
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    info!("running");
+
+    let audio = wrapper.audio.take().unwrap();
+    let config = AudioConfig {
+        sample_rate: SINK_RATE,
+        channels: 1,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
+    let volume: Volume = settings::load(&mut wrapper.flash, Slot::Volume).unwrap_or_default();
+    writer.set_volume(volume);
+
+    let mut synth = Synth::new(SINK_RATE, POLYPHONY);
+    let mut app = App {
+        preset: 0,
+        octave: 4,
+        held: Vec::new(),
+        voices: 0,
+        volume: VolumeControl::new(volume),
+    };
+    synth.patch = PRESETS[app.preset].1;
+    // notes waiting to be released, and when
+    let mut gates: Vec<(u8, u64)> = Vec::new();
+    let mut samples = vec![0i16; MIX_FRAMES];
+
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(wrapper.screen_bounds());
+
+    loop {
+        wrapper.poll_trackball();
+        let mut key = wrapper.read_key();
+        let [left, right, mut up, mut down, _] = wrapper.trackball_changes();
+        let now = Instant::now().as_millis();
+        if app.volume.handle(key, up, down, now) {
+            writer.set_volume(app.volume.volume);
+            (key, up, down) = (None, false, false);
+        }
+        let before = (app.preset, app.octave);
+
+        if let Some(semis) = key.and_then(key_semitones) {
+            let note = app.base_note() + semis;
+            synth.note_on(note, VELOCITY);
+            gates.retain(|(n, _)| *n != note);
+            gates.push((note, now + GATE_MS));
+        }
+        if (key == Some(b'z') || down) && app.octave > 0 {
+            app.octave -= 1;
+        }
+        if (key == Some(b'x') || up) && app.octave < 8 {
+            app.octave += 1;
+        }
+        if key == Some(b'c') || left {
+            app.preset = (app.preset + PRESETS.len() - 1) % PRESETS.len();
+        }
+        if key == Some(b'v') || right {
+            app.preset = (app.preset + 1) % PRESETS.len();
+        }
+        if app.preset != before.0 {
+            synth.patch = PRESETS[app.preset].1;
+            info!("{}", PRESETS[app.preset].0);
+        }
+
+        gates.retain(|(note, until)| {
+            if now >= *until {
+                synth.note_off(*note);
+            }
+            now < *until
+        });
+
+        mix_ahead(&mut synth, &mut writer, &mut samples);
+
+        let base = app.base_note();
+        let mut held: Vec<u8> = synth
+            .held_notes()
+            .filter_map(|(_, note)| note.checked_sub(base))
+            .collect();
+        held.sort_unstable();
+        let voices = synth.active();
+        if held != app.held {
+            app.held = held;
+            dirty.add(KEYBOARD);
+        }
+        if voices != app.voices || before != (app.preset, app.octave) {
+            dirty.add(HEADER);
+        }
+        app.voices = voices;
+        if app.volume.poll(now) {
+            dirty.add(VolumeControl::AREA);
+        }
+        if let Some(volume) = app.volume.take_unsaved() {
+            if let Err(e) = settings::save(&mut wrapper.flash, Slot::Volume, &volume) {
+                info!("couldn't save the volume {:?}", e);
+            }
+        }
+        render(&app, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        Timer::after_millis(2).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
@@ -0,0 +1,164 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use esp_hal::clock::CpuClock;
+use esp_hal::main;
+use esp_hal::time::Instant;
+use log::info;
+use rust_tdeck_experiments::game::{render, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::power::{PowerManager, PowerState, PowerTimeouts};
+use rust_tdeck_experiments::{Gamma, Wrapper};
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+Shows the time since boot and steps the display down when left alone: idle mode (8 colors)
+after 10 seconds, only the clock after 20 seconds, and asleep with the backlight off after
+a minute. Any key, trackball move or touch wakes it up.
+g cycles the gamma curves, i toggles color inversion and s goes to sleep right away.
+ */
+
+const CLOCK_AREA: Rectangle = Rectangle::new(Point::new(110, 100), Size::new(100, 40));
+
+struct Clock {
+    seconds: u64,
+    state: PowerState,
+    gamma: Gamma,
+    inverted: bool,
+}
+
+impl Scene for Clock {
+    fn draw(&self, target: &mut FrameBuffer) {
+        let centered = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Middle)
+            .build();
+        let time = format!(
+            "{:02}:{:02}:{:02}",
+            self.seconds / 3600,
+            self.seconds / 60 % 60,
+            self.seconds % 60
+        );
+        // pure colors look the same in idle mode
+        let style = MonoTextStyle::new(&FONT_10X20, Rgb565::CYAN);
+        Text::with_text_style(&time, CLOCK_AREA.center(), style, centered)
+            .draw(target)
+            .unwrap();
+
+        let small = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+        let status = format!("{:?}  gamma {:?}  inverted {}", self.state, self.gamma, self.inverted);
+        Text::with_baseline(&status, Point::new(4, 4), small, Baseline::Top)
+            .draw(target)
+            .unwrap();
+    }
+}
+
+#[main]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    // an ST7789V says 85 85 52
+    match wrapper.read_display_id() {
+        Ok(id) => info!("display id {:02x?}", id),
+        Err(e) => info!("couldn't read the display id {:?}", e),
+    }
+    info!("running");
+
+    let now = || Instant::now().duration_since_epoch().as_millis();
+    let timeouts = PowerTimeouts {
+        dim_ms: Some(10_000),
+        clock_ms: Some(20_000),
+        sleep_ms: Some(60_000),
+    };
+    let mut power = PowerManager::new(timeouts, now());
+    let mut clock = Clock {
+        seconds: 0,
+        state: PowerState::Active,
+        gamma: Gamma::Curve1,
+        inverted: true,
+    };
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(wrapper.screen_bounds());
+
+    loop {
+        wrapper.poll_trackball();
+        let key = wrapper.read_key();
+        let touched = wrapper.poll_touchscreen().is_ok_and(|points| !points.is_empty());
+        let moved = wrapper.trackball_changes().contains(&true);
+        let t = now();
+
+        if key.is_some() || moved || touched {
+            if let Some(state) = power.activity(t) {
+                wrapper.set_power_state(state, CLOCK_AREA).unwrap();
+                // the panel wasn't refreshed while asleep or partial
+                dirty.add(wrapper.screen_bounds());
+            }
+            match key {
+                Some(b'g') => {
+                    clock.gamma = match clock.gamma {
+                        Gamma::Curve1 => Gamma::Curve2,
+                        Gamma::Curve2 => Gamma::Curve3,
+                        Gamma::Curve3 => Gamma::Curve4,
+                        Gamma::Curve4 => Gamma::Curve1,
+                    };
+                    wrapper.set_gamma(clock.gamma).unwrap();
+                }
+                Some(b'i') => {
+                    clock.inverted = !clock.inverted;
+                    wrapper.set_inversion(clock.inverted).unwrap();
+                }
+                Some(b's') => {
+                    power.timeouts.sleep_ms = Some(0);
+                }
+                _ => {}
+            }
+            dirty.add(wrapper.screen_bounds());
+        }
+        if let Some(state) = power.update(t) {
+            wrapper.set_power_state(state, CLOCK_AREA).unwrap();
+            power.timeouts = timeouts;
+            dirty.add(wrapper.screen_bounds());
+        }
+
+        if clock.state != power.state() {
+            clock.state = power.state();
+            dirty.add(wrapper.screen_bounds());
+        }
+        if t / 1000 != clock.seconds {
+            clock.seconds = t / 1000;
+            dirty.add(CLOCK_AREA);
+        }
+        if !wrapper.is_display_asleep() {
+            render(&clock, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        }
+        // poll less often when nobody is looking
+        wrapper.delay.delay_millis(if power.is_active() { 20 } else { 100 });
+    }
+}
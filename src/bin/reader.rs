@@ -0,0 +1,352 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_graphics::mono_font::ascii::{FONT_6X10, FONT_7X13};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+use esp_hal::clock::CpuClock;
+use esp_hal::main;
+use esp_hal::time::Instant;
+use log::info;
+use rust_tdeck_experiments::game::{render, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::markdown::MarkdownView;
+use rust_tdeck_experiments::reader::{Bookmarks, PageLayout, Reader, TextSource};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::theme::Theme;
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+A text reader for .TXT and .MD files in the root of the SD card.
+Pick a file with the trackball and click or press enter to open it.
+Text files are shown a page at a time. Trackball down/right or space for the next page,
+up/left or b for the previous page.
+Markdown files are formatted and scroll smoothly. The trackball scrolls a few lines,
+space and b scroll a page.
+Backspace or q goes back to the list. The last place read in each file is remembered.
+Press r to turn the screen a quarter turn, for reading in portrait.
+ */
+
+const STATUS_HEIGHT: u32 = 14;
+const SCROLL_STEP: i32 = 26;
+const PAPER: Rgb565 = Rgb565::new(30, 60, 27);
+const INK: Rgb565 = Rgb565::new(4, 8, 4);
+// don't write to flash on every page turn
+const BOOKMARK_DELAY_MS: u64 = 3000;
+
+// a file on the SD card, opened again for each read
+struct SdText<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+    size: u32,
+}
+
+impl TextSource for SdText<'_> {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        self.wrapper.read_file_at(self.name, offset, buf).unwrap_or_else(|e| {
+            info!("couldn't read {} {:?}", self.name, e);
+            0
+        })
+    }
+}
+
+fn text_area(screen: Size) -> Rectangle {
+    Rectangle::new(Point::new(2, 0), Size::new(screen.width - 2, screen.height - STATUS_HEIGHT))
+}
+
+fn document_area(screen: Size) -> Rectangle {
+    Rectangle::new(Point::zero(), Size::new(screen.width, screen.height - STATUS_HEIGHT))
+}
+
+fn status_area(screen: Size) -> Rectangle {
+    Rectangle::new(
+        Point::new(0, (screen.height - STATUS_HEIGHT) as i32),
+        Size::new(screen.width, STATUS_HEIGHT),
+    )
+}
+
+fn page_layout(screen: Size) -> PageLayout {
+    PageLayout::for_font(&FONT_7X13, text_area(screen).size)
+}
+
+enum Screen {
+    Files { files: Vec<(String, u32)>, selected: usize },
+    Reading { name: String, size: u32, reader: Reader },
+    Document { name: String, size: u32, view: MarkdownView },
+}
+
+struct App {
+    screen: Screen,
+    /// the screen size in the current rotation
+    size: Size,
+}
+
+impl Scene for App {
+    fn background(&self) -> Rgb565 {
+        match &self.screen {
+            Screen::Files { .. } => Rgb565::BLACK,
+            Screen::Reading { .. } => PAPER,
+            Screen::Document { view, .. } => view.theme.background,
+        }
+    }
+
+    fn draw(&self, target: &mut FrameBuffer) {
+        let small = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+        let status_area = status_area(self.size);
+        let status_at = status_area.top_left + Point::new(4, 2);
+        status_area
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_GRAY))
+            .draw(target)
+            .unwrap();
+        match &self.screen {
+            Screen::Files { files, selected } => {
+                let style = MonoTextStyle::new(&FONT_7X13, Rgb565::WHITE);
+                let highlight = MonoTextStyle::new(&FONT_7X13, Rgb565::YELLOW);
+                // scroll the list so the selection stays visible
+                let rows = (status_area.top_left.y / 13) as usize;
+                let first = selected.saturating_sub(rows - 1);
+                for (i, (name, size)) in files.iter().enumerate().skip(first).take(rows) {
+                    let line = format!("{:<14}{:>10} bytes", name, size);
+                    let style = if i == *selected { highlight } else { style };
+                    let p = Point::new(4, ((i - first) * 13) as i32);
+                    Text::with_baseline(&line, p, style, Baseline::Top).draw(target).unwrap();
+                }
+                if files.is_empty() {
+                    Text::with_baseline("no .TXT or .MD files", Point::new(4, 4), style, Baseline::Top)
+                        .draw(target)
+                        .unwrap();
+                }
+                Text::with_baseline("pick a file", status_at, small, Baseline::Top)
+                    .draw(target)
+                    .unwrap();
+            }
+            Screen::Reading { name, reader, .. } => {
+                let style = MonoTextStyle::new(&FONT_7X13, INK);
+                reader.draw(target, text_area(self.size), style, PAPER).unwrap();
+                let status = format!("{}  {}%", name, reader.percent());
+                Text::with_baseline(&status, status_at, small, Baseline::Top)
+                    .draw(target)
+                    .unwrap();
+            }
+            Screen::Document { name, view, .. } => {
+                view.draw(target, document_area(self.size)).unwrap();
+                // the length is only known once the end has been laid out
+                let status = match view.percent() {
+                    Some(percent) => format!("{}  {}%", name, percent),
+                    None => name.clone(),
+                };
+                Text::with_baseline(&status, status_at, small, Baseline::Top)
+                    .draw(target)
+                    .unwrap();
+            }
+        }
+    }
+}
+
+fn text_files(wrapper: &mut Wrapper) -> Vec<(String, u32)> {
+    let mut files = wrapper.list_files().unwrap_or_default();
+    files.retain(|(name, _)| name.ends_with(".TXT") || name.ends_with(".MD"));
+    files.sort();
+    files
+}
+
+// turn the screen and lay out the open file again for the new size, keeping the place
+fn rotate(wrapper: &mut Wrapper, app: &mut App) {
+    if let Err(e) = wrapper.set_rotation(wrapper.rotation().clockwise()) {
+        info!("couldn't rotate the screen {:?}", e);
+        return;
+    }
+    app.size = wrapper.screen_size();
+    info!("rotated to {} degrees", wrapper.rotation().degrees());
+    match &mut app.screen {
+        Screen::Files { .. } => {}
+        Screen::Reading { name, size, reader } => {
+            let start = reader.page.start;
+            *reader = Reader::new(page_layout(app.size));
+            reader.open_at(&mut SdText { wrapper, name, size: *size }, start);
+        }
+        Screen::Document { name, size, view } => {
+            let scroll = view.scroll;
+            *view = MarkdownView::new(view.theme, document_area(app.size).size);
+            view.scroll_to(&mut SdText { wrapper, name, size: *size }, scroll);
+        }
+    }
+}
+
+fn save_bookmarks(wrapper: &mut Wrapper, bookmarks: &Bookmarks) {
+    if let Err(e) = settings::save(&mut wrapper.flash, Slot::Bookmarks, bookmarks) {
+        info!("couldn't save the bookmarks {:?}", e);
+    }
+}
+
+#[main]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    info!("running");
+
+    let mut bookmarks: Bookmarks = settings::load(&mut wrapper.flash, Slot::Bookmarks).unwrap_or_default();
+    // when the current position was last changed but not saved yet
+    let mut unsaved_since: Option<u64> = None;
+
+    let mut app = App {
+        screen: Screen::Files {
+            files: text_files(&mut wrapper),
+            selected: 0,
+        },
+        size: wrapper.screen_size(),
+    };
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(wrapper.screen_bounds());
+
+    loop {
+        wrapper.poll_trackball();
+        let key = wrapper.read_key();
+        let [left, right, up, down, click] = wrapper.trackball_changes();
+        let now = Instant::now().duration_since_epoch().as_millis();
+
+        if key == Some(b'r') {
+            rotate(&mut wrapper, &mut app);
+            dirty.add(wrapper.screen_bounds());
+        }
+
+        match &mut app.screen {
+            Screen::Files { files, selected } => {
+                if (down || right) && *selected + 1 < files.len() {
+                    *selected += 1;
+                    dirty.add(wrapper.screen_bounds());
+                }
+                if (up || left) && *selected > 0 {
+                    *selected -= 1;
+                    dirty.add(wrapper.screen_bounds());
+                }
+                if (click || key == Some(b'\r')) && !files.is_empty() {
+                    let (name, size) = files[*selected].clone();
+                    // a byte offset for text files, the scroll position for markdown
+                    let position = bookmarks.get(&name).unwrap_or(0);
+                    info!("opening {} at {}", name, position);
+                    let mut src = SdText {
+                        wrapper: &mut wrapper,
+                        name: &name,
+                        size,
+                    };
+                    if name.ends_with(".MD") {
+                        let mut view = MarkdownView::new(Theme::dark(), document_area(app.size).size);
+                        view.scroll_to(&mut src, position);
+                        app.screen = Screen::Document { name, size, view };
+                    } else {
+                        let mut reader = Reader::new(page_layout(app.size));
+                        reader.open_at(&mut src, position);
+                        app.screen = Screen::Reading { name, size, reader };
+                    }
+                    dirty.add(wrapper.screen_bounds());
+                }
+            }
+            Screen::Reading { name, size, reader } => {
+                let mut src = SdText {
+                    wrapper: &mut wrapper,
+                    name,
+                    size: *size,
+                };
+                let turned = if down || right || key == Some(b' ') || key == Some(b'\r') {
+                    reader.next_page(&mut src)
+                } else if up || left || key == Some(b'b') {
+                    reader.prev_page(&mut src)
+                } else {
+                    false
+                };
+                if turned {
+                    bookmarks.set(name, reader.page.start);
+                    unsaved_since = Some(now);
+                    dirty.add(wrapper.screen_bounds());
+                }
+                if key == Some(8) || key == Some(b'q') {
+                    bookmarks.set(name, reader.page.start);
+                    save_bookmarks(&mut wrapper, &bookmarks);
+                    unsaved_since = None;
+                    app.screen = Screen::Files {
+                        files: text_files(&mut wrapper),
+                        selected: 0,
+                    };
+                    dirty.add(wrapper.screen_bounds());
+                }
+            }
+            Screen::Document { name, size, view } => {
+                let mut src = SdText {
+                    wrapper: &mut wrapper,
+                    name,
+                    size: *size,
+                };
+                let page = document_area(app.size).size.height as i32 - SCROLL_STEP;
+                let dy = if down || right {
+                    SCROLL_STEP
+                } else if up || left {
+                    -SCROLL_STEP
+                } else if key == Some(b' ') || key == Some(b'\r') {
+                    page
+                } else if key == Some(b'b') {
+                    -page
+                } else {
+                    0
+                };
+                if dy != 0 {
+                    let before = view.scroll;
+                    view.scroll_by(&mut src, dy);
+                    if view.scroll != before {
+                        bookmarks.set(name, view.scroll);
+                        unsaved_since = Some(now);
+                        dirty.add(wrapper.screen_bounds());
+                    }
+                }
+                if key == Some(8) || key == Some(b'q') {
+                    bookmarks.set(name, view.scroll);
+                    save_bookmarks(&mut wrapper, &bookmarks);
+                    unsaved_since = None;
+                    app.screen = Screen::Files {
+                        files: text_files(&mut wrapper),
+                        selected: 0,
+                    };
+                    dirty.add(wrapper.screen_bounds());
+                }
+            }
+        }
+
+        if unsaved_since.is_some_and(|t| now - t > BOOKMARK_DELAY_MS) {
+            save_bookmarks(&mut wrapper, &bookmarks);
+            unsaved_since = None;
+        }
+        render(&app, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        wrapper.delay.delay_millis(10);
+    }
+}
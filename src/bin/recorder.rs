@@ -0,0 +1,553 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use embassy_executor::Spawner;
+use embassy_time::{Instant, Timer};
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10, FONT_7X13};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use embedded_sdmmc::SdCardError;
+use esp_hal::clock::CpuClock;
+use log::info;
+use rust_tdeck_experiments::audio::{AudioConfig, AudioPlayer, AudioSink, AudioWriter};
+use rust_tdeck_experiments::capture::{AudioRecorder, AudioSource, CaptureConfig, LevelMeter};
+use rust_tdeck_experiments::game::{render, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::music::{format_time, to_stereo};
+use rust_tdeck_experiments::reader::TextSource;
+use rust_tdeck_experiments::resample::{Quality, Resampler};
+use rust_tdeck_experiments::settings::{self, Slot};
+use rust_tdeck_experiments::volume::{Volume, VolumeControl};
+use rust_tdeck_experiments::wav::{self, WavSink, WavStream, WavWriter};
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+A voice recorder. Records from the microphone to 16kHz mono WAV files named REC000.WAV,
+REC001.WAV and so on in the root of the SD card, and plays them back through the speaker.
+
+r starts a new recording, space pauses and carries on, and s stops and finishes the file.
+trackball up/down picks a recording and click or enter plays it, s stops playing. The
+meter shows how loud the microphone is all the time, so you can check the level first.
++ and - change the playback volume, and so do trackball up and down while it's showing,
+and 0 mutes.
+
+A recording cut off by a reset still plays, it just has the sizes in its header missing.
+ */
+
+const CAPTURE_RATE: u32 = 16_000;
+const MIC_GAIN_DB: u8 = 24;
+const SINK_RATE: u32 = 44_100;
+// bytes written to the card at a time, a quarter of a second
+const WRITE_BYTES: usize = 8192;
+// bytes read from the card at a time when playing
+const READ_BYTES: usize = 4096;
+// how much the meter falls each time round the loop
+const METER_DECAY: u16 = 400;
+
+const HEADER: Rectangle = Rectangle::new(Point::new(0, 0), Size::new(320, 60));
+const METER: Rectangle = Rectangle::new(Point::new(0, 60), Size::new(320, 26));
+const BAR: Rectangle = Rectangle::new(Point::new(8, 64), Size::new(250, 16));
+const LIST: Rectangle = Rectangle::new(Point::new(0, 86), Size::new(320, 142));
+const LIST_TOP: i32 = 90;
+const ROW_HEIGHT: i32 = 13;
+const STATUS: Rectangle = Rectangle::new(Point::new(0, 228), Size::new(320, 12));
+
+struct SdFile<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+    size: u32,
+}
+
+impl TextSource for SdFile<'_> {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        self.wrapper.read_file_at(self.name, offset, buf).unwrap_or_else(|e| {
+            info!("couldn't read {} {:?}", self.name, e);
+            0
+        })
+    }
+}
+
+// a recording being written to the card
+struct SdWav<'a> {
+    wrapper: &'a mut Wrapper,
+    name: &'a str,
+}
+
+impl WavSink for SdWav<'_> {
+    type Error = embedded_sdmmc::Error<SdCardError>;
+
+    fn append(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.wrapper.append_file(self.name, data)
+    }
+
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        self.wrapper.write_file_at(self.name, offset, data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Stopped,
+    Recording,
+    Paused,
+    Playing,
+}
+
+struct App {
+    state: State,
+    /// the recording or playback
+    name: Option<String>,
+    time_ms: u32,
+    meter: LevelMeter,
+    /// the recordings on the card, with their lengths
+    files: Vec<(String, u32)>,
+    selected: usize,
+    message: String,
+    volume: VolumeControl,
+}
+
+impl Scene for App {
+    fn draw(&self, target: &mut FrameBuffer) {
+        let small = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_LIGHT_GRAY);
+        let list = MonoTextStyle::new(&FONT_7X13, Rgb565::WHITE);
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Top).build();
+
+        HEADER
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_MIDNIGHT_BLUE))
+            .draw(target)
+            .unwrap();
+        let (label, color) = match self.state {
+            State::Stopped => ("stopped", Rgb565::WHITE),
+            State::Recording => ("recording", Rgb565::CSS_RED),
+            State::Paused => ("paused", Rgb565::CSS_ORANGE),
+            State::Playing => ("playing", Rgb565::CSS_LIGHT_GREEN),
+        };
+        let big = MonoTextStyle::new(&FONT_10X20, color);
+        Text::with_baseline(label, Point::new(8, 6), big, Baseline::Top)
+            .draw(target)
+            .unwrap();
+        let big = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+        Text::with_text_style(&format_time(self.time_ms), Point::new(312, 6), big, right)
+            .draw(target)
+            .unwrap();
+        let style = MonoTextStyle::new(&FONT_7X13, Rgb565::CSS_LIGHT_SKY_BLUE);
+        if let Some(name) = &self.name {
+            Text::with_baseline(name, Point::new(8, 34), style, Baseline::Top)
+                .draw(target)
+                .unwrap();
+        }
+
+        // the level, green then yellow then red for the last 6dB
+        METER
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(target)
+            .unwrap();
+        BAR.into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_GRAY))
+            .draw(target)
+            .unwrap();
+        let db = self.meter.db();
+        let lit = meter_width(db);
+        let color = match db {
+            -6.. => Rgb565::CSS_RED,
+            -18.. => Rgb565::YELLOW,
+            _ => Rgb565::CSS_LIME_GREEN,
+        };
+        Rectangle::new(BAR.top_left, Size::new(lit, BAR.size.height))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(target)
+            .unwrap();
+        let level = if db <= -60 { "-inf".into() } else { format!("{}dB", db) };
+        Text::with_text_style(&level, Point::new(312, 66), list, right)
+            .draw(target)
+            .unwrap();
+
+        // the recordings, scrolled so the selection stays visible
+        let rows = ((STATUS.top_left.y - LIST_TOP) / ROW_HEIGHT) as usize;
+        let first = self.selected.saturating_sub(rows - 1);
+        for (i, (name, ms)) in self.files.iter().enumerate().skip(first).take(rows) {
+            let color = if i == self.selected { Rgb565::YELLOW } else { Rgb565::WHITE };
+            let p = Point::new(4, LIST_TOP + (i - first) as i32 * ROW_HEIGHT);
+            Text::with_baseline(name, p, MonoTextStyle::new(&FONT_7X13, color), Baseline::Top)
+                .draw(target)
+                .unwrap();
+            Text::with_text_style(&format_time(*ms), Point::new(316, p.y), list, right)
+                .draw(target)
+                .unwrap();
+        }
+        if self.files.is_empty() {
+            Text::with_baseline("no recordings, press r", Point::new(4, LIST_TOP), list, Baseline::Top)
+                .draw(target)
+                .unwrap();
+        }
+
+        STATUS
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_GRAY))
+            .draw(target)
+            .unwrap();
+        let status = if self.message.is_empty() {
+            "r record  space pause  s stop  click play"
+        } else {
+            &self.message
+        };
+        Text::with_baseline(status, STATUS.top_left + Point::new(2, 1), small, Baseline::Top)
+            .draw(target)
+            .unwrap();
+        self.volume.draw(target).unwrap();
+    }
+}
+
+// -60dB to 0dB across the bar
+fn meter_width(db: i32) -> u32 {
+    ((db + 60).clamp(0, 60) as u32 * BAR.size.width) / 60
+}
+
+fn is_recording(name: &str) -> bool {
+    name.len() == 10 && name.starts_with("REC") && name.ends_with(".WAV")
+}
+
+// the recordings on the card and how long they are
+fn list_recordings(wrapper: &mut Wrapper) -> Vec<(String, u32)> {
+    let mut files = wrapper.list_files().unwrap_or_default();
+    files.retain(|(name, _)| is_recording(name));
+    files.sort();
+    let mut recordings = Vec::new();
+    for (name, size) in files {
+        let mut src = SdFile {
+            wrapper: &mut *wrapper,
+            name: &name,
+            size,
+        };
+        let ms = wav::parse(&mut src).map(|info| info.duration_ms()).unwrap_or(0);
+        recordings.push((name, ms));
+    }
+    recordings
+}
+
+// one after the highest number on the card
+fn next_name(files: &[(String, u32)]) -> Option<String> {
+    let next = files
+        .iter()
+        .filter_map(|(name, _)| name[3..6].parse::<u32>().ok())
+        .max()
+        .map_or(0, |n| n + 1);
+    (next < 1000).then(|| format!("REC{:03}.WAV", next))
+}
+
+/// A recording being played back.
+struct Playback {
+    name: String,
+    size: u32,
+    stream: WavStream,
+    resampler: Resampler,
+    samples: Vec<i16>,
+    resampled: Vec<i16>,
+    pending: Vec<i16>,
+    written: usize,
+}
+
+impl Playback {
+    fn open(wrapper: &mut Wrapper, name: &str) -> Option<Playback> {
+        let size = wrapper
+            .list_files()
+            .ok()?
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, size)| size)?;
+        let mut src = SdFile { wrapper, name, size };
+        let info = wav::parse(&mut src).inspect_err(|e| info!("can't play {} {:?}", name, e)).ok()?;
+        Some(Playback {
+            name: name.into(),
+            size,
+            resampler: Resampler::new(info.sample_rate, SINK_RATE, info.channels, Quality::Sinc),
+            stream: WavStream::new(info, READ_BYTES),
+            samples: Vec::new(),
+            resampled: Vec::new(),
+            pending: Vec::new(),
+            written: 0,
+        })
+    }
+
+    fn position_ms(&self) -> u32 {
+        (self.stream.position() as u64 * 1000 / self.stream.info.sample_rate.max(1) as u64) as u32
+    }
+
+    /// Decodes into the queue until it's full. Returns false at the end.
+    fn fill(&mut self, wrapper: &mut Wrapper, writer: &mut AudioWriter) -> bool {
+        loop {
+            if self.written < self.pending.len() {
+                self.written += writer.write(&self.pending[self.written..]);
+                if self.written < self.pending.len() {
+                    return true;
+                }
+            }
+            let mut src = SdFile {
+                wrapper: &mut *wrapper,
+                name: &self.name,
+                size: self.size,
+            };
+            self.samples.clear();
+            self.resampled.clear();
+            let more = self.stream.next_chunk(&mut src, &mut self.samples);
+            if more {
+                self.resampler.process(&self.samples, &mut self.resampled);
+            } else {
+                self.resampler.flush(&mut self.resampled);
+            }
+            self.pending.clear();
+            to_stereo(&self.resampled, self.stream.info.channels, &mut self.pending);
+            self.written = writer.write(&self.pending);
+            if !more {
+                return false;
+            }
+        }
+    }
+}
+
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    info!("running");
+
+    let audio = wrapper.audio.take().unwrap();
+    let config = AudioConfig {
+        sample_rate: SINK_RATE,
+        channels: 2,
+    };
+    let (mut writer, player) = AudioSink::new(audio, config).unwrap();
+    spawner.spawn(play(player)).unwrap();
+    let volume: Volume = settings::load(&mut wrapper.flash, Slot::Volume).unwrap_or_default();
+    writer.set_volume(volume);
+
+    // the microphone's clock has to be running before the ADC will take its settings
+    let mic = wrapper.mic.take().unwrap();
+    let capture = CaptureConfig {
+        sample_rate: CAPTURE_RATE,
+        gain_db: MIC_GAIN_DB,
+    };
+    let (mut reader, recorder) = AudioSource::new(mic, capture).unwrap();
+    spawner.spawn(record(recorder)).unwrap();
+    Timer::after_millis(10).await;
+    wrapper.init_microphone(capture.gain_db).unwrap();
+
+    let mut app = App {
+        state: State::Stopped,
+        name: None,
+        time_ms: 0,
+        meter: LevelMeter::default(),
+        files: list_recordings(&mut wrapper),
+        selected: 0,
+        message: String::new(),
+        volume: VolumeControl::new(volume),
+    };
+    let mut recording: Option<WavWriter> = None;
+    let mut playback: Option<Playback> = None;
+    let mut samples = vec![0i16; 512];
+    let mut overruns = reader.stats().overruns;
+
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(wrapper.screen_bounds());
+
+    loop {
+        wrapper.poll_trackball();
+        let [_, _, mut up, mut down, click] = wrapper.trackball_changes();
+        let mut key = wrapper.read_key();
+        let now = Instant::now().as_millis();
+        if app.volume.handle(key, up, down, now) {
+            writer.set_volume(app.volume.volume);
+            (key, up, down) = (None, false, false);
+        }
+        let before = (app.state, app.selected, app.files.len(), app.time_ms / 1000);
+        let meter_before = meter_width(app.meter.db());
+
+        if down && app.selected + 1 < app.files.len() {
+            app.selected += 1;
+        }
+        if up && app.selected > 0 {
+            app.selected -= 1;
+        }
+        let mut stop = false;
+        match key {
+            Some(b'r') if recording.is_none() => {
+                if playback.take().is_some() {
+                    writer.stop().await;
+                }
+                match next_name(&app.files) {
+                    Some(name) => {
+                        let mut sink = SdWav {
+                            wrapper: &mut wrapper,
+                            name: &name,
+                        };
+                        match WavWriter::new(&mut sink, CAPTURE_RATE, 1, WRITE_BYTES) {
+                            Ok(wav) => {
+                                info!("recording to {}", name);
+                                // start from the key press, not what's been waiting
+                                reader.clear();
+                                recording = Some(wav);
+                                app.state = State::Recording;
+                                app.name = Some(name);
+                                app.message.clear();
+                            }
+                            Err(e) => app.message = format!("couldn't create {} {:?}", name, e),
+                        }
+                    }
+                    None => app.message = "the card is full of recordings".into(),
+                }
+            }
+            Some(b' ') if recording.is_some() => {
+                app.state = match app.state {
+                    State::Paused => {
+                        reader.clear();
+                        State::Recording
+                    }
+                    _ => State::Paused,
+                };
+            }
+            Some(b's') => stop = true,
+            _ => {}
+        }
+        if (click || key == Some(b'\r')) && recording.is_none() && !app.files.is_empty() {
+            if playback.take().is_some() {
+                writer.stop().await;
+            }
+            let name = app.files[app.selected].0.clone();
+            playback = Playback::open(&mut wrapper, &name);
+            if playback.is_some() {
+                app.state = State::Playing;
+                app.name = Some(name);
+                app.message.clear();
+            }
+        }
+
+        // everything the microphone has picked up goes to the meter, and to the file when
+        // recording
+        loop {
+            let n = reader.read(&mut samples);
+            if n == 0 {
+                break;
+            }
+            app.meter.update(&samples[..n], 0);
+            if app.state != State::Recording {
+                continue;
+            }
+            if let (Some(wav), Some(name)) = (recording.as_mut(), app.name.as_deref()) {
+                let mut sink = SdWav {
+                    wrapper: &mut wrapper,
+                    name,
+                };
+                if let Err(e) = wav.write(&mut sink, &samples[..n]) {
+                    info!("couldn't write {} {:?}", name, e);
+                    app.message = "couldn't write to the card".into();
+                    stop = true;
+                    break;
+                }
+            }
+        }
+        app.meter.update(&[], METER_DECAY);
+        let stats = reader.stats();
+        if stats.overruns != overruns && recording.is_some() {
+            info!("lost {} frames, the card was too slow", stats.overruns - overruns);
+            app.message = "the card fell behind, some was lost".into();
+            dirty.add(STATUS);
+        }
+        overruns = stats.overruns;
+
+        if let Some(p) = playback.as_mut() {
+            if !p.fill(&mut wrapper, &mut writer) {
+                writer.finish();
+                stop = true;
+            }
+        }
+        if stop {
+            if let (Some(wav), Some(name)) = (recording.take(), app.name.as_deref()) {
+                let mut sink = SdWav {
+                    wrapper: &mut wrapper,
+                    name,
+                };
+                match wav.finish(&mut sink) {
+                    Ok(frames) => info!("{} finished, {} frames", name, frames),
+                    Err(e) => info!("couldn't finish {} {:?}", name, e),
+                }
+                app.files = list_recordings(&mut wrapper);
+                app.selected = app.files.iter().position(|(n, _)| n == name).unwrap_or(0);
+                dirty.add(LIST);
+            }
+            if playback.take().is_some() && key == Some(b's') {
+                writer.stop().await;
+            }
+            app.state = State::Stopped;
+        }
+
+        app.time_ms = match (&recording, &playback) {
+            (Some(wav), _) => wav.duration_ms(),
+            (_, Some(p)) => p.position_ms(),
+            _ => app.time_ms,
+        };
+        let after = (app.state, app.selected, app.files.len(), app.time_ms / 1000);
+        if after.0 != before.0 || after.3 != before.3 {
+            dirty.add(HEADER);
+        }
+        if after.1 != before.1 || after.2 != before.2 {
+            dirty.add(LIST);
+        }
+        if meter_width(app.meter.db()) != meter_before {
+            dirty.add(METER);
+        }
+        if key.is_some() || click {
+            dirty.add(STATUS);
+        }
+        if app.volume.poll(now) {
+            dirty.add(VolumeControl::AREA);
+        }
+        if let Some(volume) = app.volume.take_unsaved() {
+            if let Err(e) = settings::save(&mut wrapper.flash, Slot::Volume, &volume) {
+                info!("couldn't save the volume {:?}", e);
+            }
+        }
+        render(&app, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+        Timer::after_millis(10).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn play(player: AudioPlayer) {
+    player.run().await
+}
+
+#[embassy_executor::task]
+async fn record(recorder: AudioRecorder) {
+    recorder.run().await
+}
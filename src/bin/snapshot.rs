@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use alloc::string::String;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use esp_hal::clock::CpuClock;
+use esp_hal::main;
+use log::info;
+use rust_tdeck_experiments::breakout::{Breakout, BreakoutView, HighScores, Phase};
+use rust_tdeck_experiments::sim::{compare, parse_ppm_header, ppm_pixels, render_scene, SimDisplay, Snapshot};
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+Renders a few screens off-screen and compares them to golden images on the SD card.
+For a snapshot named BRK1 the golden image is BRK1.PPM. If it is missing the current
+render is saved as the new golden. If it changed the diff is saved as BRK1D.PPM with
+the changed pixels in red, and the diff is shown on the screen.
+
+A screen takes 150KB, so the golden image is streamed from the card in small pieces
+and the diff is made in place instead of loading everything at once.
+ */
+fn check(wrapper: &mut Wrapper, name: &str, actual: SimDisplay) -> bool {
+    let golden_name = format!("{}.PPM", name);
+    let mut header = [0u8; 32];
+    let header = match wrapper.read_file_at(&golden_name, 0, &mut header) {
+        Ok(n) => parse_ppm_header(&header[..n]),
+        Err(_) => None,
+    };
+    let Some((golden_size, offset)) = header else {
+        info!("{}: no golden image, saving {}", name, golden_name);
+        save_ppm(wrapper, &golden_name, &actual);
+        return true;
+    };
+
+    // golden pixels read 1KB at a time
+    let mut buf = [0u8; 1023];
+    let mut len = 0;
+    let mut pos = 0;
+    let mut file_offset = offset as u32;
+    let golden = core::iter::from_fn(|| {
+        if pos + 3 > len {
+            len = wrapper.read_file_at(&golden_name, file_offset, &mut buf).ok()?;
+            file_offset += len as u32;
+            pos = 0;
+        }
+        let pixel = ppm_pixels(buf.get(pos..pos + 3)?).next();
+        pos += 3;
+        pixel
+    });
+
+    match compare(actual, golden_size, golden, 0) {
+        Snapshot::Matched => {
+            info!("{}: matched", name);
+            true
+        }
+        Snapshot::Missing => false,
+        Snapshot::SizeChanged { golden, actual } => {
+            info!("{}: size changed from {:?} to {:?}", name, golden, actual);
+            false
+        }
+        Snapshot::Changed(diff) => {
+            info!(
+                "{}: {} pixels changed inside {:?}",
+                name, diff.mismatched, diff.bounds
+            );
+            save_ppm(wrapper, &format!("{}D.PPM", name), &diff.image);
+            show(wrapper, &diff.image);
+            false
+        }
+    }
+}
+
+fn save_ppm(wrapper: &mut Wrapper, name: &str, image: &SimDisplay) {
+    wrapper.write_file(name, &[]).unwrap();
+    image.write_ppm(16, |chunk| wrapper.append_file(name, chunk).unwrap());
+}
+
+fn show(wrapper: &mut Wrapper, image: &SimDisplay) {
+    let area = Rectangle::new(Point::zero(), image.size());
+    wrapper
+        .display
+        .fill_contiguous(&area, image.pixels().iter().copied())
+        .unwrap();
+}
+
+#[main]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+
+    esp_alloc::heap_allocator!(size: 200 * 1024);
+
+    info!("running");
+
+    let mut failures = 0;
+
+    // the first frame of a new game
+    let view = BreakoutView::new(
+        Breakout::new(Breakout::builtin_levels(), HighScores::default()),
+        BreakoutView::default_bricks(),
+    );
+    let frame = render_scene(&view);
+    show(&mut wrapper, &frame);
+    if !check(&mut wrapper, "BRK1", frame) {
+        failures += 1;
+    }
+
+    // the game over screen with a high score table
+    let mut scores = HighScores::default();
+    scores.insert(String::from("JOS"), 1200, 2);
+    scores.insert(String::from("ABC"), 800, 1);
+    let mut state = Breakout::new(Breakout::builtin_levels(), scores);
+    state.phase = Phase::GameOver { won: false };
+    let view = BreakoutView::new(state, BreakoutView::default_bricks());
+    let frame = render_scene(&view);
+    if !check(&mut wrapper, "BRK2", frame) {
+        failures += 1;
+    }
+
+    info!("snapshots done, {} failed", failures);
+    loop {
+        wrapper.delay.delay_millis(1000);
+    }
+}
@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use alloc::format;
+use embassy_executor::Spawner;
+use embassy_time::Timer;
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use esp_hal::clock::CpuClock;
+use log::info;
+use rust_tdeck_experiments::anim::FrameScheduler;
+use rust_tdeck_experiments::capture::{AudioRecorder, AudioSource, CaptureConfig, CAPTURE_TAP};
+use rust_tdeck_experiments::game::{render, DirtyRegions, FrameBuffer, Scene};
+use rust_tdeck_experiments::visualizer::{Mode, Visualizer};
+use rust_tdeck_experiments::Wrapper;
+
+extern crate alloc;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/*
+Shows what the microphone hears, through the visualizer module: spectrum bars, a
+waterfall or the waveform, at 30 frames a second. Space, or a trackball click, goes to the
+next view. The music player has the same views of what it's playing, press v there.
+ */
+
+const FPS: u32 = 30;
+const CAPTURE_RATE: u32 = 16_000;
+
+const HEADER: Rectangle = Rectangle::new(Point::new(0, 0), Size::new(320, 30));
+const VISUAL: Rectangle = Rectangle::new(Point::new(0, 30), Size::new(320, 210));
+
+struct App {
+    visual: Visualizer,
+    fps: u32,
+}
+
+impl Scene for App {
+    fn draw(&self, target: &mut FrameBuffer) {
+        HEADER
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_MIDNIGHT_BLUE))
+            .draw(target)
+            .unwrap();
+        let big = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+        Text::with_baseline(self.visual.mode.name(), Point::new(8, 5), big, Baseline::Top)
+            .draw(target)
+            .unwrap();
+        let small = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_LIGHT_GRAY);
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Top).build();
+        let status = format!("microphone {}Hz  {}fps", CAPTURE_RATE, self.fps);
+        Text::with_text_style(&status, Point::new(312, 10), small, right)
+            .draw(target)
+            .unwrap();
+        self.visual.draw(target).unwrap();
+    }
+}
+
+#[esp_rtos::main]
+async fn main(spawner: Spawner) {
+    esp_println::logger::init_logger_from_env();
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals = esp_hal::init(config);
+    let mut wrapper = Wrapper::init(peripherals);
+    wrapper.start_scheduler();
+
+    // the waterfall keeps a byte for every two pixels
+    esp_alloc::heap_allocator!(size: 96 * 1024);
+
+    info!("running");
+
+    let mic = wrapper.mic.take().unwrap();
+    let capture = CaptureConfig {
+        sample_rate: CAPTURE_RATE,
+        ..CaptureConfig::default()
+    };
+    let (mut reader, recorder) = AudioSource::new(mic, capture).unwrap();
+    spawner.spawn(record(recorder)).unwrap();
+    // the ADC takes its settings once its clock is running
+    Timer::after_millis(10).await;
+    wrapper.init_microphone(capture.gain_db).unwrap();
+
+    let mut app = App {
+        visual: Visualizer::new(VISUAL, Mode::Bars),
+        fps: 0,
+    };
+    let mut scheduler = FrameScheduler::new(FPS);
+    let mut fb = FrameBuffer::new(320 * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(wrapper.screen_bounds());
+
+    loop {
+        let frame = scheduler.next_frame().await;
+        // the visualizer reads the tap, nothing else wants the samples
+        reader.clear();
+
+        wrapper.poll_trackball();
+        let [_, _, _, _, click] = wrapper.trackball_changes();
+        if click || wrapper.read_key() == Some(b' ') {
+            app.visual.set_mode(app.visual.mode.next());
+            dirty.add(HEADER);
+        }
+        if scheduler.stats.fps != app.fps {
+            app.fps = scheduler.stats.fps;
+            dirty.add(HEADER);
+        }
+        app.visual.update(&CAPTURE_TAP, frame.dt_ms);
+        dirty.add(VISUAL);
+        render(&app, &mut dirty, &mut fb, &mut wrapper.display).unwrap();
+    }
+}
+
+#[embassy_executor::task]
+async fn record(recorder: AudioRecorder) {
+    recorder.run().await
+}
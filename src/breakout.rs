@@ -0,0 +1,743 @@
+//! Game state for the brickbreaker example.
+//!
+//! Everything here is plain data and arithmetic so the rules can be run on the host.
+//! The binary feeds in input, calls `update` at a fixed rate and draws the result.
+
+use crate::game::{
+    sweep, union, Action, DirtyRegions, FrameBuffer, InputMap, Scene, Sprite, SpriteSheet,
+    SweepHit, TileMap, EMPTY_TILE,
+};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_graphics::mono_font::ascii::{FONT_6X10, FONT_9X15_BOLD};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::text::{Alignment, Text};
+use micromath::F32Ext;
+use serde::{Deserialize, Serialize};
+
+pub const FIELD_WIDTH: u32 = 320;
+pub const FIELD_HEIGHT: u32 = 240;
+/// the score line at the top of the screen
+pub const HUD_HEIGHT: u32 = 14;
+pub const TILE_SIZE: Size = Size::new(40, 16);
+/// the most columns and rows of bricks a level can have. Eight rows leave a gap of almost
+/// four rows above the paddle.
+pub const LEVEL_COLS: u32 = FIELD_WIDTH / TILE_SIZE.width;
+pub const LEVEL_ROWS: u32 = 8;
+pub const BRICK_SIZE: Size = Size::new(38, 14);
+/// bricks `1` to `6` in a level, drawn with one frame of the brick sheet each
+pub const BRICK_KINDS: usize = 6;
+pub const BALL_SIZE: Size = Size::new(8, 8);
+pub const PADDLE_SIZE: Size = Size::new(50, 8);
+pub const PADDLE_Y: i32 = 220;
+pub const PADDLE_STEP: i32 = 20;
+pub const START_LIVES: u8 = 3;
+/// steepest bounce off the paddle edge, measured from straight up
+const MAX_BOUNCE_ANGLE: f32 = 1.047; // 60 degrees
+const BASE_SPEED: f32 = 2.5;
+const SPEED_PER_LEVEL: f32 = 0.3;
+
+/// Levels used when there are no level files on the SD card. Digits 1-6 are bricks of
+/// different colors worth 10 points per digit, anything else is an empty cell. Levels
+/// bigger than `LEVEL_COLS` by `LEVEL_ROWS` have the rest cut off.
+pub const BUILTIN_LEVELS: [&str; 3] = [
+    "
+.666666.
+.555555.
+.444444.
+.333333.
+",
+    "
+66....66
+55.55.55
+44444444
+3.3.3.3.
+22222222
+",
+    "
+6.6.6.6.
+.5.5.5.5
+44444444
+33....33
+2.2222.2
+11111111
+",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// the ball sits on the paddle until fire is pressed
+    Serving,
+    Playing,
+    /// the score made the high score table and we are waiting for initials
+    EnterName,
+    GameOver { won: bool },
+}
+
+pub struct Breakout {
+    pub levels: Vec<String>,
+    pub level: usize,
+    pub bricks: TileMap,
+    pub ball: (f32, f32),
+    pub velocity: (f32, f32),
+    pub paddle: Rectangle,
+    pub lives: u8,
+    pub score: u32,
+    pub phase: Phase,
+    pub high_scores: HighScores,
+    pub name: String,
+    won: bool,
+    scores_changed: bool,
+    // where the ball hit things since `take_hits`, for sound effects
+    hits: Vec<f32>,
+}
+
+impl Breakout {
+    pub fn new(levels: Vec<String>, high_scores: HighScores) -> Self {
+        let mut game = Breakout {
+            levels,
+            level: 0,
+            bricks: TileMap::new(Point::zero(), 0, 0, TILE_SIZE),
+            ball: (0.0, 0.0),
+            velocity: (0.0, 0.0),
+            paddle: Rectangle::new(
+                Point::new((FIELD_WIDTH - PADDLE_SIZE.width) as i32 / 2, PADDLE_Y),
+                PADDLE_SIZE,
+            ),
+            lives: START_LIVES,
+            score: 0,
+            phase: Phase::Serving,
+            high_scores,
+            name: String::new(),
+            won: false,
+            scores_changed: false,
+            hits: Vec::new(),
+        };
+        game.start_level(0);
+        game
+    }
+
+    pub fn builtin_levels() -> Vec<String> {
+        BUILTIN_LEVELS.iter().map(|l| String::from(*l)).collect()
+    }
+
+    pub fn parse_level(text: &str) -> TileMap {
+        let parsed = TileMap::from_text(Point::zero(), TILE_SIZE, text, |ch| match ch {
+            '1'..='6' => ch as u8 - b'1',
+            _ => EMPTY_TILE,
+        });
+        // keep the top left of levels too big for the field, the rest can't be reached
+        let cols = parsed.cols.min(LEVEL_COLS);
+        let rows = parsed.rows.min(LEVEL_ROWS);
+        // center the bricks and leave a row of space under the score
+        let origin = Point::new(
+            (FIELD_WIDTH - cols * TILE_SIZE.width) as i32 / 2,
+            (HUD_HEIGHT + TILE_SIZE.height) as i32,
+        );
+        let mut map = TileMap::new(origin, cols, rows, TILE_SIZE);
+        for row in 0..rows {
+            for col in 0..cols {
+                map.set(col, row, parsed.get(col, row));
+            }
+        }
+        map
+    }
+
+    fn start_level(&mut self, level: usize) {
+        self.level = level;
+        let text = self.levels.get(level).map(|s| s.as_str()).unwrap_or(BUILTIN_LEVELS[0]);
+        self.bricks = Self::parse_level(text);
+        self.serve();
+    }
+
+    fn serve(&mut self) {
+        self.phase = Phase::Serving;
+        self.velocity = (0.0, 0.0);
+        self.stick_ball_to_paddle();
+    }
+
+    fn stick_ball_to_paddle(&mut self) {
+        let center = self.paddle.center().x as f32;
+        self.ball = (
+            center - BALL_SIZE.width as f32 / 2.0,
+            (self.paddle.top_left.y - BALL_SIZE.height as i32) as f32,
+        );
+    }
+
+    pub fn speed(&self) -> f32 {
+        BASE_SPEED + SPEED_PER_LEVEL * self.level as f32
+    }
+
+    pub fn ball_bounds(&self) -> Rectangle {
+        Rectangle::new(Point::new(self.ball.0 as i32, self.ball.1 as i32), BALL_SIZE)
+    }
+
+    pub fn brick_bounds(&self, col: u32, row: u32) -> Rectangle {
+        Rectangle::new(self.bricks.cell_bounds(col, row).top_left, BRICK_SIZE)
+    }
+
+    pub fn hud_bounds() -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(FIELD_WIDTH, HUD_HEIGHT))
+    }
+
+    /// True once after the high score table changes, so the caller knows to save it.
+    pub fn take_scores_changed(&mut self) -> bool {
+        core::mem::take(&mut self.scores_changed)
+    }
+
+    /// Handles input once per frame. Movement is edge triggered like the trackball.
+    pub fn handle_input(&mut self, input: &InputMap, key: Option<u8>, dirty: &mut DirtyRegions) {
+        match self.phase {
+            Phase::Serving | Phase::Playing => {
+                let old = self.paddle;
+                let x = (self.paddle.top_left.x + input.axis_x() * PADDLE_STEP)
+                    .clamp(0, (FIELD_WIDTH - PADDLE_SIZE.width) as i32);
+                self.paddle.top_left.x = x;
+                dirty.add_moved(old, self.paddle);
+                if self.phase == Phase::Serving {
+                    let old_ball = self.ball_bounds();
+                    self.stick_ball_to_paddle();
+                    dirty.add_moved(old_ball, self.ball_bounds());
+                    if input.just_pressed(Action::Fire) || input.just_pressed(Action::Up) {
+                        self.launch();
+                    }
+                }
+            }
+            Phase::EnterName => {
+                if let Some(key) = key {
+                    self.type_name_key(key);
+                    dirty.add(Rectangle::new(Point::zero(), Size::new(FIELD_WIDTH, FIELD_HEIGHT)));
+                }
+            }
+            Phase::GameOver { .. } => {
+                if input.just_pressed(Action::Fire) {
+                    self.restart();
+                    dirty.add(Rectangle::new(Point::zero(), Size::new(FIELD_WIDTH, FIELD_HEIGHT)));
+                }
+            }
+        }
+    }
+
+    fn launch(&mut self) {
+        // start a little to the right so the first bounce isn't straight up and down
+        let angle: f32 = 0.3;
+        let speed = self.speed();
+        self.velocity = (speed * angle.sin(), -speed * angle.cos());
+        self.phase = Phase::Playing;
+    }
+
+    fn type_name_key(&mut self, key: u8) {
+        match key {
+            // backspace
+            8 => {
+                self.name.pop();
+            }
+            b'\r' | b'\n' => {
+                let name = if self.name.is_empty() {
+                    String::from("???")
+                } else {
+                    self.name.clone()
+                };
+                self.high_scores.insert(name, self.score, self.level as u8 + 1);
+                self.scores_changed = true;
+                self.phase = Phase::GameOver { won: self.won };
+            }
+            k if k.is_ascii_alphanumeric() && self.name.len() < 3 => {
+                self.name.push(k.to_ascii_uppercase() as char);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn restart(&mut self) {
+        self.lives = START_LIVES;
+        self.score = 0;
+        self.won = false;
+        self.name.clear();
+        self.start_level(0);
+    }
+
+    fn finish(&mut self, won: bool, dirty: &mut DirtyRegions) {
+        self.won = won;
+        self.velocity = (0.0, 0.0);
+        self.phase = if self.high_scores.qualifies(self.score) {
+            Phase::EnterName
+        } else {
+            Phase::GameOver { won }
+        };
+        dirty.add(Rectangle::new(Point::zero(), Size::new(FIELD_WIDTH, FIELD_HEIGHT)));
+    }
+
+    /// Where the ball has hit a brick or the paddle since the last call, from -1.0 at the
+    /// left of the field to 1.0 at the right.
+    pub fn take_hits(&mut self) -> Vec<f32> {
+        core::mem::take(&mut self.hits)
+    }
+
+    /// Advances the ball by one fixed time step.
+    pub fn update(&mut self, dirty: &mut DirtyRegions) {
+        if self.phase != Phase::Playing {
+            return;
+        }
+        let old_ball = self.ball_bounds();
+        let mut remaining = 1.0f32;
+        // a ball can hit a few things in one step, e.g. a brick and then a wall
+        for _ in 0..4 {
+            if remaining <= 0.0 {
+                break;
+            }
+            let motion = (self.velocity.0 * remaining, self.velocity.1 * remaining);
+            match self.earliest_hit(motion) {
+                None => {
+                    self.ball.0 += motion.0;
+                    self.ball.1 += motion.1;
+                    break;
+                }
+                Some((hit, target)) => {
+                    self.ball.0 += motion.0 * hit.time;
+                    self.ball.1 += motion.1 * hit.time;
+                    remaining *= 1.0 - hit.time;
+                    self.resolve(hit, target, dirty);
+                }
+            }
+        }
+        dirty.add_moved(old_ball, self.ball_bounds());
+
+        if self.ball.1 > FIELD_HEIGHT as f32 {
+            self.lives = self.lives.saturating_sub(1);
+            dirty.add(Self::hud_bounds());
+            if self.lives == 0 {
+                self.finish(false, dirty);
+            } else {
+                self.serve();
+                dirty.add(self.ball_bounds());
+            }
+        } else if self.bricks.count_filled() == 0 {
+            if self.level + 1 < self.levels.len() {
+                self.start_level(self.level + 1);
+                dirty.add(Rectangle::new(Point::zero(), Size::new(FIELD_WIDTH, FIELD_HEIGHT)));
+            } else {
+                self.finish(true, dirty);
+            }
+        }
+    }
+
+    fn earliest_hit(&self, motion: (f32, f32)) -> Option<(SweepHit, Target)> {
+        let mut best: Option<(SweepHit, Target)> = None;
+        let mut consider = |hit: Option<SweepHit>, target: Target| {
+            if let Some(hit) = hit {
+                if best.as_ref().map(|(b, _)| hit.time < b.time).unwrap_or(true) {
+                    best = Some((hit, target));
+                }
+            }
+        };
+
+        // walls are thick boxes just outside the field. there is no floor.
+        let w = FIELD_WIDTH as i32;
+        let walls = [
+            Rectangle::new(Point::new(-100, -100), Size::new(100, FIELD_HEIGHT + 200)),
+            Rectangle::new(Point::new(w, -100), Size::new(100, FIELD_HEIGHT + 200)),
+            Rectangle::new(Point::new(-100, HUD_HEIGHT as i32 - 100), Size::new(FIELD_WIDTH + 200, 100)),
+        ];
+        for wall in &walls {
+            consider(sweep(self.ball, BALL_SIZE, motion, wall), Target::Wall);
+        }
+
+        // only check bricks near the path of the ball
+        let start = self.ball_bounds();
+        let end = Rectangle::new(
+            Point::new((self.ball.0 + motion.0) as i32, (self.ball.1 + motion.1) as i32),
+            BALL_SIZE,
+        );
+        let path = union(&start, &end).offset(1);
+        for (col, row, tile) in self.bricks.tiles_overlapping(&path) {
+            let brick = self.brick_bounds(col, row);
+            consider(sweep(self.ball, BALL_SIZE, motion, &brick), Target::Brick(col, row, tile));
+        }
+
+        // only bounce off the paddle on the way down
+        if motion.1 > 0.0 {
+            consider(sweep(self.ball, BALL_SIZE, motion, &self.paddle), Target::Paddle);
+        }
+        best
+    }
+
+    fn resolve(&mut self, hit: SweepHit, target: Target, dirty: &mut DirtyRegions) {
+        if !matches!(target, Target::Wall) {
+            let center = self.ball.0 + BALL_SIZE.width as f32 / 2.0;
+            self.hits.push((center / FIELD_WIDTH as f32 * 2.0 - 1.0).clamp(-1.0, 1.0));
+        }
+        match target {
+            Target::Paddle if hit.hit_y => {
+                // the further from the center the ball lands, the steeper it goes off
+                let center = self.paddle.top_left.x as f32 + PADDLE_SIZE.width as f32 / 2.0;
+                let ball_center = self.ball.0 + BALL_SIZE.width as f32 / 2.0;
+                let half = (PADDLE_SIZE.width + BALL_SIZE.width) as f32 / 2.0;
+                let offset = ((ball_center - center) / half).clamp(-1.0, 1.0);
+                let angle = offset * MAX_BOUNCE_ANGLE;
+                let speed = self.speed();
+                self.velocity = (speed * angle.sin(), -speed * angle.cos());
+                return;
+            }
+            Target::Brick(col, row, tile) => {
+                self.bricks.set(col, row, EMPTY_TILE);
+                self.score += 10 * (tile as u32 + 1);
+                dirty.add(self.brick_bounds(col, row));
+                dirty.add(Self::hud_bounds());
+            }
+            _ => {}
+        }
+        if hit.hit_x {
+            self.velocity.0 = -self.velocity.0;
+        }
+        if hit.hit_y {
+            self.velocity.1 = -self.velocity.1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Wall,
+    Paddle,
+    Brick(u32, u32, u8),
+}
+
+// ---------- drawing ----------
+
+/// Draws the game. The sprite sheets are separate from the state so the art can come
+/// from the SD card.
+pub struct BreakoutView {
+    pub state: Breakout,
+    pub brick_sheet: SpriteSheet,
+    pub ball_sheet: SpriteSheet,
+    pub paddle_sheet: SpriteSheet,
+}
+
+impl BreakoutView {
+    pub fn new(state: Breakout, brick_sheet: SpriteSheet) -> Self {
+        BreakoutView {
+            state,
+            brick_sheet,
+            ball_sheet: SpriteSheet::solid(BALL_SIZE, &[Rgb565::MAGENTA]),
+            paddle_sheet: SpriteSheet::solid(PADDLE_SIZE, &[Rgb565::RED]),
+        }
+    }
+
+    pub fn default_bricks() -> SpriteSheet {
+        let colors = [
+            Rgb565::BLUE,
+            Rgb565::CYAN,
+            Rgb565::GREEN,
+            Rgb565::YELLOW,
+            Rgb565::CSS_ORANGE,
+            Rgb565::RED,
+        ];
+        SpriteSheet::solid(BRICK_SIZE, &colors)
+    }
+
+    fn draw_hud(&self, target: &mut FrameBuffer) {
+        let style = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+        let state = &self.state;
+        let text = format!(
+            "SCORE {:05}   LIVES {}   LEVEL {}",
+            state.score,
+            state.lives,
+            state.level + 1
+        );
+        Text::new(&text, Point::new(4, 10), style).draw(target).unwrap();
+    }
+
+    fn draw_overlay(&self, target: &mut FrameBuffer) {
+        let title = MonoTextStyle::new(&FONT_9X15_BOLD, Rgb565::YELLOW);
+        let body = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+        let center = FIELD_WIDTH as i32 / 2;
+        match self.state.phase {
+            Phase::EnterName => {
+                Text::with_alignment("NEW HIGH SCORE", Point::new(center, 80), title, Alignment::Center)
+                    .draw(target)
+                    .unwrap();
+                let prompt = format!("type your initials: {}_", self.state.name);
+                Text::with_alignment(&prompt, Point::new(center, 110), body, Alignment::Center)
+                    .draw(target)
+                    .unwrap();
+                Text::with_alignment("press enter when done", Point::new(center, 130), body, Alignment::Center)
+                    .draw(target)
+                    .unwrap();
+            }
+            Phase::GameOver { won } => {
+                let message = if won { "YOU WIN!" } else { "GAME OVER" };
+                Text::with_alignment(message, Point::new(center, 60), title, Alignment::Center)
+                    .draw(target)
+                    .unwrap();
+                for (i, entry) in self.state.high_scores.entries.iter().enumerate() {
+                    let line = format!("{}. {:<3} {:05}  L{}", i + 1, entry.name, entry.score, entry.level);
+                    Text::with_alignment(
+                        &line,
+                        Point::new(center, 90 + i as i32 * 14),
+                        body,
+                        Alignment::Center,
+                    )
+                    .draw(target)
+                    .unwrap();
+                }
+                Text::with_alignment("click to play again", Point::new(center, 190), body, Alignment::Center)
+                    .draw(target)
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Scene for BreakoutView {
+    fn draw(&self, target: &mut FrameBuffer) {
+        self.draw_hud(target);
+        if matches!(self.state.phase, Phase::EnterName | Phase::GameOver { .. }) {
+            self.draw_overlay(target);
+            return;
+        }
+        self.state.bricks.draw(&self.brick_sheet, target).unwrap();
+        let ball = self.state.ball_bounds();
+        Sprite::new(ball.top_left, BALL_SIZE, 0)
+            .draw(&self.ball_sheet, target)
+            .unwrap();
+        Sprite::new(self.state.paddle.top_left, PADDLE_SIZE, 0)
+            .draw(&self.paddle_sheet, target)
+            .unwrap();
+    }
+}
+
+// ---------- high scores ----------
+
+pub const HIGH_SCORE_COUNT: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HighScore {
+    pub name: String,
+    pub score: u32,
+    pub level: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HighScores {
+    pub entries: Vec<HighScore>,
+}
+
+impl HighScores {
+    pub fn qualifies(&self, score: u32) -> bool {
+        score > 0
+            && (self.entries.len() < HIGH_SCORE_COUNT
+                || self.entries.iter().any(|e| score > e.score))
+    }
+
+    /// Adds a score, keeping the table sorted highest first. Returns the new rank.
+    pub fn insert(&mut self, name: String, score: u32, level: u8) -> Option<usize> {
+        if !self.qualifies(score) {
+            return None;
+        }
+        let rank = self
+            .entries
+            .iter()
+            .position(|e| score > e.score)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(rank, HighScore { name, score, level });
+        self.entries.truncate(HIGH_SCORE_COUNT);
+        Some(rank)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn game(levels: &[&str]) -> Breakout {
+        let mut game = Breakout::new(levels.iter().map(|l| String::from(*l)).collect(), HighScores::default());
+        game.phase = Phase::Playing;
+        game
+    }
+
+    // runs until something other than moving happens, or gives up
+    fn run(game: &mut Breakout, frames: usize) {
+        let mut dirty = DirtyRegions::new();
+        for _ in 0..frames {
+            game.update(&mut dirty);
+            if game.phase != Phase::Playing {
+                return;
+            }
+        }
+    }
+
+    fn speed(velocity: (f32, f32)) -> f32 {
+        (velocity.0 * velocity.0 + velocity.1 * velocity.1).sqrt()
+    }
+
+    #[test]
+    fn levels_fit_the_field() {
+        let level = Breakout::parse_level(BUILTIN_LEVELS[0]);
+        assert_eq!((level.cols, level.rows), (8, 4));
+        assert_eq!(level.count_filled(), 24);
+        assert_eq!(level.origin, Point::new(0, 30));
+        // a narrow level sits in the middle
+        let level = Breakout::parse_level("11\n22\n");
+        assert_eq!((level.cols, level.rows, level.origin.x), (2, 2, 120));
+        // too wide and too tall is cut down to the top left
+        let big = "123456123456\n".repeat(20);
+        let level = Breakout::parse_level(&big);
+        assert_eq!((level.cols, level.rows), (LEVEL_COLS, LEVEL_ROWS));
+        assert_eq!(level.count_filled() as u32, LEVEL_COLS * LEVEL_ROWS);
+        assert_eq!(level.get(7, 0), 1);
+        let field = Rectangle::new(Point::new(0, HUD_HEIGHT as i32), Size::new(FIELD_WIDTH, FIELD_HEIGHT));
+        let bounds = level.bounds();
+        assert_eq!(field.intersection(&bounds), bounds);
+        // with room for the ball to come back between the bricks and the paddle
+        assert!(bounds.bottom_right().unwrap().y + 3 * TILE_SIZE.height as i32 <= PADDLE_Y);
+        assert_eq!(Breakout::parse_level("").count_filled(), 0);
+    }
+
+    #[test]
+    fn sweeping_into_corners() {
+        let target = Rectangle::new(Point::new(20, 20), Size::new(10, 10));
+        let size = Size::new(4, 4);
+        // straight into a face
+        let hit = sweep((0.0, 22.0), size, (32.0, 0.0), &target).unwrap();
+        assert_eq!((hit.time, hit.hit_x, hit.hit_y), (0.5, true, false));
+        let hit = sweep((22.0, 40.0), size, (0.0, -20.0), &target).unwrap();
+        assert_eq!((hit.time, hit.hit_x, hit.hit_y), (0.5, false, true));
+        // exactly into the top left corner hits both faces
+        let hit = sweep((0.0, 0.0), size, (32.0, 32.0), &target).unwrap();
+        assert_eq!((hit.time, hit.hit_x, hit.hit_y), (0.5, true, true));
+        // just under the corner is the side, just to its right the top
+        let hit = sweep((0.0, 1.0), size, (32.0, 32.0), &target).unwrap();
+        assert!(hit.hit_x && !hit.hit_y);
+        let hit = sweep((1.0, 0.0), size, (32.0, 32.0), &target).unwrap();
+        assert!(!hit.hit_x && hit.hit_y);
+        // brushing past the corner touches nothing
+        assert_eq!(sweep((0.0, 0.0), size, (32.0, 0.0), &target), None);
+        assert_eq!(sweep((0.0, 31.5), size, (32.0, -32.0), &target), None);
+        // too short to get there, or already inside
+        assert_eq!(sweep((0.0, 0.0), size, (10.0, 10.0), &target), None);
+        assert_eq!(sweep((22.0, 22.0), size, (5.0, 5.0), &target), None);
+    }
+
+    #[test]
+    fn bouncing_off_a_brick_corner() {
+        // a second brick so the level isn't over
+        let mut game = game(&["..1....1"]);
+        let brick = game.brick_bounds(2, 0);
+        // coming up and right at the bottom left corner
+        let step = 2.0;
+        game.ball = (
+            (brick.top_left.x - BALL_SIZE.width as i32) as f32 - 10.0,
+            (brick.top_left.y + brick.size.height as i32) as f32 + 10.0,
+        );
+        game.velocity = (step, -step);
+        run(&mut game, 20);
+        assert_eq!(game.bricks.get(2, 0), EMPTY_TILE);
+        assert_eq!(game.score, 10);
+        // both ways turned round
+        assert!(game.velocity.0 < 0.0 && game.velocity.1 > 0.0);
+        assert_eq!(game.take_hits().len(), 1);
+    }
+
+    #[test]
+    fn paddle_bounce_angles() {
+        let mut game = game(&[BUILTIN_LEVELS[0]]);
+        let paddle = game.paddle;
+        let centre = (paddle.top_left.x + PADDLE_SIZE.width as i32 / 2 - BALL_SIZE.width as i32 / 2) as f32;
+        let half = (PADDLE_SIZE.width + BALL_SIZE.width) as f32 / 2.0;
+        // straight up from the middle, steeper out to the very edge
+        for offset in [0.0, half / 2.0, 1.0 - half, half - 1.0] {
+            let angle = offset / half * MAX_BOUNCE_ANGLE;
+            game.ball = (centre + offset, (paddle.top_left.y - BALL_SIZE.height as i32) as f32 - 2.0);
+            game.velocity = (0.0, 2.0);
+            let mut dirty = DirtyRegions::new();
+            game.update(&mut dirty);
+            let (vx, vy) = game.velocity;
+            assert!(vy < 0.0, "{} went through", offset);
+            assert!((vx.atan2(-vy) - angle).abs() < 0.01, "{}: {} against {}", offset, vx.atan2(-vy), angle);
+            assert!((speed(game.velocity) - game.speed()).abs() < 0.01);
+        }
+        // going up through the paddle doesn't bounce
+        game.ball = (centre, (paddle.top_left.y + PADDLE_SIZE.height as i32) as f32 + 1.0);
+        game.velocity = (0.0, -2.0);
+        game.update(&mut DirtyRegions::new());
+        assert_eq!(game.velocity, (0.0, -2.0));
+    }
+
+    #[test]
+    fn losing_lives() {
+        let mut game = game(&[BUILTIN_LEVELS[0]]);
+        for lives in (0..START_LIVES).rev() {
+            game.phase = Phase::Playing;
+            // past the paddle and falling
+            game.ball = (5.0, PADDLE_Y as f32 + 10.0);
+            game.velocity = (0.0, 3.0);
+            run(&mut game, 20);
+            assert_eq!(game.lives, lives);
+            if lives > 0 {
+                assert_eq!(game.phase, Phase::Serving);
+                assert_eq!(game.velocity, (0.0, 0.0));
+                assert_eq!(game.ball_bounds().bottom_right().unwrap().y + 1, game.paddle.top_left.y);
+            }
+        }
+        // no score, so straight to game over
+        assert_eq!(game.phase, Phase::GameOver { won: false });
+        game.restart();
+        assert_eq!((game.lives, game.score, game.level, game.phase), (START_LIVES, 0, 0, Phase::Serving));
+    }
+
+    #[test]
+    fn clearing_levels_and_the_high_score() {
+        let mut game = game(&["..1..", ".....6"]);
+        // straight up into the one brick
+        let brick = game.brick_bounds(2, 0);
+        game.ball = (brick.top_left.x as f32 + 10.0, 100.0);
+        game.velocity = (0.0, -3.0);
+        run(&mut game, 100);
+        assert_eq!((game.level, game.score, game.phase), (1, 10, Phase::Serving));
+        assert_eq!(game.bricks.count_filled(), 1);
+        assert_eq!(game.speed(), BASE_SPEED + SPEED_PER_LEVEL);
+
+        game.phase = Phase::Playing;
+        let brick = game.brick_bounds(5, 0);
+        game.ball = (brick.top_left.x as f32 + 10.0, 100.0);
+        game.velocity = (0.0, -3.0);
+        run(&mut game, 100);
+        assert_eq!(game.score, 70);
+        assert_eq!(game.phase, Phase::EnterName);
+        for key in [b'a', b'b', 8, b'x', b'-', b'y', b'z', b'\r'] {
+            game.handle_input(&InputMap::default(), Some(key), &mut DirtyRegions::new());
+        }
+        assert_eq!(game.phase, Phase::GameOver { won: true });
+        assert!(game.take_scores_changed());
+        assert!(!game.take_scores_changed());
+        let entry = HighScore { name: String::from("AXY"), score: 70, level: 2 };
+        assert_eq!(game.high_scores.entries, vec![entry]);
+    }
+
+    #[test]
+    fn high_score_table() {
+        let mut scores = HighScores::default();
+        assert!(!scores.qualifies(0));
+        for (i, score) in [50, 10, 30, 40, 20].into_iter().enumerate() {
+            scores.insert(format!("P{}", i), score, 1);
+        }
+        let order: Vec<u32> = scores.entries.iter().map(|e| e.score).collect();
+        assert_eq!(order, [50, 40, 30, 20, 10]);
+        // full, so it has to beat the lowest
+        assert!(!scores.qualifies(10));
+        assert_eq!(scores.insert(String::from("LOW"), 5, 1), None);
+        assert_eq!(scores.insert(String::from("NEW"), 45, 3), Some(1));
+        let order: Vec<u32> = scores.entries.iter().map(|e| e.score).collect();
+        assert_eq!(order, [50, 45, 40, 30, 20]);
+        // a tie goes below the score it ties with
+        assert_eq!(scores.insert(String::from("TIE"), 40, 1), Some(3));
+        assert_eq!(scores.entries.len(), HIGH_SCORE_COUNT);
+    }
+}
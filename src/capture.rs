@@ -0,0 +1,304 @@
+//! Sound from the microphone.
+//!
+//! The T-Deck's microphone goes through an ES7210 ADC, which is set up over I2C by
+//! `Wrapper::init_microphone` and sends its samples over I2S1. `AudioSource::new` starts
+//! I2S1 receiving into a circular DMA buffer and splits it in two, like the `AudioSink`.
+//! The `AudioRecorder` runs in its own task, takes what the DMA has received and puts it
+//! in a lock free ring buffer. The `AudioReader` goes to whatever wants the samples and
+//! reads them out as mono i16.
+//!
+//! If the reader doesn't keep up the ring buffer fills and the newest samples are dropped
+//! and counted as overruns. The ring buffer holds about half a second at 16kHz, enough to
+//! cover writing to the SD card.
+//!
+//! Everything captured also goes to `CAPTURE_TAP` for the visualizer, whether it's read
+//! or not.
+
+use crate::audio::AudioError;
+use crate::visualizer::Tap;
+use core::sync::atomic::{AtomicU32, Ordering};
+use esp_hal::dma_circular_buffers;
+use esp_hal::i2c::master::{Error as I2cError, I2c};
+use esp_hal::i2s::master::asynch::I2sReadDmaTransferAsync;
+use esp_hal::i2s::master::{Config, DataFormat, I2s};
+use esp_hal::peripherals::{DMA_CH1, GPIO14, GPIO21, GPIO47, GPIO48, I2S1};
+use esp_hal::time::Rate;
+use esp_hal::Blocking;
+use heapless::spsc::{Consumer, Producer, Queue};
+use log::info;
+use static_cell::StaticCell;
+
+/// frames held between the recorder and the reader, about half a second at 16kHz
+pub const CAPTURE_FRAMES: usize = 8192;
+// three DMA descriptors, each one is a little under 1024 frames
+const DMA_BYTES: usize = 3 * 4092;
+const BYTES_PER_FRAME: usize = 4;
+// bytes taken from the DMA buffer at a time
+const POP_BYTES: usize = 1024;
+
+const ES7210_ADDRESS: u8 = 0x40;
+// the most the microphone amplifier goes up to in 3dB steps, above this it's 1.5dB steps
+const MAX_GAIN_DB: u8 = 30;
+
+/// The peripherals the microphone ADC is wired to. `Wrapper::init` keeps these for
+/// `AudioSource::new`.
+pub struct MicPeripherals {
+    pub i2s: I2S1<'static>,
+    pub dma: DMA_CH1<'static>,
+    pub mclk: GPIO48<'static>,
+    pub bclk: GPIO47<'static>,
+    pub ws: GPIO21<'static>,
+    pub din: GPIO14<'static>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub sample_rate: u32,
+    /// microphone amplifier gain, 0 to 30dB in 3dB steps
+    pub gain_db: u8,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            sample_rate: 16_000,
+            gain_db: 24,
+        }
+    }
+}
+
+struct Counters {
+    frames_captured: AtomicU32,
+    overruns: AtomicU32,
+}
+
+static COUNTERS: Counters = Counters {
+    frames_captured: AtomicU32::new(0),
+    overruns: AtomicU32::new(0),
+};
+
+/// The last samples captured.
+pub static CAPTURE_TAP: Tap = Tap::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureStats {
+    /// frames received from the microphone
+    pub frames_captured: u32,
+    /// frames dropped because the ring buffer was full
+    pub overruns: u32,
+    pub queued_frames: u32,
+}
+
+pub struct AudioSource;
+
+impl AudioSource {
+    /// Starts I2S input and returns the two halves. The ES7210 needs setting up too, with
+    /// `Wrapper::init_microphone`, once the clock from here is running.
+    pub fn new(
+        peripherals: MicPeripherals,
+        config: CaptureConfig,
+    ) -> Result<(AudioReader, AudioRecorder), AudioError> {
+        let (rx_buffer, rx_descriptors, _, _) = dma_circular_buffers!(DMA_BYTES, 0);
+
+        // the ES7210 runs off the master clock, 256 times the sample rate
+        let i2s = I2s::new(
+            peripherals.i2s,
+            peripherals.dma,
+            Config::new_tdm_philips()
+                .with_data_format(DataFormat::Data16Channel16)
+                .with_sample_rate(Rate::from_hz(config.sample_rate)),
+        )?
+        .with_mclk(peripherals.mclk)
+        .into_async();
+        let i2s_rx = i2s
+            .i2s_rx
+            .with_bclk(peripherals.bclk)
+            .with_ws(peripherals.ws)
+            .with_din(peripherals.din)
+            .build(rx_descriptors);
+        let transfer = i2s_rx.read_dma_circular_async(rx_buffer)?;
+        info!("capture started at {}Hz", config.sample_rate);
+        CAPTURE_TAP.set_sample_rate(config.sample_rate);
+
+        static QUEUE: StaticCell<Queue<i16, CAPTURE_FRAMES>> = StaticCell::new();
+        let (producer, consumer) = QUEUE.init(Queue::new()).split();
+        Ok((
+            AudioReader { consumer, config },
+            AudioRecorder {
+                transfer,
+                producer,
+                buf: [0; POP_BYTES],
+            },
+        ))
+    }
+}
+
+/// Gives out the samples the recorder has captured.
+pub struct AudioReader {
+    consumer: Consumer<'static, i16, CAPTURE_FRAMES>,
+    config: CaptureConfig,
+}
+
+impl AudioReader {
+    pub fn config(&self) -> CaptureConfig {
+        self.config
+    }
+
+    /// Frames waiting to be read.
+    pub fn available(&self) -> usize {
+        self.consumer.len()
+    }
+
+    /// Takes as many mono samples as are waiting and fit, returning how many.
+    pub fn read(&mut self, out: &mut [i16]) -> usize {
+        let mut n = 0;
+        for s in out.iter_mut() {
+            let Some(sample) = self.consumer.dequeue() else {
+                break;
+            };
+            *s = sample;
+            n += 1;
+        }
+        n
+    }
+
+    /// Throws away everything waiting, so the next read starts from now.
+    pub fn clear(&mut self) {
+        while self.consumer.dequeue().is_some() {}
+    }
+
+    pub fn stats(&self) -> CaptureStats {
+        CaptureStats {
+            frames_captured: COUNTERS.frames_captured.load(Ordering::Relaxed),
+            overruns: COUNTERS.overruns.load(Ordering::Relaxed),
+            queued_frames: self.consumer.len() as u32,
+        }
+    }
+}
+
+/// Moves received frames from the DMA buffer to the ring buffer. Give it its own task and
+/// call `run`.
+pub struct AudioRecorder {
+    transfer: I2sReadDmaTransferAsync<'static, &'static mut [u8]>,
+    producer: Producer<'static, i16, CAPTURE_FRAMES>,
+    buf: [u8; POP_BYTES],
+}
+
+impl AudioRecorder {
+    pub async fn run(mut self) -> ! {
+        loop {
+            self.pump().await;
+        }
+    }
+
+    /// Waits for the DMA to receive something, then queues it as mono.
+    pub async fn pump(&mut self) {
+        let n = match self.transfer.pop(&mut self.buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                info!("capture fell behind {:?}", e);
+                embassy_time::Timer::after_millis(1).await;
+                return;
+            }
+        };
+        let mut captured = 0;
+        let mut dropped = 0;
+        for frame in self.buf[..n].chunks_exact(BYTES_PER_FRAME) {
+            // the two microphone channels, averaged
+            let left = i16::from_le_bytes([frame[0], frame[1]]) as i32;
+            let right = i16::from_le_bytes([frame[2], frame[3]]) as i32;
+            let sample = ((left + right) / 2) as i16;
+            CAPTURE_TAP.push(sample);
+            if self.producer.enqueue(sample).is_err() {
+                dropped += 1;
+            }
+            captured += 1;
+        }
+        COUNTERS.frames_captured.fetch_add(captured, Ordering::Relaxed);
+        if dropped > 0 {
+            COUNTERS.overruns.fetch_add(dropped, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sets up the ES7210 as an I2S slave sending 16 bit samples from microphones 1 and 2,
+/// with a master clock of 256 times the sample rate. This follows the order of Everest's
+/// own driver.
+pub fn init_es7210(i2c: &mut I2c<'static, Blocking>, gain_db: u8) -> Result<(), I2cError> {
+    // 0 to 10 for 0 to 30dB, with bit 4 turning the amplifier on
+    let gain = 0x10 | (gain_db.min(MAX_GAIN_DB) / 3);
+    let registers: [(u8, u8); 29] = [
+        // reset, then hold the clocks off while it's set up
+        (0x00, 0xFF),
+        (0x00, 0x41),
+        (0x01, 0x3F),
+        // power up timing
+        (0x09, 0x30),
+        (0x0A, 0x30),
+        // high pass filters to take out DC
+        (0x23, 0x2A),
+        (0x22, 0x0A),
+        (0x20, 0x0A),
+        (0x21, 0x2A),
+        // slave mode, the clocks come from the ESP32
+        (0x08, 0x00),
+        // analog power and microphone bias, 2.87V
+        (0x40, 0x43),
+        (0x41, 0x70),
+        (0x42, 0x70),
+        // oversampling, and the clock dividers for MCLK = 256 fs
+        (0x07, 0x20),
+        (0x02, 0xC1),
+        (0x04, 0x01),
+        (0x05, 0x00),
+        // 16 bit I2S, not TDM
+        (0x11, 0x60),
+        (0x12, 0x00),
+        // gain on 1 and 2, 3 and 4 off
+        (0x43, gain),
+        (0x44, gain),
+        (0x45, 0x00),
+        (0x46, 0x00),
+        // power 1 and 2 up and 3 and 4 down
+        (0x4B, 0x00),
+        (0x4C, 0xFF),
+        (0x06, 0x00),
+        // clocks on and a last reset to start
+        (0x01, 0x00),
+        (0x00, 0x71),
+        (0x00, 0x41),
+    ];
+    for (register, value) in registers {
+        i2c.write(ES7210_ADDRESS, &[register, value])?;
+    }
+    info!("ES7210 set up, gain {}dB", gain_db.min(MAX_GAIN_DB));
+    Ok(())
+}
+
+/// Follows how loud the microphone is, for a meter: the peak jumps up at once and falls
+/// back slowly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LevelMeter {
+    /// 0 to `i16::MAX`
+    pub peak: u16,
+}
+
+impl LevelMeter {
+    /// Takes samples as they're read. `decay` is how much the peak falls each call.
+    pub fn update(&mut self, samples: &[i16], decay: u16) {
+        let loudest = samples.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0).min(i16::MAX as u16);
+        self.peak = self.peak.saturating_sub(decay).max(loudest);
+    }
+
+    /// The peak in dB below full scale, down to -60.
+    pub fn db(&self) -> i32 {
+        let mut db = 0;
+        let mut level = i16::MAX as u32;
+        // 6dB a halving is close enough for a meter
+        while db > -60 && (self.peak as u32) < level {
+            level /= 2;
+            db -= 6;
+        }
+        db
+    }
+}
@@ -0,0 +1,518 @@
+//! Line, bar and sparkline charts for values that change over time.
+//!
+//! Samples go into a `Series`, a fixed size ring buffer that drops the oldest sample when
+//! it is full. The charts draw a series with embedded-graphics, scaling the value axis to
+//! fit the samples with gridlines at round numbers.
+//!
+//! The axis only grows straight away. It shrinks once the samples use less than half of
+//! it, so most new samples leave it alone and only the plot area needs drawing again.
+//! `update` says which part of the chart changed.
+
+use crate::theme::Theme;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use micromath::F32Ext;
+
+/// The last `capacity` samples, oldest first.
+#[derive(Debug, Clone)]
+pub struct Series {
+    samples: Vec<f32>,
+    capacity: usize,
+    // where the next sample goes once the buffer is full
+    next: usize,
+    /// how many samples were ever pushed
+    pub total: u32,
+}
+
+impl Series {
+    pub fn new(capacity: usize) -> Self {
+        Series {
+            samples: Vec::with_capacity(capacity.max(1)),
+            capacity: capacity.max(1),
+            next: 0,
+            total: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: f32) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(value);
+        } else {
+            self.samples[self.next] = value;
+            self.next = (self.next + 1) % self.capacity;
+        }
+        self.total = self.total.wrapping_add(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.next = 0;
+    }
+
+    /// oldest first
+    pub fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+        let (newer, older) = self.samples.split_at(self.next);
+        older.iter().chain(newer.iter()).copied()
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let i = (self.next + self.samples.len() - 1) % self.samples.len();
+        Some(self.samples[i])
+    }
+
+    /// the smallest and largest sample, ignoring NaN
+    pub fn range(&self) -> Option<(f32, f32)> {
+        self.iter().filter(|v| !v.is_nan()).fold(None, |range, v| match range {
+            None => Some((v, v)),
+            Some((lo, hi)) => Some((lo.min(v), hi.max(v))),
+        })
+    }
+}
+
+/// A value axis from `min` to `max` with gridlines every `step`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    pub min: f32,
+    pub max: f32,
+    pub step: f32,
+}
+
+impl Scale {
+    /// The smallest range with round steps (1, 2 or 5 times a power of ten) that covers
+    /// `lo` to `hi` with at most `max_steps` gridlines.
+    pub fn nice(lo: f32, hi: f32, max_steps: u32) -> Scale {
+        let (mut lo, mut hi) = (lo.min(hi), lo.max(hi));
+        if hi - lo < 1e-6 {
+            // a flat line, give it some room
+            let pad = if lo.abs() > 1e-6 { lo.abs() * 0.1 } else { 1.0 };
+            lo -= pad;
+            hi += pad;
+        }
+        let rough = (hi - lo) / max_steps.max(1) as f32;
+        // powers of ten by multiplying, micromath's powf is only approximate
+        let exponent = rough.log10().floor() as i32;
+        let mut magnitude = 1.0f32;
+        for _ in 0..exponent.abs() {
+            magnitude = if exponent > 0 { magnitude * 10.0 } else { magnitude / 10.0 };
+        }
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .iter()
+            .map(|m| m * magnitude)
+            .find(|&s| s >= rough)
+            .unwrap_or(10.0 * magnitude);
+        Scale {
+            min: (lo / step).floor() * step,
+            max: (hi / step).ceil() * step,
+            step,
+        }
+    }
+
+    pub fn contains(&self, lo: f32, hi: f32) -> bool {
+        lo >= self.min && hi <= self.max
+    }
+
+    /// 0 at `min`, 1 at `max`
+    pub fn fraction(&self, value: f32) -> f32 {
+        ((value - self.min) / (self.max - self.min)).clamp(0.0, 1.0)
+    }
+
+    /// the values of the gridlines, from the bottom
+    pub fn ticks(&self) -> impl Iterator<Item = f32> {
+        let count = ((self.max - self.min) / self.step).round() as u32;
+        let (min, step) = (self.min, self.step);
+        (0..=count).map(move |i| min + i as f32 * step)
+    }
+
+    /// How many decimals the labels need to show the steps.
+    pub fn decimals(&self) -> usize {
+        if self.step >= 1.0 {
+            0
+        } else {
+            (-self.step.log10().floor()) as usize
+        }
+    }
+
+    /// The value with as many decimals as the steps need. Large round values are
+    /// shortened, 45000 becomes 45k, so they fit next to the axis.
+    pub fn label(&self, value: f32) -> String {
+        if value.abs() >= 10_000.0 && self.step >= 1000.0 {
+            return format!("{}k", (value / 1000.0).round() as i32);
+        }
+        format!("{:.*}", self.decimals(), value)
+    }
+}
+
+/// What changed in a chart since it was last drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    None,
+    /// only the plotted data, the axis is the same
+    Plot,
+    /// the axis changed so the whole chart needs drawing
+    All,
+}
+
+/// The axis shared by the line and bar charts.
+#[derive(Debug, Clone)]
+pub struct Axis {
+    /// a fixed range, instead of scaling to the samples
+    pub fixed: Option<(f32, f32)>,
+    pub max_steps: u32,
+    pub scale: Scale,
+    last_total: Option<u32>,
+}
+
+impl Axis {
+    pub fn new() -> Self {
+        Axis {
+            fixed: None,
+            max_steps: 4,
+            scale: Scale::nice(0.0, 1.0, 4),
+            last_total: None,
+        }
+    }
+
+    fn update(&mut self, series: &Series) -> Change {
+        let changed_data = self.last_total != Some(series.total);
+        self.last_total = Some(series.total);
+        let wanted = match (self.fixed, series.range()) {
+            (Some((lo, hi)), _) => Scale::nice(lo, hi, self.max_steps),
+            (None, None) => return if changed_data { Change::Plot } else { Change::None },
+            (None, Some((lo, hi))) => {
+                let current = self.scale;
+                let used = (hi - lo) / (current.max - current.min);
+                if current.contains(lo, hi) && used >= 0.5 {
+                    current
+                } else {
+                    Scale::nice(lo, hi, self.max_steps)
+                }
+            }
+        };
+        if wanted != self.scale {
+            self.scale = wanted;
+            Change::All
+        } else if changed_data {
+            Change::Plot
+        } else {
+            Change::None
+        }
+    }
+}
+
+impl Default for Axis {
+    fn default() -> Self {
+        Axis::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChartKind {
+    Line,
+    Bar,
+}
+
+/// A line or bar chart with a title, gridlines and labels.
+#[derive(Clone)]
+pub struct Chart {
+    pub kind: ChartKind,
+    pub bounds: Rectangle,
+    pub title: String,
+    pub unit: String,
+    pub color: Rgb565,
+    pub axis: Axis,
+    /// time between samples, for labelling the time axis
+    pub sample_ms: Option<u32>,
+    pub theme: Theme,
+}
+
+const LABEL_WIDTH: u32 = 32;
+const TITLE_HEIGHT: u32 = 12;
+const TIME_HEIGHT: u32 = 10;
+
+impl Chart {
+    pub fn new(kind: ChartKind, bounds: Rectangle, title: &str, unit: &str, color: Rgb565) -> Self {
+        Chart {
+            kind,
+            bounds,
+            title: String::from(title),
+            unit: String::from(unit),
+            color,
+            axis: Axis::new(),
+            sample_ms: None,
+            theme: Theme::dark(),
+        }
+    }
+
+    pub fn line(bounds: Rectangle, title: &str, unit: &str, color: Rgb565) -> Self {
+        Chart::new(ChartKind::Line, bounds, title, unit, color)
+    }
+
+    pub fn bar(bounds: Rectangle, title: &str, unit: &str, color: Rgb565) -> Self {
+        Chart::new(ChartKind::Bar, bounds, title, unit, color)
+    }
+
+    pub fn with_range(mut self, min: f32, max: f32) -> Self {
+        self.axis.fixed = Some((min, max));
+        self
+    }
+
+    pub fn with_sample_ms(mut self, ms: u32) -> Self {
+        self.sample_ms = Some(ms);
+        self
+    }
+
+    /// Where the data is drawn, inside the labels.
+    pub fn plot_area(&self) -> Rectangle {
+        let b = self.bounds;
+        let bottom = if self.sample_ms.is_some() { TIME_HEIGHT } else { 0 };
+        Rectangle::new(
+            b.top_left + Point::new(LABEL_WIDTH as i32, TITLE_HEIGHT as i32),
+            Size::new(
+                b.size.width.saturating_sub(LABEL_WIDTH + 2),
+                b.size.height.saturating_sub(TITLE_HEIGHT + bottom + 1),
+            ),
+        )
+    }
+
+    /// The area the title and latest value are drawn in.
+    pub fn title_area(&self) -> Rectangle {
+        Rectangle::new(self.bounds.top_left, Size::new(self.bounds.size.width, TITLE_HEIGHT))
+    }
+
+    /// Rescales the axis for the samples and says what needs drawing again.
+    pub fn update(&mut self, series: &Series) -> Change {
+        self.axis.update(series)
+    }
+
+    /// Adds the parts that changed to the areas to draw.
+    pub fn dirty_area(&self, change: Change) -> Option<Rectangle> {
+        match change {
+            Change::None => None,
+            // the title shows the latest value, so include it
+            Change::Plot => Some(crate::game::union(&self.plot_area(), &self.title_area())),
+            Change::All => Some(self.bounds),
+        }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, series: &Series, target: &mut D) -> Result<(), D::Error> {
+        let small = MonoTextStyle::new(self.theme.small, self.theme.quote);
+        let plot = self.plot_area();
+        let scale = self.axis.scale;
+        self.bounds
+            .into_styled(PrimitiveStyle::with_fill(self.theme.background))
+            .draw(target)?;
+
+        // title and the latest value
+        let title_style = MonoTextStyle::new(self.theme.small, self.theme.heading);
+        Text::with_baseline(&self.title, self.bounds.top_left + Point::new(2, 1), title_style, Baseline::Top)
+            .draw(target)?;
+        if let Some(latest) = series.latest() {
+            let value = format!("{}{}", scale.label(latest), self.unit);
+            let right = TextStyleBuilder::new()
+                .alignment(Alignment::Right)
+                .baseline(Baseline::Top)
+                .build();
+            let p = Point::new(self.bounds.top_left.x + self.bounds.size.width as i32 - 2, self.bounds.top_left.y + 1);
+            Text::with_text_style(&value, p, MonoTextStyle::new(self.theme.small, self.color), right)
+                .draw(target)?;
+        }
+
+        // gridlines and their labels
+        let label_style = TextStyleBuilder::new()
+            .alignment(Alignment::Right)
+            .baseline(Baseline::Middle)
+            .build();
+        for tick in scale.ticks() {
+            let y = value_y(&plot, &scale, tick);
+            dotted_hline(target, plot.top_left.x, plot.size.width, y, self.theme.grid)?;
+            let p = Point::new(plot.top_left.x - 3, y);
+            Text::with_text_style(&scale.label(tick), p, small, label_style).draw(target)?;
+        }
+        // time gridlines at each quarter
+        if let Some(ms) = self.sample_ms {
+            let span_s = ms as u64 * series.capacity() as u64 / 1000;
+            let centered = TextStyleBuilder::new()
+                .alignment(Alignment::Center)
+                .baseline(Baseline::Top)
+                .build();
+            for q in 0..=4u32 {
+                let x = plot.top_left.x + ((plot.size.width - 1) * q / 4) as i32;
+                if q < 4 {
+                    dotted_vline(target, x, plot.top_left.y, plot.size.height, self.theme.grid)?;
+                }
+                let ago = span_s * (4 - q) as u64 / 4;
+                let label = if ago == 0 { String::from("now") } else { format!("-{}s", ago) };
+                let p = Point::new(x.min(plot.top_left.x + plot.size.width as i32 - 10), plot.top_left.y + plot.size.height as i32 + 1);
+                Text::with_text_style(&label, p, small, centered).draw(target)?;
+            }
+        }
+        // the axis lines
+        let axis = PrimitiveStyle::with_stroke(self.theme.quote, 1);
+        let bottom_left = Point::new(plot.top_left.x - 1, plot.top_left.y + plot.size.height as i32);
+        Line::new(Point::new(plot.top_left.x - 1, plot.top_left.y), bottom_left)
+            .into_styled(axis)
+            .draw(target)?;
+        Line::new(bottom_left, bottom_left + Point::new(plot.size.width as i32, 0))
+            .into_styled(axis)
+            .draw(target)?;
+
+        match self.kind {
+            ChartKind::Line => draw_line_plot(series, &plot, &scale, self.color, target),
+            ChartKind::Bar => draw_bars(series, &plot, &scale, self.color, target),
+        }
+    }
+}
+
+/// A small chart with no axes, to show a trend next to a number.
+#[derive(Debug, Clone)]
+pub struct Sparkline {
+    pub bounds: Rectangle,
+    pub color: Rgb565,
+    pub background: Rgb565,
+    last_total: Option<u32>,
+}
+
+impl Sparkline {
+    pub fn new(bounds: Rectangle, color: Rgb565) -> Self {
+        Sparkline {
+            bounds,
+            color,
+            background: Rgb565::BLACK,
+            last_total: None,
+        }
+    }
+
+    /// Whether there are new samples since the last call.
+    pub fn update(&mut self, series: &Series) -> Change {
+        let changed = self.last_total != Some(series.total);
+        self.last_total = Some(series.total);
+        if changed {
+            Change::All
+        } else {
+            Change::None
+        }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, series: &Series, target: &mut D) -> Result<(), D::Error> {
+        self.bounds
+            .into_styled(PrimitiveStyle::with_fill(self.background))
+            .draw(target)?;
+        // always fit the samples exactly, a sparkline shows the shape not the values
+        let Some((lo, hi)) = series.range() else {
+            return Ok(());
+        };
+        let scale = if hi - lo < 1e-6 {
+            Scale { min: lo - 1.0, max: hi + 1.0, step: 1.0 }
+        } else {
+            Scale { min: lo, max: hi, step: hi - lo }
+        };
+        let area = Rectangle::new(
+            self.bounds.top_left + Point::new(0, 1),
+            Size::new(self.bounds.size.width.saturating_sub(2), self.bounds.size.height.saturating_sub(2)),
+        );
+        draw_line_plot(series, &area, &scale, self.color, target)?;
+        // a dot on the latest value
+        if let Some(latest) = series.latest() {
+            let p = Point::new(area.top_left.x + area.size.width as i32 - 1, value_y(&area, &scale, latest));
+            Rectangle::with_center(p, Size::new(3, 3))
+                .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+fn value_y(area: &Rectangle, scale: &Scale, value: f32) -> i32 {
+    let h = area.size.height.saturating_sub(1) as f32;
+    area.top_left.y + (h - scale.fraction(value) * h).round() as i32
+}
+
+// x of sample `i` when the newest sample is on the right edge
+fn sample_x(area: &Rectangle, capacity: usize, len: usize, i: usize) -> i32 {
+    let w = area.size.width.saturating_sub(1) as i32;
+    let slots = (capacity.max(2) - 1) as i32;
+    let from_right = (len - 1 - i) as i32;
+    area.top_left.x + w - from_right * w / slots
+}
+
+fn draw_line_plot<D: DrawTarget<Color = Rgb565>>(
+    series: &Series,
+    area: &Rectangle,
+    scale: &Scale,
+    color: Rgb565,
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let style = PrimitiveStyle::with_stroke(color, 1);
+    let len = series.len();
+    let mut prev: Option<Point> = None;
+    for (i, v) in series.iter().enumerate() {
+        if v.is_nan() {
+            // a gap in the data
+            prev = None;
+            continue;
+        }
+        let p = Point::new(sample_x(area, series.capacity(), len, i), value_y(area, scale, v));
+        match prev {
+            Some(q) => Line::new(q, p).into_styled(style).draw(target)?,
+            None => Pixel(p, color).draw(target)?,
+        }
+        prev = Some(p);
+    }
+    Ok(())
+}
+
+fn draw_bars<D: DrawTarget<Color = Rgb565>>(
+    series: &Series,
+    area: &Rectangle,
+    scale: &Scale,
+    color: Rgb565,
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let slot = (area.size.width / series.capacity() as u32).max(1);
+    let width = slot.saturating_sub(1).max(1);
+    // bars grow from zero, or from the bottom if zero is off the axis
+    let base = value_y(area, scale, 0.0f32.clamp(scale.min, scale.max));
+    let len = series.len() as u32;
+    let right = area.top_left.x + area.size.width as i32;
+    for (i, v) in series.iter().enumerate() {
+        if v.is_nan() {
+            continue;
+        }
+        let x = right - ((len - i as u32) * slot) as i32;
+        let y = value_y(area, scale, v);
+        let (top, bottom) = (y.min(base), y.max(base));
+        Rectangle::new(Point::new(x, top), Size::new(width, (bottom - top + 1) as u32))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(target)?;
+    }
+    Ok(())
+}
+
+fn dotted_hline<D: DrawTarget<Color = Rgb565>>(target: &mut D, x: i32, width: u32, y: i32, color: Rgb565) -> Result<(), D::Error> {
+    target.draw_iter((0..width as i32).step_by(3).map(|dx| Pixel(Point::new(x + dx, y), color)))
+}
+
+fn dotted_vline<D: DrawTarget<Color = Rgb565>>(target: &mut D, x: i32, y: i32, height: u32, color: Rgb565) -> Result<(), D::Error> {
+    target.draw_iter((0..height as i32).step_by(3).map(|dy| Pixel(Point::new(x, y + dy), color)))
+}
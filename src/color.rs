@@ -0,0 +1,455 @@
+//! Converting between Rgb565, Rgb888 and grayscale, blending colors, and dithering 24 bit
+//! images down to the 16 bit display.
+//!
+//! Blending and alpha compositing are done in linear light, the way light actually adds
+//! up, using the sRGB curve. Mixing red and green this way gives a bright yellow rather
+//! than the muddy one you get from averaging the stored values.
+//!
+//! The panel only has 32 levels of red and blue and 64 of green, so smooth gradients
+//! show bands. Dithering trades the bands for a fine pattern: `bayer` uses a fixed 8x8
+//! pattern and can draw pixels in any order, `FloydSteinberg` spreads the rounding error
+//! to the pixels around it and needs them in rows, top to bottom.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Gray8, Rgb565, Rgb888};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+
+/// Stretches 5 bits to 8 so that 31 becomes 255, not 248.
+pub fn expand5(v: u8) -> u8 {
+    (v << 3) | (v >> 2)
+}
+
+/// Stretches 6 bits to 8 so that 63 becomes 255, not 252.
+pub fn expand6(v: u8) -> u8 {
+    (v << 2) | (v >> 4)
+}
+
+// nearest 5 or 6 bit level
+fn round5(v: u8) -> u8 {
+    ((v as u16 * 31 + 127) / 255) as u8
+}
+
+fn round6(v: u8) -> u8 {
+    ((v as u16 * 63 + 127) / 255) as u8
+}
+
+pub fn to_rgb888(c: Rgb565) -> Rgb888 {
+    Rgb888::new(expand5(c.r()), expand6(c.g()), expand5(c.b()))
+}
+
+/// The nearest 16 bit color. Just dropping the low bits makes everything slightly darker.
+pub fn to_rgb565(c: Rgb888) -> Rgb565 {
+    Rgb565::new(round5(c.r()), round6(c.g()), round5(c.b()))
+}
+
+/// Perceived brightness, using the Rec. 601 weights.
+pub fn luma(c: Rgb888) -> u8 {
+    ((c.r() as u32 * 77 + c.g() as u32 * 150 + c.b() as u32 * 29 + 128) >> 8) as u8
+}
+
+pub fn to_gray(c: Rgb888) -> Gray8 {
+    Gray8::new(luma(c))
+}
+
+pub fn gray_to_rgb565(g: Gray8) -> Rgb565 {
+    to_rgb565(Rgb888::new(g.luma(), g.luma(), g.luma()))
+}
+
+// sRGB values to linear light, 0 to 65535
+const TO_LINEAR: [u16; 256] = [
+    0, 20, 40, 60, 80, 99, 119, 139, 159, 179, 199, 219,
+    241, 264, 288, 313, 340, 367, 396, 427, 458, 491, 526, 562,
+    599, 637, 677, 718, 761, 805, 851, 898, 947, 997, 1048, 1101,
+    1156, 1212, 1270, 1330, 1391, 1453, 1517, 1583, 1651, 1720, 1790, 1863,
+    1937, 2013, 2090, 2170, 2250, 2333, 2418, 2504, 2592, 2681, 2773, 2866,
+    2961, 3058, 3157, 3258, 3360, 3464, 3570, 3678, 3788, 3900, 4014, 4129,
+    4247, 4366, 4488, 4611, 4736, 4864, 4993, 5124, 5257, 5392, 5530, 5669,
+    5810, 5953, 6099, 6246, 6395, 6547, 6700, 6856, 7014, 7174, 7335, 7500,
+    7666, 7834, 8004, 8177, 8352, 8528, 8708, 8889, 9072, 9258, 9445, 9635,
+    9828, 10022, 10219, 10417, 10619, 10822, 11028, 11235, 11446, 11658, 11873, 12090,
+    12309, 12530, 12754, 12980, 13209, 13440, 13673, 13909, 14146, 14387, 14629, 14874,
+    15122, 15371, 15623, 15878, 16135, 16394, 16656, 16920, 17187, 17456, 17727, 18001,
+    18277, 18556, 18837, 19121, 19407, 19696, 19987, 20281, 20577, 20876, 21177, 21481,
+    21787, 22096, 22407, 22721, 23038, 23357, 23678, 24002, 24329, 24658, 24990, 25325,
+    25662, 26001, 26344, 26688, 27036, 27386, 27739, 28094, 28452, 28813, 29176, 29542,
+    29911, 30282, 30656, 31033, 31412, 31794, 32179, 32567, 32957, 33350, 33745, 34143,
+    34544, 34948, 35355, 35764, 36176, 36591, 37008, 37429, 37852, 38278, 38706, 39138,
+    39572, 40009, 40449, 40891, 41337, 41785, 42236, 42690, 43147, 43606, 44069, 44534,
+    45002, 45473, 45947, 46423, 46903, 47385, 47871, 48359, 48850, 49344, 49841, 50341,
+    50844, 51349, 51858, 52369, 52884, 53401, 53921, 54445, 54971, 55500, 56032, 56567,
+    57105, 57646, 58190, 58737, 59287, 59840, 60396, 60955, 61517, 62082, 62650, 63221,
+    63795, 64372, 64952, 65535,
+];
+
+/// An sRGB channel value in linear light, from 0 to 65535.
+pub fn to_linear(v: u8) -> u16 {
+    TO_LINEAR[v as usize]
+}
+
+/// The sRGB value closest to a linear light level.
+pub fn from_linear(l: u16) -> u8 {
+    // the table is sorted, so find the nearest entry
+    let i = TO_LINEAR.partition_point(|&x| x < l);
+    if i == 0 {
+        return 0;
+    }
+    if i == 256 {
+        return 255;
+    }
+    if l - TO_LINEAR[i - 1] < TO_LINEAR[i] - l {
+        (i - 1) as u8
+    } else {
+        i as u8
+    }
+}
+
+fn mix_linear(a: u8, b: u8, t: u8) -> u8 {
+    let (la, lb) = (to_linear(a) as u32, to_linear(b) as u32);
+    from_linear(((la * (255 - t as u32) + lb * t as u32 + 127) / 255) as u16)
+}
+
+/// Mixes two colors in linear light. `t` of 0 gives `a`, 255 gives `b`.
+pub fn blend(a: Rgb888, b: Rgb888, t: u8) -> Rgb888 {
+    Rgb888::new(
+        mix_linear(a.r(), b.r(), t),
+        mix_linear(a.g(), b.g(), t),
+        mix_linear(a.b(), b.b(), t),
+    )
+}
+
+pub fn blend565(a: Rgb565, b: Rgb565, t: u8) -> Rgb565 {
+    to_rgb565(blend(to_rgb888(a), to_rgb888(b), t))
+}
+
+/// A color with straight (not premultiplied) alpha. 255 is opaque.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgba8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Rgba8 {
+    pub const TRANSPARENT: Rgba8 = Rgba8::new(0, 0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Rgba8 { r, g, b, a }
+    }
+
+    pub fn opaque(c: Rgb888) -> Self {
+        Rgba8::new(c.r(), c.g(), c.b(), 255)
+    }
+
+    pub fn rgb(&self) -> Rgb888 {
+        Rgb888::new(self.r, self.g, self.b)
+    }
+
+    /// This color drawn over `dst`, which may also be see-through (Porter-Duff over).
+    pub fn over(self, dst: Rgba8) -> Rgba8 {
+        let sa = self.a as u32;
+        let da = dst.a as u32 * (255 - sa) / 255;
+        let a = sa + da;
+        if a == 0 {
+            return Rgba8::TRANSPARENT;
+        }
+        let channel = |s: u8, d: u8| {
+            let l = (to_linear(s) as u32 * sa + to_linear(d) as u32 * da) / a;
+            from_linear(l as u16)
+        };
+        Rgba8::new(
+            channel(self.r, dst.r),
+            channel(self.g, dst.g),
+            channel(self.b, dst.b),
+            a as u8,
+        )
+    }
+}
+
+/// Draws a see-through color over an opaque one.
+pub fn composite(src: Rgba8, dst: Rgb888) -> Rgb888 {
+    blend(dst, src.rgb(), src.a)
+}
+
+// ---------- dithering ----------
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// a channel quantized to `max` levels, rounding up when the value is past the threshold
+fn ordered(v: u8, max: u16, threshold: u16) -> u8 {
+    ((v as u16 * max + threshold) / 255).min(max) as u8
+}
+
+/// Ordered dithering of one pixel, using its position on the screen to pick a threshold.
+pub fn bayer(c: Rgb888, x: i32, y: i32) -> Rgb565 {
+    let t = BAYER_8X8[(y & 7) as usize][(x & 7) as usize] as u16;
+    // spread the thresholds evenly over 0..255, averaging out to plain rounding
+    let threshold = (t * 2 + 1) * 255 / 128;
+    Rgb565::new(ordered(c.r(), 31, threshold), ordered(c.g(), 63, threshold), ordered(c.b(), 31, threshold))
+}
+
+/// Floyd-Steinberg error diffusion for an image `width` pixels wide. Feed it pixels in
+/// rows from top to bottom.
+pub struct FloydSteinberg {
+    width: usize,
+    x: usize,
+    // errors for this row and the next, with a spare pixel at each end
+    current: Vec<[i16; 3]>,
+    next: Vec<[i16; 3]>,
+}
+
+impl FloydSteinberg {
+    pub fn new(width: usize) -> Self {
+        FloydSteinberg {
+            width,
+            x: 0,
+            current: vec![[0; 3]; width + 2],
+            next: vec![[0; 3]; width + 2],
+        }
+    }
+
+    /// Starts again from the top left.
+    pub fn reset(&mut self) {
+        self.x = 0;
+        self.current.fill([0; 3]);
+        self.next.fill([0; 3]);
+    }
+
+    /// Dithers the next pixel.
+    pub fn push(&mut self, c: Rgb888) -> Rgb565 {
+        let i = self.x + 1;
+        let mut out = [0u8; 3];
+        for (ch, (&v, max)) in [c.r(), c.g(), c.b()].iter().zip([31u16, 63, 31]).enumerate() {
+            let wanted = (v as i16 + self.current[i][ch] / 16).clamp(0, 255);
+            let q = ((wanted as u16 * max + 127) / 255) as u8;
+            out[ch] = q;
+            let shown = if max == 31 { expand5(q) } else { expand6(q) } as i16;
+            // errors are kept in sixteenths
+            let err = wanted - shown;
+            self.current[i + 1][ch] += err * 7;
+            self.next[i - 1][ch] += err * 3;
+            self.next[i][ch] += err * 5;
+            self.next[i + 1][ch] += err;
+        }
+        self.x += 1;
+        if self.x == self.width {
+            self.x = 0;
+            core::mem::swap(&mut self.current, &mut self.next);
+            self.next.fill([0; 3]);
+        }
+        Rgb565::new(out[0], out[1], out[2])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// round to the nearest color
+    None,
+    Bayer,
+    FloydSteinberg,
+}
+
+/// Draws 24 bit pixels, given row by row, into `area` of a 16 bit display.
+pub fn draw_rgb888<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    area: &Rectangle,
+    pixels: impl IntoIterator<Item = Rgb888>,
+    dither: Dither,
+) -> Result<(), D::Error> {
+    let width = area.size.width as usize;
+    let top_left = area.top_left;
+    let mut diffusion = match dither {
+        Dither::FloydSteinberg => Some(FloydSteinberg::new(width)),
+        _ => None,
+    };
+    let converted = pixels.into_iter().enumerate().map(move |(i, c)| match dither {
+        Dither::None => to_rgb565(c),
+        Dither::Bayer => {
+            let x = top_left.x + (i % width) as i32;
+            let y = top_left.y + (i / width) as i32;
+            bayer(c, x, y)
+        }
+        Dither::FloydSteinberg => diffusion.as_mut().unwrap().push(c),
+    });
+    target.fill_contiguous(area, converted)
+}
+
+/// Lets anything drawn in Rgb888 go onto a Rgb565 display, with ordered dithering.
+/// Handy for gradients and anti-aliased shapes.
+pub struct Dithered<'a, D> {
+    pub target: &'a mut D,
+    pub dither: bool,
+}
+
+impl<'a, D: DrawTarget<Color = Rgb565>> Dithered<'a, D> {
+    pub fn new(target: &'a mut D) -> Self {
+        Dithered { target, dither: true }
+    }
+}
+
+impl<D: DrawTarget<Color = Rgb565>> OriginDimensions for Dithered<'_, D> {
+    fn size(&self) -> Size {
+        self.target.bounding_box().size
+    }
+}
+
+impl<D: DrawTarget<Color = Rgb565>> DrawTarget for Dithered<'_, D> {
+    type Color = Rgb888;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let dither = self.dither;
+        self.target.draw_iter(pixels.into_iter().map(|Pixel(p, c)| {
+            let c = if dither { bayer(c, p.x, p.y) } else { to_rgb565(c) };
+            Pixel(p, c)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDisplay;
+
+    fn all_565() -> impl Iterator<Item = Rgb565> {
+        (0..=u16::MAX).map(|raw| Rgb565::from(embedded_graphics::pixelcolor::raw::RawU16::new(raw)))
+    }
+
+    // the mean of each channel, back in 8 bits
+    fn mean(pixels: &[Rgb565]) -> [f64; 3] {
+        let n = pixels.len() as f64;
+        let sum = pixels.iter().fold([0.0; 3], |s, c| {
+            let c = to_rgb888(*c);
+            [s[0] + c.r() as f64, s[1] + c.g() as f64, s[2] + c.b() as f64]
+        });
+        sum.map(|s| s / n)
+    }
+
+    #[test]
+    fn round_trips() {
+        for c in all_565() {
+            assert_eq!(to_rgb565(to_rgb888(c)), c);
+        }
+        assert_eq!(to_rgb888(Rgb565::WHITE), Rgb888::WHITE);
+        assert_eq!(to_rgb888(Rgb565::BLACK), Rgb888::BLACK);
+        // 888 comes back as the nearest 565 level
+        for v in 0..=255u8 {
+            let c = to_rgb888(to_rgb565(Rgb888::new(v, v, v)));
+            assert!((c.r() as i32 - v as i32).abs() <= 4, "{}", v);
+            assert!((c.g() as i32 - v as i32).abs() <= 2, "{}", v);
+            assert_eq!(c.r(), c.b());
+        }
+    }
+
+    #[test]
+    fn linear_light() {
+        for v in 0..=255u8 {
+            let s = v as f64 / 255.0;
+            let l = if s <= 0.04045 { s / 12.92 } else { ((s + 0.055) / 1.055).powf(2.4) };
+            assert!((to_linear(v) as f64 - l * 65535.0).abs() <= 1.0, "{}", v);
+            assert_eq!(from_linear(to_linear(v)), v);
+        }
+        assert_eq!(from_linear(0), 0);
+        assert_eq!(from_linear(u16::MAX), 255);
+        assert_eq!(luma(Rgb888::WHITE), 255);
+        assert_eq!(luma(Rgb888::BLACK), 0);
+        assert_eq!(gray_to_rgb565(to_gray(Rgb888::WHITE)), Rgb565::WHITE);
+    }
+
+    #[test]
+    fn blend_endpoints() {
+        let colors = [Rgb888::BLACK, Rgb888::WHITE, Rgb888::new(200, 30, 90), Rgb888::new(1, 128, 254)];
+        for a in colors {
+            for b in colors {
+                assert_eq!(blend(a, b, 0), a);
+                assert_eq!(blend(a, b, 255), b);
+                assert_eq!(composite(Rgba8::new(b.r(), b.g(), b.b(), 0), a), a);
+                assert_eq!(composite(Rgba8::opaque(b), a), b);
+                assert_eq!(Rgba8::opaque(b).over(Rgba8::opaque(a)), Rgba8::opaque(b));
+                assert_eq!(Rgba8::new(b.r(), b.g(), b.b(), 0).over(Rgba8::opaque(a)), Rgba8::opaque(a));
+            }
+        }
+        for a in all_565().step_by(97) {
+            assert_eq!(blend565(a, Rgb565::CSS_ORANGE, 0), a);
+            assert_eq!(blend565(Rgb565::CSS_ORANGE, a, 255), a);
+        }
+        // in linear light half way is brighter than half the stored value
+        assert_eq!(blend(Rgb888::RED, Rgb888::GREEN, 128), Rgb888::new(187, 188, 0));
+        assert_eq!(Rgba8::TRANSPARENT.over(Rgba8::TRANSPARENT), Rgba8::TRANSPARENT);
+        let half = Rgba8::new(255, 255, 255, 128).over(Rgba8::TRANSPARENT);
+        assert_eq!(half, Rgba8::new(255, 255, 255, 128));
+    }
+
+    #[test]
+    fn dithering_a_flat_color_averages_out() {
+        // levels that fall between two 565 colors, where plain rounding is off by up to 4
+        for v in [3u8, 20, 100, 130, 200, 252] {
+            let c = Rgb888::new(v, v, v);
+            let tile: Vec<Rgb565> = (0..64).map(|i| bayer(c, i % 8, i / 8)).collect();
+            let mut diffusion = FloydSteinberg::new(32);
+            let rows: Vec<Rgb565> = (0..32 * 32).map(|_| diffusion.push(c)).collect();
+            for [r, g, b] in [mean(&tile), mean(&rows)] {
+                assert!((r - v as f64).abs() < 1.0 && (b - v as f64).abs() < 1.0, "{} {}", v, r);
+                assert!((g - v as f64).abs() < 1.0, "{} {}", v, g);
+            }
+            // and it's only ever the levels either side
+            let levels = [to_rgb565(c).r(), to_rgb565(c).r().saturating_sub(1), to_rgb565(c).r() + 1];
+            assert!(tile.iter().chain(&rows).all(|p| levels.contains(&p.r())));
+        }
+        // black and white have nothing to dither
+        for c in [Rgb888::BLACK, Rgb888::WHITE] {
+            assert!((0..64).all(|i| bayer(c, i % 8, i / 8) == to_rgb565(c)));
+            let mut diffusion = FloydSteinberg::new(8);
+            assert!((0..64).all(|_| diffusion.push(c) == to_rgb565(c)));
+        }
+    }
+
+    #[test]
+    fn dithering_a_gradient() {
+        // a slow ramp, 4 pixels a level, which bands badly when rounded
+        let area = Rectangle::new(Point::zero(), Size::new(256, 16));
+        let ramp = |x: u32| Rgb888::new((x / 4) as u8, (x / 4) as u8, (x / 4) as u8);
+        let pixels = || (0..area.size.height).flat_map(move |_| (0..area.size.width).map(ramp));
+        for dither in [Dither::None, Dither::Bayer, Dither::FloydSteinberg] {
+            let mut display = SimDisplay::new(area.size);
+            draw_rgb888(&mut display, &area, pixels(), dither).unwrap();
+            // 8 column strips average out to the ramp when dithered
+            let mut worst = 0f64;
+            for strip in 0..32 {
+                let column: Vec<Rgb565> = (0..16)
+                    .flat_map(|y| (0..8).map(move |x| Point::new(strip * 8 + x, y)))
+                    .map(|p| display.pixel(p).unwrap())
+                    .collect();
+                let wanted = (0..8).map(|x| ramp((strip * 8 + x) as u32).r() as f64).sum::<f64>() / 8.0;
+                worst = worst.max((mean(&column)[0] - wanted).abs());
+            }
+            match dither {
+                Dither::None => assert!(worst > 2.0, "{}", worst),
+                _ => assert!(worst < 1.0, "{:?} {}", dither, worst),
+            }
+            if dither == Dither::None {
+                assert!(display.pixels().iter().zip(pixels()).all(|(p, c)| *p == to_rgb565(c)));
+            }
+        }
+        // drawing through Dithered is the same as Bayer
+        let mut bayer_display = SimDisplay::new(area.size);
+        draw_rgb888(&mut bayer_display, &area, pixels(), Dither::Bayer).unwrap();
+        let mut display = SimDisplay::new(area.size);
+        Dithered::new(&mut display).fill_contiguous(&area, pixels()).unwrap();
+        assert_eq!(display.pixels(), bayer_display.pixels());
+    }
+}
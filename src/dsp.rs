@@ -0,0 +1,691 @@
+//! Shaping the sound on its way to the speaker.
+//!
+//! The T-Deck's speaker is tiny: it can't move enough air for anything much below 300Hz,
+//! and pushing bass at it just uses up headroom and makes it buzz, while a few kHz comes
+//! out harsh. The `AudioPlayer` runs everything through a `Dsp`, an `Equalizer` and then
+//! a `Limiter`, set up from a `Preset`.
+//!
+//! `Biquad` is the one filter everything is built from, with the shapes from Robert
+//! Bristow-Johnson's audio EQ cookbook: low and high shelves, peaking, low and high pass.
+//! Coefficients are worked out in f64, without `libm`, then rounded to Q27 so filtering
+//! is all integer. The rounding error of each output is carried into the next, and
+//! samples keep 4 more bits between filters, which keeps low frequency filters quiet even
+//! though their coefficients are close to 1. Each filter is 5 multiplies a sample.
+//!
+//! `Limiter` turns everything up, for loudness, then keeps the peaks just under full scale
+//! so boosted bands and the extra gain don't clip. It drops the gain straight away on a
+//! peak and brings it back over `RELEASE_MS`.
+
+use crate::fft::cos_sin;
+use crate::volume::UNITY;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::f64::consts::{LN_10, LN_2, PI};
+use serde::{Deserialize, Serialize};
+
+/// Fraction bits in the filter coefficients. The biggest a coefficient gets is about 11,
+/// from a shelf at `MAX_GAIN_DB` with its corner right at one end.
+pub const COEF_BITS: u32 = 27;
+// bits below a 16 bit sample kept between filters, so each one's rounding is quieter
+const FRACTION_BITS: u32 = 4;
+/// the most any band can boost or cut
+pub const MAX_GAIN_DB: f32 = 15.0;
+/// the loudest the limiter lets through, a little under full scale
+pub const LIMIT: i32 = 32_000;
+/// how long the limiter takes to come back to full gain after a peak
+pub const RELEASE_MS: u32 = 150;
+/// where the bands of `Equalizer::graphic` go, spaced evenly in pitch between these
+pub const GRAPHIC_LOW_HZ: f32 = 60.0;
+pub const GRAPHIC_HIGH_HZ: f32 = 12_000.0;
+
+/// e^x without `libm`: halved until the series is quick, then squared back up.
+fn exp(x: f64) -> f64 {
+    let mut halvings = 0;
+    let mut x = x;
+    while x.abs() > 0.1 {
+        x /= 2.0;
+        halvings += 1;
+    }
+    let (mut sum, mut term) = (1.0, 1.0);
+    for k in 1..12 {
+        term *= x / k as f64;
+        sum += term;
+    }
+    for _ in 0..halvings {
+        sum *= sum;
+    }
+    sum
+}
+
+/// The natural log without `libm`: halved or doubled to near 1, then a series.
+fn ln(x: f64) -> f64 {
+    if x <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    let (mut x, mut twos) = (x, 0);
+    while x > 1.5 {
+        x /= 2.0;
+        twos += 1;
+    }
+    while x < 0.75 {
+        x *= 2.0;
+        twos -= 1;
+    }
+    // ln x = 2 atanh((x - 1) / (x + 1))
+    let t = (x - 1.0) / (x + 1.0);
+    let (mut sum, mut power) = (0.0, t);
+    for k in 0..12 {
+        sum += power / (2 * k + 1) as f64;
+        power *= t * t;
+    }
+    2.0 * sum + twos as f64 * LN_2
+}
+
+/// A level change in dB as an amplitude ratio, so 6dB is about 2.
+pub fn db_to_gain(db: f32) -> f64 {
+    exp(db as f64 * LN_10 / 20.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterKind {
+    /// boosts or cuts everything below the frequency
+    LowShelf,
+    /// boosts or cuts everything above the frequency
+    HighShelf,
+    /// boosts or cuts around the frequency, narrower the higher the Q
+    Peaking,
+    /// lets through what's below the frequency, ignores the gain
+    LowPass,
+    /// lets through what's above the frequency, ignores the gain
+    HighPass,
+}
+
+/// One filter of an equalizer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Band {
+    pub kind: FilterKind,
+    pub freq_hz: f32,
+    /// how sharp it is. 0.707 is the flattest a pass filter or shelf gets without a bump.
+    pub q: f32,
+    pub gain_db: f32,
+}
+
+impl Band {
+    pub const fn new(kind: FilterKind, freq_hz: f32, q: f32, gain_db: f32) -> Self {
+        Band {
+            kind,
+            freq_hz,
+            q,
+            gain_db,
+        }
+    }
+}
+
+/// Q27 coefficients of a biquad, divided through so a0 is 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coefficients {
+    pub b0: i32,
+    pub b1: i32,
+    pub b2: i32,
+    pub a1: i32,
+    pub a2: i32,
+}
+
+impl Coefficients {
+    /// Lets everything through unchanged.
+    pub const IDENTITY: Coefficients = Coefficients {
+        b0: 1 << COEF_BITS,
+        b1: 0,
+        b2: 0,
+        a1: 0,
+        a2: 0,
+    };
+
+    pub fn new(band: Band, sample_rate: u32) -> Self {
+        let rate = sample_rate.max(1) as f64;
+        // a filter right up at half the sample rate falls apart
+        let freq = (band.freq_hz as f64).clamp(1.0, rate * 0.49);
+        let q = (band.q as f64).max(0.1);
+        let gain_db = band.gain_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+        let (cos, sin) = cos_sin(2.0 * PI * freq / rate);
+        let alpha = sin / (2.0 * q);
+        // the amplitude at the middle of a peak or the far side of a shelf is a^2
+        let a = db_to_gain(gain_db / 2.0);
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            FilterKind::LowPass => {
+                let b = (1.0 - cos) / 2.0;
+                (b, 2.0 * b, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+            }
+            FilterKind::HighPass => {
+                let b = (1.0 + cos) / 2.0;
+                (b, -2.0 * b, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+            }
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterKind::LowShelf => {
+                let s = 2.0 * db_to_gain(gain_db / 4.0) * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + s),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - s),
+                    (a + 1.0) + (a - 1.0) * cos + s,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - s,
+                )
+            }
+            FilterKind::HighShelf => {
+                let s = 2.0 * db_to_gain(gain_db / 4.0) * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + s),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - s),
+                    (a + 1.0) - (a - 1.0) * cos + s,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - s,
+                )
+            }
+        };
+        let fixed = |v: f64| {
+            let v = v / a0 * (1u32 << COEF_BITS) as f64;
+            let v = if v < 0.0 { v - 0.5 } else { v + 0.5 };
+            v.clamp(i32::MIN as f64, i32::MAX as f64) as i32
+        };
+        Coefficients {
+            b0: fixed(b0),
+            b1: fixed(b1),
+            b2: fixed(b2),
+            a1: fixed(a1),
+            a2: fixed(a2),
+        }
+    }
+
+    /// How much the filter multiplies the power of a sine at `freq_hz`, the square of its
+    /// gain. Worked out from the rounded coefficients, so it's what really comes out.
+    pub fn power_gain(&self, freq_hz: f32, sample_rate: u32) -> f64 {
+        let scale = (1u32 << COEF_BITS) as f64;
+        let [b0, b1, b2, a1, a2] = [self.b0, self.b1, self.b2, self.a1, self.a2].map(|c| c as f64 / scale);
+        let (cos, sin) = cos_sin(2.0 * PI * freq_hz as f64 / sample_rate.max(1) as f64);
+        let (cos2, sin2) = (cos * cos - sin * sin, 2.0 * sin * cos);
+        // the top and bottom of the transfer function at e^(-iw)
+        let (nr, ni) = (b0 + b1 * cos + b2 * cos2, -(b1 * sin + b2 * sin2));
+        let (dr, di) = (1.0 + a1 * cos + a2 * cos2, -(a1 * sin + a2 * sin2));
+        (nr * nr + ni * ni) / (dr * dr + di * di)
+    }
+}
+
+/// A biquad filter on stereo samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Biquad {
+    pub coefficients: Coefficients,
+    // the last two inputs and outputs of each channel
+    history: [[i32; 4]; 2],
+    // what was rounded off each channel's last output
+    error: [i64; 2],
+}
+
+impl Biquad {
+    pub fn new(coefficients: Coefficients) -> Self {
+        Biquad {
+            coefficients,
+            history: [[0; 4]; 2],
+            error: [0; 2],
+        }
+    }
+
+    /// Filters one sample of a channel, 0 or 1. Samples can be bigger than i16, boosts
+    /// between filters aren't clipped.
+    pub fn process(&mut self, channel: usize, x: i32) -> i32 {
+        let c = &self.coefficients;
+        let [x1, x2, y1, y2] = self.history[channel];
+        let acc = c.b0 as i64 * x as i64 + c.b1 as i64 * x1 as i64 + c.b2 as i64 * x2 as i64
+            - c.a1 as i64 * y1 as i64
+            - c.a2 as i64 * y2 as i64
+            + self.error[channel];
+        let y = (acc >> COEF_BITS) as i32;
+        self.error[channel] = acc - ((y as i64) << COEF_BITS);
+        self.history[channel] = [x, x1, y, y1];
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.history = [[0; 4]; 2];
+        self.error = [0; 2];
+    }
+}
+
+/// Any number of bands, one after another.
+pub struct Equalizer {
+    pub sample_rate: u32,
+    pub bands: Vec<Band>,
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(bands: &[Band], sample_rate: u32) -> Self {
+        Equalizer {
+            sample_rate,
+            bands: bands.to_vec(),
+            filters: bands.iter().map(|b| Biquad::new(Coefficients::new(*b, sample_rate))).collect(),
+        }
+    }
+
+    /// A graphic equalizer: a peaking band for each gain, spaced evenly in pitch from
+    /// `GRAPHIC_LOW_HZ` to `GRAPHIC_HIGH_HZ`, each as wide as the gap between them.
+    pub fn graphic(gains_db: &[f32], sample_rate: u32) -> Self {
+        Equalizer::new(&graphic_bands(gains_db), sample_rate)
+    }
+
+    /// Changes the gain of a band without a click, the filter carries on from where it was.
+    pub fn set_gain(&mut self, band: usize, gain_db: f32) {
+        if let (Some(b), Some(f)) = (self.bands.get_mut(band), self.filters.get_mut(band)) {
+            b.gain_db = gain_db;
+            f.coefficients = Coefficients::new(*b, self.sample_rate);
+        }
+    }
+
+    /// Filters a stereo frame. What comes out can go past i16 where bands boost.
+    pub fn process(&mut self, frame: [i32; 2]) -> [i32; 2] {
+        let mut frame = frame.map(|s| s << FRACTION_BITS);
+        for f in &mut self.filters {
+            frame = [f.process(0, frame[0]), f.process(1, frame[1])];
+        }
+        frame.map(|s| (s + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS)
+    }
+
+    /// The power gain of all the bands together at a frequency.
+    pub fn power_gain(&self, freq_hz: f32) -> f64 {
+        let rate = self.sample_rate;
+        self.filters.iter().map(|f| f.coefficients.power_gain(freq_hz, rate)).product()
+    }
+}
+
+/// The bands of `Equalizer::graphic`.
+pub fn graphic_bands(gains_db: &[f32]) -> Vec<Band> {
+    let n = gains_db.len();
+    if n == 0 {
+        return Vec::new();
+    }
+    // the ratio from one band to the next, and the Q that makes a band that wide
+    let octaves = ln(GRAPHIC_HIGH_HZ as f64 / GRAPHIC_LOW_HZ as f64) / LN_2;
+    let width = octaves / (n - 1).max(1) as f64;
+    let ratio = exp(width * LN_2);
+    let q = exp(width * LN_2 / 2.0) / (ratio - 1.0);
+    let mut freq = GRAPHIC_LOW_HZ as f64;
+    gains_db
+        .iter()
+        .map(|gain| {
+            let band = Band::new(FilterKind::Peaking, freq as f32, q as f32, *gain);
+            freq *= ratio;
+            band
+        })
+        .collect()
+}
+
+/// Makes everything louder and keeps the peaks under `LIMIT`.
+pub struct Limiter {
+    /// Q16 gain before limiting, for loudness
+    pub makeup: u32,
+    // Q16 gain keeping the peaks down, at most UNITY
+    gain: u32,
+    // Q16 fraction of the way back to UNITY the gain goes each frame
+    release: u32,
+}
+
+impl Limiter {
+    pub fn new(makeup_db: f32, sample_rate: u32) -> Self {
+        let frames = (sample_rate as u64 * RELEASE_MS as u64 / 1000).max(1) as u32;
+        Limiter {
+            makeup: (db_to_gain(makeup_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB)) * UNITY as f64) as u32,
+            gain: UNITY,
+            // close enough to all the way back over RELEASE_MS
+            release: (4 * UNITY / frames).max(1),
+        }
+    }
+
+    /// The Q16 gain taken off to keep the last peak down, `UNITY` when it's not limiting.
+    pub fn gain(&self) -> u32 {
+        self.gain
+    }
+
+    pub fn process(&mut self, frame: [i32; 2]) -> [i16; 2] {
+        let makeup = self.makeup as i64;
+        let frame = frame.map(|s| (s as i64 * makeup) >> 16);
+        let peak = frame[0].abs().max(frame[1].abs());
+        let back = ((UNITY - self.gain) as u64 * self.release as u64) >> 16;
+        self.gain = (self.gain + back as u32 + 1).min(UNITY);
+        if (peak * self.gain as i64) >> 16 > LIMIT as i64 {
+            self.gain = ((LIMIT as i64) << 16).checked_div(peak).unwrap_or(0) as u32;
+        }
+        let gain = self.gain as i64;
+        // rounding down can leave a negative peak 1 past the limit
+        frame.map(|s| ((s * gain) >> 16).clamp(-LIMIT as i64, LIMIT as i64) as i16)
+    }
+}
+
+/// Ready made settings for the equalizer and limiter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Preset {
+    /// nothing changed, and nothing spent on it
+    #[default]
+    Flat,
+    /// no deep bass the speaker can't play, softer 3 to 4kHz, and louder
+    Speaker,
+    /// speech forward and clear, everything else out of the way
+    Voice,
+    /// more bass, for headphones or a bigger speaker
+    Bass,
+    /// quiet parts brought up and loud parts held down, for listening low
+    Night,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 5] = [Preset::Flat, Preset::Speaker, Preset::Voice, Preset::Bass, Preset::Night];
+
+    pub fn next(self) -> Preset {
+        Preset::ALL[(self as usize + 1) % Preset::ALL.len()]
+    }
+
+    /// The preset numbered by `self as u8`, or flat for a number that isn't one.
+    pub fn from_index(index: u8) -> Preset {
+        Preset::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Flat => "flat",
+            Preset::Speaker => "speaker",
+            Preset::Voice => "voice",
+            Preset::Bass => "bass",
+            Preset::Night => "night",
+        }
+    }
+
+    pub fn bands(self) -> Vec<Band> {
+        use FilterKind::*;
+        match self {
+            Preset::Flat => Vec::new(),
+            Preset::Speaker => vec![
+                Band::new(HighPass, 180.0, 0.707, 0.0),
+                Band::new(Peaking, 3500.0, 1.2, -4.0),
+                Band::new(HighShelf, 9000.0, 0.707, -3.0),
+            ],
+            Preset::Voice => vec![
+                Band::new(HighPass, 220.0, 0.707, 0.0),
+                Band::new(Peaking, 2500.0, 0.8, 4.0),
+                Band::new(LowPass, 8000.0, 0.707, 0.0),
+            ],
+            Preset::Bass => vec![
+                Band::new(HighPass, 40.0, 0.707, 0.0),
+                Band::new(LowShelf, 150.0, 0.707, 6.0),
+            ],
+            Preset::Night => vec![
+                Band::new(HighPass, 120.0, 0.707, 0.0),
+                Band::new(HighShelf, 6000.0, 0.707, -4.0),
+            ],
+        }
+    }
+
+    /// How much the limiter turns things up.
+    pub fn loudness_db(self) -> f32 {
+        match self {
+            Preset::Flat | Preset::Bass => 0.0,
+            Preset::Speaker | Preset::Voice => 6.0,
+            Preset::Night => 12.0,
+        }
+    }
+}
+
+/// The equalizer and limiter together, as the `AudioPlayer` runs them.
+pub struct Dsp {
+    pub preset: Preset,
+    pub equalizer: Equalizer,
+    pub limiter: Limiter,
+}
+
+impl Dsp {
+    pub fn new(preset: Preset, sample_rate: u32) -> Self {
+        Dsp {
+            preset,
+            equalizer: Equalizer::new(&preset.bands(), sample_rate),
+            limiter: Limiter::new(preset.loudness_db(), sample_rate),
+        }
+    }
+
+    pub fn process(&mut self, frame: [i16; 2]) -> [i16; 2] {
+        if self.preset == Preset::Flat {
+            return frame;
+        }
+        let frame = self.equalizer.process(frame.map(|s| s as i32));
+        self.limiter.process(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+
+    fn db(power_gain: f64) -> f64 {
+        10.0 * power_gain.log10()
+    }
+
+    fn gain_db(band: Band, freq_hz: f32) -> f64 {
+        db(Coefficients::new(band, RATE).power_gain(freq_hz, RATE))
+    }
+
+    // a xorshift, for noise that's the same every run
+    fn noise(len: usize) -> Vec<i16> {
+        let mut x = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exp_and_ln_match_std() {
+        for i in -400..=400 {
+            let x = i as f64 / 20.0;
+            assert!((exp(x) / x.exp() - 1.0).abs() < 1e-12, "exp({})", x);
+        }
+        for i in -300..=300 {
+            let x = 10f64.powf(i as f64 / 50.0);
+            assert!((ln(x) - x.ln()).abs() < 1e-12, "ln({})", x);
+        }
+        assert_eq!(ln(0.0), f64::NEG_INFINITY);
+        assert_eq!(ln(-1.0), f64::NEG_INFINITY);
+        assert!((db_to_gain(20.0) - 10.0).abs() < 1e-12);
+        assert!((db_to_gain(-6.0) - 0.501_187).abs() < 1e-6);
+        assert_eq!(db_to_gain(0.0), 1.0);
+    }
+
+    #[test]
+    fn peaking() {
+        for gain in [-15.0, -6.0, 3.0, 15.0] {
+            let band = Band::new(FilterKind::Peaking, 1000.0, 1.0, gain);
+            assert!((gain_db(band, 1000.0) - gain as f64).abs() < 0.01, "{} dB", gain);
+            // well away from the peak it's flat again
+            assert!(gain_db(band, 20.0).abs() < 0.1);
+            assert!(gain_db(band, 15_000.0).abs() < 0.1);
+            // and the higher the Q the narrower it is
+            let narrow = Band::new(FilterKind::Peaking, 1000.0, 4.0, gain);
+            assert!(gain_db(narrow, 1500.0).abs() < gain_db(band, 1500.0).abs());
+        }
+        // gains past the limit are held to it
+        let band = Band::new(FilterKind::Peaking, 1000.0, 1.0, 40.0);
+        assert!((gain_db(band, 1000.0) - MAX_GAIN_DB as f64).abs() < 0.01);
+    }
+
+    #[test]
+    fn shelves() {
+        for gain in [-15.0, -4.0, 6.0, 15.0] {
+            let gain_f64 = gain as f64;
+            // half the gain at the corner, all of it on the far side and none on the near
+            let low = Band::new(FilterKind::LowShelf, 300.0, 0.707, gain);
+            assert!((gain_db(low, 300.0) - gain_f64 / 2.0).abs() < 0.01, "low {} dB", gain);
+            assert!((gain_db(low, 10.0) - gain_f64).abs() < 0.1);
+            assert!(gain_db(low, 15_000.0).abs() < 0.1);
+            let high = Band::new(FilterKind::HighShelf, 3000.0, 0.707, gain);
+            assert!((gain_db(high, 3000.0) - gain_f64 / 2.0).abs() < 0.01, "high {} dB", gain);
+            assert!((gain_db(high, 20_000.0) - gain_f64).abs() < 0.2);
+            assert!(gain_db(high, 50.0).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn pass_filters() {
+        // 3dB down at the corner whatever the gain says, since they ignore it
+        for gain in [0.0, 10.0] {
+            let low = Band::new(FilterKind::LowPass, 2000.0, 0.707, gain);
+            assert!((gain_db(low, 2000.0) + 3.01).abs() < 0.01);
+            assert!(gain_db(low, 20.0).abs() < 0.01);
+            // 12dB an octave past the corner
+            assert!((gain_db(low, 8000.0) + 24.0).abs() < 3.0);
+            let high = Band::new(FilterKind::HighPass, 200.0, 0.707, gain);
+            assert!((gain_db(high, 200.0) + 3.01).abs() < 0.01);
+            assert!(gain_db(high, 20_000.0).abs() < 0.01);
+            assert!((gain_db(high, 50.0) + 24.0).abs() < 3.0);
+        }
+        // a high Q makes a bump at the corner
+        let low = Band::new(FilterKind::LowPass, 2000.0, 2.0, 0.0);
+        assert!((gain_db(low, 2000.0) - 6.02).abs() < 0.01);
+        // corners at or past Nyquist are brought back under it
+        let c = Coefficients::new(Band::new(FilterKind::LowPass, 30_000.0, 0.707, 0.0), RATE);
+        assert_eq!(c, Coefficients::new(Band::new(FilterKind::LowPass, 21_609.0, 0.707, 0.0), RATE));
+    }
+
+    #[test]
+    fn flat_bands_are_the_identity() {
+        for kind in [FilterKind::Peaking, FilterKind::LowShelf, FilterKind::HighShelf] {
+            let c = Coefficients::new(Band::new(kind, 1000.0, 0.707, 0.0), RATE);
+            assert_eq!((c.b1, c.b2), (c.a1, c.a2), "{:?}", kind);
+            assert_eq!(c.b0, Coefficients::IDENTITY.b0, "{:?}", kind);
+            for freq in [20.0, 1000.0, 20_000.0] {
+                assert!(gain_db(Band::new(kind, 1000.0, 0.707, 0.0), freq).abs() < 1e-6);
+            }
+        }
+        assert_eq!(Coefficients::IDENTITY.power_gain(1000.0, RATE), 1.0);
+    }
+
+    #[test]
+    fn biquads_at_0_db_are_within_a_bit() {
+        let input = noise(20_000);
+        for kind in [FilterKind::Peaking, FilterKind::LowShelf, FilterKind::HighShelf] {
+            for freq in [30.0, 1000.0, 15_000.0] {
+                let mut biquad = Biquad::new(Coefficients::new(Band::new(kind, freq, 0.707, 0.0), RATE));
+                for (i, x) in input.iter().enumerate() {
+                    let x = *x as i32;
+                    let y = biquad.process(i % 2, x);
+                    assert!((y - x).abs() <= 1, "{:?} at {}Hz: {} for {}", kind, freq, y, x);
+                }
+            }
+        }
+        // a whole graphic equalizer of them too
+        let mut equalizer = Equalizer::graphic(&[0.0; 10], RATE);
+        for frame in input.chunks(2) {
+            let out = equalizer.process([frame[0] as i32, frame[1] as i32]);
+            assert!((out[0] - frame[0] as i32).abs() <= 1 && (out[1] - frame[1] as i32).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn graphic_band_spacing() {
+        assert!(graphic_bands(&[]).is_empty());
+        let one = graphic_bands(&[3.0]);
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].freq_hz, GRAPHIC_LOW_HZ);
+        for n in [2, 5, 10] {
+            let gains: Vec<f32> = (0..n).map(|i| i as f32 - 4.0).collect();
+            let bands = graphic_bands(&gains);
+            assert_eq!(bands.len(), n);
+            assert_eq!(bands[0].freq_hz, GRAPHIC_LOW_HZ);
+            assert!((bands[n - 1].freq_hz / GRAPHIC_HIGH_HZ - 1.0).abs() < 1e-4);
+            let ratio = bands[1].freq_hz / bands[0].freq_hz;
+            for (pair, gain) in bands.windows(2).zip(&gains) {
+                assert!((pair[1].freq_hz / pair[0].freq_hz / ratio - 1.0).abs() < 1e-4);
+                assert_eq!(pair[0].kind, FilterKind::Peaking);
+                assert_eq!(pair[0].q, bands[0].q);
+                assert_eq!(pair[0].gain_db, *gain);
+            }
+            if n < 5 {
+                // bands this wide run into 0Hz and Nyquist
+                continue;
+            }
+            // each band is as wide as the gap, so it's half its gain half way to the next
+            let band = bands[1];
+            let edge = band.freq_hz * ratio.sqrt();
+            let equalizer = Equalizer::new(&[Band { gain_db: 6.0, ..band }], RATE);
+            assert!((db(equalizer.power_gain(band.freq_hz)) - 6.0).abs() < 0.01);
+            assert!((db(equalizer.power_gain(edge)) - 3.0).abs() < 0.01, "{} bands", n);
+            assert!((db(equalizer.power_gain(band.freq_hz / ratio.sqrt())) - 3.0).abs() < 0.01);
+        }
+        // one band boosted, the others flat, boosts at that band's centre
+        let mut equalizer = Equalizer::graphic(&[0.0; 10], RATE);
+        equalizer.set_gain(4, 9.0);
+        assert!((db(equalizer.power_gain(equalizer.bands[4].freq_hz)) - 9.0).abs() < 0.01);
+        assert!(db(equalizer.power_gain(equalizer.bands[0].freq_hz)).abs() < 0.1);
+    }
+
+    #[test]
+    fn limiter_keeps_full_scale_under_the_limit() {
+        for makeup in [0.0, 6.0, 12.0, MAX_GAIN_DB] {
+            let mut limiter = Limiter::new(makeup, RATE);
+            let mut peak = 0;
+            for (i, s) in noise(RATE as usize).into_iter().enumerate() {
+                // full scale, with the equalizer's boosts past it
+                let s = if i % 100 == 0 { s as i32 * 4 } else { s as i32 };
+                for out in limiter.process([s, i16::MIN as i32]) {
+                    peak = peak.max((out as i32).abs());
+                }
+            }
+            assert!(peak <= LIMIT, "{} dB makeup: {}", makeup, peak);
+            assert!(peak > LIMIT - 100);
+            assert!(limiter.gain() < UNITY);
+        }
+    }
+
+    #[test]
+    fn limiter_gain_comes_back() {
+        let mut limiter = Limiter::new(6.0, RATE);
+        // quiet enough that it's only turned up, rounding down
+        assert_eq!(limiter.process([1000, -1000]), [1995, -1996]);
+        assert_eq!(limiter.gain(), UNITY);
+        limiter.process([32767, 0]);
+        assert!(limiter.gain() < UNITY / 2 + UNITY / 100);
+        for _ in 0..RATE * RELEASE_MS / 1000 {
+            limiter.process([0, 0]);
+        }
+        assert!(limiter.gain() > UNITY * 97 / 100, "{}", limiter.gain());
+    }
+
+    #[test]
+    fn presets() {
+        let input = noise(RATE as usize);
+        for preset in Preset::ALL {
+            assert_eq!(Preset::from_index(preset as u8), preset);
+            let mut dsp = Dsp::new(preset, RATE);
+            for frame in input.chunks(2) {
+                let out = dsp.process([frame[0], frame[1]]);
+                if preset == Preset::Flat {
+                    // not even limited
+                    assert_eq!(out, [frame[0], frame[1]]);
+                } else {
+                    assert!(out.iter().all(|s| (*s as i32).abs() <= LIMIT), "{}", preset.name());
+                }
+            }
+        }
+        assert_eq!(Preset::from_index(200), Preset::Flat);
+        assert_eq!(Preset::Night.next(), Preset::Flat);
+    }
+}
@@ -0,0 +1,439 @@
+//! Fast Fourier transforms, for seeing what's in a sound.
+//!
+//! `Fft` is a radix-2 transform on f32, `FixedFft` the same on Q15 integers for when
+//! floats are too slow or the numbers need to be exact from run to run. Both work in
+//! place on a power of two number of points and are set up once, with their twiddle
+//! factors worked out in f64 so they're accurate to the last bit without `sin` or `cos`.
+//!
+//! `Spectrum` wraps the f32 transform for audio: it takes i16 samples, applies a
+//! `Window` and gives the level of each frequency bin, scaled so a full scale sine wave
+//! reads 0dB. `bands` groups the bins into bands spaced evenly in pitch, the way a
+//! spectrum analyzer shows them.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use micromath::F32Ext;
+
+/// The quietest level reported, anything below reads as this.
+pub const FLOOR_DB: f32 = -96.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub const fn new(re: f32, im: f32) -> Self {
+        Complex { re, im }
+    }
+
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+// cos and sin of a small angle from their series, accurate to f64 below about 0.1
+fn small_angle(angle: f64) -> (f64, f64) {
+    let x2 = angle * angle;
+    let (mut cos, mut sin) = (1.0, angle);
+    let (mut c, mut s) = (1.0, angle);
+    for k in 1..10 {
+        let k = k as f64;
+        c *= -x2 / ((2.0 * k - 1.0) * (2.0 * k));
+        s *= -x2 / ((2.0 * k) * (2.0 * k + 1.0));
+        cos += c;
+        sin += s;
+    }
+    (cos, sin)
+}
+
+/// cos and sin of any angle, without `libm`. The angle is split in two until it's small
+/// enough for the series, then doubled back up.
+pub(crate) fn cos_sin(angle: f64) -> (f64, f64) {
+    let mut halvings = 0;
+    let mut angle = angle;
+    while angle.abs() > 0.1 {
+        angle /= 2.0;
+        halvings += 1;
+    }
+    let (mut cos, mut sin) = small_angle(angle);
+    for _ in 0..halvings {
+        (cos, sin) = (cos * cos - sin * sin, 2.0 * sin * cos);
+    }
+    (cos, sin)
+}
+
+// e^(i step k) for k from 0, by turning a unit vector round `count` times
+fn phasors(step: f64, count: usize) -> impl Iterator<Item = (f64, f64)> {
+    let (cos, sin) = cos_sin(step);
+    let mut at = (1.0, 0.0);
+    (0..count).map(move |_| {
+        let here = at;
+        at = (at.0 * cos - at.1 * sin, at.0 * sin + at.1 * cos);
+        here
+    })
+}
+
+fn bit_reverse_table(n: usize) -> Vec<u32> {
+    let bits = n.trailing_zeros();
+    (0..n as u32)
+        .map(|i| if bits == 0 { 0 } else { i.reverse_bits() >> (32 - bits) })
+        .collect()
+}
+
+/// A forward FFT on f32. `n` must be a power of two.
+pub struct Fft {
+    n: usize,
+    // e^(-2 pi i k / n) for k up to n / 2
+    twiddles: Vec<Complex>,
+    reversed: Vec<u32>,
+}
+
+impl Fft {
+    pub fn new(n: usize) -> Self {
+        assert!(n.is_power_of_two(), "FFT size has to be a power of two");
+        let step = -2.0 * core::f64::consts::PI / n as f64;
+        Fft {
+            n,
+            twiddles: phasors(step, n / 2).map(|(c, s)| Complex::new(c as f32, s as f32)).collect(),
+            reversed: bit_reverse_table(n),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// Transforms `data` in place. It has to be `len` long. Nothing is scaled, so a bin
+    /// can come out up to `len` times bigger than the input.
+    pub fn process(&self, data: &mut [Complex]) {
+        assert_eq!(data.len(), self.n);
+        for (i, &j) in self.reversed.iter().enumerate() {
+            if i < j as usize {
+                data.swap(i, j as usize);
+            }
+        }
+        let mut size = 2;
+        while size <= self.n {
+            let half = size / 2;
+            let stride = self.n / size;
+            for block in data.chunks_exact_mut(size) {
+                let (low, high) = block.split_at_mut(half);
+                for (k, (a, b)) in low.iter_mut().zip(high.iter_mut()).enumerate() {
+                    let t = b.mul(self.twiddles[k * stride]);
+                    *b = Complex::new(a.re - t.re, a.im - t.im);
+                    *a = Complex::new(a.re + t.re, a.im + t.im);
+                }
+            }
+            size *= 2;
+        }
+    }
+}
+
+/// A forward FFT on Q15 numbers. `n` must be a power of two.
+///
+/// Every stage halves its results so nothing can overflow, which means the output is
+/// the transform divided by `len`.
+pub struct FixedFft {
+    n: usize,
+    // Q15 cos and sin of -2 pi k / n
+    twiddles: Vec<(i32, i32)>,
+    reversed: Vec<u32>,
+}
+
+impl FixedFft {
+    pub fn new(n: usize) -> Self {
+        assert!(n.is_power_of_two(), "FFT size has to be a power of two");
+        let step = -2.0 * core::f64::consts::PI / n as f64;
+        let q15 = |v: f64| ((v * 32768.0) as i32).clamp(-32767, 32767);
+        FixedFft {
+            n,
+            twiddles: phasors(step, n / 2).map(|(c, s)| (q15(c), q15(s))).collect(),
+            reversed: bit_reverse_table(n),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// Transforms the real and imaginary parts in place, both `len` long and in i16 range.
+    pub fn process(&self, re: &mut [i32], im: &mut [i32]) {
+        assert!(re.len() == self.n && im.len() == self.n);
+        for (i, &j) in self.reversed.iter().enumerate() {
+            if i < j as usize {
+                re.swap(i, j as usize);
+                im.swap(i, j as usize);
+            }
+        }
+        let mut size = 2;
+        while size <= self.n {
+            let half = size / 2;
+            let stride = self.n / size;
+            for start in (0..self.n).step_by(size) {
+                for k in 0..half {
+                    let (a, b) = (start + k, start + k + half);
+                    let (c, s) = self.twiddles[k * stride];
+                    // rounded Q15 multiply
+                    let tr = (re[b] * c - im[b] * s + (1 << 14)) >> 15;
+                    let ti = (re[b] * s + im[b] * c + (1 << 14)) >> 15;
+                    let (ar, ai) = (re[a], im[a]);
+                    re[a] = (ar + tr) >> 1;
+                    im[a] = (ai + ti) >> 1;
+                    re[b] = (ar - tr) >> 1;
+                    im[b] = (ai - ti) >> 1;
+                }
+            }
+            size *= 2;
+        }
+    }
+}
+
+/// The shape faded in and out over a block before transforming it, which stops the
+/// sudden start and end of the block smearing loud frequencies across the spectrum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Window {
+    /// no window, sharpest but leaks the most
+    Rectangular,
+    #[default]
+    Hann,
+    Hamming,
+    /// leaks the least, but spreads each frequency widest
+    Blackman,
+}
+
+impl Window {
+    /// The weight of each of `n` samples.
+    pub fn coefficients(self, n: usize) -> Vec<f32> {
+        if n < 2 || self == Window::Rectangular {
+            return vec![1.0; n];
+        }
+        let step = 2.0 * core::f64::consts::PI / (n - 1) as f64;
+        let cos2: Vec<f64> = phasors(2.0 * step, n).map(|(c, _)| c).collect();
+        phasors(step, n)
+            .zip(cos2)
+            .map(|((cos, _), cos2)| {
+                let w = match self {
+                    Window::Rectangular => 1.0,
+                    Window::Hann => 0.5 - 0.5 * cos,
+                    Window::Hamming => 0.54 - 0.46 * cos,
+                    Window::Blackman => 0.42 - 0.5 * cos + 0.08 * cos2,
+                };
+                w as f32
+            })
+            .collect()
+    }
+}
+
+/// Levels of the frequencies in blocks of i16 samples.
+pub struct Spectrum {
+    fft: Fft,
+    window: Vec<f32>,
+    buf: Vec<Complex>,
+    /// the level of each bin from 0Hz up to half the sample rate, 1.0 is full scale
+    pub magnitudes: Vec<f32>,
+}
+
+impl Spectrum {
+    /// `n` samples a block, a power of two. Bins are `sample_rate / n` apart.
+    pub fn new(n: usize, window: Window) -> Self {
+        let mut window = window.coefficients(n);
+        // scale so a full scale sine reads 1.0 whatever the window takes off
+        let sum: f32 = window.iter().sum();
+        let scale = if sum > 0.0 { 2.0 / (sum * i16::MAX as f32) } else { 0.0 };
+        window.iter_mut().for_each(|w| *w *= scale);
+        Spectrum {
+            fft: Fft::new(n),
+            window,
+            buf: vec![Complex::default(); n],
+            magnitudes: vec![0.0; n / 2],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fft.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fft.is_empty()
+    }
+
+    /// The frequency in the middle of a bin.
+    pub fn bin_hz(&self, bin: usize, sample_rate: u32) -> f32 {
+        bin as f32 * sample_rate as f32 / self.len() as f32
+    }
+
+    /// Works out `magnitudes` from the newest `len` samples, padding with silence if
+    /// there are fewer.
+    pub fn analyze(&mut self, samples: &[i16]) {
+        let n = self.len();
+        let samples = &samples[samples.len().saturating_sub(n)..];
+        let pad = n - samples.len();
+        for (i, out) in self.buf.iter_mut().enumerate() {
+            let s = if i < pad { 0.0 } else { samples[i - pad] as f32 };
+            *out = Complex::new(s * self.window[i], 0.0);
+        }
+        self.fft.process(&mut self.buf);
+        for (m, c) in self.magnitudes.iter_mut().zip(&self.buf) {
+            // micromath's sqrt isn't quite 0 for 0
+            let power = c.norm_sqr();
+            *m = if power > 0.0 { power.sqrt() } else { 0.0 };
+        }
+    }
+
+    /// The level of a bin in dB below full scale.
+    pub fn db(&self, bin: usize) -> f32 {
+        to_db(self.magnitudes.get(bin).copied().unwrap_or(0.0))
+    }
+}
+
+/// A level as dB below full scale, down to `FLOOR_DB`.
+pub fn to_db(level: f32) -> f32 {
+    if level <= 0.0 {
+        return FLOOR_DB;
+    }
+    (20.0 * level.log10()).max(FLOOR_DB)
+}
+
+/// Groups spectrum bins into `out.len()` bands from `low_hz` to half the sample rate,
+/// each the same number of octaves wide, giving the loudest bin in each in dB. Low bands
+/// narrower than a bin share it with their neighbours.
+pub fn bands(magnitudes: &[f32], sample_rate: u32, low_hz: f32, out: &mut [f32]) {
+    let bins = magnitudes.len();
+    if bins == 0 || out.is_empty() {
+        out.fill(FLOOR_DB);
+        return;
+    }
+    let bin_hz = sample_rate as f32 / (2 * bins) as f32;
+    let high_hz = sample_rate as f32 / 2.0;
+    let low_hz = low_hz.clamp(bin_hz, high_hz);
+    let ratio = (high_hz / low_hz).powf(1.0 / out.len() as f32);
+    let mut edge = low_hz;
+    for band in out.iter_mut() {
+        let next = edge * ratio;
+        let first = ((edge / bin_hz) as usize).min(bins - 1);
+        let last = ((next / bin_hz) as usize).clamp(first + 1, bins);
+        let loudest = magnitudes[first..last].iter().fold(0.0f32, |a, &m| a.max(m));
+        *band = to_db(loudest);
+        edge = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `amplitude` times a sine of `cycles` over `n` samples
+    fn sine(n: usize, cycles: f64, amplitude: f64) -> Vec<f64> {
+        (0..n)
+            .map(|i| amplitude * cos_sin(2.0 * core::f64::consts::PI * cycles * i as f64 / n as f64).1)
+            .collect()
+    }
+
+    fn loudest(magnitudes: &[f32]) -> usize {
+        (0..magnitudes.len()).fold(0, |best, i| if magnitudes[i] > magnitudes[best] { i } else { best })
+    }
+
+    #[test]
+    fn a_sine_lands_in_its_bin() {
+        let n = 64;
+        let fft = Fft::new(n);
+        let mut data: Vec<Complex> = sine(n, 5.0, 1.0).iter().map(|&s| Complex::new(s as f32, 0.0)).collect();
+        fft.process(&mut data);
+        for (bin, c) in data.iter().enumerate() {
+            let magnitude = c.norm_sqr().sqrt();
+            if bin == 5 || bin == n - 5 {
+                // half the amplitude in each of the positive and negative frequency
+                assert!((magnitude - n as f32 / 2.0).abs() < 1e-3, "bin {bin}: {magnitude}");
+            } else {
+                assert!(magnitude < 1e-3, "bin {bin}: {magnitude}");
+            }
+        }
+        // a sine is all imaginary, negative at the positive frequency
+        assert!(data[5].im < 0.0 && data[5].re.abs() < 1e-3);
+    }
+
+    #[test]
+    fn the_dc_bin_is_the_sum() {
+        let fft = Fft::new(16);
+        let mut data = [Complex::new(0.25, 0.0); 16];
+        fft.process(&mut data);
+        assert_eq!(data[0], Complex::new(4.0, 0.0));
+        assert!(data[1..].iter().all(|c| c.norm_sqr() < 1e-12));
+
+        let fixed = FixedFft::new(16);
+        let mut re = [-1000; 16];
+        let mut im = [0; 16];
+        fixed.process(&mut re, &mut im);
+        // scaled down by `len`, so it's the mean
+        assert_eq!((re[0], im[0]), (-1000, 0));
+        assert!(re[1..].iter().chain(&im).all(|&v| v == 0));
+    }
+
+    #[test]
+    fn fixed_point_matches_float() {
+        let n = 256;
+        let fixed = FixedFft::new(n);
+        let mut re: Vec<i32> = sine(n, 17.0, 16000.0).iter().map(|&s| s as i32).collect();
+        let mut im = vec![0; n];
+        fixed.process(&mut re, &mut im);
+        // 16000 / 2 in the bin, divided through by nothing more than rounding
+        assert!((im[17] + 8000).abs() <= 4, "{}", im[17]);
+        assert!((im[n - 17] - 8000).abs() <= 4, "{}", im[n - 17]);
+        for bin in (0..n).filter(|&b| b != 17 && b != n - 17) {
+            assert!(re[bin].abs() <= 4 && im[bin].abs() <= 4, "bin {bin}: {} {}", re[bin], im[bin]);
+        }
+    }
+
+    #[test]
+    fn a_full_scale_sine_reads_0db() {
+        let n = 1024;
+        let sample_rate = 48_000;
+        for window in [Window::Rectangular, Window::Hann, Window::Hamming, Window::Blackman] {
+            let mut spectrum = Spectrum::new(n, window);
+            // 1kHz sits a third of the way between bins 21 and 22
+            let samples: Vec<i16> = sine(n, 1000.0 * n as f64 / sample_rate as f64, i16::MAX as f64)
+                .iter()
+                .map(|&s| s as i16)
+                .collect();
+            spectrum.analyze(&samples);
+            let bin = loudest(&spectrum.magnitudes);
+            assert_eq!(bin, 21, "{window:?}");
+            assert!(spectrum.bin_hz(bin, sample_rate) < 1000.0);
+            assert!(spectrum.bin_hz(bin + 1, sample_rate) > 1000.0);
+
+            // exactly on a bin it reads full scale
+            let samples: Vec<i16> = sine(n, 21.0, i16::MAX as f64).iter().map(|&s| s as i16).collect();
+            spectrum.analyze(&samples);
+            assert_eq!(loudest(&spectrum.magnitudes), 21);
+            assert!(spectrum.db(21).abs() < 0.01, "{window:?}: {}", spectrum.db(21));
+        }
+    }
+
+    #[test]
+    fn silence_is_the_floor() {
+        let mut spectrum = Spectrum::new(64, Window::Hann);
+        spectrum.analyze(&[]);
+        assert!(spectrum.magnitudes.iter().all(|&m| m == 0.0));
+        assert_eq!(spectrum.db(0), FLOOR_DB);
+        assert_eq!(spectrum.db(1000), FLOOR_DB);
+        assert_eq!(to_db(1.0), 0.0);
+        assert_eq!(to_db(1e-9), FLOOR_DB);
+    }
+}
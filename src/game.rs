@@ -0,0 +1,1000 @@
+//! A tiny 2D game engine for the T-Deck.
+//!
+//! This pulls the pieces out of the brickbreaker example so other games can reuse them:
+//! sprite sheets (loaded from BMP files on the SD card), tile maps, a fixed timestep
+//! clock with frame timing, layered rendering through a small off-screen framebuffer,
+//! axis aligned collision helpers, and mapping of trackball / keyboard input to actions.
+//!
+//! Nothing in here touches the hardware directly, so it can be used from any binary
+//! and exercised on the host.
+
+use crate::color::to_rgb565;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+
+pub const SCREEN_WIDTH: u32 = 320;
+pub const SCREEN_HEIGHT: u32 = 240;
+
+pub fn screen_bounds() -> Rectangle {
+    Rectangle::new(Point::zero(), Size::new(SCREEN_WIDTH, SCREEN_HEIGHT))
+}
+
+// ---------- sprite sheets ----------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    NotABitmap,
+    Truncated,
+    Unsupported,
+}
+
+/// A sheet of equally sized frames stored as Rgb565 pixels.
+pub struct SpriteSheet {
+    pub size: Size,
+    pub frame_size: Size,
+    pixels: Vec<Rgb565>,
+    /// pixels of this color are skipped when drawing
+    pub transparent: Option<Rgb565>,
+}
+
+impl SpriteSheet {
+    pub fn new(size: Size, frame_size: Size, pixels: Vec<Rgb565>) -> Self {
+        assert_eq!(pixels.len(), (size.width * size.height) as usize);
+        SpriteSheet {
+            size,
+            frame_size,
+            pixels,
+            transparent: None,
+        }
+    }
+
+    /// Makes a sheet with one solid colored frame per color. Handy when there are no
+    /// image assets on the SD card.
+    pub fn solid(frame_size: Size, colors: &[Rgb565]) -> Self {
+        let width = frame_size.width * colors.len() as u32;
+        let mut pixels = vec![Rgb565::BLACK; (width * frame_size.height) as usize];
+        for y in 0..frame_size.height {
+            for x in 0..width {
+                let color = colors[(x / frame_size.width) as usize];
+                pixels[(y * width + x) as usize] = color;
+            }
+        }
+        SpriteSheet::new(Size::new(width, frame_size.height), frame_size, pixels)
+    }
+
+    /// Loads an uncompressed 16, 24 or 32 bit BMP file. 16 bit images are assumed to
+    /// be RGB565 when they have a bitfields header, RGB555 otherwise.
+    pub fn from_bmp(data: &[u8], frame_size: Size) -> Result<Self, ImageError> {
+        if data.len() < 54 || &data[0..2] != b"BM" {
+            return Err(ImageError::NotABitmap);
+        }
+        let offset = le_u32(data, 10) as usize;
+        let width = le_u32(data, 18) as i32;
+        let height = le_u32(data, 22) as i32;
+        let bpp = le_u16(data, 28);
+        let compression = le_u32(data, 30);
+        if width <= 0 || height == 0 || !(compression == 0 || compression == 3) {
+            return Err(ImageError::Unsupported);
+        }
+        if !matches!(bpp, 16 | 24 | 32) {
+            return Err(ImageError::Unsupported);
+        }
+        // the bitfields masks come after the header, and green's is the one that tells
+        if compression == 3 && data.len() < 62 {
+            return Err(ImageError::Truncated);
+        }
+        // bottom up is the normal case, negative height means top down
+        let top_down = height < 0;
+        let width = width as usize;
+        let height = height.unsigned_abs() as usize;
+        // a made up size can overflow, which is as good as truncated
+        let stride = width
+            .checked_mul(bpp as usize / 8)
+            .and_then(|row| row.checked_add(3))
+            .map(|row| row & !3);
+        let end = stride
+            .and_then(|stride| stride.checked_mul(height))
+            .and_then(|size| size.checked_add(offset));
+        let (Some(stride), Some(end)) = (stride, end) else {
+            return Err(ImageError::Truncated);
+        };
+        if data.len() < end {
+            return Err(ImageError::Truncated);
+        }
+        let rgb565 = compression == 3 && le_u32(data, 54 + 4) == 0x07E0;
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = if top_down { y } else { height - 1 - y };
+            let start = offset + row * stride;
+            for x in 0..width {
+                let color = match bpp {
+                    16 => {
+                        let v = le_u16(data, start + x * 2);
+                        if rgb565 {
+                            Rgb565::new((v >> 11) as u8, ((v >> 5) & 0x3F) as u8, (v & 0x1F) as u8)
+                        } else {
+                            Rgb565::new(
+                                ((v >> 10) & 0x1F) as u8,
+                                {
+                                    let g = ((v >> 5) & 0x1F) as u8;
+                                    (g << 1) | (g >> 4)
+                                },
+                                (v & 0x1F) as u8,
+                            )
+                        }
+                    }
+                    _ => {
+                        let p = start + x * (bpp as usize / 8);
+                        to_rgb565(Rgb888::new(data[p + 2], data[p + 1], data[p]))
+                    }
+                };
+                pixels.push(color);
+            }
+        }
+        Ok(SpriteSheet::new(
+            Size::new(width as u32, height as u32),
+            frame_size,
+            pixels,
+        ))
+    }
+
+    pub fn with_transparent(mut self, color: Rgb565) -> Self {
+        self.transparent = Some(color);
+        self
+    }
+
+    /// The number of whole frames on the sheet, 0 if it is smaller than one frame.
+    pub fn frame_count(&self) -> usize {
+        if self.frame_size.width == 0 || self.frame_size.height == 0 {
+            return 0;
+        }
+        let cols = self.size.width / self.frame_size.width;
+        let rows = self.size.height / self.frame_size.height;
+        (cols * rows) as usize
+    }
+
+    fn frame_origin(&self, frame: usize) -> (u32, u32) {
+        let cols = (self.size.width / self.frame_size.width).max(1);
+        let frame = frame as u32;
+        (
+            (frame % cols) * self.frame_size.width,
+            (frame / cols) * self.frame_size.height,
+        )
+    }
+
+    /// Draws one frame with its top left corner at `position`. Frames past the end of the
+    /// sheet draw nothing.
+    pub fn draw_frame<D>(&self, frame: usize, position: Point, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        if frame >= self.frame_count() {
+            return Ok(());
+        }
+        let (fx, fy) = self.frame_origin(frame);
+        let w = self.frame_size.width;
+        let h = self.frame_size.height;
+        let sheet_width = self.size.width;
+        let pixels = (0..h).flat_map(move |y| {
+            (0..w).map(move |x| {
+                let color = self.pixels[((fy + y) * sheet_width + fx + x) as usize];
+                (Point::new(x as i32, y as i32), color)
+            })
+        });
+        match self.transparent {
+            None => target.fill_contiguous(
+                &Rectangle::new(position, self.frame_size),
+                pixels.map(|(_, c)| c),
+            ),
+            Some(key) => target.draw_iter(
+                pixels
+                    .filter(|(_, c)| *c != key)
+                    .map(|(p, c)| Pixel(position + p, c)),
+            ),
+        }
+    }
+}
+
+fn le_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn le_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+// ---------- sprites ----------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+    pub position: Point,
+    pub size: Size,
+    pub frame: usize,
+    pub visible: bool,
+    /// higher layers are drawn on top
+    pub layer: u8,
+}
+
+impl Sprite {
+    pub fn new(position: Point, size: Size, frame: usize) -> Self {
+        Sprite {
+            position,
+            size,
+            frame,
+            visible: true,
+            layer: 0,
+        }
+    }
+
+    pub fn bounds(&self) -> Rectangle {
+        Rectangle::new(self.position, self.size)
+    }
+
+    pub fn draw<D>(&self, sheet: &SpriteSheet, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        if !self.visible {
+            return Ok(());
+        }
+        sheet.draw_frame(self.frame, self.position, target)
+    }
+}
+
+/// Draws the sprites back to front according to their layer.
+pub fn draw_sprites<D>(sprites: &[Sprite], sheet: &SpriteSheet, target: &mut D) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let mut order: Vec<usize> = (0..sprites.len()).collect();
+    order.sort_unstable_by_key(|i| (sprites[*i].layer, *i));
+    for i in order {
+        sprites[i].draw(sheet, target)?;
+    }
+    Ok(())
+}
+
+// ---------- tile maps ----------
+
+pub const EMPTY_TILE: u8 = u8::MAX;
+
+/// A grid of tiles drawn from a sprite sheet. Each cell holds a frame index or `EMPTY_TILE`.
+pub struct TileMap {
+    pub origin: Point,
+    pub cols: u32,
+    pub rows: u32,
+    pub tile_size: Size,
+    tiles: Vec<u8>,
+}
+
+impl TileMap {
+    pub fn new(origin: Point, cols: u32, rows: u32, tile_size: Size) -> Self {
+        TileMap {
+            origin,
+            cols,
+            rows,
+            tile_size,
+            tiles: vec![EMPTY_TILE; (cols * rows) as usize],
+        }
+    }
+
+    /// Builds a map from lines of text. The `legend` turns each character into a tile,
+    /// returning `EMPTY_TILE` for blank cells. Short lines are padded with empty tiles.
+    pub fn from_text(origin: Point, tile_size: Size, text: &str, legend: impl Fn(char) -> u8) -> Self {
+        let lines = text.lines().filter(|l| !l.trim().is_empty());
+        let rows = lines.clone().count() as u32;
+        let cols = lines.clone().map(|l| l.chars().count()).max().unwrap_or(0) as u32;
+        let mut map = TileMap::new(origin, cols, rows, tile_size);
+        for (y, line) in lines.enumerate() {
+            for (x, ch) in line.chars().enumerate() {
+                map.set(x as u32, y as u32, legend(ch));
+            }
+        }
+        map
+    }
+
+    pub fn bounds(&self) -> Rectangle {
+        Rectangle::new(
+            self.origin,
+            Size::new(self.cols * self.tile_size.width, self.rows * self.tile_size.height),
+        )
+    }
+
+    pub fn get(&self, col: u32, row: u32) -> u8 {
+        if col >= self.cols || row >= self.rows {
+            return EMPTY_TILE;
+        }
+        self.tiles[(row * self.cols + col) as usize]
+    }
+
+    pub fn set(&mut self, col: u32, row: u32, tile: u8) {
+        if col < self.cols && row < self.rows {
+            self.tiles[(row * self.cols + col) as usize] = tile;
+        }
+    }
+
+    pub fn cell_bounds(&self, col: u32, row: u32) -> Rectangle {
+        Rectangle::new(
+            self.origin
+                + Point::new(
+                    (col * self.tile_size.width) as i32,
+                    (row * self.tile_size.height) as i32,
+                ),
+            self.tile_size,
+        )
+    }
+
+    pub fn count_filled(&self) -> usize {
+        self.tiles.iter().filter(|t| **t != EMPTY_TILE).count()
+    }
+
+    /// Range of cells overlapping the area, as (col_start, row_start, col_end, row_end) exclusive.
+    fn cell_range(&self, area: &Rectangle) -> Option<(u32, u32, u32, u32)> {
+        let overlap = self.bounds().intersection(area);
+        if overlap.is_zero_sized() {
+            return None;
+        }
+        let br = overlap.bottom_right()?;
+        let local_tl = overlap.top_left - self.origin;
+        let local_br = br - self.origin;
+        let tw = self.tile_size.width as i32;
+        let th = self.tile_size.height as i32;
+        Some((
+            (local_tl.x / tw) as u32,
+            (local_tl.y / th) as u32,
+            (local_br.x / tw + 1) as u32,
+            (local_br.y / th + 1) as u32,
+        ))
+    }
+
+    /// Non-empty cells overlapping the area, as (col, row, tile).
+    pub fn tiles_overlapping(&self, area: &Rectangle) -> impl Iterator<Item = (u32, u32, u8)> + '_ {
+        let (c0, r0, c1, r1) = self.cell_range(area).unwrap_or((0, 0, 0, 0));
+        (r0..r1)
+            .flat_map(move |row| (c0..c1).map(move |col| (col, row, self.get(col, row))))
+            .filter(|(_, _, tile)| *tile != EMPTY_TILE)
+    }
+
+    /// Draws the tiles that overlap the target's bounding box. Empty cells are left alone.
+    pub fn draw<D>(&self, sheet: &SpriteSheet, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let area = target.bounding_box();
+        for (col, row, tile) in self.tiles_overlapping(&area) {
+            sheet.draw_frame(tile as usize, self.cell_bounds(col, row).top_left, target)?;
+        }
+        Ok(())
+    }
+}
+
+// ---------- collision ----------
+
+pub fn overlaps(a: &Rectangle, b: &Rectangle) -> bool {
+    !a.intersection(b).is_zero_sized()
+}
+
+/// The smallest translation that moves `a` out of `b`, or `None` if they don't overlap.
+pub fn penetration(a: &Rectangle, b: &Rectangle) -> Option<Point> {
+    if !overlaps(a, b) {
+        return None;
+    }
+    let a_right = a.top_left.x + a.size.width as i32;
+    let a_bottom = a.top_left.y + a.size.height as i32;
+    let b_right = b.top_left.x + b.size.width as i32;
+    let b_bottom = b.top_left.y + b.size.height as i32;
+    let push_left = b.top_left.x - a_right;
+    let push_right = b_right - a.top_left.x;
+    let push_up = b.top_left.y - a_bottom;
+    let push_down = b_bottom - a.top_left.y;
+    let dx = if push_right < -push_left { push_right } else { push_left };
+    let dy = if push_down < -push_up { push_down } else { push_up };
+    if dx.abs() < dy.abs() {
+        Some(Point::new(dx, 0))
+    } else {
+        Some(Point::new(0, dy))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepHit {
+    /// fraction of the motion, 0 to 1, before the boxes touch
+    pub time: f32,
+    /// which faces were hit. both are set when hitting a corner exactly.
+    pub hit_x: bool,
+    pub hit_y: bool,
+}
+
+/// Swept AABB test: moves a box of `size` from `origin` by `motion` and reports when it
+/// first touches `target`. Boxes that already overlap at the start don't count as a hit.
+pub fn sweep(origin: (f32, f32), size: Size, motion: (f32, f32), target: &Rectangle) -> Option<SweepHit> {
+    // grow the target by the moving box so the moving box can be treated as a point
+    let left = target.top_left.x as f32 - size.width as f32;
+    let top = target.top_left.y as f32 - size.height as f32;
+    let right = (target.top_left.x + target.size.width as i32) as f32;
+    let bottom = (target.top_left.y + target.size.height as i32) as f32;
+
+    fn axis(pos: f32, delta: f32, low: f32, high: f32) -> Option<(f32, f32)> {
+        if delta > 0.0 {
+            Some(((low - pos) / delta, (high - pos) / delta))
+        } else if delta < 0.0 {
+            Some(((high - pos) / delta, (low - pos) / delta))
+        } else if pos > low && pos < high {
+            Some((f32::NEG_INFINITY, f32::INFINITY))
+        } else {
+            None
+        }
+    }
+
+    let (x_entry, x_exit) = axis(origin.0, motion.0, left, right)?;
+    let (y_entry, y_exit) = axis(origin.1, motion.1, top, bottom)?;
+    let entry = x_entry.max(y_entry);
+    let exit = x_exit.min(y_exit);
+    if entry >= exit || !(0.0..=1.0).contains(&entry) {
+        return None;
+    }
+    Some(SweepHit {
+        time: entry,
+        hit_x: x_entry >= y_entry,
+        hit_y: y_entry >= x_entry,
+    })
+}
+
+/// Moves the rectangle so it lies inside the bounds.
+pub fn clamp_inside(rect: Rectangle, bounds: &Rectangle) -> Rectangle {
+    let max_x = bounds.top_left.x + bounds.size.width as i32 - rect.size.width as i32;
+    let max_y = bounds.top_left.y + bounds.size.height as i32 - rect.size.height as i32;
+    let x = rect.top_left.x.min(max_x).max(bounds.top_left.x);
+    let y = rect.top_left.y.min(max_y).max(bounds.top_left.y);
+    Rectangle::new(Point::new(x, y), rect.size)
+}
+
+/// The smallest rectangle covering both.
+pub fn union(a: &Rectangle, b: &Rectangle) -> Rectangle {
+    if a.is_zero_sized() {
+        return *b;
+    }
+    if b.is_zero_sized() {
+        return *a;
+    }
+    let x0 = a.top_left.x.min(b.top_left.x);
+    let y0 = a.top_left.y.min(b.top_left.y);
+    let x1 = (a.top_left.x + a.size.width as i32).max(b.top_left.x + b.size.width as i32);
+    let y1 = (a.top_left.y + a.size.height as i32).max(b.top_left.y + b.size.height as i32);
+    Rectangle::new(Point::new(x0, y0), Size::new((x1 - x0) as u32, (y1 - y0) as u32))
+}
+
+// ---------- input ----------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Left,
+    Right,
+    Up,
+    Down,
+    Fire,
+    Pause,
+    Back,
+}
+
+const ACTION_COUNT: usize = 7;
+
+/// Maps trackball motion and keyboard characters onto game actions and remembers
+/// which actions were active on the previous frame.
+pub struct InputMap {
+    keys: Vec<(u8, Action)>,
+    current: [bool; ACTION_COUNT],
+    previous: [bool; ACTION_COUNT],
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        // WASD plus space for fire, p for pause and backspace to leave
+        InputMap::new(&[
+            (b'a', Action::Left),
+            (b'd', Action::Right),
+            (b'w', Action::Up),
+            (b's', Action::Down),
+            (b' ', Action::Fire),
+            (b'\r', Action::Fire),
+            (b'p', Action::Pause),
+            (8, Action::Back),
+        ])
+    }
+}
+
+impl InputMap {
+    pub fn new(keys: &[(u8, Action)]) -> Self {
+        InputMap {
+            keys: keys.to_vec(),
+            current: [false; ACTION_COUNT],
+            previous: [false; ACTION_COUNT],
+        }
+    }
+
+    pub fn bind_key(&mut self, key: u8, action: Action) {
+        self.keys.retain(|(k, _)| *k != key);
+        self.keys.push((key, action));
+    }
+
+    /// Call once per frame with the last key read from the keyboard and the trackball
+    /// pins which changed since the last poll.
+    pub fn update(&mut self, key: Option<u8>, trackball: [bool; 5]) {
+        self.previous = self.current;
+        self.current = [false; ACTION_COUNT];
+        let [left, right, up, down, click] = trackball;
+        self.current[Action::Left as usize] = left;
+        self.current[Action::Right as usize] = right;
+        self.current[Action::Up as usize] = up;
+        self.current[Action::Down as usize] = down;
+        self.current[Action::Fire as usize] = click;
+        if let Some(key) = key {
+            for (k, action) in &self.keys {
+                if *k == key {
+                    self.current[*action as usize] = true;
+                }
+            }
+        }
+    }
+
+    pub fn is_active(&self, action: Action) -> bool {
+        self.current[action as usize]
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.current[action as usize] && !self.previous[action as usize]
+    }
+
+    /// -1, 0 or 1 along the horizontal axis
+    pub fn axis_x(&self) -> i32 {
+        self.is_active(Action::Right) as i32 - self.is_active(Action::Left) as i32
+    }
+
+    /// -1, 0 or 1 along the vertical axis
+    pub fn axis_y(&self) -> i32 {
+        self.is_active(Action::Down) as i32 - self.is_active(Action::Up) as i32
+    }
+}
+
+// ---------- timing ----------
+
+/// Runs game updates at a fixed rate no matter how long drawing takes. Feed it the
+/// current time in milliseconds once per frame and run `update` that many times.
+pub struct GameClock {
+    pub step_ms: u64,
+    /// never run more than this many updates per frame, so a long stall can't snowball
+    pub max_steps: u32,
+    last_ms: Option<u64>,
+    accumulator: u64,
+    window_start: u64,
+    window_frames: u32,
+    fps: u32,
+    frame_ms: u64,
+}
+
+impl GameClock {
+    pub fn new(updates_per_second: u32) -> Self {
+        GameClock {
+            step_ms: 1000 / updates_per_second.max(1) as u64,
+            max_steps: 5,
+            last_ms: None,
+            accumulator: 0,
+            window_start: 0,
+            window_frames: 0,
+            fps: 0,
+            frame_ms: 0,
+        }
+    }
+
+    /// Returns the number of fixed updates to run for this frame.
+    pub fn tick(&mut self, now_ms: u64) -> u32 {
+        let last = match self.last_ms {
+            Some(last) => last,
+            None => {
+                self.last_ms = Some(now_ms);
+                self.window_start = now_ms;
+                return 1;
+            }
+        };
+        self.frame_ms = now_ms.saturating_sub(last);
+        self.last_ms = Some(now_ms);
+        self.accumulator += self.frame_ms;
+
+        self.window_frames += 1;
+        if now_ms - self.window_start >= 1000 {
+            self.fps = (self.window_frames as u64 * 1000 / (now_ms - self.window_start)) as u32;
+            self.window_start = now_ms;
+            self.window_frames = 0;
+        }
+
+        let mut steps = 0;
+        while self.accumulator >= self.step_ms && steps < self.max_steps {
+            self.accumulator -= self.step_ms;
+            steps += 1;
+        }
+        if steps == self.max_steps {
+            self.accumulator = 0;
+        }
+        steps
+    }
+
+    /// Frames drawn per second, averaged over the last second.
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    /// How long the last frame took in milliseconds.
+    pub fn frame_ms(&self) -> u64 {
+        self.frame_ms
+    }
+
+    /// How long to sleep before the next update is due.
+    pub fn remaining_ms(&self) -> u64 {
+        self.step_ms.saturating_sub(self.accumulator)
+    }
+}
+
+// ---------- rendering ----------
+
+/// An off-screen buffer for a small area of the screen. Layers are drawn into it in
+/// order and then it is sent to the display in a single transfer, so moving sprites
+/// don't flicker. The area must fit in the capacity given at creation.
+pub struct FrameBuffer {
+    area: Rectangle,
+    pixels: Vec<Rgb565>,
+}
+
+impl FrameBuffer {
+    pub fn new(capacity: usize) -> Self {
+        FrameBuffer {
+            area: Rectangle::zero(),
+            pixels: vec![Rgb565::BLACK; capacity],
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.pixels.len()
+    }
+
+    pub fn set_area(&mut self, area: Rectangle) {
+        assert!((area.size.width * area.size.height) as usize <= self.pixels.len());
+        self.area = area;
+    }
+
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    pub fn pixel(&self, p: Point) -> Option<Rgb565> {
+        self.index(p).map(|i| self.pixels[i])
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        let local = p - self.area.top_left;
+        if local.x < 0
+            || local.y < 0
+            || local.x >= self.area.size.width as i32
+            || local.y >= self.area.size.height as i32
+        {
+            return None;
+        }
+        Some((local.y as u32 * self.area.size.width + local.x as u32) as usize)
+    }
+
+    pub fn flush<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let len = (self.area.size.width * self.area.size.height) as usize;
+        display.fill_contiguous(&self.area, self.pixels[..len].iter().copied())
+    }
+}
+
+impl Dimensions for FrameBuffer {
+    fn bounding_box(&self) -> Rectangle {
+        self.area
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = Rgb565;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            if let Some(i) = self.index(p) {
+                self.pixels[i] = color;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.area);
+        if let Some(br) = area.bottom_right() {
+            for y in area.top_left.y..=br.y {
+                let start = self.index(Point::new(area.top_left.x, y)).unwrap();
+                self.pixels[start..start + area.size.width as usize].fill(color);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Something that draws itself in layers, back to front.
+pub trait Scene {
+    fn background(&self) -> Rgb565 {
+        Rgb565::BLACK
+    }
+    fn draw(&self, target: &mut FrameBuffer);
+}
+
+/// Areas of the screen which need to be redrawn this frame.
+pub struct DirtyRegions {
+    rects: heapless::Vec<Rectangle, 16>,
+}
+
+impl Default for DirtyRegions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DirtyRegions {
+    pub fn new() -> Self {
+        DirtyRegions {
+            rects: heapless::Vec::new(),
+        }
+    }
+
+    /// Marks an area as dirty, merging it with any area it touches. If there are too many
+    /// separate areas they get merged into one big one.
+    pub fn add(&mut self, rect: Rectangle) {
+        if rect.is_zero_sized() {
+            return;
+        }
+        let mut rect = rect;
+        let mut i = 0;
+        while i < self.rects.len() {
+            if overlaps(&self.rects[i].offset(1), &rect) {
+                rect = union(&rect, &self.rects[i]);
+                self.rects.swap_remove(i);
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        if let Err(rect) = self.rects.push(rect) {
+            let all = self.rects.iter().fold(rect, |acc, r| union(&acc, r));
+            self.rects.clear();
+            let _ = self.rects.push(all);
+        }
+    }
+
+    pub fn add_moved(&mut self, old: Rectangle, new: Rectangle) {
+        if old != new {
+            self.add(old);
+            self.add(new);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rectangle> {
+        self.rects.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+/// Redraws every dirty area of the scene through the framebuffer, splitting areas
+/// into horizontal bands that fit the buffer, then clears the dirty list. Areas wider
+/// than the whole buffer are split into columns too.
+pub fn render<S, D>(
+    scene: &S,
+    dirty: &mut DirtyRegions,
+    fb: &mut FrameBuffer,
+    display: &mut D,
+) -> Result<(), D::Error>
+where
+    S: Scene,
+    D: DrawTarget<Color = Rgb565>,
+{
+    let screen = display.bounding_box();
+    for rect in dirty.iter() {
+        let rect = rect.intersection(&screen);
+        if rect.is_zero_sized() {
+            continue;
+        }
+        let band_width = rect.size.width.min(fb.capacity() as u32);
+        if band_width == 0 {
+            break;
+        }
+        let band_height = (fb.capacity() as u32 / band_width).clamp(1, rect.size.height);
+        let mut y = 0;
+        while y < rect.size.height {
+            let h = band_height.min(rect.size.height - y);
+            let mut x = 0;
+            while x < rect.size.width {
+                let w = band_width.min(rect.size.width - x);
+                fb.set_area(Rectangle::new(
+                    rect.top_left + Point::new(x as i32, y as i32),
+                    Size::new(w, h),
+                ));
+                let _ = fb.clear(scene.background());
+                scene.draw(fb);
+                fb.flush(display)?;
+                x += w;
+            }
+            y += h;
+        }
+    }
+    dirty.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a BMP with a 40 byte info header, and the three bitfields masks after it for 565
+    fn bmp(width: i32, height: i32, bpp: u16, rgb565: bool, pixels: &[u8]) -> Vec<u8> {
+        let offset = if rgb565 { 66 } else { 54 };
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&((offset + pixels.len()) as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(offset as u32).to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bpp.to_le_bytes());
+        data.extend_from_slice(&(if rgb565 { 3u32 } else { 0 }).to_le_bytes());
+        data.resize(54, 0);
+        if rgb565 {
+            for mask in [0xF800u32, 0x07E0, 0x001F] {
+                data.extend_from_slice(&mask.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(pixels);
+        data
+    }
+
+    fn load(data: &[u8]) -> Result<SpriteSheet, ImageError> {
+        SpriteSheet::from_bmp(data, Size::new(1, 1))
+    }
+
+    #[test]
+    fn bmp_pixels() {
+        // 24 bit rows are padded to 4 bytes and stored bottom up, blue first
+        let pixels = [0, 0, 255, 0, 255, 0, 0, 0, 255, 0, 0, 255, 255, 255, 255, 0];
+        let sheet = load(&bmp(2, 2, 24, false, &pixels)).unwrap();
+        assert_eq!(sheet.size, Size::new(2, 2));
+        assert_eq!(sheet.pixels, [Rgb565::BLUE, Rgb565::WHITE, Rgb565::RED, Rgb565::GREEN]);
+        let sheet = load(&bmp(2, -2, 24, false, &pixels)).unwrap();
+        assert_eq!(sheet.pixels, [Rgb565::RED, Rgb565::GREEN, Rgb565::BLUE, Rgb565::WHITE]);
+        // 32 bit has no padding and ignores the fourth byte
+        let pixels = [0, 0, 255, 9, 255, 0, 0, 9];
+        assert_eq!(load(&bmp(2, 1, 32, false, &pixels)).unwrap().pixels, [Rgb565::RED, Rgb565::BLUE]);
+        // 16 bit is 555 unless the masks say 565
+        let pixels = [0x00, 0x7C, 0x1F, 0x00];
+        assert_eq!(load(&bmp(2, 1, 16, false, &pixels)).unwrap().pixels, [Rgb565::RED, Rgb565::BLUE]);
+        let pixels = [0x00, 0xF8, 0xE0, 0x07];
+        assert_eq!(load(&bmp(2, 1, 16, true, &pixels)).unwrap().pixels, [Rgb565::RED, Rgb565::GREEN]);
+    }
+
+    #[test]
+    fn broken_bmps() {
+        let good = bmp(2, 1, 24, false, &[0; 8]);
+        assert!(load(&good).is_ok());
+        assert_eq!(load(&good[..53]).err(), Some(ImageError::NotABitmap));
+        assert_eq!(load(b"PNG").err(), Some(ImageError::NotABitmap));
+        assert_eq!(load(&good[..61]).err(), Some(ImageError::Truncated));
+        for bpp in [0, 1, 4, 8, 15, 64, 65535] {
+            assert_eq!(load(&bmp(2, 1, bpp, false, &[0; 8])).err(), Some(ImageError::Unsupported), "{}", bpp);
+        }
+        assert_eq!(load(&bmp(0, 1, 24, false, &[])).err(), Some(ImageError::Unsupported));
+        assert_eq!(load(&bmp(-2, 1, 24, false, &[0; 8])).err(), Some(ImageError::Unsupported));
+        assert_eq!(load(&bmp(2, 0, 24, false, &[])).err(), Some(ImageError::Unsupported));
+        // bitfields with the header cut off before the masks
+        let mut short = bmp(1, 1, 16, true, &[0; 4]);
+        short.truncate(58);
+        assert_eq!(load(&short).err(), Some(ImageError::Truncated));
+        // sizes made up to overflow, which mustn't panic or allocate
+        for (width, height) in [(i32::MAX, i32::MAX), (i32::MAX, i32::MIN), (1 << 30, 4), (1, i32::MAX)] {
+            assert_eq!(load(&bmp(width, height, 32, false, &[0; 8])).err(), Some(ImageError::Truncated));
+        }
+        let mut far = good.clone();
+        far[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(load(&far).err(), Some(ImageError::Truncated));
+    }
+
+    struct Stripes;
+
+    impl Scene for Stripes {
+        fn background(&self) -> Rgb565 {
+            Rgb565::BLUE
+        }
+
+        fn draw(&self, target: &mut FrameBuffer) {
+            for x in (0..SCREEN_WIDTH as i32).step_by(7) {
+                Rectangle::new(Point::new(x, x / 3), Size::new(3, 50))
+                    .into_styled(embedded_graphics::primitives::PrimitiveStyle::with_fill(Rgb565::YELLOW))
+                    .draw(target)
+                    .unwrap();
+            }
+        }
+    }
+
+    // the whole screen in one buffer, standing in for the display
+    fn display() -> FrameBuffer {
+        let mut display = FrameBuffer::new((SCREEN_WIDTH * SCREEN_HEIGHT) as usize);
+        display.set_area(screen_bounds());
+        display
+    }
+
+    #[test]
+    fn rendering_through_small_buffers() {
+        let screen = screen_bounds();
+        let mut expected = display();
+        let mut dirty = DirtyRegions::new();
+        dirty.add(screen);
+        let mut fb = FrameBuffer::new(screen.size.width as usize * 16);
+        render(&Stripes, &mut dirty, &mut fb, &mut expected).unwrap();
+        assert!(dirty.is_empty());
+        assert!(expected.pixels.contains(&Rgb565::YELLOW) && expected.pixels.contains(&Rgb565::BLUE));
+        // buffers narrower than the screen, down to a single pixel, draw the same
+        for capacity in [1, 7, 100, 319, 321] {
+            let mut display = display();
+            dirty.add(screen);
+            render(&Stripes, &mut dirty, &mut FrameBuffer::new(capacity), &mut display).unwrap();
+            assert!(display.pixels == expected.pixels, "{} pixels", capacity);
+        }
+        // and one with no room at all draws nothing rather than panicking
+        let mut display = display();
+        dirty.add(screen);
+        render(&Stripes, &mut dirty, &mut FrameBuffer::new(0), &mut display).unwrap();
+        assert!(dirty.is_empty());
+        assert!(display.pixels.iter().all(|p| *p == Rgb565::BLACK));
+    }
+
+    #[test]
+    fn drawing_frames() {
+        let mut target = FrameBuffer::new(4);
+        target.set_area(Rectangle::new(Point::zero(), Size::new(2, 2)));
+        let sheet = SpriteSheet::solid(Size::new(2, 2), &[Rgb565::RED, Rgb565::GREEN]);
+        assert_eq!(sheet.frame_count(), 2);
+        sheet.draw_frame(1, Point::zero(), &mut target).unwrap();
+        assert_eq!(target.pixels, [Rgb565::GREEN; 4]);
+        // frames past the end draw nothing, with or without a transparent color
+        let _ = target.clear(Rgb565::BLACK);
+        sheet.draw_frame(2, Point::zero(), &mut target).unwrap();
+        sheet.draw_frame(usize::MAX, Point::zero(), &mut target).unwrap();
+        let sheet = sheet.with_transparent(Rgb565::RED);
+        sheet.draw_frame(2, Point::zero(), &mut target).unwrap();
+        assert_eq!(target.pixels, [Rgb565::BLACK; 4]);
+        // and so does any frame of a sheet smaller than one frame
+        let small = SpriteSheet::from_bmp(&bmp(2, 1, 24, false, &[0; 8]), Size::new(38, 14)).unwrap();
+        assert_eq!(small.frame_count(), 0);
+        small.draw_frame(0, Point::zero(), &mut target).unwrap();
+        let narrow = SpriteSheet::new(Size::new(1, 4), Size::new(2, 2), vec![Rgb565::RED; 4]);
+        assert_eq!(narrow.frame_count(), 0);
+        narrow.draw_frame(0, Point::zero(), &mut target).unwrap();
+        let empty = SpriteSheet::new(Size::new(2, 2), Size::zero(), vec![Rgb565::RED; 4]);
+        assert_eq!(empty.frame_count(), 0);
+        empty.draw_frame(0, Point::zero(), &mut target).unwrap();
+        assert_eq!(target.pixels, [Rgb565::BLACK; 4]);
+    }
+}
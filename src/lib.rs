@@ -2,42 +2,121 @@
 
 use esp_hal::spi::master::{Config as SpiConfig, Spi};
 // use alloc::string::String;
-use esp_hal::peripherals::{ADC1, GPIO4};
+use audio::AudioPeripherals;
+use capture::MicPeripherals;
+use esp_hal::peripherals::{ADC1, GPIO11, GPIO12, GPIO4, TIMG1, WIFI};
 extern crate alloc;
 
 use alloc::string::String;
 use core::cell::RefCell;
+use embedded_graphics::draw_target::DrawTarget;
+use embedded_graphics::geometry::Size;
+use embedded_graphics::primitives::Rectangle;
 use embedded_hal_bus::spi::{ExclusiveDevice, RefCellDevice};
-use embedded_sdmmc::{SdCard, TimeSource, Timestamp, VolumeManager};
+use embedded_sdmmc::{Mode, SdCard, SdCardError, TimeSource, Timestamp, VolumeIdx, VolumeManager};
 use esp_hal::analog::adc::{Adc, AdcConfig, AdcPin, Attenuation};
 use esp_hal::delay::Delay;
 use esp_hal::gpio::Level::{High, Low};
 use esp_hal::gpio::{Input, InputConfig, Output, OutputConfig, Pull};
 use esp_hal::i2c::master::{BusTimeout, Config, Error, I2c};
 use esp_hal::peripherals::Peripherals;
-use esp_hal::time::Rate;
+use esp_hal::time::{Instant, Rate};
+use esp_hal::timer::timg::TimerGroup;
 use esp_hal::Blocking;
+use esp_storage::FlashStorage;
 use gt911::{Error as Gt911Error, Gt911, Gt911Blocking, Point};
 use heapless::Vec;
 use log::info;
-use mipidsi::interface::SpiInterface;
+use mipidsi::interface::{Interface, SpiInterface};
 use mipidsi::models::ST7789;
-use mipidsi::options::{ColorInversion, ColorOrder, Orientation, Rotation};
+use mipidsi::options::{ColorInversion, ColorOrder, Orientation, Rotation, TearingEffect};
 use mipidsi::{Builder, Display, NoResetPin};
+use power::PowerState;
+use rotation::Rotation as ScreenRotation;
 use static_cell::StaticCell;
 
+pub mod anim;
+pub mod audio;
+pub mod breakout;
+pub mod capture;
+pub mod chart;
+pub mod color;
+pub mod dsp;
+pub mod fft;
+pub mod game;
+pub mod markdown;
+pub mod midi;
+pub mod mixer;
+pub mod mp3;
+pub mod music;
+pub mod playlist;
+pub mod power;
+pub mod qoa;
+pub mod reader;
+pub mod resample;
+pub mod rotation;
+pub mod settings;
+pub mod sim;
+pub mod synth;
+pub mod theme;
+pub mod tracker;
+pub mod visualizer;
+pub mod volume;
+pub mod wav;
+
 const LILYGO_KB_I2C_ADDRESS: u8 = 0x55;
+// the display and SD card share the bus at this speed. The panel can only be read slowly.
+const SPI_MHZ: u32 = 40;
+const DISPLAY_READ_MHZ: u32 = 4;
 
-pub struct Wrapper {
-    pub display: Display<
-        SpiInterface<
-            'static,
-            RefCellDevice<'static, Spi<'static, Blocking>, Output<'static>, Delay>,
-            Output<'static>,
-        >,
-        ST7789,
-        NoResetPin,
+// ST7789 commands that mipidsi doesn't have methods for
+const RDDID: u8 = 0x04;
+const SLPIN: u8 = 0x10;
+const SLPOUT: u8 = 0x11;
+const PTLON: u8 = 0x12;
+const NORON: u8 = 0x13;
+const INVOFF: u8 = 0x20;
+const INVON: u8 = 0x21;
+const GAMSET: u8 = 0x26;
+const DISPOFF: u8 = 0x28;
+const DISPON: u8 = 0x29;
+const PTLAR: u8 = 0x30;
+const IDMOFF: u8 = 0x38;
+const IDMON: u8 = 0x39;
+// the panel needs this long between entering and leaving sleep
+const SLEEP_CHANGE_MS: u64 = 120;
+
+pub type TDeckDisplay = Display<
+    SpiInterface<
+        'static,
+        RefCellDevice<'static, Spi<'static, Blocking>, Output<'static>, Delay>,
+        Output<'static>,
     >,
+    ST7789,
+    NoResetPin,
+>;
+
+pub type DisplayError = <TDeckDisplay as DrawTarget>::Error;
+
+/// The ST7789 has four gamma curves. `Curve1` is the default, 2.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gamma {
+    Curve1 = 1,
+    Curve2 = 2,
+    Curve3 = 4,
+    Curve4 = 8,
+}
+
+pub struct Wrapper {
+    pub display: TDeckDisplay,
+    // shared by the display and the SD card
+    spi_bus: &'static RefCell<Spi<'static, Blocking>>,
+    rotation: ScreenRotation,
+    backlight: Output<'static>,
+    display_on: bool,
+    display_asleep: bool,
+    // when the panel last went into or out of sleep
+    sleep_changed_at: Instant,
     i2c: I2c<'static, Blocking>,
     pub delay: Delay,
     adc: Adc<'static, ADC1<'static>, Blocking>,
@@ -52,6 +131,15 @@ pub struct Wrapper {
         SdCard<RefCellDevice<'static, Spi<'static, Blocking>, Output<'static>, Delay>, Delay>,
         DummyTimesource,
     >,
+    pub flash: FlashStorage<'static>,
+    /// the radio isn't set up by the wrapper. take this to start wifi.
+    pub wifi: Option<WIFI<'static>>,
+    /// the speaker isn't set up by the wrapper either. take this for `AudioSink::new`.
+    pub audio: Option<AudioPeripherals>,
+    /// the microphone too. take this for `AudioSource::new`, then call `init_microphone`.
+    pub mic: Option<MicPeripherals>,
+    /// the timer `start_scheduler` runs esp-rtos on, for apps that don't need it to use
+    timg1: Option<TIMG1<'static>>,
 }
 
 pub struct TrackballPin {
@@ -87,12 +175,34 @@ impl Wrapper {
         }
     }
 
+    /// Reads the last key pressed on the keyboard without blocking, if there is one.
+    pub fn read_key(&mut self) -> Option<u8> {
+        let mut data = [0u8; 1];
+        match self.i2c.read(LILYGO_KB_I2C_ADDRESS, &mut data) {
+            Ok(_) if data[0] != 0x00 => Some(data[0]),
+            _ => None,
+        }
+    }
+
     pub fn read_battery_level(&mut self) -> u16 {
         let pin_value: u16 = self.adc.read_blocking(&mut self.battery_pin);
         info!("bat adc is {pin_value} ");
         pin_value
     }
 
+    /// A rough battery voltage in millivolts. The battery is read through a divider that
+    /// halves it, and the ADC isn't calibrated, so expect to be off by 100mV or so.
+    pub fn read_battery_millivolts(&mut self) -> u32 {
+        let raw = self.adc.read_blocking(&mut self.battery_pin) as u32;
+        raw * 3300 * 2 / 4095
+    }
+
+    /// Sets up the microphone ADC, which shares the I2C bus with the keyboard. Start the
+    /// `AudioSource` first, the ADC needs its clock.
+    pub fn init_microphone(&mut self, gain_db: u8) -> Result<(), Error> {
+        capture::init_es7210(&mut self.i2c, gain_db)
+    }
+
     pub fn poll_trackball(&mut self) {
         self.left.poll();
         self.right.poll();
@@ -101,8 +211,292 @@ impl Wrapper {
         self.click.poll();
     }
 
+    /// Which trackball directions changed on the last poll, as [left, right, up, down, click].
+    /// The directions follow the screen rotation.
+    pub fn trackball_changes(&self) -> [bool; 5] {
+        self.rotation.map_trackball([
+            self.left.changed,
+            self.right.changed,
+            self.up.changed,
+            self.down.changed,
+            self.click.changed,
+        ])
+    }
+
+    /// Touched points in screen coordinates.
     pub fn poll_touchscreen(&mut self) -> Result<Vec<Point, 5>, Gt911Error<Error>> {
-        self.touch.get_multi_touch(&mut self.i2c)
+        let mut points = self.touch.get_multi_touch(&mut self.i2c)?;
+        for point in points.iter_mut() {
+            let p = self
+                .rotation
+                .map_touch(embedded_graphics::geometry::Point::new(point.x as i32, point.y as i32));
+            point.x = p.x as u16;
+            point.y = p.y as u16;
+        }
+        Ok(points)
+    }
+
+    pub fn rotation(&self) -> ScreenRotation {
+        self.rotation
+    }
+
+    /// Turns the screen. The screen size, touch points and trackball directions all change
+    /// with it. Nothing is redrawn, so apps should redraw everything afterwards.
+    pub fn set_rotation(&mut self, rotation: ScreenRotation) -> Result<(), DisplayError> {
+        self.display
+            .set_orientation(Orientation::new().rotate(mipidsi_rotation(rotation)))?;
+        self.rotation = rotation;
+        Ok(())
+    }
+
+    /// The size of the screen in the current rotation.
+    pub fn screen_size(&self) -> Size {
+        self.rotation.screen_size()
+    }
+
+    pub fn screen_bounds(&self) -> Rectangle {
+        self.rotation.screen_bounds()
+    }
+
+    fn display_command(&mut self, command: u8, args: &[u8]) -> Result<(), DisplayError> {
+        // safe because none of these commands change the state mipidsi keeps
+        unsafe { self.display.dcs() }.send_command(command, args)
+    }
+
+    pub fn is_display_on(&self) -> bool {
+        self.display_on
+    }
+
+    pub fn is_display_asleep(&self) -> bool {
+        self.display_asleep
+    }
+
+    /// Shows the panel contents and turns on the backlight.
+    pub fn display_on(&mut self) -> Result<(), DisplayError> {
+        self.display_command(DISPON, &[])?;
+        self.backlight.set_high();
+        self.display_on = true;
+        Ok(())
+    }
+
+    /// Blanks the panel and turns off the backlight. The panel keeps its contents and
+    /// can still be drawn to.
+    pub fn display_off(&mut self) -> Result<(), DisplayError> {
+        self.backlight.set_low();
+        self.display_command(DISPOFF, &[])?;
+        self.display_on = false;
+        Ok(())
+    }
+
+    // the panel ignores sleep changes too soon after the last one
+    fn wait_for_sleep_change(&mut self) {
+        let elapsed = self.sleep_changed_at.elapsed().as_millis();
+        if elapsed < SLEEP_CHANGE_MS {
+            self.delay.delay_millis((SLEEP_CHANGE_MS - elapsed) as u32);
+        }
+    }
+
+    /// Puts the panel to sleep with the backlight off. It draws very little power but keeps
+    /// its contents. Drawing doesn't work until `wake_display`.
+    pub fn sleep_display(&mut self) -> Result<(), DisplayError> {
+        if self.display_asleep {
+            return Ok(());
+        }
+        self.backlight.set_low();
+        self.wait_for_sleep_change();
+        self.display_command(SLPIN, &[])?;
+        self.sleep_changed_at = Instant::now();
+        self.display_asleep = true;
+        // no commands for 5ms after sleep in
+        self.delay.delay_millis(5);
+        Ok(())
+    }
+
+    /// Wakes the panel from sleep and turns the backlight back on if the display is on.
+    pub fn wake_display(&mut self) -> Result<(), DisplayError> {
+        if !self.display_asleep {
+            return Ok(());
+        }
+        self.wait_for_sleep_change();
+        self.display_command(SLPOUT, &[])?;
+        self.sleep_changed_at = Instant::now();
+        self.display_asleep = false;
+        // the supply and clocks need 5ms to settle before the next command
+        self.delay.delay_millis(5);
+        if self.display_on {
+            self.backlight.set_high();
+        }
+        Ok(())
+    }
+
+    /// Idle mode only shows 8 colors, the top bit of each channel, and uses less power.
+    pub fn set_idle_mode(&mut self, idle: bool) -> Result<(), DisplayError> {
+        self.display_command(if idle { IDMON } else { IDMOFF }, &[])
+    }
+
+    /// Only refreshes the panel rows covering `area` and leaves the rest black, for
+    /// showing a clock while saving power. `None` goes back to the whole screen.
+    /// In landscape the panel rows run across the screen, so the shown area is as tall
+    /// as the screen.
+    pub fn set_partial_area(&mut self, area: Option<Rectangle>) -> Result<(), DisplayError> {
+        match area.and_then(|area| self.rotation.panel_rows(area)) {
+            Some((start, end)) => {
+                let [s1, s0] = start.to_be_bytes();
+                let [e1, e0] = end.to_be_bytes();
+                self.display_command(PTLAR, &[s1, s0, e1, e0])?;
+                self.display_command(PTLON, &[])
+            }
+            None => self.display_command(NORON, &[]),
+        }
+    }
+
+    /// Turns the tearing effect output on or off. The T-Deck doesn't connect the TE pin,
+    /// so this only matters on boards that do.
+    pub fn set_tearing_effect(&mut self, tearing_effect: TearingEffect) -> Result<(), DisplayError> {
+        self.display.set_tearing_effect(tearing_effect)
+    }
+
+    /// The T-Deck's panel needs inversion on for the colors to be right, which `init` does.
+    pub fn set_inversion(&mut self, inverted: bool) -> Result<(), DisplayError> {
+        self.display_command(if inverted { INVON } else { INVOFF }, &[])
+    }
+
+    pub fn set_gamma(&mut self, gamma: Gamma) -> Result<(), DisplayError> {
+        self.display_command(GAMSET, &[gamma as u8])
+    }
+
+    /// Reads the manufacturer, version and driver IDs from the panel with RDDID. The panel
+    /// answers on the bus's MISO line, after one dummy clock, and much slower than it can be
+    /// written to, so the bus slows down for the read.
+    pub fn read_display_id(&mut self) -> Result<[u8; 3], esp_hal::spi::Error> {
+        let mut bus = self.spi_bus.borrow_mut();
+        // mipidsi's interface owns these pins and only writes, so drive them directly. it
+        // sets them again before each command it sends.
+        let mut cs = Output::new(unsafe { GPIO12::steal() }, High, OutputConfig::default());
+        let mut dc = Output::new(unsafe { GPIO11::steal() }, Low, OutputConfig::default());
+        let speed = |mhz| SpiConfig::default().with_frequency(Rate::from_mhz(mhz));
+        bus.apply_config(&speed(DISPLAY_READ_MHZ)).unwrap();
+        let mut reply = [0u8; 4];
+        cs.set_low();
+        let result = bus.write(&[RDDID]).and_then(|_| {
+            dc.set_high();
+            bus.read(&mut reply)
+        });
+        cs.set_high();
+        bus.apply_config(&speed(SPI_MHZ)).unwrap();
+        result?;
+        // drop the dummy bit in front
+        let [_, manufacturer, version, driver] = (u32::from_be_bytes(reply) >> 7).to_be_bytes();
+        Ok([manufacturer, version, driver])
+    }
+
+    /// Puts the display into a state from the `PowerManager`. `clock_area` is the part of
+    /// the screen kept on in the `Clock` state.
+    pub fn set_power_state(
+        &mut self,
+        state: PowerState,
+        clock_area: Rectangle,
+    ) -> Result<(), DisplayError> {
+        info!("display power {:?}", state);
+        if state == PowerState::Sleep {
+            self.display_off()?;
+            return self.sleep_display();
+        }
+        self.wake_display()?;
+        self.set_idle_mode(state != PowerState::Active)?;
+        self.set_partial_area((state == PowerState::Clock).then_some(clock_area))?;
+        self.display_on()
+    }
+
+    /// Reads a whole file from the root directory of the SD card into memory.
+    pub fn read_file(&mut self, name: &str) -> Result<alloc::vec::Vec<u8>, embedded_sdmmc::Error<SdCardError>> {
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0))?;
+        let root_dir = volume.open_root_dir()?;
+        let file = root_dir.open_file_in_dir(name, Mode::ReadOnly)?;
+        let mut data = alloc::vec![0u8; file.length() as usize];
+        let mut total = 0;
+        while !file.is_eof() && total < data.len() {
+            total += file.read(&mut data[total..])?;
+        }
+        data.truncate(total);
+        Ok(data)
+    }
+
+    /// Names and sizes of the files in the root directory of the SD card.
+    pub fn list_files(&mut self) -> Result<alloc::vec::Vec<(String, u32)>, embedded_sdmmc::Error<SdCardError>> {
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0))?;
+        let root_dir = volume.open_root_dir()?;
+        let mut files = alloc::vec::Vec::new();
+        root_dir.iterate_dir(|entry| {
+            if !entry.attributes.is_directory() && !entry.attributes.is_volume() {
+                files.push((alloc::format!("{}", entry.name), entry.size));
+            }
+        })?;
+        Ok(files)
+    }
+
+    /// Reads part of a file in the root directory of the SD card, starting at `offset`.
+    /// Returns how many bytes were read, which is less than `buf.len()` at the end of the file.
+    pub fn read_file_at(
+        &mut self,
+        name: &str,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, embedded_sdmmc::Error<SdCardError>> {
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0))?;
+        let root_dir = volume.open_root_dir()?;
+        let file = root_dir.open_file_in_dir(name, Mode::ReadOnly)?;
+        if offset >= file.length() {
+            return Ok(0);
+        }
+        file.seek_from_start(offset)?;
+        let mut total = 0;
+        while !file.is_eof() && total < buf.len() {
+            total += file.read(&mut buf[total..])?;
+        }
+        Ok(total)
+    }
+
+    /// Adds data to the end of a file in the root directory of the SD card, creating it if needed.
+    pub fn append_file(&mut self, name: &str, data: &[u8]) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0))?;
+        let root_dir = volume.open_root_dir()?;
+        let file = root_dir.open_file_in_dir(name, Mode::ReadWriteCreateOrAppend)?;
+        file.write(data)?;
+        file.close()
+    }
+
+    /// Overwrites part of an existing file in the root directory of the SD card, starting at `offset`.
+    pub fn write_file_at(
+        &mut self,
+        name: &str,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0))?;
+        let root_dir = volume.open_root_dir()?;
+        let file = root_dir.open_file_in_dir(name, Mode::ReadWriteAppend)?;
+        file.seek_from_start(offset)?;
+        file.write(data)?;
+        file.close()
+    }
+
+    /// Writes a file to the root directory of the SD card, replacing it if it exists.
+    pub fn write_file(&mut self, name: &str, data: &[u8]) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0))?;
+        let root_dir = volume.open_root_dir()?;
+        let file = root_dir.open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)?;
+        file.write(data)?;
+        file.close()
+    }
+}
+
+fn mipidsi_rotation(rotation: ScreenRotation) -> Rotation {
+    match rotation {
+        ScreenRotation::Deg0 => Rotation::Deg0,
+        ScreenRotation::Deg90 => Rotation::Deg90,
+        ScreenRotation::Deg180 => Rotation::Deg180,
+        ScreenRotation::Deg270 => Rotation::Deg270,
     }
 }
 
@@ -143,12 +537,12 @@ impl Wrapper {
         let tft_sck = peripherals.GPIO40;
         let tft_mosi = peripherals.GPIO41;
         let tft_dc = Output::new(peripherals.GPIO11, Low, OutputConfig::default());
-        let mut tft_enable = Output::new(peripherals.GPIO42, High, OutputConfig::default());
-        tft_enable.set_high();
+        let mut backlight = Output::new(peripherals.GPIO42, High, OutputConfig::default());
+        backlight.set_high();
         info!("creating spi device");
         let spi = Spi::new(
             peripherals.SPI2,
-            SpiConfig::default().with_frequency(Rate::from_mhz(40)), // .with_mode(Mode::_0)
+            SpiConfig::default().with_frequency(Rate::from_mhz(SPI_MHZ)), // .with_mode(Mode::_0)
         )
         .unwrap()
         .with_sck(tft_sck)
@@ -159,7 +553,7 @@ impl Wrapper {
         let spi_delay = Delay::new();
         // let spi_device = ExclusiveDevice::new(spi, tft_cs, spi_delay).unwrap();
         let shared_spi_bus = RefCell::new(spi);
-        let shared_spi_bus = SPI_BUS.init(shared_spi_bus);
+        let shared_spi_bus: &'static RefCell<_> = SPI_BUS.init(shared_spi_bus);
 
         let tft_device = RefCellDevice::new(shared_spi_bus, tft_cs, spi_delay)
             .expect("failed to create spi device");
@@ -172,7 +566,7 @@ impl Wrapper {
             .display_size(240, 320)
             .invert_colors(ColorInversion::Inverted)
             .color_order(ColorOrder::Rgb)
-            .orientation(Orientation::new().rotate(Rotation::Deg90))
+            .orientation(Orientation::new().rotate(mipidsi_rotation(ScreenRotation::default())))
             .init(&mut delay)
             .unwrap();
 
@@ -207,10 +601,35 @@ impl Wrapper {
         // set up the trackball button pins
         Wrapper {
             display,
+            spi_bus: shared_spi_bus,
+            rotation: ScreenRotation::default(),
+            backlight,
+            display_on: true,
+            display_asleep: false,
+            // init just woke the panel up
+            sleep_changed_at: Instant::now(),
             i2c,
             delay,
             touch,
             volume_mgr,
+            flash: FlashStorage::new(peripherals.FLASH),
+            wifi: Some(peripherals.WIFI),
+            audio: Some(AudioPeripherals {
+                i2s: peripherals.I2S0,
+                dma: peripherals.DMA_CH0,
+                bclk: peripherals.GPIO7,
+                ws: peripherals.GPIO5,
+                dout: peripherals.GPIO6,
+            }),
+            mic: Some(MicPeripherals {
+                i2s: peripherals.I2S1,
+                dma: peripherals.DMA_CH1,
+                mclk: peripherals.GPIO48,
+                bclk: peripherals.GPIO47,
+                ws: peripherals.GPIO21,
+                din: peripherals.GPIO14,
+            }),
+            timg1: Some(peripherals.TIMG1),
             adc: Adc::new(peripherals.ADC1, adc_config),
             battery_pin: pin,
             left: TrackballPin {
@@ -240,8 +659,9 @@ impl Wrapper {
             down: TrackballPin {
                 changed: false,
                 prev: false,
+                // GPIO5 is the speaker's word select
                 pin: Input::new(
-                    peripherals.GPIO5,
+                    peripherals.GPIO15,
                     InputConfig::default().with_pull(Pull::Up),
                 ),
             },
@@ -270,4 +690,14 @@ impl Wrapper {
             // trackball_down:false,
         }
     }
+
+    /// Starts the esp-rtos scheduler on TIMG1, so embassy timers and `.await` work. Apps
+    /// with an `#[esp_rtos::main]` call this straight after `init`, blocking apps don't
+    /// need it. Calling it again does nothing.
+    pub fn start_scheduler(&mut self) {
+        if let Some(timg1) = self.timg1.take() {
+            let timer_g1 = TimerGroup::new(timg1);
+            esp_rtos::start(timer_g1.timer0);
+        }
+    }
 }
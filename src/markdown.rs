@@ -0,0 +1,931 @@
+//! Shows Markdown documents, streamed from a `TextSource` so they can be any size.
+//!
+//! The source is read a line at a time and turned into blocks: headings, paragraphs,
+//! list items, block quotes, code and rules. Inline text is split into spans for
+//! emphasis, strong, code and links. Blocks are then wrapped into lines using the fonts
+//! and colors of a `Theme`.
+//!
+//! `MarkdownView` only keeps the lines around the visible part of the document. As it
+//! lays out the document it remembers where some blocks start, in the file and on the
+//! screen, so scrolling back only has to lay out from the nearest of those instead of
+//! from the top.
+
+use crate::reader::{LineReader, TextSource};
+use crate::theme::Theme;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyleBuilder};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+
+// longest source line read at once, longer ones are split
+const LINE_BUF: usize = 1024;
+// pixels
+const INDENT: u32 = 14;
+const QUOTE_INDENT: u32 = 10;
+const BLANK_HEIGHT: u32 = 6;
+const RULE_HEIGHT: u32 = 9;
+
+// ---------- blocks ----------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockKind {
+    Heading(u8),
+    Paragraph,
+    /// a list item, `depth` counts from 0
+    Item { depth: u8, marker: String },
+    Quote(u8),
+    /// one line of a code block
+    Code,
+    Rule,
+    /// the space between blocks
+    Blank,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub kind: BlockKind,
+    pub text: String,
+    /// where the block starts in the source
+    pub offset: u32,
+}
+
+/// Turns lines of Markdown into blocks. Paragraphs can span several lines, so a block
+/// comes out once the line after it is seen.
+#[derive(Default)]
+pub struct BlockParser {
+    pending: Option<Block>,
+    in_code: bool,
+    last_blank: bool,
+}
+
+impl BlockParser {
+    pub fn new(in_code: bool) -> Self {
+        BlockParser {
+            in_code,
+            ..Default::default()
+        }
+    }
+
+    /// true if the next line is inside a fenced code block
+    pub fn in_code(&self) -> bool {
+        self.in_code
+    }
+
+    /// true if nothing is waiting for more lines, so parsing could start again from the
+    /// next line
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    pub fn push_line(&mut self, line: &str, offset: u32, out: &mut Vec<Block>) {
+        let trimmed = line.trim_start();
+        // only spaces and tabs count, so the indent can be used to slice the line
+        let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+
+        if self.in_code {
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                self.in_code = false;
+            } else {
+                self.emit(BlockKind::Code, String::from(line), offset, out);
+            }
+            return;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            self.flush(out);
+            self.in_code = true;
+            return;
+        }
+        if trimmed.is_empty() {
+            self.flush(out);
+            if !self.last_blank {
+                self.emit(BlockKind::Blank, String::new(), offset, out);
+            }
+            return;
+        }
+        // indented code, but not when it continues a list item or paragraph
+        if indent >= 4 && self.pending.is_none() && list_marker(trimmed).is_none() {
+            self.emit(BlockKind::Code, String::from(&line[4..]), offset, out);
+            return;
+        }
+        if let Some(level) = heading_level(trimmed) {
+            self.flush(out);
+            let text = trimmed[level as usize..].trim().trim_end_matches('#').trim_end();
+            self.emit(BlockKind::Heading(level), String::from(text), offset, out);
+            return;
+        }
+        if let Some(pending) = &mut self.pending {
+            // setext headings underline the paragraph before
+            if pending.kind == BlockKind::Paragraph && is_underline(trimmed) {
+                pending.kind = BlockKind::Heading(if trimmed.starts_with('=') { 1 } else { 2 });
+                self.flush(out);
+                return;
+            }
+        }
+        if is_rule(trimmed) {
+            self.flush(out);
+            self.emit(BlockKind::Rule, String::new(), offset, out);
+            return;
+        }
+        if trimmed.starts_with('>') {
+            let mut depth = 0;
+            let mut rest = trimmed;
+            while let Some(r) = rest.strip_prefix('>') {
+                depth += 1;
+                rest = r.trim_start();
+            }
+            match &mut self.pending {
+                Some(p) if p.kind == BlockKind::Quote(depth) && !rest.is_empty() => {
+                    p.text.push(' ');
+                    p.text.push_str(rest);
+                }
+                _ => {
+                    self.flush(out);
+                    self.start(BlockKind::Quote(depth), rest, offset);
+                }
+            }
+            return;
+        }
+        if let Some((marker, rest)) = list_marker(trimmed) {
+            self.flush(out);
+            let depth = (indent / 2).min(8) as u8;
+            self.start(BlockKind::Item { depth, marker }, rest, offset);
+            return;
+        }
+        match &mut self.pending {
+            // lazy continuation of a paragraph, list item or quote
+            Some(p) => {
+                p.text.push(' ');
+                p.text.push_str(trimmed);
+            }
+            None => self.start(BlockKind::Paragraph, trimmed, offset),
+        }
+    }
+
+    /// Call at the end of the source to get the last block.
+    pub fn finish(&mut self, out: &mut Vec<Block>) {
+        self.flush(out);
+    }
+
+    fn start(&mut self, kind: BlockKind, text: &str, offset: u32) {
+        self.pending = Some(Block {
+            kind,
+            text: String::from(text.trim_end()),
+            offset,
+        });
+    }
+
+    fn flush(&mut self, out: &mut Vec<Block>) {
+        if let Some(block) = self.pending.take() {
+            self.last_blank = false;
+            out.push(block);
+        }
+    }
+
+    fn emit(&mut self, kind: BlockKind, text: String, offset: u32, out: &mut Vec<Block>) {
+        self.last_blank = kind == BlockKind::Blank;
+        out.push(Block { kind, text, offset });
+    }
+}
+
+fn heading_level(line: &str) -> Option<u8> {
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    let rest = &line[level..];
+    if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+        return Some(level as u8);
+    }
+    None
+}
+
+fn is_rule(line: &str) -> bool {
+    let chars: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    chars.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|&m| chars.chars().all(|c| c == m))
+}
+
+fn is_underline(line: &str) -> bool {
+    let line = line.trim_end();
+    !line.is_empty() && (line.chars().all(|c| c == '=') || line.chars().all(|c| c == '-'))
+}
+
+// the bullet to show and the rest of the line
+fn list_marker(line: &str) -> Option<(String, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(rest) = line.strip_prefix(bullet) {
+            // task list checkboxes
+            if let Some(rest) = rest.strip_prefix("[ ] ") {
+                return Some((String::from("[ ]"), rest));
+            }
+            if let Some(rest) = rest.strip_prefix("[x] ").or_else(|| rest.strip_prefix("[X] ")) {
+                return Some((String::from("[x]"), rest));
+            }
+            return Some((String::from("*"), rest));
+        }
+    }
+    let digits = line.bytes().take_while(|b| b.is_ascii_digit()).count();
+    if digits > 0 && digits < 10 {
+        let rest = &line[digits..];
+        if let Some(rest) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return Some((String::from(&line[..digits + 1]), rest));
+        }
+    }
+    None
+}
+
+// ---------- inline text ----------
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpanStyle {
+    pub strong: bool,
+    pub emphasis: bool,
+    pub code: bool,
+    pub link: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: SpanStyle,
+}
+
+/// Splits the text of a block into styled spans. Link targets are dropped, only the
+/// link text is kept. Images show their alt text.
+pub fn parse_inline(text: &str) -> Vec<Span> {
+    let mut spans: Vec<Span> = Vec::new();
+    let mut style = SpanStyle::default();
+    let mut current = String::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+
+    fn push(spans: &mut Vec<Span>, current: &mut String, style: SpanStyle) {
+        if !current.is_empty() {
+            spans.push(Span {
+                text: core::mem::take(current),
+                style,
+            });
+        }
+    }
+    // true if `pattern` appears again after position `from`
+    let closes = |from: usize, pattern: &[char]| {
+        chars.len() >= pattern.len()
+            && (from..=chars.len() - pattern.len()).any(|j| chars[j..j + pattern.len()] == *pattern)
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            '\\' if next.is_some_and(|n| n.is_ascii_punctuation()) => {
+                current.push(next.unwrap());
+                i += 2;
+                continue;
+            }
+            '`' => {
+                // code spans are taken literally
+                if let Some(len) = chars[i + 1..].iter().position(|&c| c == '`') {
+                    push(&mut spans, &mut current, style);
+                    current.extend(&chars[i + 1..i + 1 + len]);
+                    push(&mut spans, &mut current, SpanStyle { code: true, ..style });
+                    i += len + 2;
+                    continue;
+                }
+            }
+            '*' | '_' if next == Some(c) && (style.strong || closes(i + 2, &[c, c])) => {
+                push(&mut spans, &mut current, style);
+                style.strong = !style.strong;
+                i += 2;
+                continue;
+            }
+            // a doubled marker that isn't closed stays as text
+            '*' | '_' if next == Some(c) => {}
+            '*' | '_' => {
+                // inside a word an underscore is just an underscore
+                let in_word = c == '_'
+                    && i > 0
+                    && chars[i - 1].is_alphanumeric()
+                    && next.is_some_and(|n| n.is_alphanumeric());
+                if !in_word && (style.emphasis || closes(i + 1, &[c])) {
+                    push(&mut spans, &mut current, style);
+                    style.emphasis = !style.emphasis;
+                    i += 1;
+                    continue;
+                }
+            }
+            '!' if next == Some('[') => {
+                if let Some((label, end)) = link_at(&chars, i + 1) {
+                    push(&mut spans, &mut current, style);
+                    current.push_str("[image: ");
+                    current.push_str(&label);
+                    current.push(']');
+                    push(&mut spans, &mut current, SpanStyle { link: true, ..style });
+                    i = end;
+                    continue;
+                }
+            }
+            '[' => {
+                if let Some((label, end)) = link_at(&chars, i) {
+                    push(&mut spans, &mut current, style);
+                    for mut span in parse_inline(&label) {
+                        span.style.link = true;
+                        span.style.strong |= style.strong;
+                        span.style.emphasis |= style.emphasis;
+                        spans.push(span);
+                    }
+                    i = end;
+                    continue;
+                }
+            }
+            '<' => {
+                // autolinks like <https://example.com>
+                if let Some(len) = chars[i + 1..].iter().position(|&c| c == '>') {
+                    let inside: String = chars[i + 1..i + 1 + len].iter().collect();
+                    if inside.contains("://") || inside.contains('@') {
+                        push(&mut spans, &mut current, style);
+                        current.push_str(&inside);
+                        push(&mut spans, &mut current, SpanStyle { link: true, ..style });
+                        i += len + 2;
+                        continue;
+                    }
+                }
+            }
+            _ => {}
+        }
+        current.push(c);
+        i += 1;
+    }
+    push(&mut spans, &mut current, style);
+    spans
+}
+
+// a `[label](target)` starting at `start`. returns the label and the index after it.
+fn link_at(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let mut depth = 0;
+    let mut close = None;
+    for (j, &c) in chars.iter().enumerate().skip(start) {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(j);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let close = close?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    let end = chars[close + 2..].iter().position(|&c| c == ')')? + close + 2;
+    Some((chars[start + 1..close].iter().collect(), end + 1))
+}
+
+// ---------- layout ----------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineKind {
+    /// text, with the heading level or 0 for body text
+    Text { heading: u8 },
+    Code,
+    Rule,
+    Blank,
+}
+
+/// One line on the screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VisualLine {
+    pub kind: LineKind,
+    pub height: u32,
+    /// left edge of the text in pixels
+    pub indent: u32,
+    pub quote_depth: u8,
+    /// list marker, only on the first line of an item
+    pub bullet: Option<String>,
+    pub spans: Vec<Span>,
+}
+
+impl VisualLine {
+    fn empty(kind: LineKind, height: u32) -> Self {
+        VisualLine {
+            kind,
+            height,
+            indent: 0,
+            quote_depth: 0,
+            bullet: None,
+            spans: Vec::new(),
+        }
+    }
+}
+
+fn char_width(font: &MonoFont) -> u32 {
+    font.character_size.width + font.character_spacing
+}
+
+/// Wraps a block into lines `width` pixels wide.
+pub fn layout_block(block: &Block, width: u32, theme: &Theme, out: &mut Vec<VisualLine>) {
+    let (font, heading, indent, quote_depth, bullet) = match &block.kind {
+        BlockKind::Blank => {
+            out.push(VisualLine::empty(LineKind::Blank, BLANK_HEIGHT));
+            return;
+        }
+        BlockKind::Rule => {
+            out.push(VisualLine::empty(LineKind::Rule, RULE_HEIGHT));
+            return;
+        }
+        BlockKind::Code => {
+            let font = theme.code_font;
+            let cols = (width.saturating_sub(4) / char_width(font)).max(1) as usize;
+            // code isn't wrapped, long lines are cut
+            let text: String = block.text.replace('\t', "    ").chars().take(cols).collect();
+            let mut line = VisualLine::empty(LineKind::Code, font.character_size.height);
+            line.indent = 4;
+            line.spans.push(Span {
+                text,
+                style: SpanStyle {
+                    code: true,
+                    ..Default::default()
+                },
+            });
+            out.push(line);
+            return;
+        }
+        BlockKind::Heading(level) => (theme.heading_font(*level), *level, 0, 0, None),
+        BlockKind::Paragraph => (theme.body, 0, 0, 0, None),
+        BlockKind::Quote(depth) => (theme.body, 0, *depth as u32 * QUOTE_INDENT, *depth, None),
+        BlockKind::Item { depth, marker } => {
+            let marker_width = (marker.chars().count() as u32 + 1) * char_width(theme.body);
+            let indent = *depth as u32 * INDENT + marker_width.max(INDENT);
+            (theme.body, 0, indent, 0, Some(marker.clone()))
+        }
+    };
+    let cols = (width.saturating_sub(indent) / char_width(font)).max(1) as usize;
+    let mut height = font.character_size.height + 1;
+    if heading > 0 {
+        height += 3;
+    }
+
+    let first = out.len();
+    let mut line: Vec<Span> = Vec::new();
+    let mut used = 0;
+    let finish_line = |line: &mut Vec<Span>, out: &mut Vec<VisualLine>| {
+        // spaces at the end of a line don't show
+        if let Some(last) = line.last_mut() {
+            let trimmed = last.text.trim_end().len();
+            last.text.truncate(trimmed);
+        }
+        let mut visual = VisualLine::empty(LineKind::Text { heading }, height);
+        visual.indent = indent;
+        visual.quote_depth = quote_depth;
+        visual.spans = core::mem::take(line);
+        out.push(visual);
+    };
+
+    for span in parse_inline(&block.text) {
+        for word in split_words(&span.text) {
+            let len = word.chars().count();
+            let is_space = word.starts_with(' ');
+            if is_space && used == 0 {
+                continue;
+            }
+            if used + len > cols && !is_space {
+                if used > 0 {
+                    finish_line(&mut line, out);
+                }
+                // a word longer than a whole line gets split
+                let mut rest = word;
+                while rest.chars().count() > cols {
+                    let cut = rest.char_indices().nth(cols).map(|(i, _)| i).unwrap();
+                    add_text(&mut line, &rest[..cut], span.style);
+                    finish_line(&mut line, out);
+                    rest = &rest[cut..];
+                }
+                add_text(&mut line, rest, span.style);
+                used = rest.chars().count();
+                continue;
+            }
+            if used + len > cols {
+                // a space that doesn't fit ends the line
+                finish_line(&mut line, out);
+                used = 0;
+                continue;
+            }
+            add_text(&mut line, word, span.style);
+            used += len;
+        }
+    }
+    if used > 0 || out.len() == first {
+        finish_line(&mut line, out);
+    }
+    if let Some(first) = out.get_mut(first) {
+        first.bullet = bullet;
+    }
+}
+
+fn add_text(line: &mut Vec<Span>, text: &str, style: SpanStyle) {
+    match line.last_mut() {
+        Some(last) if last.style == style => last.text.push_str(text),
+        _ => line.push(Span {
+            text: String::from(text),
+            style,
+        }),
+    }
+}
+
+// words and the runs of spaces between them
+fn split_words(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let space = rest.starts_with(' ');
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| (c == ' ') != space)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(end);
+        rest = tail;
+        Some(word)
+    })
+}
+
+/// Draws a line with its top left corner at `top_left`, `width` pixels wide.
+pub fn draw_line<D: DrawTarget<Color = Rgb565>>(
+    line: &VisualLine,
+    top_left: Point,
+    width: u32,
+    theme: &Theme,
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let x = top_left.x;
+    let y = top_left.y;
+    let accent = PrimitiveStyle::with_stroke(theme.accent, 1);
+    for d in 0..line.quote_depth as i32 {
+        let bar_x = x + d * QUOTE_INDENT as i32 + 2;
+        Line::new(Point::new(bar_x, y), Point::new(bar_x, y + line.height as i32 - 1))
+            .into_styled(PrimitiveStyle::with_stroke(theme.accent, 2))
+            .draw(target)?;
+    }
+    match line.kind {
+        LineKind::Blank => return Ok(()),
+        LineKind::Rule => {
+            let mid = y + line.height as i32 / 2;
+            return Line::new(Point::new(x, mid), Point::new(x + width as i32 - 1, mid))
+                .into_styled(accent)
+                .draw(target);
+        }
+        LineKind::Code => {
+            Rectangle::new(top_left, Size::new(width, line.height))
+                .into_styled(PrimitiveStyle::with_fill(theme.code_background))
+                .draw(target)?;
+        }
+        LineKind::Text { .. } => {}
+    }
+
+    let heading = match line.kind {
+        LineKind::Text { heading } => heading,
+        _ => 0,
+    };
+    let mut p = Point::new(x + line.indent as i32, y);
+    if let Some(bullet) = &line.bullet {
+        let style = MonoTextStyleBuilder::new()
+            .font(theme.body)
+            .text_color(theme.accent)
+            .build();
+        let bx = p.x - (bullet.chars().count() as u32 + 1) as i32 * char_width(theme.body) as i32;
+        Text::with_baseline(bullet, Point::new(bx, y), style, Baseline::Top).draw(target)?;
+    }
+    for span in &line.spans {
+        let font = if heading > 0 {
+            theme.heading_font(heading)
+        } else if span.style.code {
+            theme.code_font
+        } else if span.style.strong {
+            theme.bold
+        } else if span.style.emphasis {
+            theme.italic
+        } else {
+            theme.body
+        };
+        let color = if span.style.link {
+            theme.link
+        } else if span.style.code {
+            theme.code
+        } else if heading > 0 {
+            theme.heading
+        } else if line.quote_depth > 0 {
+            theme.quote
+        } else {
+            theme.text
+        };
+        let mut style = MonoTextStyleBuilder::new().font(font).text_color(color);
+        if span.style.link {
+            style = style.underline();
+        }
+        if span.style.code && line.kind != LineKind::Code {
+            style = style.background_color(theme.code_background);
+        }
+        p = Text::with_baseline(&span.text, p, style.build(), Baseline::Top).draw(target)?;
+    }
+    if heading == 1 {
+        // level 1 headings are underlined across the page
+        let uy = y + line.height as i32 - 2;
+        Line::new(Point::new(x, uy), Point::new(x + width as i32 - 1, uy))
+            .into_styled(accent)
+            .draw(target)?;
+    }
+    Ok(())
+}
+
+// ---------- scrolling view ----------
+
+// a place layout can start again from
+#[derive(Debug, Clone, Copy)]
+struct Checkpoint {
+    offset: u32,
+    y: u32,
+    in_code: bool,
+}
+
+/// A scrollable Markdown document.
+pub struct MarkdownView {
+    pub theme: Theme,
+    pub size: Size,
+    /// how far down the document the top of the screen is, in pixels
+    pub scroll: u32,
+    checkpoints: Vec<Checkpoint>,
+    /// laid out lines and their positions, covering `cached`
+    lines: Vec<(u32, VisualLine)>,
+    cached: (u32, u32),
+    /// the height of the whole document, once the end has been reached
+    height: Option<u32>,
+}
+
+impl MarkdownView {
+    pub fn new(theme: Theme, size: Size) -> Self {
+        MarkdownView {
+            theme,
+            size,
+            scroll: 0,
+            checkpoints: alloc::vec![Checkpoint {
+                offset: 0,
+                y: 0,
+                in_code: false,
+            }],
+            lines: Vec::new(),
+            cached: (0, 0),
+            height: None,
+        }
+    }
+
+    /// The height of the document, if it is known yet.
+    pub fn height(&self) -> Option<u32> {
+        self.height
+    }
+
+    /// How far through the document the bottom of the screen is, 0-100, if the height
+    /// is known.
+    pub fn percent(&self) -> Option<u32> {
+        let height = self.height?;
+        if height <= self.size.height {
+            return Some(100);
+        }
+        Some(((self.scroll + self.size.height) * 100 / height).min(100))
+    }
+
+    /// Scrolls by `dy` pixels, stopping at the top and the bottom.
+    pub fn scroll_by(&mut self, src: &mut impl TextSource, dy: i32) {
+        self.scroll_to(src, self.scroll.saturating_add_signed(dy));
+    }
+
+    pub fn scroll_to(&mut self, src: &mut impl TextSource, y: u32) {
+        self.scroll = y;
+        self.update(src);
+        if let Some(height) = self.height {
+            let max = height.saturating_sub(self.size.height);
+            if self.scroll > max {
+                self.scroll = max;
+                self.update(src);
+            }
+        }
+    }
+
+    /// Lays out the part of the document around the screen, if it isn't already.
+    pub fn update(&mut self, src: &mut impl TextSource) {
+        let top = self.scroll;
+        let bottom = self.scroll + self.size.height;
+        let at_end = self.height.is_some_and(|h| self.cached.1 >= h);
+        if top >= self.cached.0 && (bottom <= self.cached.1 || at_end) && !self.lines.is_empty() {
+            return;
+        }
+
+        let checkpoint = *self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|c| c.y <= top)
+            .unwrap_or(&self.checkpoints[0]);
+        // lay out a screen more than needed, so small scrolls don't read the card
+        let until = bottom + self.size.height;
+        let mut reader = LineReader::new(checkpoint.offset, LINE_BUF);
+        let mut parser = BlockParser::new(checkpoint.in_code);
+        let mut blocks = Vec::new();
+        let mut laid_out = Vec::new();
+        let mut y = checkpoint.y;
+        self.lines.clear();
+
+        loop {
+            let line = reader.next_line(src);
+            let done = line.is_none();
+            match line {
+                Some((offset, text)) => {
+                    let in_code = parser.in_code();
+                    // blank lines are left out since a run of them only makes one gap
+                    if parser.is_idle() && !text.trim().is_empty() {
+                        self.add_checkpoint(Checkpoint { offset, y, in_code });
+                    }
+                    parser.push_line(&text, offset, &mut blocks);
+                }
+                None => parser.finish(&mut blocks),
+            }
+            for block in blocks.drain(..) {
+                laid_out.clear();
+                layout_block(&block, self.size.width - 4, &self.theme, &mut laid_out);
+                for line in laid_out.drain(..) {
+                    let h = line.height;
+                    if y + h > top.saturating_sub(self.size.height) {
+                        self.lines.push((y, line));
+                    }
+                    y += h;
+                }
+            }
+            if done {
+                self.height = Some(y);
+                break;
+            }
+            if y >= until && parser.is_idle() {
+                break;
+            }
+        }
+        self.cached = (
+            self.lines.first().map(|(y, _)| *y).unwrap_or(checkpoint.y),
+            y,
+        );
+    }
+
+    fn add_checkpoint(&mut self, checkpoint: Checkpoint) {
+        // about one per screen is plenty
+        let last = self.checkpoints.last().unwrap();
+        if checkpoint.offset > last.offset && checkpoint.y >= last.y + self.size.height {
+            self.checkpoints.push(checkpoint);
+        }
+    }
+
+    /// Draws the visible part of the document into `area`.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D, area: Rectangle) -> Result<(), D::Error> {
+        let mut target = target.clipped(&area);
+        area.into_styled(PrimitiveStyle::with_fill(self.theme.background))
+            .draw(&mut target)?;
+        let top = self.scroll as i32;
+        for (y, line) in &self.lines {
+            let screen_y = *y as i32 - top;
+            if screen_y + line.height as i32 <= 0 || screen_y >= self.size.height as i32 {
+                continue;
+            }
+            let p = area.top_left + Point::new(2, screen_y);
+            draw_line(line, p, self.size.width - 4, &self.theme, &mut target)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn blocks(doc: &str) -> Vec<(BlockKind, String)> {
+        let mut parser = BlockParser::new(false);
+        let mut out = Vec::new();
+        let mut offset = 0;
+        for line in doc.lines() {
+            parser.push_line(line, offset, &mut out);
+            offset += line.len() as u32 + 1;
+        }
+        parser.finish(&mut out);
+        out.into_iter().map(|b| (b.kind, b.text)).collect()
+    }
+
+    fn item(depth: u8, marker: &str, text: &str) -> (BlockKind, String) {
+        (BlockKind::Item { depth, marker: String::from(marker) }, String::from(text))
+    }
+
+    fn block(kind: BlockKind, text: &str) -> (BlockKind, String) {
+        (kind, String::from(text))
+    }
+
+    #[test]
+    fn block_parsing() {
+        let doc = "# Title #\nSome text\nrunning on\n\n\nSub\n---\n- one\n  - two\n3. three\n\
+                   - [x] done\n> quoted\n> more\n>> deeper\n\n```\n# not a heading\n```\n\n    \
+                   indented\n***";
+        assert_eq!(
+            blocks(doc),
+            vec![
+                block(BlockKind::Heading(1), "Title"),
+                block(BlockKind::Paragraph, "Some text running on"),
+                block(BlockKind::Blank, ""),
+                block(BlockKind::Heading(2), "Sub"),
+                item(0, "*", "one"),
+                item(1, "*", "two"),
+                item(0, "3.", "three"),
+                item(0, "[x]", "done"),
+                block(BlockKind::Quote(1), "quoted more"),
+                block(BlockKind::Quote(2), "deeper"),
+                block(BlockKind::Blank, ""),
+                block(BlockKind::Code, "# not a heading"),
+                block(BlockKind::Blank, ""),
+                block(BlockKind::Code, "indented"),
+                block(BlockKind::Rule, ""),
+            ]
+        );
+        // blocks remember where they start
+        let mut parser = BlockParser::new(false);
+        let mut out = Vec::new();
+        parser.push_line("text", 0, &mut out);
+        parser.push_line("", 5, &mut out);
+        parser.push_line("## Heading", 6, &mut out);
+        let offsets: Vec<u32> = out.iter().map(|b| b.offset).collect();
+        assert_eq!(offsets, [0, 5, 6]);
+    }
+
+    #[test]
+    fn unicode_whitespace_in_the_indent() {
+        // an ideographic space is whitespace but not indent, and is three bytes long
+        assert_eq!(blocks("  \u{3000}x"), vec![block(BlockKind::Paragraph, "x")]);
+        assert_eq!(blocks("  \u{3000}- x"), vec![item(1, "*", "x")]);
+        assert_eq!(blocks("\u{3000}\u{3000}\u{3000}x"), vec![block(BlockKind::Paragraph, "x")]);
+        assert_eq!(blocks("    \u{3000}x"), vec![block(BlockKind::Code, "\u{3000}x")]);
+        assert_eq!(blocks("\t\t\t\tx"), vec![block(BlockKind::Code, "x")]);
+        assert_eq!(blocks("\u{3000}\u{3000}"), vec![block(BlockKind::Blank, "")]);
+    }
+
+    fn spans(text: &str) -> Vec<(&'static str, String)> {
+        parse_inline(text)
+            .into_iter()
+            .map(|s| {
+                let style = match s.style {
+                    SpanStyle { link: true, .. } => "link",
+                    SpanStyle { code: true, .. } => "code",
+                    SpanStyle { strong: true, emphasis: true, .. } => "strong emphasis",
+                    SpanStyle { strong: true, .. } => "strong",
+                    SpanStyle { emphasis: true, .. } => "emphasis",
+                    _ => "plain",
+                };
+                (style, s.text)
+            })
+            .collect()
+    }
+
+    fn span(style: &'static str, text: &str) -> (&'static str, String) {
+        (style, String::from(text))
+    }
+
+    #[test]
+    fn inline_spans() {
+        assert_eq!(
+            spans("a **b _c_** `*d*` e"),
+            vec![
+                span("plain", "a "),
+                span("strong", "b "),
+                span("strong emphasis", "c"),
+                span("plain", " "),
+                span("code", "*d*"),
+                span("plain", " e"),
+            ]
+        );
+        assert_eq!(
+            spans("see [the *docs*](https://x.y) or <https://z.w>"),
+            vec![
+                span("plain", "see "),
+                span("link", "the "),
+                span("link", "docs"),
+                span("plain", " or "),
+                span("link", "https://z.w"),
+            ]
+        );
+        assert_eq!(spans("![a cat](cat.png)"), vec![span("link", "[image: a cat]")]);
+        // markers that aren't closed, escaped, or inside a word stay as they are
+        assert_eq!(spans("2 * 3"), vec![span("plain", "2 * 3")]);
+        assert_eq!(spans("**4 _5"), vec![span("plain", "**4 _5")]);
+        assert_eq!(spans("\\*not\\* snake_case_name"), vec![span("plain", "*not* snake_case_name")]);
+        assert_eq!(spans("[no target] `open"), vec![span("plain", "[no target] `open")]);
+    }
+}
@@ -0,0 +1,913 @@
+//! Playing Standard MIDI Files through the synth.
+//!
+//! `parse` reads the header and finds the tracks, then runs through every event once to
+//! get the length and the song's name. Format 0 files have one track and format 1 files
+//! several played together, both are supported. Format 2 files, a set of separate
+//! patterns, aren't. RIFF `RMID` files are unwrapped.
+//!
+//! Tracks aren't loaded into memory, a file can have tens of thousands of events and the
+//! heap is small. Instead the `Sequencer` keeps a read position and a small buffer for
+//! each track and merges them as it goes, taking whichever track has the earliest next
+//! event. It follows tempo changes and running status and skips SysEx and the meta events
+//! it doesn't need.
+//!
+//! `MidiPlayer` sends the events to a `Synth`, choosing patches from the General MIDI
+//! program numbers, with channel 10 as drums. It follows the channel volume, expression,
+//! sustain pedal and pitch bend controllers.
+
+use crate::reader::TextSource;
+use crate::synth::{FilterKind, Patch, Synth, Wave, PRESETS};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// more than any real file, in case of a broken header
+const MAX_TRACKS: usize = 64;
+// bytes of each track read from the file at a time
+const TRACK_BUFFER: usize = 256;
+// 120 beats a minute, until the file says otherwise
+const DEFAULT_TEMPO: u32 = 500_000;
+const DRUM_CHANNEL: u8 = 9;
+const POLYPHONY: usize = 16;
+// pitch bend goes this far either way unless the file changes it, which isn't followed
+const BEND_RANGE: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiError {
+    /// doesn't start with MThd
+    NotMidi,
+    /// format 2, or a header this doesn't understand
+    Unsupported(u16),
+    NoTracks,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Division {
+    /// ticks in a quarter note, the length of which the tempo says
+    PerQuarter(u16),
+    /// frames a second and ticks a frame, so tempo doesn't matter
+    Smpte { fps: u8, ticks: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidiInfo {
+    pub format: u16,
+    pub division: Division,
+    /// where each track's events start in the file and how long they are
+    pub tracks: Vec<(u32, u32)>,
+    pub duration_ms: u32,
+    /// the name of the first track, which is usually the song's
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    Controller { channel: u8, controller: u8, value: u8 },
+    Program { channel: u8, program: u8 },
+    /// -8192 to 8191, 0 is no bend
+    PitchBend { channel: u8, value: i16 },
+    /// microseconds in a quarter note
+    Tempo(u32),
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn be_u16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+/// Reads the header and track list, then goes through the events to find the length.
+pub fn parse(src: &mut impl TextSource) -> Result<MidiInfo, MidiError> {
+    let mut header = [0u8; 14];
+    let mut start = 0;
+    if src.read_at(0, &mut header) < 14 {
+        return Err(MidiError::NotMidi);
+    }
+    // RMID files are a RIFF file with the MIDI file in a data chunk
+    if &header[0..4] == b"RIFF" && &header[8..12] == b"RMID" {
+        start = 20;
+        if src.read_at(start, &mut header) < 14 {
+            return Err(MidiError::NotMidi);
+        }
+    }
+    if &header[0..4] != b"MThd" || be_u32(&header[4..8]) < 6 {
+        return Err(MidiError::NotMidi);
+    }
+    let format = be_u16(&header[8..10]);
+    let count = be_u16(&header[10..12]) as usize;
+    let division = be_u16(&header[12..14]);
+    if format > 1 {
+        return Err(MidiError::Unsupported(format));
+    }
+    let division = if division & 0x8000 != 0 {
+        // the frame rate is stored negated, 29 means 29.97
+        Division::Smpte {
+            fps: (-((division >> 8) as u8 as i8)) as u8,
+            ticks: division as u8,
+        }
+    } else {
+        Division::PerQuarter(division.max(1))
+    };
+
+    let mut tracks = Vec::new();
+    let mut offset = start + 8 + be_u32(&header[4..8]);
+    let size = src.size();
+    while tracks.len() < count.min(MAX_TRACKS) && offset + 8 <= size {
+        let mut chunk = [0u8; 8];
+        if src.read_at(offset, &mut chunk) < 8 {
+            break;
+        }
+        let len = be_u32(&chunk[4..8]).min(size - offset - 8);
+        // other chunk types are allowed and skipped
+        if &chunk[0..4] == b"MTrk" {
+            tracks.push((offset + 8, len));
+        }
+        offset = offset.saturating_add(8 + len);
+    }
+    if tracks.is_empty() {
+        return Err(MidiError::NoTracks);
+    }
+
+    let mut info = MidiInfo {
+        format,
+        division,
+        tracks,
+        duration_ms: 0,
+        title: None,
+    };
+    let mut seq = Sequencer::new(&info);
+    let mut end = 0;
+    while let Some((us, _)) = seq.next_event(src) {
+        end = us;
+    }
+    // running off the end of a track still takes time, as does its end of track event
+    end = end.max(seq.us_at(seq.end_tick()));
+    info.duration_ms = (end / 1000) as u32;
+    info.title = seq.title;
+    Ok(info)
+}
+
+// one track's read position
+struct TrackCursor {
+    offset: u32,
+    end: u32,
+    buf: [u8; TRACK_BUFFER],
+    // file offset of buf[0], and how much of it is filled
+    buf_at: u32,
+    buf_len: usize,
+    running: u8,
+    // the tick of the event at `offset`, once its delta has been read
+    tick: u64,
+    started: bool,
+    done: bool,
+}
+
+impl TrackCursor {
+    fn byte(&mut self, src: &mut impl TextSource) -> Option<u8> {
+        if self.offset >= self.end {
+            return None;
+        }
+        let at = self.offset.wrapping_sub(self.buf_at) as usize;
+        if self.offset < self.buf_at || at >= self.buf_len {
+            let want = TRACK_BUFFER.min((self.end - self.offset) as usize);
+            self.buf_len = src.read_at(self.offset, &mut self.buf[..want]);
+            self.buf_at = self.offset;
+            if self.buf_len == 0 {
+                return None;
+            }
+        }
+        let b = self.buf[(self.offset - self.buf_at) as usize];
+        self.offset += 1;
+        Some(b)
+    }
+
+    // a variable length number, seven bits a byte
+    fn number(&mut self, src: &mut impl TextSource) -> Option<u32> {
+        let mut n = 0u32;
+        for _ in 0..4 {
+            let b = self.byte(src)?;
+            n = (n << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        Some(n)
+    }
+
+    fn skip(&mut self, len: u32) {
+        self.offset = self.offset.saturating_add(len).min(self.end);
+    }
+
+    // reads the delta time of the next event, or marks the track done
+    fn advance(&mut self, src: &mut impl TextSource) {
+        match self.number(src) {
+            Some(delta) => self.tick += delta as u64,
+            None => self.done = true,
+        }
+    }
+}
+
+/// Reads the events of all the tracks in time order.
+pub struct Sequencer {
+    division: Division,
+    tracks: Vec<TrackCursor>,
+    // microseconds a quarter note, and when it last changed, in ticks and microseconds
+    tempo: u32,
+    tempo_tick: u64,
+    tempo_us: u64,
+    /// the first track name seen in the first track
+    pub title: Option<String>,
+}
+
+impl Sequencer {
+    pub fn new(info: &MidiInfo) -> Self {
+        let tracks = info
+            .tracks
+            .iter()
+            .map(|(offset, len)| TrackCursor {
+                offset: *offset,
+                end: offset + len,
+                buf: [0; TRACK_BUFFER],
+                buf_at: 0,
+                buf_len: 0,
+                running: 0,
+                tick: 0,
+                started: false,
+                done: false,
+            })
+            .collect();
+        Sequencer {
+            division: info.division,
+            tracks,
+            tempo: DEFAULT_TEMPO,
+            tempo_tick: 0,
+            tempo_us: 0,
+            title: None,
+        }
+    }
+
+    // microseconds from the start to `tick`, at the current tempo since it last changed
+    fn us_at(&self, tick: u64) -> u64 {
+        let ticks = tick.saturating_sub(self.tempo_tick);
+        let us = match self.division {
+            Division::PerQuarter(tpq) => ticks * self.tempo as u64 / tpq as u64,
+            Division::Smpte { fps, ticks: per_frame } => {
+                ticks * 1_000_000 / (fps.max(1) as u64 * per_frame.max(1) as u64)
+            }
+        };
+        self.tempo_us + us
+    }
+
+    // the last tick any track got to
+    fn end_tick(&self) -> u64 {
+        self.tracks.iter().map(|t| t.tick).max().unwrap_or(0)
+    }
+
+    /// The next event and when it happens in microseconds, or `None` at the end.
+    pub fn next_event(&mut self, src: &mut impl TextSource) -> Option<(u64, Event)> {
+        loop {
+            // the first delta of each track
+            for track in self.tracks.iter_mut().filter(|t| !t.started) {
+                track.started = true;
+                track.advance(src);
+            }
+            // earliest first, and the lower track on a tie, so tempo in track 0 comes first
+            let (index, _) = self
+                .tracks
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| !t.done)
+                .min_by_key(|(i, t)| (t.tick, *i))?;
+            let tick = self.tracks[index].tick;
+            let event = self.read_event(index, src);
+            if !self.tracks[index].done {
+                self.tracks[index].advance(src);
+            }
+            if let Some(event) = event {
+                let us = self.us_at(tick);
+                if let Event::Tempo(tempo) = event {
+                    self.tempo_us = us;
+                    self.tempo_tick = tick;
+                    self.tempo = tempo.max(1);
+                }
+                return Some((us, event));
+            }
+        }
+    }
+
+    // reads the event a track is at, `None` for ones that don't matter
+    fn read_event(&mut self, index: usize, src: &mut impl TextSource) -> Option<Event> {
+        let track = &mut self.tracks[index];
+        let Some(first) = track.byte(src) else {
+            track.done = true;
+            return None;
+        };
+        let (status, data1) = if first & 0x80 != 0 {
+            (first, None)
+        } else if track.running >= 0x80 && track.running < 0xF0 {
+            // running status, the first byte is data for the last status
+            (track.running, Some(first))
+        } else {
+            // data with nothing to go with it, skip the byte
+            return None;
+        };
+        match status {
+            // running status is meant to stop at meta and SysEx events, but plenty of files
+            // carry on using it and nothing else could be meant, so it's kept
+            0xFF => {
+                let kind = track.byte(src)?;
+                let len = track.number(src)?;
+                match kind {
+                    0x2F => {
+                        track.done = true;
+                        None
+                    }
+                    0x51 if len == 3 => {
+                        let a = track.byte(src)? as u32;
+                        let b = track.byte(src)? as u32;
+                        let c = track.byte(src)? as u32;
+                        Some(Event::Tempo((a << 16) | (b << 8) | c))
+                    }
+                    0x03 if index == 0 && self.title.is_none() => {
+                        let mut name = Vec::new();
+                        for _ in 0..len.min(64) {
+                            name.push(track.byte(src)?);
+                        }
+                        track.skip(len.saturating_sub(64));
+                        let name: String = String::from_utf8_lossy(&name).trim().into();
+                        self.title = Some(name).filter(|n| !n.is_empty());
+                        None
+                    }
+                    _ => {
+                        track.skip(len);
+                        None
+                    }
+                }
+            }
+            0xF0 | 0xF7 => {
+                let len = track.number(src)?;
+                track.skip(len);
+                None
+            }
+            // system messages without a length shouldn't be in a file, give up on the track
+            0xF1..=0xFE => {
+                track.done = true;
+                None
+            }
+            _ => {
+                track.running = status;
+                let channel = status & 0x0F;
+                let data1 = match data1 {
+                    Some(d) => d,
+                    None => track.byte(src)?,
+                } & 0x7F;
+                let kind = status & 0xF0;
+                // program change and channel pressure have one data byte, the rest two
+                let data2 = if kind == 0xC0 || kind == 0xD0 {
+                    0
+                } else {
+                    track.byte(src)? & 0x7F
+                };
+                match kind {
+                    0x80 => Some(Event::NoteOff { channel, note: data1 }),
+                    0x90 if data2 == 0 => Some(Event::NoteOff { channel, note: data1 }),
+                    0x90 => Some(Event::NoteOn {
+                        channel,
+                        note: data1,
+                        velocity: data2,
+                    }),
+                    0xB0 => Some(Event::Controller {
+                        channel,
+                        controller: data1,
+                        value: data2,
+                    }),
+                    0xC0 => Some(Event::Program { channel, program: data1 }),
+                    0xE0 => Some(Event::PitchBend {
+                        channel,
+                        value: (((data2 as i16) << 7) | data1 as i16) - 8192,
+                    }),
+                    // aftertouch and channel pressure
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// A synth patch for a General MIDI program, by the family it's in.
+pub fn gm_patch(program: u8) -> Patch {
+    let [piano, organ, lead, pad, bass, _] = PRESETS.map(|(_, p)| p);
+    let patch = match program / 8 {
+        // piano, chromatic percussion, guitar, ethnic
+        0 | 1 | 3 | 13 => piano,
+        // organ, pipe
+        2 | 9 => organ,
+        4 => bass,
+        // strings, ensemble, synth pad, synth effects
+        5 | 6 | 11 | 12 => pad,
+        // brass, reed, synth lead
+        7 | 8 | 10 => lead,
+        // percussive and sound effects
+        _ => Patch {
+            wave: Wave::Noise,
+            ..piano
+        },
+    };
+    // songs play a lot of notes at once
+    Patch {
+        volume: patch.volume * 0.6,
+        ..patch
+    }
+}
+
+/// The note to play and the patch for a drum on channel 10.
+pub fn gm_drum(note: u8) -> (u8, Patch) {
+    let drum = Patch {
+        wave: Wave::Noise,
+        attack_ms: 1,
+        decay_ms: 80,
+        sustain: 0.0,
+        release_ms: 80,
+        filter: FilterKind::BandPass,
+        cutoff_hz: 3000,
+        resonance: 0.2,
+        volume: 0.3,
+    };
+    let tom = Patch {
+        wave: Wave::Sine,
+        decay_ms: 200,
+        filter: FilterKind::Off,
+        volume: 0.4,
+        ..drum
+    };
+    match note {
+        // bass drums, a low sine that dies away fast
+        35 | 36 => (33, Patch { decay_ms: 150, ..tom }),
+        // snares and claps
+        37..=40 => (note, Patch { decay_ms: 150, cutoff_hz: 2000, ..drum }),
+        // closed and pedal hi-hats
+        42 | 44 => (note, Patch { decay_ms: 40, filter: FilterKind::HighPass, cutoff_hz: 7000, ..drum }),
+        46 => (note, Patch { decay_ms: 250, filter: FilterKind::HighPass, cutoff_hz: 7000, ..drum }),
+        41 | 43 | 45 | 47 | 48 | 50 => (note, tom),
+        // cymbals
+        49 | 51 | 52 | 53 | 55 | 57 | 59 => (
+            note,
+            Patch {
+                decay_ms: 700,
+                filter: FilterKind::HighPass,
+                cutoff_hz: 5000,
+                volume: 0.2,
+                ..drum
+            },
+        ),
+        _ => (note, drum),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    program: u8,
+    volume: u8,
+    expression: u8,
+    pedal: bool,
+    // notes let go of while the pedal was down
+    pedalled: Vec<u8>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel {
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pedal: false,
+            pedalled: Vec::new(),
+        }
+    }
+}
+
+/// Plays a MIDI file through a synth.
+pub struct MidiPlayer {
+    pub info: MidiInfo,
+    pub synth: Synth,
+    seq: Sequencer,
+    // the next event, read but not yet due
+    pending: Option<(u64, Event)>,
+    ended: bool,
+    // frames rendered since the start
+    frame: u64,
+    channels: Vec<Channel>,
+    // where to go the next time it's rendered
+    seek_to: Option<u64>,
+}
+
+impl MidiPlayer {
+    pub fn new(info: MidiInfo, sample_rate: u32) -> Self {
+        MidiPlayer {
+            seq: Sequencer::new(&info),
+            info,
+            synth: Synth::new(sample_rate, POLYPHONY),
+            pending: None,
+            ended: false,
+            frame: 0,
+            channels: vec![Channel::default(); 16],
+            seek_to: None,
+        }
+    }
+
+    pub fn position_ms(&self) -> u32 {
+        match self.seek_to {
+            Some(us) => (us / 1000) as u32,
+            None => (self.frame * 1000 / self.synth.sample_rate as u64) as u32,
+        }
+    }
+
+    /// True once the last event has played and the notes have died away.
+    pub fn is_finished(&self) -> bool {
+        self.ended && self.synth.active() == 0 && self.seek_to.is_none()
+    }
+
+    /// Goes to `ms` from the start. It happens on the next `render`, which runs through the
+    /// events before that point to set up the instruments, without playing the notes.
+    pub fn seek(&mut self, ms: u32) {
+        self.seek_to = Some(ms as u64 * 1000);
+    }
+
+    fn restart(&mut self) {
+        self.seq = Sequencer::new(&self.info);
+        self.pending = None;
+        self.ended = false;
+        self.frame = 0;
+        self.synth.silence();
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            *channel = Channel::default();
+            self.synth.pitch_bend(i as u8, 0.0);
+        }
+    }
+
+    fn frame_at(&self, us: u64) -> u64 {
+        us * self.synth.sample_rate as u64 / 1_000_000
+    }
+
+    /// Renders `out.len()` mono samples. Returns false once the song has finished.
+    pub fn render(&mut self, src: &mut impl TextSource, out: &mut [i16]) -> bool {
+        if let Some(target) = self.seek_to.take() {
+            self.fast_forward(src, target);
+        }
+        let mut done = 0;
+        while done < out.len() {
+            if self.pending.is_none() && !self.ended {
+                self.pending = self.seq.next_event(src);
+                self.ended = self.pending.is_none();
+            }
+            let n = match self.pending {
+                Some((us, event)) if self.frame_at(us) <= self.frame => {
+                    self.pending = None;
+                    self.apply(event, true);
+                    continue;
+                }
+                Some((us, _)) => (self.frame_at(us) - self.frame).min((out.len() - done) as u64) as usize,
+                None => out.len() - done,
+            };
+            self.synth.render(&mut out[done..done + n]);
+            done += n;
+            self.frame += n as u64;
+        }
+        !self.is_finished()
+    }
+
+    // starts again and goes through the events before `target` without the notes
+    fn fast_forward(&mut self, src: &mut impl TextSource, target: u64) {
+        self.restart();
+        while let Some((us, event)) = self.seq.next_event(src) {
+            if us >= target {
+                self.pending = Some((us, event));
+                break;
+            }
+            self.apply(event, false);
+        }
+        self.ended = self.pending.is_none();
+        self.frame = self.frame_at(target);
+    }
+
+    fn apply(&mut self, event: Event, play: bool) {
+        match event {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if play => {
+                let state = &self.channels[channel as usize];
+                let velocity =
+                    (velocity as u32 * state.volume as u32 * state.expression as u32 / (127 * 127)).max(1) as u8;
+                if channel == DRUM_CHANNEL {
+                    let (note, patch) = gm_drum(note);
+                    self.synth.note_on_patch(channel, note, velocity, &patch);
+                } else {
+                    let patch = gm_patch(state.program);
+                    self.synth.note_on_patch(channel, note, velocity, &patch);
+                }
+            }
+            Event::NoteOff { channel, note } if play => {
+                let state = &mut self.channels[channel as usize];
+                if channel == DRUM_CHANNEL {
+                    // drums die away by themselves
+                } else if state.pedal {
+                    state.pedalled.push(note);
+                } else {
+                    self.synth.note_off_channel(channel, note);
+                }
+            }
+            Event::Program { channel, program } => self.channels[channel as usize].program = program,
+            Event::PitchBend { channel, value } => {
+                self.synth.pitch_bend(channel, value as f32 / 8192.0 * BEND_RANGE);
+            }
+            Event::Controller {
+                channel,
+                controller,
+                value,
+            } => {
+                let state = &mut self.channels[channel as usize];
+                match controller {
+                    7 => state.volume = value,
+                    11 => state.expression = value,
+                    64 => {
+                        state.pedal = value >= 64;
+                        if !state.pedal {
+                            for note in state.pedalled.drain(..) {
+                                self.synth.note_off_channel(channel, note);
+                            }
+                        }
+                    }
+                    // all sound off and all notes off
+                    120 | 123 => {
+                        state.pedalled.clear();
+                        self.synth.channel_off(channel);
+                    }
+                    121 => {
+                        let program = state.program;
+                        *state = Channel {
+                            program,
+                            ..Channel::default()
+                        };
+                        self.synth.pitch_bend(channel, 0.0);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable_length(mut n: u32, out: &mut Vec<u8>) {
+        let mut bytes = vec![(n & 0x7F) as u8];
+        n >>= 7;
+        while n > 0 {
+            bytes.push((n & 0x7F) as u8 | 0x80);
+            n >>= 7;
+        }
+        bytes.reverse();
+        out.extend_from_slice(&bytes);
+    }
+
+    fn track(events: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (delta, bytes) in events {
+            variable_length(*delta, &mut body);
+            body.extend_from_slice(bytes);
+        }
+        let mut out = b"MTrk".to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn header(format: u16, tracks: u16, division: u16) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend_from_slice(&6u32.to_be_bytes());
+        out.extend_from_slice(&format.to_be_bytes());
+        out.extend_from_slice(&tracks.to_be_bytes());
+        out.extend_from_slice(&division.to_be_bytes());
+        out
+    }
+
+    // format 1 at 480 ticks a quarter: a tempo track, a tune and drums. 120 beats a minute
+    // for 2 beats (1s), then 60 for the last beat (1s).
+    fn song() -> Vec<u8> {
+        let mut file = header(1, 3, 480);
+        file.extend(track(&[
+            (0, b"\xFF\x03\x09Test Song"),
+            (0, b"\xFF\x51\x03\x07\xA1\x20"),
+            (0, b"\xF0\x03\x7E\x09\xF7"),
+            (960, b"\xFF\x51\x03\x0F\x42\x40"),
+            (0, b"\xFF\x2F\x00"),
+        ]));
+        // C for a beat, E for a beat, G for the last beat, most of it with running status
+        file.extend(track(&[
+            (0, b"\xC0\x00"),
+            (0, b"\x90\x3C\x64"),
+            // a note on with velocity 0 is a note off
+            (480, b"\x3C\x00"),
+            (0, b"\x40\x64"),
+            (480, b"\x80\x40\x40"),
+            (0, b"\xB0\x07\x7F"),
+            (0, b"\x90\x43\x64"),
+            (480, b"\x43\x00"),
+            (0, b"\xFF\x2F\x00"),
+        ]));
+        // a kick and a hi-hat on channel 10, and a bend up a semitone
+        file.extend(track(&[
+            (0, b"\x99\x24\x7F"),
+            (240, b"\x89\x24\x00"),
+            (240, b"\x99\x2A\x64"),
+            (0, b"\xE0\x00\x60"),
+            (0, b"\xFF\x2F\x00"),
+        ]));
+        file
+    }
+
+    fn sequence(data: &[u8]) -> (MidiInfo, Vec<(u64, Event)>) {
+        let info = parse(&mut &data[..]).unwrap();
+        let mut sequencer = Sequencer::new(&info);
+        let mut events = Vec::new();
+        while let Some(event) = sequencer.next_event(&mut &data[..]) {
+            events.push(event);
+        }
+        // merged in time order
+        assert!(events.windows(2).all(|w| w[0].0 <= w[1].0));
+        (info, events)
+    }
+
+    fn peak(samples: &[i16]) -> u32 {
+        samples.iter().map(|s| s.unsigned_abs() as u32).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn format_1() {
+        let (info, events) = sequence(&song());
+        assert_eq!(info.format, 1);
+        assert_eq!(info.division, Division::PerQuarter(480));
+        assert_eq!(info.tracks.len(), 3);
+        assert_eq!(info.title.as_deref(), Some("Test Song"));
+        assert_eq!(info.duration_ms, 2000);
+
+        let notes: Vec<(u64, Event)> = events
+            .iter()
+            .copied()
+            .filter(|(_, e)| matches!(e, Event::NoteOn { .. } | Event::NoteOff { .. }))
+            .collect();
+        assert_eq!(
+            notes,
+            [
+                (0, Event::NoteOn { channel: 0, note: 60, velocity: 100 }),
+                (0, Event::NoteOn { channel: 9, note: 36, velocity: 127 }),
+                (250_000, Event::NoteOff { channel: 9, note: 36 }),
+                (500_000, Event::NoteOff { channel: 0, note: 60 }),
+                (500_000, Event::NoteOn { channel: 0, note: 64, velocity: 100 }),
+                (500_000, Event::NoteOn { channel: 9, note: 42, velocity: 100 }),
+                (1_000_000, Event::NoteOff { channel: 0, note: 64 }),
+                (1_000_000, Event::NoteOn { channel: 0, note: 67, velocity: 100 }),
+                // a beat at the new tempo is a whole second
+                (2_000_000, Event::NoteOff { channel: 0, note: 67 }),
+            ]
+        );
+        assert!(events.contains(&(0, Event::Program { channel: 0, program: 0 })));
+        assert!(events.contains(&(500_000, Event::PitchBend { channel: 0, value: 4096 })));
+        assert!(events.contains(&(1_000_000, Event::Controller { channel: 0, controller: 7, value: 127 })));
+        assert!(events.contains(&(1_000_000, Event::Tempo(1_000_000))));
+    }
+
+    #[test]
+    fn format_0() {
+        // everything in one track, with a tempo change after the first note
+        let mut file = header(0, 1, 96);
+        file.extend(track(&[
+            (0, b"\xFF\x03\x04Solo"),
+            (0, b"\x91\x45\x50"),
+            (96, b"\x45\x00"),
+            (0, b"\xFF\x51\x03\x03\xD0\x90"),
+            (0, b"\x45\x50"),
+            (192, b"\x81\x45\x00"),
+            (0, b"\xFF\x2F\x00"),
+        ]));
+        let (info, events) = sequence(&file);
+        assert_eq!((info.format, info.tracks.len()), (0, 1));
+        assert_eq!(info.title.as_deref(), Some("Solo"));
+        // a beat at 500000µs, then two at 250000µs
+        assert_eq!(info.duration_ms, 1000);
+        assert_eq!(
+            events,
+            [
+                (0, Event::NoteOn { channel: 1, note: 69, velocity: 80 }),
+                (500_000, Event::NoteOff { channel: 1, note: 69 }),
+                (500_000, Event::Tempo(250_000)),
+                (500_000, Event::NoteOn { channel: 1, note: 69, velocity: 80 }),
+                (1_000_000, Event::NoteOff { channel: 1, note: 69 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 frames a second and 40 ticks a frame is a millisecond a tick, whatever the tempo
+        let mut file = header(0, 1, 0xE728);
+        file.extend(track(&[
+            (0, b"\xFF\x51\x03\x0F\x42\x40"),
+            (0, b"\x90\x3C\x64"),
+            (1500, b"\x80\x3C\x00"),
+            (0, b"\xFF\x2F\x00"),
+        ]));
+        let (info, events) = sequence(&file);
+        assert_eq!(info.division, Division::Smpte { fps: 25, ticks: 40 });
+        assert_eq!(info.duration_ms, 1500);
+        assert_eq!(events.last(), Some(&(1_500_000, Event::NoteOff { channel: 0, note: 60 })));
+    }
+
+    #[test]
+    fn rmid_and_broken_files() {
+        let song = song();
+        let mut rmid = b"RIFF".to_vec();
+        rmid.extend_from_slice(&(song.len() as u32 + 12).to_le_bytes());
+        rmid.extend_from_slice(b"RMIDdata");
+        rmid.extend_from_slice(&(song.len() as u32).to_le_bytes());
+        rmid.extend_from_slice(&song);
+        let (info, events) = sequence(&rmid);
+        assert_eq!((info.duration_ms, info.title.as_deref()), (2000, Some("Test Song")));
+        assert_eq!(events, sequence(&song).1);
+
+        assert_eq!(parse(&mut &b"hello world, not midi"[..]), Err(MidiError::NotMidi));
+        let mut format_2 = song.clone();
+        format_2[9] = 2;
+        assert_eq!(parse(&mut &format_2[..]), Err(MidiError::Unsupported(2)));
+        assert_eq!(parse(&mut &header(1, 0, 480)[..]), Err(MidiError::NoTracks));
+        // cut off in the middle of the last track, what's there still plays
+        let (info, _) = sequence(&song[..song.len() - 20]);
+        assert!(info.duration_ms <= 2000);
+    }
+
+    #[test]
+    fn render() {
+        let data = song();
+        let rate = 44100;
+        let mut player = MidiPlayer::new(parse(&mut &data[..]).unwrap(), rate);
+        let mut pcm = vec![0i16; rate as usize * 4];
+        let mut done = 0;
+        while done < pcm.len() {
+            let more = player.render(&mut &data[..], &mut pcm[done..done + 1000]);
+            done += 1000;
+            if !more {
+                break;
+            }
+        }
+        assert!(player.is_finished());
+        // the release of the last note, not much more
+        assert!(done > rate as usize * 2 && done < rate as usize * 3, "{}", done);
+
+        // sound while the notes play, silence once they've all been let go
+        let at = |ms: usize| &pcm[ms * 44..ms * 44 + 2205];
+        assert!(peak(at(100)) > 2000);
+        assert!(peak(at(1200)) > 2000);
+        assert_eq!(peak(at(3000)), 0);
+        // the G, bent up a semitone to G#, by counting upward zero crossings over 0.5s
+        let g = &pcm[44100 + 4410..44100 + 4410 + 22050];
+        let hz = g.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count() as f32 * 2.0;
+        assert!((hz - 415.3).abs() < 8.0, "{}Hz", hz);
+    }
+
+    #[test]
+    fn seek() {
+        let data = song();
+        let rate = 44100;
+        let mut player = MidiPlayer::new(parse(&mut &data[..]).unwrap(), rate);
+        // the drums are over and the G hasn't started
+        player.seek(900);
+        assert_eq!(player.position_ms(), 900);
+        let mut out = vec![0i16; 8820];
+        player.render(&mut &data[..], &mut out);
+        assert_eq!(peak(&out[..4000]), 0);
+        assert!(peak(&out[4500..]) > 1000);
+        assert_eq!(player.position_ms(), 1100);
+        // past the end
+        player.seek(5000);
+        assert!(!player.render(&mut &data[..], &mut out));
+        assert!(player.is_finished());
+        assert_eq!(peak(&out), 0);
+    }
+
+    #[test]
+    fn general_midi() {
+        assert_eq!(gm_patch(0).wave, gm_patch(7).wave);
+        assert_ne!(gm_patch(16).wave, gm_patch(0).wave);
+        assert_eq!(gm_drum(36).0, 33);
+        assert_eq!(gm_drum(42).0, 42);
+    }
+}
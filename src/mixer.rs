@@ -0,0 +1,479 @@
+//! Playing several sounds at once.
+//!
+//! The `AudioSink` takes one stream of samples, so everything that wants to make a sound
+//! goes through a `Mixer`, which adds up its voices and hands the result to the
+//! `AudioWriter`. A voice plays one of:
+//!
+//! - a `Clip`, a sound decoded into memory, which can loop. Several voices can play the
+//!   same clip.
+//! - a stream, stereo samples pushed in as they're decoded, like background music.
+//! - anything implementing `Sound`, which makes its own samples, like a synth.
+//!
+//! Each voice has a volume and a pan. Everything is mixed in 32 bits and the sum is soft
+//! clipped: below three quarters of full scale samples pass through unchanged, above that
+//! they're bent over smoothly towards full scale instead of wrapping or clipping hard.
+//!
+//! The mixer doesn't resample, everything has to be at its sample rate already.
+//! `Clip::from_wav` and `Clip::from_qoa` resample as they load.
+
+use crate::qoa::{self, QoaError, QoaStream};
+use crate::reader::TextSource;
+use crate::resample::{Quality, Resampler};
+use crate::wav::{self, WavError, WavStream};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+// gains are Q12, so a full scale sample times a gain of a few still fits an i32 many times
+const GAIN_BITS: u32 = 12;
+// the loudest a voice can be turned up to
+const MAX_VOLUME: f32 = 4.0;
+// where soft clipping starts
+const KNEE: i32 = 24576;
+
+/// Something that makes its own samples, like a synth voice.
+pub trait Sound {
+    /// Writes `out.len()` mono samples. Returns false once the sound has finished, and
+    /// whatever it didn't write is left as silence.
+    fn render(&mut self, out: &mut [i16]) -> bool;
+}
+
+/// A sound in memory, mono or stereo, at the mixer's rate. Cloning it is cheap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clip {
+    /// interleaved when there are two channels
+    pub samples: Arc<[i16]>,
+    pub channels: u16,
+}
+
+impl Clip {
+    /// `channels` is 1 or 2.
+    pub fn new(samples: Vec<i16>, channels: u16) -> Self {
+        Clip {
+            samples: samples.into(),
+            channels: channels.clamp(1, 2),
+        }
+    }
+
+    /// Decodes a whole WAV file, resampled to `sample_rate`. Files with more than two
+    /// channels keep the first two.
+    pub fn from_wav(src: &mut impl TextSource, sample_rate: u32) -> Result<Clip, WavError> {
+        let info = wav::parse(src)?;
+        let (rate, channels) = (info.sample_rate, info.channels);
+        let mut stream = WavStream::new(info, 4096);
+        Ok(Clip::decode(rate, channels, sample_rate, |out| stream.next_chunk(src, out)))
+    }
+
+    /// The same for a QOA file.
+    pub fn from_qoa(src: &mut impl TextSource, sample_rate: u32) -> Result<Clip, QoaError> {
+        let info = qoa::parse(src)?;
+        let (rate, channels) = (info.sample_rate, info.channels);
+        let mut stream = QoaStream::new(info);
+        Ok(Clip::decode(rate, channels, sample_rate, |out| stream.next_chunk(src, out)))
+    }
+
+    // `next_chunk` decodes onto the end of a buffer until it returns false
+    fn decode(from: u32, channels: u16, to: u32, mut next_chunk: impl FnMut(&mut Vec<i16>) -> bool) -> Clip {
+        let mut resampler = Resampler::new(from, to, channels, Quality::Sinc);
+        let mut decoded = Vec::new();
+        let mut samples = Vec::new();
+        loop {
+            decoded.clear();
+            if !next_chunk(&mut decoded) {
+                break;
+            }
+            resampler.process(&decoded, &mut samples);
+        }
+        resampler.flush(&mut samples);
+        if channels > 2 {
+            let n = channels as usize;
+            samples = samples.chunks_exact(n).flat_map(|f| [f[0], f[1]]).collect();
+        }
+        Clip::new(samples, channels.min(2))
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    pub fn duration_ms(&self, sample_rate: u32) -> u32 {
+        (self.frames() as u64 * 1000 / sample_rate.max(1) as u64) as u32
+    }
+}
+
+/// Names a voice, so it can be changed or stopped while it plays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VoiceId(u32);
+
+enum Source {
+    Clip { clip: Clip, pos: usize },
+    Stream { queue: VecDeque<i16>, ended: bool },
+    Sound(Box<dyn Sound>),
+}
+
+pub struct Voice {
+    /// 1.0 is as recorded
+    pub volume: f32,
+    /// -1.0 is all left, 0.0 the middle and 1.0 all right
+    pub pan: f32,
+    /// start a clip again when it gets to the end
+    pub looping: bool,
+    id: VoiceId,
+    source: Source,
+}
+
+impl Voice {
+    pub fn id(&self) -> VoiceId {
+        self.id
+    }
+
+    // left and right gains. the middle is full volume on both sides, panning turns the
+    // other side down, so a voice in the middle comes out exactly as it went in.
+    fn gains(&self) -> (i32, i32) {
+        let volume = self.volume.clamp(0.0, MAX_VOLUME);
+        let pan = self.pan.clamp(-1.0, 1.0);
+        let unity = (1 << GAIN_BITS) as f32;
+        let left = volume * (1.0 - pan).min(1.0) * unity;
+        let right = volume * (1.0 + pan).min(1.0) * unity;
+        (left as i32, right as i32)
+    }
+}
+
+pub struct Mixer {
+    pub sample_rate: u32,
+    /// playing another sound with this many voices going stops the oldest
+    pub max_voices: usize,
+    /// samples that were soft clipped, to see if things are too loud
+    pub clipped: u32,
+    voices: Vec<Voice>,
+    next_id: u32,
+    // the sum being built, stereo
+    acc: Vec<i32>,
+    // a voice's samples for `Sound` voices
+    scratch: Vec<i16>,
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32, max_voices: usize) -> Self {
+        Mixer {
+            sample_rate,
+            max_voices: max_voices.max(1),
+            clipped: 0,
+            voices: Vec::new(),
+            next_id: 0,
+            acc: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Starts a clip playing at full volume in the middle.
+    pub fn play_clip(&mut self, clip: &Clip) -> VoiceId {
+        self.add(Source::Clip {
+            clip: clip.clone(),
+            pos: 0,
+        })
+    }
+
+    /// Starts a voice that plays stereo samples given to `push`. It plays silence when it
+    /// runs out, until `end_stream` is called.
+    pub fn play_stream(&mut self) -> VoiceId {
+        self.add(Source::Stream {
+            queue: VecDeque::new(),
+            ended: false,
+        })
+    }
+
+    pub fn play_sound(&mut self, sound: Box<dyn Sound>) -> VoiceId {
+        self.add(Source::Sound(sound))
+    }
+
+    fn add(&mut self, source: Source) -> VoiceId {
+        if self.voices.len() >= self.max_voices {
+            // the oldest voice goes, though not a stream if anything else can
+            let at = self
+                .voices
+                .iter()
+                .position(|v| !matches!(v.source, Source::Stream { .. }))
+                .unwrap_or(0);
+            self.voices.remove(at);
+        }
+        let id = VoiceId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.voices.push(Voice {
+            volume: 1.0,
+            pan: 0.0,
+            looping: false,
+            id,
+            source,
+        });
+        id
+    }
+
+    /// The voice, if it's still playing.
+    pub fn voice_mut(&mut self, id: VoiceId) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|v| v.id == id)
+    }
+
+    pub fn is_playing(&self, id: VoiceId) -> bool {
+        self.voices.iter().any(|v| v.id == id)
+    }
+
+    /// How many voices are playing.
+    pub fn active(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn stop(&mut self, id: VoiceId) {
+        self.voices.retain(|v| v.id != id);
+    }
+
+    pub fn stop_all(&mut self) {
+        self.voices.clear();
+    }
+
+    /// Adds interleaved stereo samples to a stream voice.
+    pub fn push(&mut self, id: VoiceId, samples: &[i16]) {
+        if let Some(Source::Stream { queue, .. }) = self.voice_mut(id).map(|v| &mut v.source) {
+            queue.extend(&samples[..samples.len() & !1]);
+        }
+    }
+
+    /// Frames waiting to be played by a stream voice.
+    pub fn queued(&self, id: VoiceId) -> usize {
+        match self.voices.iter().find(|v| v.id == id).map(|v| &v.source) {
+            Some(Source::Stream { queue, .. }) => queue.len() / 2,
+            _ => 0,
+        }
+    }
+
+    /// Lets a stream voice finish once what's been pushed has played.
+    pub fn end_stream(&mut self, id: VoiceId) {
+        if let Some(Source::Stream { ended, .. }) = self.voice_mut(id).map(|v| &mut v.source) {
+            *ended = true;
+        }
+    }
+
+    /// Mixes `frames` stereo frames onto the end of `out`. Voices that finish are dropped.
+    pub fn render(&mut self, frames: usize, out: &mut Vec<i16>) {
+        self.acc.clear();
+        self.acc.resize(frames * 2, 0);
+        let acc = &mut self.acc;
+        let scratch = &mut self.scratch;
+        self.voices.retain_mut(|voice| {
+            let (left, right) = voice.gains();
+            let mut mix = |i: usize, l: i16, r: i16| {
+                acc[i * 2] += (l as i32 * left) >> GAIN_BITS;
+                acc[i * 2 + 1] += (r as i32 * right) >> GAIN_BITS;
+            };
+            match &mut voice.source {
+                Source::Clip { clip, pos } => {
+                    let channels = clip.channels as usize;
+                    let len = clip.frames();
+                    let mut i = 0;
+                    while i < frames {
+                        if *pos >= len {
+                            if !voice.looping || len == 0 {
+                                return false;
+                            }
+                            *pos = 0;
+                        }
+                        let n = (frames - i).min(len - *pos);
+                        let samples = &clip.samples[*pos * channels..(*pos + n) * channels];
+                        if channels == 2 {
+                            for (k, f) in samples.chunks_exact(2).enumerate() {
+                                mix(i + k, f[0], f[1]);
+                            }
+                        } else {
+                            for (k, s) in samples.iter().enumerate() {
+                                mix(i + k, *s, *s);
+                            }
+                        }
+                        *pos += n;
+                        i += n;
+                    }
+                    voice.looping || *pos < len
+                }
+                Source::Stream { queue, ended } => {
+                    let n = frames.min(queue.len() / 2);
+                    for i in 0..n {
+                        let l = queue.pop_front().unwrap_or(0);
+                        let r = queue.pop_front().unwrap_or(0);
+                        mix(i, l, r);
+                    }
+                    !(*ended && queue.is_empty())
+                }
+                Source::Sound(sound) => {
+                    scratch.clear();
+                    scratch.resize(frames, 0);
+                    let more = sound.render(scratch);
+                    for (i, s) in scratch.iter().enumerate() {
+                        mix(i, *s, *s);
+                    }
+                    more
+                }
+            }
+        });
+        out.reserve(frames * 2);
+        for s in self.acc.iter() {
+            let (sample, clipped) = soft_clip(*s);
+            self.clipped += clipped as u32;
+            out.push(sample);
+        }
+    }
+}
+
+/// Bends samples above `KNEE` over towards full scale. The curve has the same slope as
+/// the straight part where they meet, so there's no corner to hear.
+pub fn soft_clip(sample: i32) -> (i16, bool) {
+    let over = sample.abs() - KNEE;
+    if over <= 0 {
+        return (sample as i16, false);
+    }
+    // room left above the knee, approached but never reached
+    let room = i16::MAX as i32 - KNEE;
+    let bent = KNEE as i64 + room as i64 * over as i64 / (room as i64 + over as i64);
+    (sample.signum() as i16 * bent as i16, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // one mono clip of a constant level, rendered for a frame
+    fn frame(level: i16, volume: f32, pan: f32) -> (i16, i16) {
+        let mut mixer = Mixer::new(44_100, 4);
+        let id = mixer.play_clip(&Clip::new(vec![level; 4], 1));
+        let voice = mixer.voice_mut(id).unwrap();
+        voice.volume = volume;
+        voice.pan = pan;
+        let mut out = Vec::new();
+        mixer.render(1, &mut out);
+        (out[0], out[1])
+    }
+
+    #[test]
+    fn pan_law() {
+        // the middle is unchanged on both sides, and panning turns the other side down
+        assert_eq!(frame(10_000, 1.0, 0.0), (10_000, 10_000));
+        assert_eq!(frame(10_000, 1.0, -1.0), (10_000, 0));
+        assert_eq!(frame(10_000, 1.0, 1.0), (0, 10_000));
+        assert_eq!(frame(10_000, 1.0, 0.5), (5_000, 10_000));
+        assert_eq!(frame(10_000, 1.0, -0.25), (10_000, 7_500));
+        assert_eq!(frame(-10_000, 0.5, 0.0), (-5_000, -5_000));
+        // out of range pans and volumes are held to the ends
+        assert_eq!(frame(10_000, 1.0, -3.0), (10_000, 0));
+        assert_eq!(frame(1_000, 100.0, 0.0), (4_000, 4_000));
+        assert_eq!(frame(10_000, -1.0, 0.0), (0, 0));
+    }
+
+    #[test]
+    fn soft_clipping() {
+        // below the knee samples pass through
+        for s in [0, 1, -1, 12_345, -20_000, KNEE, -KNEE] {
+            assert_eq!(soft_clip(s), (s as i16, false));
+        }
+        // above it they bend over smoothly, never getting to full scale or turning round
+        let mut last = KNEE as i16;
+        for s in (KNEE + 1..2_000_000).step_by(997) {
+            let (clipped, was) = soft_clip(s);
+            assert!(was && clipped >= last && clipped < i16::MAX, "{} -> {}", s, clipped);
+            assert_eq!(soft_clip(-s), (-clipped, true));
+            last = clipped;
+        }
+        assert!(last > 32_000);
+        // right at the knee the slope is still about one
+        assert!((soft_clip(KNEE + 100).0 as i32 - KNEE - 100).abs() <= 2);
+    }
+
+    #[test]
+    fn sums_are_clipped_not_wrapped() {
+        let mut mixer = Mixer::new(44_100, 8);
+        let loud = Clip::new(vec![30_000; 10], 1);
+        for _ in 0..4 {
+            mixer.play_clip(&loud);
+        }
+        let quiet = Clip::new(vec![-30_000, 30_000], 2);
+        mixer.play_clip(&quiet);
+        let mut out = Vec::new();
+        mixer.render(2, &mut out);
+        // four voices at 30000 add up to 120000, and the one frame of the stereo clip makes
+        // that 90000 and 150000. louder sums stay louder.
+        assert!(out.iter().all(|s| *s > KNEE as i16), "{:?}", out);
+        assert_eq!(out[2], out[3]);
+        assert!(out[0] < out[2] && out[2] < out[1]);
+        assert_eq!(mixer.clipped, 4);
+        // mixed in 32 bits, opposite voices cancel out before clipping
+        let mut mixer = Mixer::new(44_100, 8);
+        mixer.play_clip(&Clip::new(vec![20_000; 4], 1));
+        mixer.play_clip(&Clip::new(vec![20_000; 4], 1));
+        mixer.play_clip(&Clip::new(vec![-20_000; 4], 1));
+        let mut out = Vec::new();
+        mixer.render(1, &mut out);
+        assert_eq!(out, [20_000, 20_000]);
+        assert_eq!(mixer.clipped, 0);
+    }
+
+    struct Beeps(usize);
+
+    impl Sound for Beeps {
+        fn render(&mut self, out: &mut [i16]) -> bool {
+            for s in out.iter_mut().take(self.0) {
+                *s = 100;
+            }
+            self.0 = self.0.saturating_sub(out.len());
+            self.0 > 0
+        }
+    }
+
+    #[test]
+    fn voices_retire_when_they_end() {
+        let mut mixer = Mixer::new(44_100, 8);
+        let clip = Clip::new(vec![1_000; 100], 1);
+        let once = mixer.play_clip(&clip);
+        let looped = mixer.play_clip(&clip);
+        mixer.voice_mut(looped).unwrap().looping = true;
+        let stream = mixer.play_stream();
+        mixer.push(stream, &[7, 7, 7, 7, 7]);
+        let sound = mixer.play_sound(Box::new(Beeps(150)));
+        assert_eq!(mixer.active(), 4);
+        assert_eq!(mixer.queued(stream), 2);
+
+        let mut out = Vec::new();
+        mixer.render(60, &mut out);
+        assert!(mixer.is_playing(once) && mixer.is_playing(sound));
+        // a stream that runs dry plays silence until it's ended
+        assert_eq!(out[2], 2_107);
+        assert_eq!(out[4], 2_100);
+        mixer.end_stream(stream);
+        out.clear();
+        mixer.render(60, &mut out);
+        // the clip ends partway through and the rest is silence from it
+        assert!(!mixer.is_playing(once) && !mixer.is_playing(stream));
+        assert_eq!(out[39 * 2], 2_100);
+        assert_eq!(out[40 * 2], 1_100);
+        assert!(mixer.is_playing(sound));
+        out.clear();
+        mixer.render(60, &mut out);
+        assert!(!mixer.is_playing(sound));
+        assert_eq!(mixer.active(), 1);
+        assert!(mixer.is_playing(looped));
+        mixer.stop(looped);
+        assert_eq!(mixer.active(), 0);
+        out.clear();
+        mixer.render(4, &mut out);
+        assert_eq!(out, [0; 8]);
+    }
+
+    #[test]
+    fn the_oldest_voice_makes_room() {
+        let mut mixer = Mixer::new(44_100, 2);
+        let clip = Clip::new(vec![1; 100], 1);
+        let stream = mixer.play_stream();
+        let first = mixer.play_clip(&clip);
+        let second = mixer.play_clip(&clip);
+        // the stream was oldest but the first clip goes instead
+        assert!(mixer.is_playing(stream) && !mixer.is_playing(first) && mixer.is_playing(second));
+    }
+}
@@ -0,0 +1,551 @@
+//! Playing MP3 files.
+//!
+//! `parse` skips any ID3v2 tags at the start, finds the first real frame (two frames in a
+//! row with matching headers, so junk that happens to look like a sync word is passed over)
+//! and reads the Xing/Info or VBRI header when there is one. That gives the duration and
+//! seeking of variable bitrate files; without one the bitrate of the first frame is used.
+//!
+//! `read_tags` gets the title, artist and album from ID3v2 text frames, or from an ID3v1
+//! tag at the end of the file.
+//!
+//! `Mp3Stream` reads the file a buffer at a time into `nanomp3::Decoder` and turns each
+//! frame into interleaved i16 samples for the `AudioSink`.
+
+use crate::reader::TextSource;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use nanomp3::{Decoder, MAX_SAMPLES_PER_FRAME};
+
+// bytes read from the file at a time. minimp3 wants several frames to sync on and the
+// largest layer III frame is 1441 bytes.
+const BUFFER_BYTES: usize = 16 * 1024;
+// how far into the file to look for the first frame after the tags
+const MAX_SYNC_SEARCH: u32 = 64 * 1024;
+const ID3V1_LEN: u32 = 128;
+// tag text longer than this is cut short
+const MAX_TAG_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mp3Error {
+    /// no MPEG audio frames were found
+    NoFrames,
+}
+
+/// The parts of a layer III frame header needed to find the next frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// 1 for MPEG 1, 2 for MPEG 2 and 2.5
+    pub version: u8,
+    pub sample_rate: u32,
+    pub bitrate_kbps: u32,
+    pub channels: u16,
+    /// length of the whole frame in bytes, including the header
+    pub len: u32,
+    pub samples_per_frame: u32,
+}
+
+impl FrameHeader {
+    /// Reads a frame header, or None if these four bytes aren't one.
+    pub fn parse(h: &[u8]) -> Option<FrameHeader> {
+        if h.len() < 4 || h[0] != 0xFF || h[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+        let version_bits = (h[1] >> 3) & 3;
+        let layer_bits = (h[1] >> 1) & 3;
+        // only layer III, 01 is reserved for the version
+        if layer_bits != 1 || version_bits == 1 {
+            return None;
+        }
+        let bitrate_index = (h[2] >> 4) as usize;
+        let rate_index = ((h[2] >> 2) & 3) as usize;
+        // free format isn't supported and 15 is invalid
+        if bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+            return None;
+        }
+        let mpeg1 = version_bits == 3;
+        let bitrate_kbps = if mpeg1 {
+            [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320][bitrate_index]
+        } else {
+            [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160][bitrate_index]
+        };
+        let sample_rate = [44100, 48000, 32000][rate_index]
+            >> match version_bits {
+                3 => 0,
+                2 => 1,
+                _ => 2,
+            };
+        let padding = ((h[2] >> 1) & 1) as u32;
+        let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+        Some(FrameHeader {
+            version: if mpeg1 { 1 } else { 2 },
+            sample_rate,
+            bitrate_kbps,
+            channels: if h[3] >> 6 == 3 { 1 } else { 2 },
+            len: samples_per_frame / 8 * bitrate_kbps * 1000 / sample_rate + padding,
+            samples_per_frame,
+        })
+    }
+
+    // whether another header could belong to the same stream
+    fn matches(&self, other: &FrameHeader) -> bool {
+        self.version == other.version && self.sample_rate == other.sample_rate
+    }
+
+    // where a Xing or Info header would start, after the side information
+    fn xing_offset(&self) -> usize {
+        4 + match (self.version, self.channels) {
+            (1, 1) => 17,
+            (1, _) => 32,
+            (_, 1) => 9,
+            _ => 17,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mp3Info {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// bitrate of the first frame, or the average for VBR files with a header
+    pub bitrate_kbps: u32,
+    pub samples_per_frame: u32,
+    /// true when a Xing or VBRI header says the bitrate changes
+    pub vbr: bool,
+    /// number of frames, from the Xing/Info or VBRI header
+    pub frames: Option<u32>,
+    /// Xing seek table, the position in 256ths of the file at each percent of the time
+    pub toc: Option<[u8; 100]>,
+    /// the first audio frame, after the tags and any Xing frame
+    pub audio_start: u32,
+    /// the end of the audio, before an ID3v1 tag
+    pub audio_end: u32,
+}
+
+impl Mp3Info {
+    pub fn audio_len(&self) -> u32 {
+        self.audio_end - self.audio_start
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        match self.frames {
+            Some(frames) => {
+                (frames as u64 * self.samples_per_frame as u64 * 1000 / self.sample_rate as u64) as u32
+            }
+            // kbps is bits per ms
+            None => (self.audio_len() as u64 * 8 / self.bitrate_kbps.max(1) as u64) as u32,
+        }
+    }
+
+    /// Where in the file to start decoding to get to `ms`.
+    pub fn seek_offset(&self, ms: u32) -> u32 {
+        let duration = self.duration_ms().max(1);
+        let ms = ms.min(duration);
+        let len = self.audio_len() as u64;
+        let offset = match &self.toc {
+            Some(toc) => {
+                // interpolate between the entries either side
+                let percent = ms as u64 * 100 * 256 / duration as u64;
+                let i = (percent / 256) as usize;
+                let frac = percent % 256;
+                let a = toc[i.min(99)] as u64;
+                let b = if i + 1 < 100 { toc[i + 1] as u64 } else { 256 };
+                (a * 256 + (b.max(a) - a) * frac) * len / (256 * 256)
+            }
+            None => ms as u64 * len / duration as u64,
+        };
+        self.audio_start + (offset as u32).min(self.audio_len())
+    }
+}
+
+fn be_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// The length of an ID3v2 tag, including its header and footer, if `header` starts one.
+pub fn id3v2_len(header: &[u8]) -> Option<u32> {
+    if header.len() < 10 || &header[0..3] != b"ID3" {
+        return None;
+    }
+    // sizes are "sync safe", 7 bits in each byte
+    let size = syncsafe(&header[6..10]);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mp3Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+/// Reads the tags of an MP3 file. Frames other than text are skipped without reading them,
+/// so cover art doesn't cost anything.
+pub fn read_tags(src: &mut impl TextSource) -> Mp3Tags {
+    let mut tags = Mp3Tags::default();
+    let mut header = [0u8; 10];
+    if src.read_at(0, &mut header) == header.len() {
+        if let Some(len) = id3v2_len(&header) {
+            read_id3v2(src, &header, len, &mut tags);
+        }
+    }
+    if tags.title.is_none() {
+        read_id3v1(src, &mut tags);
+    }
+    tags
+}
+
+fn read_id3v2(src: &mut impl TextSource, header: &[u8], len: u32, tags: &mut Mp3Tags) {
+    let version = header[3];
+    let end = len.min(src.size());
+    let mut offset = 10;
+    if header[5] & 0x40 != 0 {
+        // skip the extended header
+        let mut size = [0u8; 4];
+        if src.read_at(offset, &mut size) < 4 {
+            return;
+        }
+        offset += match version {
+            4 => syncsafe(&size),
+            _ => u32::from_be_bytes(size) + 4,
+        };
+    }
+    // version 2 has three letter ids and three byte sizes
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    let mut frame = [0u8; 10];
+    while offset + header_len <= end {
+        if src.read_at(offset, &mut frame[..header_len as usize]) < header_len as usize || frame[0] == 0 {
+            // padding
+            return;
+        }
+        let size = match version {
+            2 => u32::from_be_bytes([0, frame[3], frame[4], frame[5]]),
+            4 => syncsafe(&frame[4..8]),
+            _ => u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]),
+        };
+        let body = offset + header_len;
+        let tag = match &frame[..id_len] {
+            b"TIT2" | b"TT2" => Some(&mut tags.title),
+            b"TPE1" | b"TP1" => Some(&mut tags.artist),
+            b"TALB" | b"TAL" => Some(&mut tags.album),
+            _ => None,
+        };
+        if let Some(tag) = tag {
+            let mut text = vec![0u8; (size as usize).min(MAX_TAG_LEN * 2 + 3)];
+            let n = src.read_at(body, &mut text);
+            text.truncate(n);
+            let text = id3_text(&text);
+            if !text.is_empty() {
+                *tag = Some(text);
+            }
+        }
+        offset = body.saturating_add(size);
+    }
+}
+
+fn syncsafe(b: &[u8]) -> u32 {
+    b[..4].iter().fold(0u32, |acc, b| (acc << 7) | (*b & 0x7F) as u32)
+}
+
+// text frames start with an encoding byte: latin 1, UTF-16 with a byte order mark,
+// UTF-16 big endian or UTF-8
+fn id3_text(data: &[u8]) -> String {
+    let Some((encoding, text)) = data.split_first() else {
+        return String::new();
+    };
+    let text: String = match encoding {
+        1 | 2 => {
+            let (little, text) = match text {
+                [0xFF, 0xFE, rest @ ..] => (true, rest),
+                [0xFE, 0xFF, rest @ ..] => (false, rest),
+                _ => (false, text),
+            };
+            let units = text.chunks_exact(2).map(|b| {
+                if little {
+                    u16::from_le_bytes([b[0], b[1]])
+                } else {
+                    u16::from_be_bytes([b[0], b[1]])
+                }
+            });
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .take_while(|c| *c != '\0')
+                .collect()
+        }
+        3 => {
+            let end = text.iter().position(|b| *b == 0).unwrap_or(text.len());
+            String::from_utf8_lossy(&text[..end]).into()
+        }
+        _ => latin1(text),
+    };
+    text.trim().chars().take(MAX_TAG_LEN).collect()
+}
+
+fn latin1(text: &[u8]) -> String {
+    text.iter().take_while(|b| **b != 0).map(|b| *b as char).collect()
+}
+
+// the old fixed size tag: "TAG", then 30 bytes each of title, artist and album
+fn read_id3v1(src: &mut impl TextSource, tags: &mut Mp3Tags) {
+    let size = src.size();
+    let mut tag = [0u8; 93];
+    if size < ID3V1_LEN || src.read_at(size - ID3V1_LEN, &mut tag) < tag.len() || &tag[..3] != b"TAG" {
+        return;
+    }
+    let field = |at: usize| {
+        let text = latin1(&tag[at..at + 30]);
+        let text = text.trim();
+        (!text.is_empty()).then(|| text.into())
+    };
+    tags.title = field(3);
+    tags.artist = tags.artist.take().or_else(|| field(33));
+    tags.album = tags.album.take().or_else(|| field(63));
+}
+
+/// Reads the header of an MP3 file.
+pub fn parse(src: &mut impl TextSource) -> Result<Mp3Info, Mp3Error> {
+    let file_len = src.size();
+    // some files have more than one tag in a row
+    let mut start = 0u32;
+    let mut header = [0u8; 10];
+    while src.read_at(start, &mut header) == header.len() {
+        match id3v2_len(&header) {
+            Some(len) => start = start.saturating_add(len),
+            None => break,
+        }
+    }
+
+    let mut end = file_len;
+    let mut tag = [0u8; 3];
+    if file_len >= start + ID3V1_LEN && src.read_at(file_len - ID3V1_LEN, &mut tag) == 3 && &tag == b"TAG" {
+        end = file_len - ID3V1_LEN;
+    }
+
+    let (offset, first) = find_frame(src, start, end).ok_or(Mp3Error::NoFrames)?;
+    let mut info = Mp3Info {
+        sample_rate: first.sample_rate,
+        channels: first.channels,
+        bitrate_kbps: first.bitrate_kbps,
+        samples_per_frame: first.samples_per_frame,
+        vbr: false,
+        frames: None,
+        toc: None,
+        audio_start: offset,
+        audio_end: end,
+    };
+
+    // a Xing, Info or VBRI header takes the place of the first frame
+    let mut frame = vec![0u8; first.len as usize];
+    let n = src.read_at(offset, &mut frame);
+    frame.truncate(n);
+    if let Some(header) = read_xing(&frame, first.xing_offset(), &mut info) {
+        info.vbr = header;
+        info.audio_start = offset + first.len;
+    } else if read_vbri(&frame, &mut info) {
+        info.vbr = true;
+        info.audio_start = offset + first.len;
+    }
+    if let (Some(frames), true) = (info.frames, info.vbr) {
+        let ms = info.duration_ms().max(1) as u64;
+        info.bitrate_kbps = (info.audio_len() as u64 * 8 / ms) as u32;
+        if frames == 0 {
+            info.frames = None;
+        }
+    }
+    Ok(info)
+}
+
+// looks for a frame header followed by another at the right distance
+fn find_frame(src: &mut impl TextSource, start: u32, end: u32) -> Option<(u32, FrameHeader)> {
+    let mut buf = vec![0u8; 4096];
+    let mut offset = start;
+    let limit = end.min(start.saturating_add(MAX_SYNC_SEARCH));
+    while offset < limit {
+        let n = src.read_at(offset, &mut buf);
+        if n < 4 {
+            return None;
+        }
+        for i in 0..n - 3 {
+            let Some(header) = FrameHeader::parse(&buf[i..]) else {
+                continue;
+            };
+            let at = offset + i as u32;
+            let next = at + header.len;
+            // the last frame in the file has nothing after it to check
+            if next + 4 > end {
+                return Some((at, header));
+            }
+            let mut h = [0u8; 4];
+            if src.read_at(next, &mut h) == 4 && FrameHeader::parse(&h).is_some_and(|h| h.matches(&header)) {
+                return Some((at, header));
+            }
+        }
+        offset += (n - 3) as u32;
+    }
+    None
+}
+
+// returns whether the header says the file is VBR ("Xing") rather than CBR ("Info")
+fn read_xing(frame: &[u8], at: usize, info: &mut Mp3Info) -> Option<bool> {
+    if frame.len() < at + 8 {
+        return None;
+    }
+    let vbr = match &frame[at..at + 4] {
+        b"Xing" => true,
+        b"Info" => false,
+        _ => return None,
+    };
+    let flags = be_u32(frame, at + 4);
+    let mut p = at + 8;
+    if flags & 1 != 0 && frame.len() >= p + 4 {
+        info.frames = Some(be_u32(frame, p));
+        p += 4;
+    }
+    if flags & 2 != 0 && frame.len() >= p + 4 {
+        let bytes = be_u32(frame, p);
+        // trust the byte count only if it fits the file
+        if bytes > 0 && bytes <= info.audio_end - info.audio_start {
+            info.audio_end = info.audio_start + bytes;
+        }
+        p += 4;
+    }
+    if flags & 4 != 0 && frame.len() >= p + 100 {
+        let mut toc = [0u8; 100];
+        toc.copy_from_slice(&frame[p..p + 100]);
+        info.toc = Some(toc);
+    }
+    Some(vbr)
+}
+
+// the Fraunhofer VBRI header is always 32 bytes after the frame header
+fn read_vbri(frame: &[u8], info: &mut Mp3Info) -> bool {
+    let at = 4 + 32;
+    if frame.len() < at + 18 || &frame[at..at + 4] != b"VBRI" {
+        return false;
+    }
+    info.frames = Some(be_u32(frame, at + 14));
+    true
+}
+
+/// Converts a decoded sample to i16, clipping anything past full scale.
+pub fn f32_to_i16(s: f32) -> i16 {
+    (s * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Decodes an MP3 file a frame at a time.
+pub struct Mp3Stream {
+    pub info: Mp3Info,
+    decoder: Decoder,
+    // bytes read from the file but not decoded yet are buf[start..end]
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    // where the next read from the file starts
+    offset: u32,
+    pcm: Box<[f32; MAX_SAMPLES_PER_FRAME]>,
+    // frames of samples given out since the start of the file
+    position: u64,
+}
+
+impl Mp3Stream {
+    pub fn new(info: Mp3Info) -> Self {
+        let offset = info.audio_start;
+        Mp3Stream {
+            info,
+            decoder: Decoder::new(),
+            buf: vec![0; BUFFER_BYTES],
+            start: 0,
+            end: 0,
+            offset,
+            pcm: Box::new([0.0; MAX_SAMPLES_PER_FRAME]),
+            position: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.offset >= self.info.audio_end && self.start == self.end
+    }
+
+    pub fn position_ms(&self) -> u32 {
+        (self.position * 1000 / self.info.sample_rate.max(1) as u64) as u32
+    }
+
+    /// Jumps to about `ms` into the file. Exact for CBR, as close as the seek table allows
+    /// for VBR.
+    pub fn seek(&mut self, ms: u32) {
+        self.offset = self.info.seek_offset(ms);
+        self.start = 0;
+        self.end = 0;
+        // the bit reservoir refers back to frames before the seek, so start fresh
+        self.decoder = Decoder::new();
+        let ms = ms.min(self.info.duration_ms());
+        self.position = ms as u64 * self.info.sample_rate as u64 / 1000;
+    }
+
+    /// Decodes the next frame onto the end of `out`, as `info.channels` interleaved
+    /// channels. Returns false at the end of the file.
+    pub fn next_frame(&mut self, src: &mut impl TextSource, out: &mut Vec<i16>) -> bool {
+        loop {
+            if self.end - self.start < self.buf.len() / 2 && self.offset < self.info.audio_end {
+                self.refill(src);
+            }
+            if self.start == self.end {
+                return false;
+            }
+            let (used, frame) = self.decoder.decode(&self.buf[self.start..self.end], &mut *self.pcm);
+            self.start += used;
+            if let Some(frame) = frame {
+                let channels = frame.channels.num() as usize;
+                let samples = frame.samples_produced * channels;
+                if samples > 0 {
+                    self.push(&self.pcm[..samples], channels, out);
+                    self.position += frame.samples_produced as u64;
+                    return true;
+                }
+            }
+            if used == 0 {
+                if self.offset >= self.info.audio_end {
+                    // a partial frame at the end of the file
+                    self.start = self.end;
+                    return false;
+                }
+                if self.end - self.start == self.buf.len() {
+                    // a whole buffer of junk, drop it and keep looking
+                    self.start = self.end;
+                }
+                self.refill(src);
+            }
+        }
+    }
+
+    fn refill(&mut self, src: &mut impl TextSource) {
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        let want = (self.buf.len() - self.end).min((self.info.audio_end - self.offset) as usize);
+        let n = src.read_at(self.offset, &mut self.buf[self.end..self.end + want]);
+        self.end += n;
+        self.offset += n as u32;
+        if n < want {
+            // the file is shorter than it said
+            self.offset = self.info.audio_end;
+        }
+    }
+
+    // converts to i16, matching the stream's channels if one frame is different
+    fn push(&self, pcm: &[f32], channels: usize, out: &mut Vec<i16>) {
+        let wanted = self.info.channels as usize;
+        for frame in pcm.chunks_exact(channels) {
+            match (channels, wanted) {
+                (1, 2) => {
+                    let s = f32_to_i16(frame[0]);
+                    out.extend([s, s]);
+                }
+                (2, 1) => out.push(f32_to_i16((frame[0] + frame[1]) * 0.5)),
+                _ => out.extend(frame.iter().map(|s| f32_to_i16(*s))),
+            }
+        }
+    }
+}
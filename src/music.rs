@@ -0,0 +1,423 @@
+//! The parts of a music player that don't touch the hardware.
+//!
+//! `Track::scan` reads the header and tags of a WAV, MP3, QOA, MIDI or MOD file for the
+//! library. `TrackDecoder` plays any of them through the same calls, MIDI files through the synth
+//! and MOD files through the tracker, which loads them whole. `Queue` keeps the play order
+//! with shuffle and repeat, and can be filled from or saved to a playlist. `Resume` is what
+//! gets saved so playing can carry on after a reboot.
+
+use crate::midi::{self, MidiPlayer};
+use crate::mp3::{self, Mp3Stream};
+use crate::playlist::{Playlist, PlaylistEntry};
+use crate::qoa::{self, QoaStream};
+use crate::reader::TextSource;
+use crate::tracker::{self, ModPlayer};
+use crate::wav::{self, WavStream};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+// bytes of a WAV file decoded at a time, about 25ms of CD audio, the same as an MP3 frame
+const WAV_CHUNK_BYTES: usize = 4096;
+// MIDI files are played at the speaker's usual rate, so there's no resampling
+const MIDI_RATE: u32 = 44_100;
+// frames of MIDI rendered at a time, about 23ms
+const MIDI_CHUNK_FRAMES: usize = 1024;
+// the same for MOD files
+const MODULE_RATE: u32 = 44_100;
+const MODULE_CHUNK_FRAMES: usize = 1024;
+/// MOD files are played from memory, so bigger ones than this are left out of the library.
+pub const MAX_MODULE_BYTES: u32 = 96 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Wav,
+    Mp3,
+    Qoa,
+    Midi,
+    Module,
+}
+
+impl TrackKind {
+    /// Picks the kind from the file extension.
+    pub fn from_name(name: &str) -> Option<TrackKind> {
+        let (_, ext) = name.rsplit_once('.')?;
+        if ext.eq_ignore_ascii_case("wav") {
+            Some(TrackKind::Wav)
+        } else if ext.eq_ignore_ascii_case("mp3") {
+            Some(TrackKind::Mp3)
+        } else if ext.eq_ignore_ascii_case("qoa") {
+            Some(TrackKind::Qoa)
+        } else if ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi") {
+            Some(TrackKind::Midi)
+        } else if ext.eq_ignore_ascii_case("mod") {
+            Some(TrackKind::Module)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub name: String,
+    /// file size in bytes
+    pub size: u32,
+    pub kind: TrackKind,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration_ms: u32,
+}
+
+impl Track {
+    /// Reads the header and tags. Returns `None` for files that can't be played.
+    pub fn scan(name: &str, src: &mut impl TextSource) -> Option<Track> {
+        let kind = TrackKind::from_name(name)?;
+        let mut track = Track {
+            name: name.into(),
+            size: src.size(),
+            kind,
+            title: None,
+            artist: None,
+            album: None,
+            duration_ms: 0,
+        };
+        match kind {
+            TrackKind::Wav => {
+                let info = wav::parse(src).ok()?;
+                track.duration_ms = info.duration_ms();
+                track.title = info.tags.title;
+                track.artist = info.tags.artist;
+                track.album = info.tags.album;
+            }
+            TrackKind::Mp3 => {
+                track.duration_ms = mp3::parse(src).ok()?.duration_ms();
+                let tags = mp3::read_tags(src);
+                track.title = tags.title;
+                track.artist = tags.artist;
+                track.album = tags.album;
+            }
+            TrackKind::Qoa => track.duration_ms = qoa::parse(src).ok()?.duration_ms(),
+            TrackKind::Midi => {
+                let info = midi::parse(src).ok()?;
+                track.duration_ms = info.duration_ms;
+                track.title = info.title;
+            }
+            TrackKind::Module => {
+                let info = tracker::parse(src).ok()?;
+                if info.memory() > MAX_MODULE_BYTES {
+                    return None;
+                }
+                track.duration_ms = info.duration_ms;
+                track.title = Some(info.title).filter(|t| !t.is_empty());
+            }
+        }
+        Some(track)
+    }
+
+    /// The title, or the file name when there's no tag.
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Decodes a WAV, MP3 or QOA file, or plays a MIDI or MOD file.
+pub enum TrackDecoder {
+    Wav(WavStream),
+    Mp3(Mp3Stream),
+    Qoa(QoaStream),
+    Midi(Box<MidiPlayer>),
+    Module(Box<ModPlayer>),
+}
+
+impl TrackDecoder {
+    pub fn open(kind: TrackKind, src: &mut impl TextSource) -> Option<TrackDecoder> {
+        match kind {
+            TrackKind::Wav => Some(TrackDecoder::Wav(WavStream::new(wav::parse(src).ok()?, WAV_CHUNK_BYTES))),
+            TrackKind::Mp3 => Some(TrackDecoder::Mp3(Mp3Stream::new(mp3::parse(src).ok()?))),
+            TrackKind::Qoa => Some(TrackDecoder::Qoa(QoaStream::new(qoa::parse(src).ok()?))),
+            TrackKind::Midi => Some(TrackDecoder::Midi(Box::new(MidiPlayer::new(midi::parse(src).ok()?, MIDI_RATE)))),
+            TrackKind::Module => {
+                let module = tracker::load(src, MAX_MODULE_BYTES).ok()?;
+                Some(TrackDecoder::Module(Box::new(ModPlayer::new(module, MODULE_RATE))))
+            }
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            TrackDecoder::Wav(s) => s.info.sample_rate,
+            TrackDecoder::Mp3(s) => s.info.sample_rate,
+            TrackDecoder::Qoa(s) => s.info.sample_rate,
+            TrackDecoder::Midi(p) => p.synth.sample_rate,
+            TrackDecoder::Module(p) => p.sample_rate,
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        match self {
+            TrackDecoder::Wav(s) => s.info.channels,
+            TrackDecoder::Mp3(s) => s.info.channels,
+            TrackDecoder::Qoa(s) => s.info.channels,
+            TrackDecoder::Midi(_) => 1,
+            TrackDecoder::Module(_) => 2,
+        }
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        match self {
+            TrackDecoder::Wav(s) => s.info.duration_ms(),
+            TrackDecoder::Mp3(s) => s.info.duration_ms(),
+            TrackDecoder::Qoa(s) => s.info.duration_ms(),
+            TrackDecoder::Midi(p) => p.info.duration_ms,
+            TrackDecoder::Module(p) => p.module.info.duration_ms,
+        }
+    }
+
+    pub fn position_ms(&self) -> u32 {
+        match self {
+            TrackDecoder::Wav(s) => (s.position() as u64 * 1000 / s.info.sample_rate.max(1) as u64) as u32,
+            TrackDecoder::Mp3(s) => s.position_ms(),
+            TrackDecoder::Qoa(s) => s.position_ms(),
+            TrackDecoder::Midi(p) => p.position_ms(),
+            TrackDecoder::Module(p) => p.position_ms(),
+        }
+    }
+
+    pub fn seek(&mut self, ms: u32) {
+        match self {
+            TrackDecoder::Wav(s) => s.seek((ms as u64 * s.info.sample_rate as u64 / 1000) as u32),
+            TrackDecoder::Mp3(s) => s.seek(ms),
+            TrackDecoder::Qoa(s) => s.seek((ms as u64 * s.info.sample_rate as u64 / 1000) as u32),
+            TrackDecoder::Midi(p) => p.seek(ms),
+            TrackDecoder::Module(p) => p.seek(ms),
+        }
+    }
+
+    /// Decodes the next piece onto the end of `out`. Returns false at the end.
+    pub fn next_chunk(&mut self, src: &mut impl TextSource, out: &mut Vec<i16>) -> bool {
+        match self {
+            TrackDecoder::Wav(s) => s.next_chunk(src, out),
+            TrackDecoder::Mp3(s) => s.next_frame(src, out),
+            TrackDecoder::Qoa(s) => s.next_chunk(src, out),
+            TrackDecoder::Midi(p) => {
+                if p.is_finished() {
+                    return false;
+                }
+                let start = out.len();
+                out.resize(start + MIDI_CHUNK_FRAMES, 0);
+                p.render(src, &mut out[start..]);
+                true
+            }
+            TrackDecoder::Module(p) => {
+                if p.is_finished() {
+                    return false;
+                }
+                let start = out.len();
+                out.resize(start + MODULE_CHUNK_FRAMES * 2, 0);
+                p.render(&mut out[start..]);
+                true
+            }
+        }
+    }
+}
+
+/// Turns `channels` interleaved channels into stereo on the end of `out`. Mono is copied
+/// to both sides and anything past two channels is dropped.
+pub fn to_stereo(samples: &[i16], channels: u16, out: &mut Vec<i16>) {
+    match channels {
+        2 => out.extend_from_slice(samples),
+        1 => out.extend(samples.iter().flat_map(|s| [*s, *s])),
+        n => out.extend(samples.chunks_exact(n.max(1) as usize).flat_map(|f| [f[0], f[1]])),
+    }
+}
+
+/// Minutes and seconds, like 3:07.
+pub fn format_time(ms: u32) -> String {
+    let seconds = ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Repeat {
+    #[default]
+    Off,
+    /// start the queue again after the last track
+    All,
+    /// play the same track over and over
+    One,
+}
+
+impl Repeat {
+    pub fn next(self) -> Repeat {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+/// The order tracks play in, as indexes into the library.
+pub struct Queue {
+    pub order: Vec<usize>,
+    /// where in `order` the current track is
+    pub pos: usize,
+    pub shuffle: bool,
+    pub repeat: Repeat,
+    // the order to go back to when shuffle is turned off
+    unshuffled: Vec<usize>,
+    rng: u32,
+}
+
+impl Queue {
+    /// A queue of `len` tracks in library order. The seed is used for shuffling.
+    pub fn new(len: usize, seed: u32) -> Self {
+        Queue {
+            order: (0..len).collect(),
+            unshuffled: (0..len).collect(),
+            pos: 0,
+            shuffle: false,
+            repeat: Repeat::Off,
+            rng: seed | 1,
+        }
+    }
+
+    /// A queue of the given library tracks, in that order.
+    pub fn from_order(order: Vec<usize>, seed: u32) -> Self {
+        Queue {
+            unshuffled: order.clone(),
+            order,
+            ..Queue::new(0, seed)
+        }
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.order.get(self.pos).copied()
+    }
+
+    /// Makes a track from the library the current one.
+    pub fn play(&mut self, track: usize) {
+        if let Some(pos) = self.order.iter().position(|t| *t == track) {
+            self.pos = pos;
+        }
+    }
+
+    /// Moves on when a track finishes, or when `skip` is pressed. Repeating one track only
+    /// applies to finishing, skipping still moves on and wraps around. Returns `None` when
+    /// the last track finishes without repeat.
+    pub fn next(&mut self, skip: bool) -> Option<usize> {
+        if self.order.is_empty() {
+            return None;
+        }
+        if !skip && self.repeat == Repeat::One {
+            return self.current();
+        }
+        if self.pos + 1 < self.order.len() {
+            self.pos += 1;
+        } else if self.repeat != Repeat::Off || skip {
+            // wrap around, with a fresh order when shuffling
+            if self.shuffle {
+                let current = self.current();
+                self.shuffle_order(current);
+                self.pos = (self.order.len() > 1) as usize;
+            } else {
+                self.pos = 0;
+            }
+        } else {
+            return None;
+        }
+        self.current()
+    }
+
+    pub fn previous(&mut self) -> Option<usize> {
+        if self.pos > 0 {
+            self.pos -= 1;
+        } else if !self.order.is_empty() {
+            self.pos = self.order.len() - 1;
+        }
+        self.current()
+    }
+
+    /// Turns shuffle on or off, keeping the current track playing.
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        let current = self.current();
+        self.shuffle = shuffle;
+        if shuffle {
+            self.shuffle_order(current);
+            self.pos = 0;
+        } else {
+            self.order = self.unshuffled.clone();
+            self.pos = current.and_then(|c| self.order.iter().position(|t| *t == c)).unwrap_or(0);
+        }
+    }
+
+    // a random order with `first` at the start
+    fn shuffle_order(&mut self, first: Option<usize>) {
+        for i in (1..self.order.len()).rev() {
+            let j = (self.random() % (i as u32 + 1)) as usize;
+            self.order.swap(i, j);
+        }
+        if let Some(first) = first {
+            if let Some(at) = self.order.iter().position(|t| *t == first) {
+                self.order.swap(0, at);
+            }
+        }
+    }
+
+    // xorshift, plenty for shuffling songs
+    fn random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+}
+
+/// The library tracks a playlist lists, in order. Only files in the root of the card are
+/// in the library, so entries are matched on their file name alone and `MUSIC/SONG.MP3`
+/// plays `SONG.MP3`. Entries without a file of that name are left out.
+pub fn playlist_tracks(library: &[Track], playlist: &Playlist) -> Vec<usize> {
+    playlist
+        .entries
+        .iter()
+        .filter_map(|entry| library.iter().position(|t| t.name.eq_ignore_ascii_case(entry.file_name())))
+        .collect()
+}
+
+/// A playlist of library tracks, with their titles and lengths.
+pub fn tracks_playlist(library: &[Track], tracks: &[usize]) -> Playlist {
+    let entries = tracks
+        .iter()
+        .map(|i| {
+            let track = &library[*i];
+            let title = match &track.artist {
+                Some(artist) => format!("{} - {}", artist, track.title()),
+                None => track.title().into(),
+            };
+            PlaylistEntry {
+                path: track.name.clone(),
+                title: Some(title),
+                duration_secs: Some(track.duration_ms.div_ceil(1000)),
+            }
+        })
+        .collect();
+    Playlist { entries }
+}
+
+/// Saved to flash so the player can carry on where it left off.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resume {
+    /// file name of the track that was playing
+    pub name: String,
+    pub position_ms: u32,
+    pub shuffle: bool,
+    pub repeat: Repeat,
+    /// the playlist the queue came from, or `None` for the whole library
+    pub playlist: Option<String>,
+}
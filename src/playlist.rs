@@ -0,0 +1,415 @@
+//! M3U, M3U8 and PLS playlists.
+//!
+//! Both kinds are text files listing paths to songs, optionally with a title and length
+//! for each. Relative paths are relative to the folder the playlist is in, so they're
+//! resolved against it while parsing. Windows style `\` separators are turned into `/`.
+//! Paths that are URLs are kept as they are.
+//!
+//! `.m3u` files are often Latin 1 rather than UTF-8; they're read as UTF-8 when they can be
+//! and Latin 1 otherwise. `.m3u8` and `.pls` files are always written as UTF-8.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// `.m3u` and `.m3u8`, extended with `#EXTINF` lines when writing
+    M3u,
+    /// `.pls`
+    Pls,
+}
+
+impl PlaylistFormat {
+    /// Picks the format from the file extension.
+    pub fn from_name(name: &str) -> Option<PlaylistFormat> {
+        let (_, ext) = name.rsplit_once('.')?;
+        if ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8") {
+            Some(PlaylistFormat::M3u)
+        } else if ext.eq_ignore_ascii_case("pls") {
+            Some(PlaylistFormat::Pls)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    /// the path from the root of the card, or a URL
+    pub path: String,
+    pub title: Option<String>,
+    /// length in seconds, when the playlist says
+    pub duration_secs: Option<u32>,
+}
+
+impl PlaylistEntry {
+    pub fn new(path: &str) -> Self {
+        PlaylistEntry {
+            path: path.into(),
+            title: None,
+            duration_secs: None,
+        }
+    }
+
+    /// The last part of the path.
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Playlist {
+    pub entries: Vec<PlaylistEntry>,
+}
+
+impl Playlist {
+    /// Reads a playlist from the file `path`, which relative entries are resolved against.
+    pub fn parse(path: &str, data: &[u8]) -> Option<Playlist> {
+        let format = PlaylistFormat::from_name(path)?;
+        let text = decode_text(data);
+        let dir = dir_of(path);
+        Some(match format {
+            PlaylistFormat::M3u => parse_m3u(&text, dir),
+            PlaylistFormat::Pls => parse_pls(&text, dir),
+        })
+    }
+
+    /// Writes the playlist in the format for `path`, with entries relative to its folder
+    /// when they're inside it.
+    pub fn write(&self, path: &str) -> Option<String> {
+        let format = PlaylistFormat::from_name(path)?;
+        let dir = dir_of(path);
+        Some(match format {
+            PlaylistFormat::M3u => self.write_m3u(dir),
+            PlaylistFormat::Pls => self.write_pls(dir),
+        })
+    }
+
+    fn write_m3u(&self, dir: &str) -> String {
+        let mut out = String::from("#EXTM3U\r\n");
+        for entry in &self.entries {
+            if entry.title.is_some() || entry.duration_secs.is_some() {
+                // -1 is the usual way of saying the length isn't known
+                let secs = entry.duration_secs.map(|s| s as i64).unwrap_or(-1);
+                let _ = write!(out, "#EXTINF:{},{}\r\n", secs, entry.title.as_deref().unwrap_or(""));
+            }
+            let _ = write!(out, "{}\r\n", relative_to(dir, &entry.path));
+        }
+        out
+    }
+
+    fn write_pls(&self, dir: &str) -> String {
+        let mut out = String::from("[playlist]\r\n");
+        for (i, entry) in self.entries.iter().enumerate() {
+            let n = i + 1;
+            let _ = write!(out, "File{}={}\r\n", n, relative_to(dir, &entry.path));
+            if let Some(title) = &entry.title {
+                let _ = write!(out, "Title{}={}\r\n", n, title);
+            }
+            if let Some(secs) = entry.duration_secs {
+                let _ = write!(out, "Length{}={}\r\n", n, secs);
+            }
+        }
+        let _ = write!(out, "NumberOfEntries={}\r\nVersion=2\r\n", self.entries.len());
+        out
+    }
+}
+
+// UTF-8 if it is, Latin 1 if not, without a byte order mark either way
+fn decode_text(data: &[u8]) -> String {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    match core::str::from_utf8(data) {
+        Ok(text) => text.into(),
+        Err(_) => data.iter().map(|b| *b as char).collect(),
+    }
+}
+
+fn parse_m3u(text: &str, dir: &str) -> Playlist {
+    let mut entries = Vec::new();
+    // an #EXTINF line describes the path on the line after it
+    let mut info: Option<(Option<u32>, Option<String>)> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("#EXTINF:") {
+            let (secs, title) = rest.split_once(',').unwrap_or((rest, ""));
+            // there can be attributes after the length, like tvg-id="..."
+            let secs = secs.split_whitespace().next().and_then(|s| s.parse::<i64>().ok());
+            let title = title.trim();
+            info = Some((
+                secs.filter(|s| *s >= 0).map(|s| s as u32),
+                (!title.is_empty()).then(|| title.into()),
+            ));
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else {
+            let (duration_secs, title) = info.take().unwrap_or((None, None));
+            entries.push(PlaylistEntry {
+                path: resolve(dir, line),
+                title,
+                duration_secs,
+            });
+        }
+    }
+    Playlist { entries }
+}
+
+fn parse_pls(text: &str, dir: &str) -> Playlist {
+    // entries are numbered and the keys can come in any order
+    let mut numbered: Vec<(u32, PlaylistEntry)> = Vec::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim();
+        let value = value.trim();
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (name, number) = key.split_at(split);
+        let Ok(number) = number.parse::<u32>() else {
+            continue;
+        };
+        let at = match numbered.iter().position(|(n, _)| *n == number) {
+            Some(at) => at,
+            None => {
+                numbered.push((number, PlaylistEntry::default()));
+                numbered.len() - 1
+            }
+        };
+        let entry = &mut numbered[at].1;
+        if name.eq_ignore_ascii_case("file") {
+            entry.path = resolve(dir, value);
+        } else if name.eq_ignore_ascii_case("title") {
+            entry.title = (!value.is_empty()).then(|| value.into());
+        } else if name.eq_ignore_ascii_case("length") {
+            entry.duration_secs = value.parse::<i64>().ok().filter(|s| *s >= 0).map(|s| s as u32);
+        }
+    }
+    numbered.sort_by_key(|(n, _)| *n);
+    Playlist {
+        entries: numbered
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| !entry.path.is_empty())
+            .collect(),
+    }
+}
+
+fn is_url(path: &str) -> bool {
+    path.find("://").is_some_and(|at| at > 0 && path[..at].chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+/// The folder a file is in, without a trailing `/`. Empty for the root.
+pub fn dir_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(at) => &path[..at],
+        None => "",
+    }
+}
+
+/// Resolves a path from a playlist against the playlist's folder, giving a path from the
+/// root without a leading `/`, `.` or `..`.
+pub fn resolve(dir: &str, path: &str) -> String {
+    if is_url(path) {
+        return path.into();
+    }
+    let path = path.replace('\\', "/");
+    // a drive letter, as in C:/Music, is as absolute as it gets on a card
+    let (absolute, path) = match path.as_bytes() {
+        [_, b':', b'/', ..] => (true, &path[3..]),
+        [b'/', ..] => (true, &path[1..]),
+        _ => (false, &path[..]),
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    if !absolute {
+        parts.extend(dir.split('/').filter(|p| !p.is_empty()));
+    }
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+// the shortest way to write `path` from inside `dir`
+fn relative_to(dir: &str, path: &str) -> String {
+    if is_url(path) {
+        return path.into();
+    }
+    let dir: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    let common = dir.iter().zip(&parts).take_while(|(a, b)| a == b).count();
+    if common < dir.len() && common == 0 {
+        // nothing in common, so go from the root
+        return format!("/{}", parts.join("/"));
+    }
+    let mut out: Vec<&str> = Vec::new();
+    out.extend(core::iter::repeat_n("..", dir.len() - common));
+    out.extend(&parts[common..]);
+    out.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn paths(playlist: &Playlist) -> Vec<&str> {
+        playlist.entries.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    fn entry(path: &str, title: Option<&str>, duration_secs: Option<u32>) -> PlaylistEntry {
+        PlaylistEntry {
+            path: path.into(),
+            title: title.map(Into::into),
+            duration_secs,
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(PlaylistFormat::from_name("MIX.M3U"), Some(PlaylistFormat::M3u));
+        assert_eq!(PlaylistFormat::from_name("lists/mix.m3u8"), Some(PlaylistFormat::M3u));
+        assert_eq!(PlaylistFormat::from_name("MIX.PLS"), Some(PlaylistFormat::Pls));
+        assert_eq!(PlaylistFormat::from_name("MIX.TXT"), None);
+        assert_eq!(PlaylistFormat::from_name("M3U"), None);
+        assert!(Playlist::parse("MIX.TXT", b"A.MP3\n").is_none());
+        assert!(Playlist::default().write("MIX.TXT").is_none());
+    }
+
+    #[test]
+    fn plain_m3u() {
+        let playlist = Playlist::parse("MIX.M3U", b"A.MP3\nsub/B.WAV\n\n# a comment\nC.QOA").unwrap();
+        assert_eq!(paths(&playlist), ["A.MP3", "sub/B.WAV", "C.QOA"]);
+        assert!(playlist.entries.iter().all(|e| e.title.is_none() && e.duration_secs.is_none()));
+    }
+
+    #[test]
+    fn extended_m3u() {
+        let text = "\u{feff}#EXTM3U\r\n#EXTINF:215,U2 - Mysterious Ways\r\nU2MYST.WAV\r\n\r\n\
+                    #EXTINF:-1 tvg-id=\"x\",Radio\r\nhttp://example.com/stream.mp3\r\n\
+                    #EXTINF:30,\r\nNOTITLE.MP3\r\nNOINFO.MP3\r\n";
+        let playlist = Playlist::parse("MUSIC/MIX.M3U", text.as_bytes()).unwrap();
+        assert_eq!(
+            playlist.entries,
+            vec![
+                entry("MUSIC/U2MYST.WAV", Some("U2 - Mysterious Ways"), Some(215)),
+                entry("http://example.com/stream.mp3", Some("Radio"), None),
+                entry("MUSIC/NOTITLE.MP3", None, Some(30)),
+                // the #EXTINF only goes with the line after it
+                entry("MUSIC/NOINFO.MP3", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn latin_1_and_utf_8() {
+        let playlist = Playlist::parse("MIX.M3U", b"#EXTINF:10,Caf\xe9\r\nCAF\xc9.MP3\r\n").unwrap();
+        assert_eq!(playlist.entries, vec![entry("CAFÉ.MP3", Some("Café"), Some(10))]);
+        let playlist = Playlist::parse("MIX.M3U8", "#EXTINF:10,Café\nCAFÉ.MP3\n".as_bytes()).unwrap();
+        assert_eq!(playlist.entries, vec![entry("CAFÉ.MP3", Some("Café"), Some(10))]);
+    }
+
+    #[test]
+    fn pls() {
+        // numbered keys in any order and case, with gaps, and a number with no file
+        let text = "[playlist]\r\nTitle2=Second\r\nFile2=B.MP3\r\nFile1=sub\\A.WAV\r\nLength1=61\r\n\
+                    Length2=-1\r\nfile10=TEN.MP3\r\nTitle5=Nothing\r\nNumberOfEntries=3\r\nVersion=2\r\n";
+        let playlist = Playlist::parse("LISTS/L.PLS", text.as_bytes()).unwrap();
+        assert_eq!(
+            playlist.entries,
+            vec![
+                entry("LISTS/sub/A.WAV", None, Some(61)),
+                entry("LISTS/B.MP3", Some("Second"), None),
+                entry("LISTS/TEN.MP3", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_m3u() {
+        let playlist = Playlist {
+            entries: vec![
+                entry("MUSIC/A.MP3", Some("Artist - A"), Some(200)),
+                entry("MUSIC/sub/B.WAV", None, None),
+                entry("OTHER/C.QOA", None, Some(5)),
+                entry("ROOT.MP3", Some("Root"), None),
+                entry("http://example.com/stream.mp3", None, None),
+            ],
+        };
+        let text = playlist.write("MUSIC/MIX.M3U").unwrap();
+        assert_eq!(
+            text,
+            "#EXTM3U\r\n#EXTINF:200,Artist - A\r\nA.MP3\r\nsub/B.WAV\r\n#EXTINF:5,\r\n/OTHER/C.QOA\r\n\
+             #EXTINF:-1,Root\r\n/ROOT.MP3\r\nhttp://example.com/stream.mp3\r\n"
+        );
+        assert_eq!(Playlist::parse("MUSIC/MIX.M3U", text.as_bytes()).unwrap(), playlist);
+    }
+
+    #[test]
+    fn write_pls() {
+        let playlist = Playlist {
+            entries: vec![
+                entry("A.MP3", Some("Artist - A"), Some(200)),
+                entry("sub/B.WAV", None, None),
+                entry("Café.MP3", Some("Café"), Some(0)),
+            ],
+        };
+        let text = playlist.write("L.PLS").unwrap();
+        assert_eq!(
+            text,
+            "[playlist]\r\nFile1=A.MP3\r\nTitle1=Artist - A\r\nLength1=200\r\nFile2=sub/B.WAV\r\n\
+             File3=Café.MP3\r\nTitle3=Café\r\nLength3=0\r\nNumberOfEntries=3\r\nVersion=2\r\n"
+        );
+        assert_eq!(Playlist::parse("L.PLS", text.as_bytes()).unwrap(), playlist);
+    }
+
+    #[test]
+    fn resolving() {
+        assert_eq!(dir_of("MIX.M3U"), "");
+        assert_eq!(dir_of("MUSIC/LISTS/MIX.M3U"), "MUSIC/LISTS");
+        assert_eq!(resolve("", "A.MP3"), "A.MP3");
+        assert_eq!(resolve("MUSIC/LISTS", "A.MP3"), "MUSIC/LISTS/A.MP3");
+        assert_eq!(resolve("MUSIC/LISTS", "./sub/./A.MP3"), "MUSIC/LISTS/sub/A.MP3");
+        assert_eq!(resolve("MUSIC/LISTS", "..\\Other\\A.MP3"), "MUSIC/Other/A.MP3");
+        assert_eq!(resolve("MUSIC/LISTS", "../../A.MP3"), "A.MP3");
+        // there's nothing above the root
+        assert_eq!(resolve("MUSIC", "../../../A.MP3"), "A.MP3");
+        assert_eq!(resolve("MUSIC/LISTS", "/A.MP3"), "A.MP3");
+        assert_eq!(resolve("MUSIC/LISTS", "C:\\Music\\A.MP3"), "Music/A.MP3");
+        assert_eq!(resolve("MUSIC", "http://example.com/a.mp3"), "http://example.com/a.mp3");
+        // a colon further in isn't a drive or a URL
+        assert_eq!(resolve("MUSIC", "a:b.mp3"), "MUSIC/a:b.mp3");
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_to("", "A.MP3"), "A.MP3");
+        assert_eq!(relative_to("", "MUSIC/A.MP3"), "MUSIC/A.MP3");
+        assert_eq!(relative_to("MUSIC", "MUSIC/sub/A.MP3"), "sub/A.MP3");
+        assert_eq!(relative_to("MUSIC/LISTS", "MUSIC/A.MP3"), "../A.MP3");
+        assert_eq!(relative_to("MUSIC/LISTS", "MUSIC/OTHER/A.MP3"), "../OTHER/A.MP3");
+        // nothing in common goes from the root rather than climbing out with ..
+        assert_eq!(relative_to("MUSIC/LISTS", "OTHER/A.MP3"), "/OTHER/A.MP3");
+        assert_eq!(relative_to("MUSIC", "A.MP3"), "/A.MP3");
+        assert_eq!(relative_to("MUSIC", "http://example.com/a.mp3"), "http://example.com/a.mp3");
+        let pairs = [("MUSIC/LISTS", "MUSIC/A.MP3"), ("MUSIC/LISTS", "OTHER/A.MP3"), ("A/B/C", "A/D/E.MP3")];
+        for (dir, path) in pairs {
+            assert_eq!(resolve(dir, &relative_to(dir, path)), path);
+        }
+    }
+
+    #[test]
+    fn file_names() {
+        // what music::playlist_tracks matches against the library
+        assert_eq!(PlaylistEntry::new("A.MP3").file_name(), "A.MP3");
+        assert_eq!(PlaylistEntry::new("MUSIC/sub/A.MP3").file_name(), "A.MP3");
+        let playlist = Playlist::parse("MUSIC/MIX.M3U", b"../Songs/B.WAV\r\n").unwrap();
+        assert_eq!(playlist.entries[0].file_name(), "B.WAV");
+    }
+}
@@ -0,0 +1,158 @@
+//! Turning the screen down when nobody is using it.
+//!
+//! `PowerManager` counts time since the last key press, trackball move or touch and steps
+//! through the power states as the timeouts pass. It doesn't touch the hardware itself;
+//! hand each new state to `Wrapper::set_power_state`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    /// full color, backlight on
+    Active,
+    /// idle mode, only 8 colors, which saves a little power while staying readable
+    Dimmed,
+    /// partial mode, only a strip of the screen is shown, like an always on clock
+    Clock,
+    /// backlight off and the panel asleep
+    Sleep,
+}
+
+/// Timeouts in milliseconds of inactivity. Each one counts from the last activity, so they
+/// should increase. `None` skips that state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerTimeouts {
+    pub dim_ms: Option<u64>,
+    pub clock_ms: Option<u64>,
+    pub sleep_ms: Option<u64>,
+}
+
+impl Default for PowerTimeouts {
+    fn default() -> Self {
+        PowerTimeouts {
+            dim_ms: Some(30_000),
+            clock_ms: None,
+            sleep_ms: Some(120_000),
+        }
+    }
+}
+
+pub struct PowerManager {
+    pub timeouts: PowerTimeouts,
+    state: PowerState,
+    last_activity_ms: u64,
+}
+
+impl PowerManager {
+    pub fn new(timeouts: PowerTimeouts, now_ms: u64) -> Self {
+        PowerManager {
+            timeouts,
+            state: PowerState::Active,
+            last_activity_ms: now_ms,
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == PowerState::Active
+    }
+
+    /// Something was pressed or touched. Returns the new state if that woke the screen.
+    pub fn activity(&mut self, now_ms: u64) -> Option<PowerState> {
+        self.last_activity_ms = now_ms;
+        self.change(PowerState::Active)
+    }
+
+    /// Call regularly. Returns the new state when a timeout has passed.
+    pub fn update(&mut self, now_ms: u64) -> Option<PowerState> {
+        let idle = now_ms.saturating_sub(self.last_activity_ms);
+        let t = self.timeouts;
+        let passed = |timeout: Option<u64>| timeout.is_some_and(|ms| idle >= ms);
+        let state = if passed(t.sleep_ms) {
+            PowerState::Sleep
+        } else if passed(t.clock_ms) {
+            PowerState::Clock
+        } else if passed(t.dim_ms) {
+            PowerState::Dimmed
+        } else {
+            PowerState::Active
+        };
+        self.change(state)
+    }
+
+    /// How long until the next timeout, for sleeping between updates.
+    pub fn next_change_ms(&self, now_ms: u64) -> Option<u64> {
+        let idle = now_ms.saturating_sub(self.last_activity_ms);
+        let t = self.timeouts;
+        [t.dim_ms, t.clock_ms, t.sleep_ms]
+            .into_iter()
+            .flatten()
+            .filter(|ms| *ms > idle)
+            .min()
+            .map(|ms| ms - idle)
+    }
+
+    fn change(&mut self, state: PowerState) -> Option<PowerState> {
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stepping_through_the_states() {
+        let timeouts = PowerTimeouts {
+            clock_ms: Some(60_000),
+            ..PowerTimeouts::default()
+        };
+        let mut power = PowerManager::new(timeouts, 1_000);
+        assert_eq!(power.update(1_000), None);
+        assert_eq!(power.next_change_ms(1_000), Some(30_000));
+        assert_eq!(power.update(30_999), None);
+        assert_eq!(power.update(31_000), Some(PowerState::Dimmed));
+        assert_eq!(power.update(31_000), None);
+        assert_eq!(power.next_change_ms(31_000), Some(30_000));
+        assert_eq!(power.update(61_000), Some(PowerState::Clock));
+        assert_eq!(power.next_change_ms(61_000), Some(60_000));
+        assert_eq!(power.update(121_000), Some(PowerState::Sleep));
+        assert_eq!(power.next_change_ms(121_000), None);
+        assert!(!power.is_active());
+        // activity wakes it up and starts the timeouts again
+        assert_eq!(power.activity(200_000), Some(PowerState::Active));
+        assert_eq!(power.activity(201_000), None);
+        assert_eq!(power.next_change_ms(201_000), Some(30_000));
+        assert_eq!(power.update(231_000), Some(PowerState::Dimmed));
+    }
+
+    #[test]
+    fn late_updates_and_skipped_states() {
+        let mut power = PowerManager::new(PowerTimeouts::default(), 0);
+        // an update that comes late goes straight to the right state
+        assert_eq!(power.update(500_000), Some(PowerState::Sleep));
+        power.activity(500_000);
+        // no clock state by default, so dimmed goes straight to sleep
+        assert_eq!(power.update(530_000), Some(PowerState::Dimmed));
+        assert_eq!(power.next_change_ms(530_000), Some(90_000));
+        assert_eq!(power.update(620_000), Some(PowerState::Sleep));
+        // a clock that goes backwards doesn't count as idle time
+        power.activity(700_000);
+        assert_eq!(power.update(10), None);
+        assert_eq!(power.next_change_ms(10), Some(30_000));
+        // and with no timeouts it stays on
+        let never = PowerTimeouts {
+            dim_ms: None,
+            clock_ms: None,
+            sleep_ms: None,
+        };
+        let mut power = PowerManager::new(never, 0);
+        assert_eq!(power.update(u64::MAX), None);
+        assert_eq!(power.next_change_ms(u64::MAX), None);
+    }
+}
@@ -0,0 +1,525 @@
+//! The Quite OK Audio format.
+//!
+//! QOA packs 16 bit samples into 3.2 bits each, a fifth of the size of a WAV, with better
+//! quality than IMA ADPCM and decoding that's just as cheap. A file is an 8 byte header
+//! followed by frames of up to 5120 samples a channel. Each frame starts with the state
+//! of a small LMS predictor for every channel, then slices of 20 samples, each one a
+//! 64 bit word with a scale factor and twenty 3 bit residuals. Frames stand alone, so
+//! seeking is working out which frame and skipping into it. The format is described at
+//! <https://qoaformat.org>.
+//!
+//! `parse` reads the header through a `TextSource`, so files play the same from the SD
+//! card or from `include_bytes!` in flash. `QoaStream` decodes a frame at a time and
+//! `encode` makes a file from samples, for the host tools and tests.
+
+use crate::reader::TextSource;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MAGIC: &[u8; 4] = b"qoaf";
+const HEADER_LEN: u32 = 8;
+const FRAME_HEADER_LEN: usize = 8;
+const SLICE_LEN: usize = 20;
+const SLICES_PER_FRAME: usize = 256;
+/// samples in each channel of a full frame
+pub const FRAME_LEN: usize = SLICE_LEN * SLICES_PER_FRAME;
+/// the most channels a file can have
+pub const MAX_CHANNELS: u16 = 8;
+// more frames than any file this size could have, when walking a streamed file's frames
+const MAX_FRAMES: u32 = 1 << 20;
+
+// 65536 / each of the 16 scalefactors, 1, 7, 21, 45 and so on up to 2048, rounded up
+const RECIPROCALS: [i32; 16] = [
+    65536, 9363, 3121, 1457, 781, 475, 311, 216, 156, 117, 90, 71, 57, 47, 39, 32,
+];
+// residuals of -8 to 8 after scaling, to the 3 bit codes
+const QUANTIZE: [u8; 17] = [7, 7, 7, 5, 5, 3, 3, 1, 0, 0, 2, 2, 4, 4, 6, 6, 6];
+// each 3 bit code, times each scalefactor: 0.75, 2.5, 4.5 and 7 up and down, rounded
+const DEQUANTIZE: [[i32; 8]; 16] = [
+    [1, -1, 3, -3, 5, -5, 7, -7],
+    [5, -5, 18, -18, 32, -32, 49, -49],
+    [16, -16, 53, -53, 95, -95, 147, -147],
+    [34, -34, 113, -113, 203, -203, 315, -315],
+    [63, -63, 210, -210, 378, -378, 588, -588],
+    [104, -104, 345, -345, 621, -621, 966, -966],
+    [158, -158, 528, -528, 950, -950, 1477, -1477],
+    [228, -228, 760, -760, 1368, -1368, 2128, -2128],
+    [316, -316, 1053, -1053, 1895, -1895, 2947, -2947],
+    [422, -422, 1405, -1405, 2529, -2529, 3934, -3934],
+    [548, -548, 1828, -1828, 3290, -3290, 5117, -5117],
+    [696, -696, 2320, -2320, 4176, -4176, 6496, -6496],
+    [868, -868, 2893, -2893, 5207, -5207, 8099, -8099],
+    [1064, -1064, 3548, -3548, 6386, -6386, 9933, -9933],
+    [1286, -1286, 4288, -4288, 7718, -7718, 12005, -12005],
+    [1536, -1536, 5120, -5120, 9216, -9216, 14336, -14336],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoaError {
+    /// doesn't start with qoaf
+    NotQoa,
+    /// no frames, or a frame header that doesn't make sense
+    BadFrame,
+    /// more channels than `MAX_CHANNELS`, or none
+    Unsupported(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QoaInfo {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// samples in each channel
+    pub frames: u32,
+}
+
+impl QoaInfo {
+    pub fn duration_ms(&self) -> u32 {
+        (self.frames as u64 * 1000 / self.sample_rate.max(1) as u64) as u32
+    }
+
+    /// Bytes in a full frame. Every frame but the last is full.
+    pub fn frame_bytes(&self) -> u32 {
+        frame_bytes(self.channels as usize, FRAME_LEN) as u32
+    }
+}
+
+fn frame_bytes(channels: usize, samples: usize) -> usize {
+    FRAME_HEADER_LEN + channels * 16 + samples.div_ceil(SLICE_LEN) * channels * 8
+}
+
+fn be_u64(data: &[u8]) -> u64 {
+    u64::from_be_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]])
+}
+
+// channels, sample rate, samples a channel and size in bytes
+fn frame_header(data: &[u8]) -> (u16, u32, usize, usize) {
+    let h = be_u64(data);
+    (
+        (h >> 56) as u16,
+        ((h >> 32) & 0xFF_FFFF) as u32,
+        ((h >> 16) & 0xFFFF) as usize,
+        (h & 0xFFFF) as usize,
+    )
+}
+
+pub fn parse(src: &mut impl TextSource) -> Result<QoaInfo, QoaError> {
+    let mut header = [0u8; 16];
+    if src.read_at(0, &mut header) < 16 || &header[0..4] != MAGIC {
+        return Err(QoaError::NotQoa);
+    }
+    let mut frames = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let (channels, sample_rate, _, _) = frame_header(&header[8..]);
+    if channels == 0 || channels > MAX_CHANNELS {
+        return Err(QoaError::Unsupported(channels));
+    }
+    if sample_rate == 0 {
+        return Err(QoaError::BadFrame);
+    }
+    if frames == 0 {
+        // a file written as a stream doesn't know its length, so add up its frames
+        let mut offset = HEADER_LEN;
+        let mut frame = [0u8; FRAME_HEADER_LEN];
+        for _ in 0..MAX_FRAMES {
+            if src.read_at(offset, &mut frame) < FRAME_HEADER_LEN {
+                break;
+            }
+            let (_, _, samples, size) = frame_header(&frame);
+            if size < FRAME_HEADER_LEN {
+                break;
+            }
+            frames += samples as u32;
+            offset += size as u32;
+        }
+        if frames == 0 {
+            return Err(QoaError::BadFrame);
+        }
+    }
+    Ok(QoaInfo {
+        channels,
+        sample_rate,
+        frames,
+    })
+}
+
+/// The predictor for one channel, 4 taps of history and weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lms {
+    pub history: [i32; 4],
+    pub weights: [i32; 4],
+}
+
+impl Default for Lms {
+    /// Where the encoder starts.
+    fn default() -> Self {
+        Lms {
+            history: [0; 4],
+            weights: [0, 0, -(1 << 13), 1 << 14],
+        }
+    }
+}
+
+impl Lms {
+    pub fn predict(&self) -> i32 {
+        // a broken file can have weights big enough to overflow an i32
+        let sum: i64 = self.history.iter().zip(&self.weights).map(|(h, w)| *h as i64 * *w as i64).sum();
+        (sum >> 13) as i32
+    }
+
+    pub fn update(&mut self, sample: i32, residual: i32) {
+        let delta = residual >> 4;
+        for (w, h) in self.weights.iter_mut().zip(&self.history) {
+            *w += if *h < 0 { -delta } else { delta };
+        }
+        self.history = [self.history[1], self.history[2], self.history[3], sample];
+    }
+
+    fn read(data: &[u8]) -> Lms {
+        let (history, weights) = (be_u64(data), be_u64(&data[8..]));
+        let tap = |v: u64, i: usize| (v >> (48 - 16 * i)) as i16 as i32;
+        Lms {
+            history: core::array::from_fn(|i| tap(history, i)),
+            weights: core::array::from_fn(|i| tap(weights, i)),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for taps in [self.history, self.weights] {
+            let v = taps.iter().fold(0u64, |v, t| (v << 16) | (*t as i16 as u16 as u64));
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+}
+
+/// Decodes one frame onto the end of `out` as interleaved samples. Returns how many
+/// samples a channel it had, or `None` if it's cut short or isn't a frame.
+pub fn decode_frame(data: &[u8], channels: u16, out: &mut Vec<i16>) -> Option<usize> {
+    if data.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    let (frame_channels, _, samples, size) = frame_header(data);
+    let channels = channels as usize;
+    if frame_channels as usize != channels
+        || samples > FRAME_LEN
+        || size > data.len()
+        || size < frame_bytes(channels, samples)
+    {
+        return None;
+    }
+    let mut lms: Vec<Lms> = (0..channels)
+        .map(|c| Lms::read(&data[FRAME_HEADER_LEN + c * 16..]))
+        .collect();
+    let slices = &data[FRAME_HEADER_LEN + channels * 16..size];
+    let start = out.len();
+    out.resize(start + samples * channels, 0);
+    let frame = &mut out[start..];
+    for (i, slice) in slices.chunks_exact(8).enumerate() {
+        let channel = i % channels;
+        let first = (i / channels) * SLICE_LEN;
+        let mut bits = be_u64(slice);
+        let dequantize = &DEQUANTIZE[(bits >> 60) as usize];
+        let lms = &mut lms[channel];
+        for s in first..(first + SLICE_LEN).min(samples) {
+            let residual = dequantize[((bits >> 57) & 7) as usize];
+            let sample = (lms.predict() + residual).clamp(i16::MIN as i32, i16::MAX as i32);
+            bits <<= 3;
+            lms.update(sample, residual);
+            frame[s * channels + channel] = sample as i16;
+        }
+    }
+    Some(samples)
+}
+
+/// Decodes a QOA file a frame at a time.
+pub struct QoaStream {
+    pub info: QoaInfo,
+    // the next frame
+    frame: u32,
+    // samples a channel to drop from the next frame after seeking into it
+    skip: u32,
+    buf: Vec<u8>,
+}
+
+impl QoaStream {
+    pub fn new(info: QoaInfo) -> Self {
+        QoaStream {
+            info,
+            frame: 0,
+            skip: 0,
+            buf: vec![0; info.frame_bytes() as usize],
+        }
+    }
+
+    fn frame_count(&self) -> u32 {
+        self.info.frames.div_ceil(FRAME_LEN as u32)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.frame_count()
+    }
+
+    /// The sample a channel the next chunk starts at.
+    pub fn position(&self) -> u32 {
+        (self.frame * FRAME_LEN as u32 + self.skip).min(self.info.frames)
+    }
+
+    pub fn position_ms(&self) -> u32 {
+        (self.position() as u64 * 1000 / self.info.sample_rate.max(1) as u64) as u32
+    }
+
+    pub fn seek(&mut self, sample: u32) {
+        let sample = sample.min(self.info.frames);
+        self.frame = sample / FRAME_LEN as u32;
+        self.skip = sample % FRAME_LEN as u32;
+    }
+
+    /// Reads and decodes the next frame onto the end of `out`. Returns false at the end.
+    pub fn next_chunk(&mut self, src: &mut impl TextSource, out: &mut Vec<i16>) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        let offset = HEADER_LEN + self.frame * self.info.frame_bytes();
+        let n = src.read_at(offset, &mut self.buf);
+        let start = out.len();
+        let Some(samples) = decode_frame(&self.buf[..n], self.info.channels, out) else {
+            // a broken or cut off file
+            self.frame = self.frame_count();
+            return false;
+        };
+        self.frame += 1;
+        let channels = self.info.channels as usize;
+        if self.skip > 0 {
+            let skip = (self.skip as usize).min(samples) * channels;
+            out.drain(start..start + skip);
+            self.skip = 0;
+        }
+        true
+    }
+}
+
+// residual / scalefactor, rounded away from 0
+fn divide(v: i32, scalefactor: usize) -> i32 {
+    let n = (v * RECIPROCALS[scalefactor] + (1 << 15)) >> 16;
+    n + (v.signum() - n.signum())
+}
+
+/// Makes a QOA file from interleaved samples. A file can't be empty, a 0 length in the
+/// header means it wasn't known when the file was written, so without a whole frame of
+/// samples there's nothing to make.
+pub fn encode(samples: &[i16], channels: u16, sample_rate: u32) -> Option<Vec<u8>> {
+    let channels = channels.clamp(1, MAX_CHANNELS) as usize;
+    let frames = samples.len() / channels;
+    if frames == 0 {
+        return None;
+    }
+    let full = frame_bytes(channels, FRAME_LEN);
+    let mut out = Vec::with_capacity(HEADER_LEN as usize + frames.div_ceil(FRAME_LEN) * full);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(frames as u32).to_be_bytes());
+    let mut lms = vec![Lms::default(); channels];
+    // where the last slice's scalefactor was, the next is usually close
+    let mut previous = vec![0usize; channels];
+    for first in (0..frames).step_by(FRAME_LEN) {
+        let len = FRAME_LEN.min(frames - first);
+        let header = ((channels as u64) << 56)
+            | ((sample_rate as u64 & 0xFF_FFFF) << 32)
+            | ((len as u64) << 16)
+            | frame_bytes(channels, len) as u64;
+        out.extend_from_slice(&header.to_be_bytes());
+        for l in &lms {
+            l.write(&mut out);
+        }
+        for slice_start in (first..first + len).step_by(SLICE_LEN) {
+            let slice_len = SLICE_LEN.min(first + len - slice_start);
+            for channel in 0..channels {
+                let slice = |i: usize| samples[(slice_start + i) * channels + channel] as i32;
+                let (bits, best, state) = encode_slice(slice, slice_len, lms[channel], previous[channel]);
+                lms[channel] = state;
+                previous[channel] = best;
+                out.extend_from_slice(&bits.to_be_bytes());
+            }
+        }
+    }
+    Some(out)
+}
+
+// tries every scalefactor on a slice and keeps the one with the least error. returns
+// the packed slice, its scalefactor and the predictor after it.
+fn encode_slice(sample: impl Fn(usize) -> i32, len: usize, start: Lms, previous: usize) -> (u64, usize, Lms) {
+    let mut best: Option<(u64, u64, usize, Lms)> = None;
+    for i in 0..16 {
+        // starting from the last one finds a good one sooner, so more are skipped early
+        let scalefactor = (previous + i) % 16;
+        let mut lms = start;
+        let mut bits = scalefactor as u64;
+        let mut error = 0u64;
+        for s in 0..len {
+            // wild weights make the predictor blow up, so count big ones against it
+            let weights: i64 = lms.weights.iter().map(|&w| w as i64 * w as i64).sum::<i64>() >> 18;
+            let penalty = (weights - 0x8FF).max(0) as u64;
+            let sample = sample(s);
+            let predicted = lms.predict();
+            let scaled = divide(sample - predicted, scalefactor).clamp(-8, 8);
+            let code = QUANTIZE[(scaled + 8) as usize];
+            let residual = DEQUANTIZE[scalefactor][code as usize];
+            let reconstructed = (predicted + residual).clamp(i16::MIN as i32, i16::MAX as i32);
+            let e = (sample - reconstructed) as i64;
+            error += (e * e) as u64 + penalty * penalty;
+            if best.as_ref().is_some_and(|b| error > b.0) {
+                break;
+            }
+            lms.update(reconstructed, residual);
+            bits = (bits << 3) | code as u64;
+        }
+        if best.as_ref().is_none_or(|b| error < b.0) {
+            bits <<= (SLICE_LEN - len) * 3;
+            best = Some((error, bits, scalefactor, lms));
+        }
+    }
+    let (_, bits, scalefactor, lms) = best.unwrap();
+    (bits, scalefactor, lms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two sines and a little noise, different in each channel
+    fn tone(frames: usize, channels: usize) -> Vec<i16> {
+        let mut seed = 1u32;
+        (0..frames * channels)
+            .map(|i| {
+                let t = (i / channels) as f64;
+                let c = (i % channels) as f64;
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = ((seed >> 16) as f64 - 32768.0) / 32768.0 * 100.0;
+                ((t * 0.031 * (1.0 + c)).sin() * 12000.0 + (t * 0.21).sin() * 4000.0 + noise) as i16
+            })
+            .collect()
+    }
+
+    fn snr_db(original: &[i16], decoded: &[i16]) -> f64 {
+        let signal: f64 = original.iter().map(|&x| (x as f64).powi(2)).sum();
+        let error: f64 = original.iter().zip(decoded).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum();
+        10.0 * (signal / error.max(1.0)).log10()
+    }
+
+    fn decode_all(data: &[u8]) -> (QoaInfo, Vec<i16>) {
+        let info = parse(&mut &data[..]).unwrap();
+        let mut stream = QoaStream::new(info);
+        let mut out = Vec::new();
+        while stream.next_chunk(&mut &data[..], &mut out) {}
+        assert!(stream.is_finished());
+        (info, out)
+    }
+
+    #[test]
+    fn dequantize_table_matches_the_spec() {
+        // scalefactor * 0.75, 2.5, 4.5 and 7, up and down, rounded away from 0. Each one
+        // checked by decoding a one sample slice with a predictor of 0.
+        let scalefactors = [1, 7, 21, 45, 84, 138, 211, 304, 421, 562, 731, 928, 1157, 1419, 1715, 2048];
+        let multipliers = [0.75f64, -0.75, 2.5, -2.5, 4.5, -4.5, 7.0, -7.0];
+        for (sf, scalefactor) in scalefactors.iter().enumerate() {
+            assert_eq!(RECIPROCALS[sf], (65536 + scalefactor - 1) / scalefactor);
+            for (code, m) in multipliers.iter().enumerate() {
+                let v = *scalefactor as f64 * m;
+                let expected = (v.abs() + 0.5).floor() * v.signum();
+                let mut frame = Vec::new();
+                let header: u64 = (1 << 56) | (44100 << 32) | (1 << 16) | frame_bytes(1, 1) as u64;
+                frame.extend_from_slice(&header.to_be_bytes());
+                frame.extend_from_slice(&[0; 16]);
+                frame.extend_from_slice(&(((sf as u64) << 60) | ((code as u64) << 57)).to_be_bytes());
+                let mut out = Vec::new();
+                assert_eq!(decode_frame(&frame, 1, &mut out), Some(1));
+                assert_eq!(out[0] as f64, expected, "scalefactor {} code {}", sf, code);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for channels in [1usize, 2] {
+            for frames in [1, 19, 20, 21, FRAME_LEN - 1, FRAME_LEN, FRAME_LEN + 1, FRAME_LEN * 2 + 1237] {
+                let input = tone(frames, channels);
+                let data = encode(&input, channels as u16, 22050).unwrap();
+                let full = frames / FRAME_LEN;
+                let last = match frames % FRAME_LEN {
+                    0 => 0,
+                    rest => frame_bytes(channels, rest),
+                };
+                assert_eq!(data.len(), 8 + full * frame_bytes(channels, FRAME_LEN) + last);
+
+                let (info, out) = decode_all(&data);
+                assert_eq!((info.channels, info.sample_rate), (channels as u16, 22050));
+                assert_eq!(info.frames as usize, frames);
+                assert_eq!(out.len(), input.len(), "{} channels, {} frames", channels, frames);
+                // the predictor takes a slice or two to learn the sound
+                let floor = match frames {
+                    f if f < SLICE_LEN => 15.0,
+                    f if f < FRAME_LEN => 30.0,
+                    _ => 45.0,
+                };
+                let snr = snr_db(&input, &out);
+                assert!(snr > floor, "{} channels, {} frames, {:.1}dB", channels, frames, snr);
+
+                // a streamed file has 0 for its length, and parse counts the frames
+                let mut streamed = data.clone();
+                streamed[4..8].fill(0);
+                assert_eq!(decode_all(&streamed), (info, out));
+            }
+        }
+    }
+
+    #[test]
+    fn silence_stays_quiet() {
+        // the smallest step is ±1, there's no 0
+        let (_, out) = decode_all(&encode(&[0; 1000], 2, 8000).unwrap());
+        assert_eq!(out.len(), 1000);
+        assert!(out.iter().all(|s| s.abs() <= 1));
+    }
+
+    #[test]
+    fn empty_has_nothing_to_encode() {
+        assert_eq!(encode(&[], 1, 8000), None);
+        // half a frame of stereo
+        assert_eq!(encode(&[1], 2, 8000), None);
+    }
+
+    #[test]
+    fn seek() {
+        let input = tone(FRAME_LEN * 3, 2);
+        let data = encode(&input, 2, 44100).unwrap();
+        let (info, all) = decode_all(&data);
+        assert_eq!(info.duration_ms(), (FRAME_LEN * 3 * 1000 / 44100) as u32);
+        let mut stream = QoaStream::new(info);
+        for at in [0u32, 1, 5119, 5120, 7000, 15359] {
+            stream.seek(at);
+            assert_eq!(stream.position(), at);
+            let mut out = Vec::new();
+            assert!(stream.next_chunk(&mut &data[..], &mut out));
+            let start = at as usize * 2;
+            assert_eq!(out[..], all[start..start + out.len()]);
+            // to the end of the frame it was in
+            assert_eq!(stream.position() as usize, (at as usize / FRAME_LEN + 1) * FRAME_LEN);
+        }
+        stream.seek(u32::MAX);
+        assert!(stream.is_finished());
+        assert!(!stream.next_chunk(&mut &data[..], &mut Vec::new()));
+    }
+
+    #[test]
+    fn broken_files() {
+        let data = encode(&tone(FRAME_LEN * 3, 2), 2, 44100).unwrap();
+        // cut off part way through the last frame, which is dropped
+        let short = &data[..data.len() - 100];
+        let mut stream = QoaStream::new(parse(&mut &short[..]).unwrap());
+        let mut out = Vec::new();
+        while stream.next_chunk(&mut &short[..], &mut out) {}
+        assert_eq!(out.len(), FRAME_LEN * 2 * 2);
+
+        assert_eq!(parse(&mut &b"RIFF0000WAVEfmt "[..]), Err(QoaError::NotQoa));
+        assert_eq!(parse(&mut &data[..12]), Err(QoaError::NotQoa));
+        let mut bad = data.clone();
+        bad[8] = 9;
+        assert_eq!(parse(&mut &bad[..]), Err(QoaError::Unsupported(9)));
+    }
+}
@@ -0,0 +1,493 @@
+//! Paging through text files too big to fit in memory.
+//!
+//! The text is read through a `TextSource` a page at a time. Lines are word wrapped to
+//! the width of a monospace font, and a page is as many lines as fit on the screen.
+//! Only the byte offset where each page starts is kept, so a file of any size can be
+//! read with a few KB of RAM. Going back a page reuses the offsets seen on the way
+//! forward; when opening a file in the middle the previous page is found by laying out
+//! the paragraph before the current position again.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+use serde::{Deserialize, Serialize};
+
+const TAB_WIDTH: usize = 4;
+
+/// Somewhere to read text from, usually a file on the SD card.
+pub trait TextSource {
+    /// length in bytes
+    fn size(&self) -> u32;
+    /// Reads bytes starting at `offset`, returning how many were read. Reading less than
+    /// `buf.len()` only happens at the end.
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize;
+}
+
+impl TextSource for &[u8] {
+    fn size(&self) -> u32 {
+        self.len() as u32
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        let start = (offset as usize).min(self.len());
+        let n = buf.len().min(self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        n
+    }
+}
+
+/// Reads a `TextSource` one line at a time through a small buffer.
+pub struct LineReader {
+    /// where the next line starts
+    pub offset: u32,
+    buf: Vec<u8>,
+    // the part of `buf` that hasn't been returned yet
+    start: usize,
+    end: usize,
+}
+
+impl LineReader {
+    /// Lines longer than `buf_size` bytes are split.
+    pub fn new(offset: u32, buf_size: usize) -> Self {
+        LineReader {
+            offset,
+            buf: vec![0; buf_size.max(8)],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// The next line without its line ending, and where it starts. Bytes that aren't
+    /// UTF-8 become U+FFFD.
+    pub fn next_line(&mut self, src: &mut impl TextSource) -> Option<(u32, String)> {
+        let mut newline = self.buf[self.start..self.end].iter().position(|&b| b == b'\n');
+        if newline.is_none() {
+            // refill, keeping what's left
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            let read_from = self.offset + self.end as u32;
+            let end = self.end;
+            self.end += src.read_at(read_from, &mut self.buf[end..]);
+            newline = self.buf[..self.end].iter().position(|&b| b == b'\n');
+        }
+        if self.start == self.end {
+            return None;
+        }
+        let (line_end, used) = match newline {
+            Some(n) => (self.start + n, n + 1),
+            None => {
+                // no newline in a full buffer, or the last line. don't split a character.
+                let mut cut = self.end;
+                if let Err(e) = core::str::from_utf8(&self.buf[self.start..self.end]) {
+                    if e.error_len().is_none() && e.valid_up_to() > 0 {
+                        cut = self.start + e.valid_up_to();
+                    }
+                }
+                (cut, cut - self.start)
+            }
+        };
+        let mut line = &self.buf[self.start..line_end];
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        let text = String::from_utf8_lossy(line).into_owned();
+        let start = self.offset;
+        self.start += used;
+        self.offset += used as u32;
+        Some((start, text))
+    }
+}
+
+/// How many characters fit on a line and how many lines fit on a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageLayout {
+    pub cols: usize,
+    pub rows: usize,
+}
+
+impl PageLayout {
+    pub fn for_font(font: &MonoFont, area: Size) -> Self {
+        let char_width = font.character_size.width + font.character_spacing;
+        PageLayout {
+            cols: ((area.width + font.character_spacing) / char_width).max(1) as usize,
+            rows: (area.height / font.character_size.height).max(1) as usize,
+        }
+    }
+
+    /// The most bytes a page can use: every character four bytes of UTF-8, plus a `\r\n`
+    /// on each line, plus one more character to see where the last line ends.
+    pub fn max_page_bytes(&self) -> usize {
+        self.rows * (self.cols * 4 + 2) + 4
+    }
+}
+
+/// Wraps the first line in `data` to `cols` characters.
+///
+/// Returns the line and how many bytes it used, including the newline or the space it
+/// was broken at. Returns `None` if `data` ends before the line does and `at_end` is
+/// false, meaning more data needs to be read first.
+pub fn wrap_line(data: &[u8], cols: usize, at_end: bool) -> Option<(String, usize)> {
+    let mut line = String::new();
+    let mut width = 0;
+    // byte position in `data` and length of `line` after the last space seen
+    let mut last_break: Option<(usize, usize)> = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let (ch, len) = match decode_char(&data[pos..]) {
+            Some(decoded) => decoded,
+            // a character cut off by the end of the buffer
+            None if !at_end => return None,
+            None => ('\u{FFFD}', data.len() - pos),
+        };
+        match ch {
+            '\n' => return Some((line, pos + len)),
+            '\r' => {}
+            '\t' => {
+                let spaces = TAB_WIDTH - width % TAB_WIDTH;
+                if width + spaces > cols {
+                    return Some((line, pos + len));
+                }
+                for _ in 0..spaces {
+                    line.push(' ');
+                }
+                width += spaces;
+                last_break = Some((pos + len, line.len()));
+            }
+            _ => {
+                if width == cols {
+                    if ch == ' ' {
+                        // the space that would have started the next line
+                        return Some((line, pos + len));
+                    }
+                    return Some(match last_break {
+                        Some((at, keep)) => {
+                            line.truncate(keep);
+                            (String::from(line.trim_end()), at)
+                        }
+                        // a word longer than the line is just cut
+                        None => (line, pos),
+                    });
+                }
+                line.push(ch);
+                width += 1;
+                if ch == ' ' {
+                    last_break = Some((pos + len, line.len()));
+                }
+            }
+        }
+        pos += len;
+    }
+    if at_end {
+        Some((line, pos))
+    } else {
+        None
+    }
+}
+
+// one UTF-8 character, or None if the buffer ends inside it. Bad bytes become U+FFFD.
+fn decode_char(data: &[u8]) -> Option<(char, usize)> {
+    let first = *data.first()?;
+    let len = match first {
+        0x00..=0x7F => return Some((first as char, 1)),
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return Some(('\u{FFFD}', 1)),
+    };
+    if data.len() < len {
+        // only incomplete if the bytes that are there are continuation bytes
+        if data[1..].iter().all(|b| b & 0xC0 == 0x80) {
+            return None;
+        }
+        return Some(('\u{FFFD}', 1));
+    }
+    match core::str::from_utf8(&data[..len]) {
+        Ok(s) => Some((s.chars().next().unwrap(), len)),
+        Err(_) => Some(('\u{FFFD}', 1)),
+    }
+}
+
+/// One screen of wrapped text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Page {
+    /// where the page starts in the file
+    pub start: u32,
+    /// where the next page starts
+    pub end: u32,
+    pub lines: Vec<String>,
+}
+
+/// Lays out the page starting at `start`.
+pub fn layout_page(src: &mut impl TextSource, start: u32, layout: PageLayout) -> Page {
+    let mut buf = vec![0u8; layout.max_page_bytes()];
+    let n = src.read_at(start, &mut buf);
+    let at_end = start as usize + n >= src.size() as usize;
+    let mut page = Page {
+        start,
+        end: start,
+        lines: Vec::with_capacity(layout.rows),
+    };
+    let mut pos = 0;
+    while page.lines.len() < layout.rows && pos < n {
+        // a line that doesn't end within the whole buffer, say thousands of `\r`s, is cut
+        // where the buffer ends so the page still moves on
+        let cut = at_end || page.lines.is_empty();
+        match wrap_line(&buf[pos..n], layout.cols, cut) {
+            Some((line, used)) => {
+                page.lines.push(line);
+                pos += used;
+            }
+            None => break,
+        }
+    }
+    page.end = start + pos as u32;
+    page
+}
+
+/// Finds where the page before the one starting at `start` begins.
+///
+/// This goes back to the start of a paragraph and wraps the text from there again to
+/// find where the lines break. If no paragraph starts within reach it starts from a
+/// space instead, so the breaks might differ slightly from reading forwards.
+pub fn previous_page_start(src: &mut impl TextSource, start: u32, layout: PageLayout) -> u32 {
+    if start == 0 {
+        return 0;
+    }
+    let from = start.saturating_sub(layout.max_page_bytes() as u32 * 2);
+    let mut buf = vec![0u8; (start - from) as usize];
+    let n = src.read_at(from, &mut buf);
+    buf.truncate(n);
+
+    let mut pos = 0;
+    if from > 0 {
+        // the newline just before the page doesn't count
+        let search = &buf[..buf.len().saturating_sub(1)];
+        pos = match search.iter().position(|&b| b == b'\n') {
+            Some(p) => p + 1,
+            None => search.iter().position(|&b| b == b' ').map(|p| p + 1).unwrap_or(0),
+        };
+    }
+
+    let mut line_starts = Vec::new();
+    while pos < buf.len() {
+        line_starts.push(pos);
+        match wrap_line(&buf[pos..], layout.cols, true) {
+            Some((_, used)) if used > 0 => pos += used,
+            _ => break,
+        }
+    }
+    let back = line_starts.len().saturating_sub(layout.rows);
+    from + line_starts.get(back).copied().unwrap_or(0) as u32
+}
+
+/// The reader's position in one file.
+pub struct Reader {
+    pub layout: PageLayout,
+    pub page: Page,
+    /// starts of the pages before this one, back to where reading started
+    history: Vec<u32>,
+    len: u32,
+}
+
+impl Reader {
+    pub fn new(layout: PageLayout) -> Self {
+        Reader {
+            layout,
+            page: Page::default(),
+            history: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Shows the page starting at `offset`, forgetting the pages read before.
+    pub fn open_at(&mut self, src: &mut impl TextSource, offset: u32) {
+        self.len = src.size();
+        self.history.clear();
+        self.page = layout_page(src, offset.min(self.len), self.layout);
+    }
+
+    pub fn is_first_page(&self) -> bool {
+        self.page.start == 0
+    }
+
+    pub fn is_last_page(&self) -> bool {
+        self.page.end >= self.len
+    }
+
+    /// Moves to the next page. Returns false if already at the end.
+    pub fn next_page(&mut self, src: &mut impl TextSource) -> bool {
+        if self.is_last_page() {
+            return false;
+        }
+        self.history.push(self.page.start);
+        self.page = layout_page(src, self.page.end, self.layout);
+        true
+    }
+
+    /// Moves to the previous page. Returns false if already at the start.
+    pub fn prev_page(&mut self, src: &mut impl TextSource) -> bool {
+        if self.is_first_page() {
+            return false;
+        }
+        let start = match self.history.pop() {
+            Some(start) => start,
+            None => previous_page_start(src, self.page.start, self.layout),
+        };
+        self.page = layout_page(src, start, self.layout);
+        true
+    }
+
+    /// How far through the file the current page is, 0-100.
+    pub fn percent(&self) -> u32 {
+        if self.len == 0 {
+            return 100;
+        }
+        (self.page.end as u64 * 100 / self.len as u64) as u32
+    }
+
+    /// Draws the page with one line of text per row, starting at the top left of `area`.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(
+        &self,
+        target: &mut D,
+        area: Rectangle,
+        style: MonoTextStyle<'_, Rgb565>,
+        background: Rgb565,
+    ) -> Result<(), D::Error> {
+        area.into_styled(PrimitiveStyle::with_fill(background)).draw(target)?;
+        let line_height = style.font.character_size.height as i32;
+        for (i, line) in self.page.lines.iter().enumerate() {
+            let p = area.top_left + Point::new(0, i as i32 * line_height);
+            Text::with_baseline(line, p, style, Baseline::Top).draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+/// The last page read in each file, most recent first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Bookmarks {
+    pub entries: Vec<(String, u32)>,
+}
+
+impl Bookmarks {
+    /// how many files are remembered
+    pub const MAX: usize = 16;
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.entries.iter().find(|(n, _)| n == name).map(|(_, offset)| *offset)
+    }
+
+    pub fn set(&mut self, name: &str, offset: u32) {
+        self.entries.retain(|(n, _)| n != name);
+        self.entries.insert(0, (String::from(name), offset));
+        self.entries.truncate(Self::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn wrap(text: &str, cols: usize) -> Vec<String> {
+        let mut data = text.as_bytes();
+        let mut lines = Vec::new();
+        while !data.is_empty() {
+            let (line, used) = wrap_line(data, cols, true).unwrap();
+            lines.push(line);
+            data = &data[used..];
+        }
+        lines
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(wrap("the quick brown fox", 10), ["the quick", "brown fox"]);
+        // the space a line breaks at is used up, other spaces are kept
+        assert_eq!(wrap("0123456789 next", 10), ["0123456789", "next"]);
+        assert_eq!(wrap("a  b", 10), ["a  b"]);
+        assert_eq!(wrap("averyveryverylongword", 10), ["averyveryv", "erylongwor", "d"]);
+        assert_eq!(wrap("one\r\ntwo\n\nthree", 10), ["one", "two", "", "three"]);
+        assert_eq!(wrap("a\tb", 10), ["a   b"]);
+        assert_eq!(wrap("ünïcödé wörds", 7), ["ünïcödé", "wörds"]);
+        assert_eq!(wrap("bad \u{FFFD}", 10), ["bad \u{FFFD}"]);
+        // without the end of the data it waits for more, including for a cut character
+        assert_eq!(wrap_line(b"no newline", 20, false), None);
+        assert_eq!(wrap_line("é".as_bytes().split_last().unwrap().1, 20, false), None);
+        assert_eq!(wrap_line(b"done\nmore", 20, false), Some((String::from("done"), 5)));
+    }
+
+    // paragraphs of different lengths with blank lines and long words
+    fn book() -> String {
+        let mut text = String::new();
+        for i in 0..60 {
+            for j in 0..(i % 7) * 5 + 1 {
+                text.push_str(&format!("word{} ", i * j));
+            }
+            if i % 11 == 0 {
+                text.push_str("supercalifragilisticexpialidocious");
+            }
+            text.push_str(if i % 3 == 0 { "\n\n" } else { "\n" });
+        }
+        text
+    }
+
+    #[test]
+    fn paging_forward_and_back() {
+        let text = book();
+        let mut src = text.as_bytes();
+        let layout = PageLayout { cols: 20, rows: 6 };
+        let mut reader = Reader::new(layout);
+        reader.open_at(&mut src, 0);
+        let mut starts = vec![reader.page.start];
+        while reader.next_page(&mut src) {
+            assert_eq!(reader.page.start, layout_page(&mut src, *starts.last().unwrap(), layout).end);
+            starts.push(reader.page.start);
+        }
+        assert!(starts.len() > 10);
+        assert!(reader.is_last_page() && reader.percent() == 100);
+        // back through the history to the same pages
+        for start in starts.iter().rev().skip(1) {
+            assert!(reader.prev_page(&mut src));
+            assert_eq!(reader.page.start, *start);
+        }
+        assert!(reader.is_first_page() && !reader.prev_page(&mut src));
+        // and opened in the middle, without the history, the breaks are found again
+        for (i, start) in starts.iter().enumerate().skip(1) {
+            reader.open_at(&mut src, *start);
+            assert!(reader.prev_page(&mut src));
+            assert_eq!(reader.page.start, starts[i - 1], "before page {}", i);
+        }
+    }
+
+    #[test]
+    fn lines_longer_than_a_page() {
+        let layout = PageLayout { cols: 10, rows: 3 };
+        // carriage returns take no room on screen, so a wrapped line can be any length
+        let mut text = String::from("x");
+        for _ in 0..layout.max_page_bytes() * 3 {
+            text.push('\r');
+        }
+        text.push_str("y\nlast line");
+        let mut src = text.as_bytes();
+        let mut reader = Reader::new(layout);
+        reader.open_at(&mut src, 0);
+        let mut pages = 1;
+        while reader.next_page(&mut src) {
+            pages += 1;
+            assert!(pages < 10, "stuck at {}", reader.page.start);
+        }
+        assert_eq!(reader.page.lines.last().map(String::as_str), Some("last line"));
+        // one long run of plain text is wrapped rather than cut
+        let text = "z".repeat(layout.max_page_bytes() * 2);
+        let page = layout_page(&mut text.as_bytes(), 0, layout);
+        assert_eq!(page.lines, ["z".repeat(10), "z".repeat(10), "z".repeat(10)]);
+        assert_eq!(page.end, 30);
+    }
+}
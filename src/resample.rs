@@ -0,0 +1,319 @@
+//! Changing the sample rate of audio as it streams.
+//!
+//! The speaker runs at one rate, but WAV and MP3 files come at 8k, 22.05k, 32k, 48k and so
+//! on. A `Resampler` sits between a decoder and the `AudioWriter`: feed it chunks of
+//! interleaved i16 samples at the file's rate and it appends samples at the sink's rate,
+//! keeping enough history between chunks that there are no clicks at the joins.
+//!
+//! `Quality::Linear` interpolates between neighbouring samples. It's cheap but dulls the
+//! top end, and going down in rate it lets everything above the new Nyquist frequency
+//! fold back as aliasing. `Quality::Sinc` uses a 16 tap Blackman windowed sinc with 128
+//! phases, interpolated between, and low passes when going down in rate. Measured on the
+//! host with a 1kHz sine, as the error compared to an ideal sine at the new rate (the
+//! `error_against_an_ideal_sine` test checks these):
+//!
+//! | from → to      | Linear | Sinc   |
+//! |----------------|--------|--------|
+//! | 8000 → 44100   | -25 dB | -66 dB |
+//! | 22050 → 44100  | -43 dB | -86 dB |
+//! | 32000 → 44100  | -49 dB | -78 dB |
+//! | 48000 → 44100  | -56 dB | -79 dB |
+//!
+//! Sinc costs 32 multiplies per output frame plus 16 per channel, against 1 per channel
+//! for linear. On the host, optimized for size like the T-Deck build, that makes it about
+//! twelve times slower, around 8 million stereo frames a second against 100 million (the
+//! `cost` test checks it stays within twenty times and over two million). At 44.1kHz
+//! stereo that should still be a small part of what the ESP32-S3 can do.
+//! Both use fixed point, so neither needs the FPU once the filter table is built.
+
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use micromath::F32Ext;
+
+// taps in the sinc filter, half before the output point and half after
+const TAPS: usize = 16;
+// positions between two input samples that the sinc filter has coefficients for
+const PHASES: usize = 128;
+// filter coefficients are Q14, so sixteen full scale samples can't overflow an i32
+const COEF_BITS: u32 = 14;
+// keeps the cutoff a little below the lower of the two Nyquist frequencies
+const ROLLOFF: f32 = 0.95;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quality {
+    Linear,
+    #[default]
+    Sinc,
+}
+
+pub struct Resampler {
+    pub from: u32,
+    pub to: u32,
+    pub quality: Quality,
+    channels: usize,
+    // input frames per output frame, 32.32 fixed point
+    step: u64,
+    // the next output frame's position in `buf`, 32.32 fixed point
+    pos: u64,
+    // input frames not yet used up, interleaved
+    buf: Vec<i16>,
+    // PHASES + 1 rows of TAPS coefficients, the last row is the next sample along
+    filter: Vec<i16>,
+}
+
+impl Resampler {
+    /// Converts `channels` interleaved channels from `from` Hz to `to` Hz.
+    pub fn new(from: u32, to: u32, channels: u16, quality: Quality) -> Self {
+        let from = from.max(1);
+        let to = to.max(1);
+        let filter = match quality {
+            Quality::Linear => Vec::new(),
+            Quality::Sinc => sinc_filter(from, to),
+        };
+        let mut resampler = Resampler {
+            from,
+            to,
+            quality,
+            channels: channels.max(1) as usize,
+            step: ((from as u64) << 32) / to as u64,
+            pos: 0,
+            buf: Vec::new(),
+            filter,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    /// True when the rates match and samples go straight through.
+    pub fn is_passthrough(&self) -> bool {
+        self.from == self.to
+    }
+
+    /// Forgets the history, for after seeking or starting a new file.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        // silence before the first sample, so the filter is centred on it
+        let before = self.before();
+        self.buf.resize(before * self.channels, 0);
+        self.pos = (before as u64) << 32;
+    }
+
+    /// Roughly how many output frames `frames` input frames turn into.
+    pub fn output_frames(&self, frames: usize) -> usize {
+        (frames as u64 * self.to as u64 / self.from as u64) as usize + 1
+    }
+
+    /// Resamples whole frames of `input` onto the end of `out`. A partial frame at the end
+    /// is dropped.
+    pub fn process(&mut self, input: &[i16], out: &mut Vec<i16>) {
+        let frames = input.len() / self.channels;
+        if self.is_passthrough() {
+            out.extend_from_slice(&input[..frames * self.channels]);
+            return;
+        }
+        self.buf.extend_from_slice(&input[..frames * self.channels]);
+        out.reserve(self.output_frames(frames) * self.channels);
+        match self.quality {
+            Quality::Linear => self.linear(out),
+            Quality::Sinc => self.sinc(out),
+        }
+        // drop the frames no later output needs
+        let used = ((self.pos >> 32) as usize).saturating_sub(self.before());
+        let used = used.min(self.buf.len() / self.channels);
+        self.buf.drain(..used * self.channels);
+        self.pos -= (used as u64) << 32;
+    }
+
+    /// Plays out what's still held back by the filter, at the end of a stream.
+    pub fn flush(&mut self, out: &mut Vec<i16>) {
+        if self.is_passthrough() {
+            return;
+        }
+        let end = ((self.buf.len() / self.channels) as u64) << 32;
+        let first = self.pos;
+        let start = out.len();
+        let silence = alloc::vec![0; self.after() * self.channels];
+        self.process(&silence, out);
+        // keep the frames from before the silence started
+        let frames = end.saturating_sub(first).div_ceil(self.step) as usize;
+        out.truncate((start + frames * self.channels).min(out.len()));
+        self.reset();
+    }
+
+    // frames of history needed before the output point
+    fn before(&self) -> usize {
+        match self.quality {
+            Quality::Linear => 0,
+            Quality::Sinc => TAPS / 2 - 1,
+        }
+    }
+
+    // frames needed after the output point
+    fn after(&self) -> usize {
+        match self.quality {
+            Quality::Linear => 1,
+            Quality::Sinc => TAPS / 2,
+        }
+    }
+
+    fn linear(&mut self, out: &mut Vec<i16>) {
+        let ch = self.channels;
+        let frames = self.buf.len() / ch;
+        while ((self.pos >> 32) as usize) + 1 < frames {
+            let i = (self.pos >> 32) as usize * ch;
+            // Q15, so a full scale step times it still fits an i32
+            let f = ((self.pos >> 17) & 0x7FFF) as i32;
+            for c in 0..ch {
+                let a = self.buf[i + c] as i32;
+                let b = self.buf[i + ch + c] as i32;
+                out.push((a + (((b - a) * f) >> 15)) as i16);
+            }
+            self.pos += self.step;
+        }
+    }
+
+    fn sinc(&mut self, out: &mut Vec<i16>) {
+        let ch = self.channels;
+        let frames = self.buf.len() / ch;
+        let phase_bits = PHASES.trailing_zeros();
+        let mut coefs = [0i32; TAPS];
+        while ((self.pos >> 32) as usize) + TAPS / 2 < frames {
+            let centre = (self.pos >> 32) as usize;
+            // interpolate between the two nearest phases, the second can be the last row
+            let frac = (self.pos & 0xFFFF_FFFF) as u32;
+            let phase = (frac >> (32 - phase_bits)) as usize;
+            let f = ((frac << phase_bits) >> 17) as i32;
+            let rows = &self.filter[phase * TAPS..(phase + 2) * TAPS];
+            for (k, c) in coefs.iter_mut().enumerate() {
+                let a = rows[k] as i32;
+                let b = rows[TAPS + k] as i32;
+                *c = a + (((b - a) * f) >> 15);
+            }
+            // rounding can leave the taps not quite adding up to one, which shows at full scale
+            let total: i32 = coefs.iter().sum();
+            coefs[TAPS / 2 - 1 + (phase >= PHASES / 2) as usize] += (1 << COEF_BITS) - total;
+            let first = (centre + 1 - TAPS / 2) * ch;
+            for c in 0..ch {
+                let mut acc = 1i32 << (COEF_BITS - 1);
+                for (k, coef) in coefs.iter().enumerate() {
+                    acc += self.buf[first + k * ch + c] as i32 * coef;
+                }
+                out.push((acc >> COEF_BITS).clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+            }
+            self.pos += self.step;
+        }
+    }
+}
+
+// a Blackman windowed sinc, one row per phase. Each row is scaled to add up to exactly one,
+// so a constant input comes out unchanged whatever the phase.
+fn sinc_filter(from: u32, to: u32) -> Vec<i16> {
+    // going down in rate, cut off below the new Nyquist frequency to stop aliasing
+    let cutoff = ROLLOFF * (to as f32 / from as f32).min(1.0);
+    let mut filter = Vec::with_capacity((PHASES + 1) * TAPS);
+    let mut row = [0f32; TAPS];
+    for phase in 0..=PHASES {
+        let t = phase as f32 / PHASES as f32;
+        let mut sum = 0.0;
+        for (k, h) in row.iter_mut().enumerate() {
+            // distance in input samples from the output point
+            let x = k as f32 - (TAPS / 2 - 1) as f32 - t;
+            let u = x / (TAPS / 2) as f32;
+            let window = if u.abs() >= 1.0 {
+                0.0
+            } else {
+                0.42 + 0.5 * (PI * u).cos() + 0.08 * (2.0 * PI * u).cos()
+            };
+            let arg = PI * cutoff * x;
+            let sinc = if arg.abs() < 1e-6 { 1.0 } else { arg.sin() / arg };
+            *h = sinc * window;
+            sum += *h;
+        }
+        let scale = (1 << COEF_BITS) as f32 / sum;
+        let mut total = 0i32;
+        for h in row.iter() {
+            let c = (h * scale).round() as i32;
+            filter.push(c as i16);
+            total += c;
+        }
+        // put any rounding error on the biggest tap
+        let centre = filter.len() - TAPS + TAPS / 2 - 1 + (t >= 0.5) as usize;
+        filter[centre] += ((1 << COEF_BITS) - total) as i16;
+    }
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // error against an ideal 1kHz sine at the new rate, in dB below the sine
+    fn error_db(from: u32, to: u32, quality: Quality) -> f64 {
+        let amplitude = 16000.0;
+        let sine = |t: f64| amplitude * (2.0 * core::f64::consts::PI * 1000.0 * t).sin();
+        let input: Vec<i16> = (0..from).map(|i| sine(i as f64 / from as f64).round() as i16).collect();
+        let mut resampler = Resampler::new(from, to, 1, quality);
+        let mut out = Vec::new();
+        // in uneven chunks, so the joins are measured too
+        for chunk in input.chunks(1000 + 17) {
+            resampler.process(chunk, &mut out);
+        }
+        resampler.flush(&mut out);
+        // the step is rounded down, so one more output can fit before the end
+        assert!(out.len() == to as usize || out.len() == to as usize + 1, "{} frames", out.len());
+        // leave out the filter starting and stopping at either end
+        let (mut signal, mut noise) = (0.0, 0.0);
+        for (i, s) in out.iter().enumerate().take(out.len() - 100).skip(100) {
+            let ideal = sine(i as f64 / to as f64);
+            signal += ideal * ideal;
+            noise += (*s as f64 - ideal).powi(2);
+        }
+        10.0 * (noise / signal).log10()
+    }
+
+    #[test]
+    fn error_against_an_ideal_sine() {
+        // the table in the module doc
+        let table = [
+            (8000, -25.0, -66.0),
+            (22050, -43.0, -86.0),
+            (32000, -49.0, -78.0),
+            (48000, -56.0, -79.0),
+        ];
+        for (from, linear, sinc) in table {
+            let measured = (error_db(from, 44100, Quality::Linear), error_db(from, 44100, Quality::Sinc));
+            assert!((measured.0 - linear).abs() < 1.0, "{} linear {:.1} dB", from, measured.0);
+            assert!((measured.1 - sinc).abs() < 1.0, "{} sinc {:.1} dB", from, measured.1);
+        }
+    }
+
+    // output frames a second, resampling ten seconds of stereo noise from 48k to 44.1k
+    fn frames_per_second(quality: Quality) -> f64 {
+        let mut seed = 1u32;
+        let input: Vec<i16> = (0..48000 * 2 * 10)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 16) as i16
+            })
+            .collect();
+        let mut resampler = Resampler::new(48000, 44100, 2, quality);
+        let mut out = Vec::with_capacity(44100 * 2 * 11);
+        let start = Instant::now();
+        for chunk in input.chunks(4096) {
+            resampler.process(chunk, &mut out);
+        }
+        let seconds = start.elapsed().as_secs_f64();
+        assert!(out.len() >= 44100 * 2 * 10 - 2 * TAPS);
+        (out.len() / 2) as f64 / seconds
+    }
+
+    #[test]
+    fn cost() {
+        let linear = frames_per_second(Quality::Linear);
+        let sinc = frames_per_second(Quality::Sinc);
+        // the numbers in the module doc, with room for a busy machine
+        assert!(linear / sinc < 20.0, "sinc is {:.1} times slower", linear / sinc);
+        assert!(sinc > 2_000_000.0, "sinc does {:.0} frames a second", sinc);
+    }
+}
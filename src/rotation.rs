@@ -0,0 +1,111 @@
+//! Which way up the screen is, and how touch and trackball input follow it.
+//!
+//! The panel is natively portrait, 240 wide and 320 tall, and the touch controller reports
+//! points in those same coordinates. The trackball pins are named for landscape with the
+//! keyboard at the bottom (`Deg90`), which is how the T-Deck is normally held. Each step of
+//! rotation turns the picture a further 90° clockwise.
+
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use serde::{Deserialize, Serialize};
+
+const NATIVE_WIDTH: u32 = 240;
+const NATIVE_HEIGHT: u32 = 320;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Rotation {
+    /// portrait, the panel's own orientation
+    Deg0,
+    /// landscape with the keyboard at the bottom
+    #[default]
+    Deg90,
+    /// portrait, upside down
+    Deg180,
+    /// landscape, upside down
+    Deg270,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 4] = [Rotation::Deg0, Rotation::Deg90, Rotation::Deg180, Rotation::Deg270];
+
+    pub fn degrees(self) -> u16 {
+        self.steps() as u16 * 90
+    }
+
+    /// Rounds to the nearest quarter turn.
+    pub fn from_degrees(degrees: u16) -> Rotation {
+        Rotation::ALL[((degrees as usize + 45) / 90) % 4]
+    }
+
+    fn steps(self) -> usize {
+        self as usize
+    }
+
+    pub fn clockwise(self) -> Rotation {
+        Rotation::ALL[(self.steps() + 1) % 4]
+    }
+
+    pub fn counter_clockwise(self) -> Rotation {
+        Rotation::ALL[(self.steps() + 3) % 4]
+    }
+
+    pub fn is_portrait(self) -> bool {
+        matches!(self, Rotation::Deg0 | Rotation::Deg180)
+    }
+
+    /// The size of the screen as apps see it.
+    pub fn screen_size(self) -> Size {
+        if self.is_portrait() {
+            Size::new(NATIVE_WIDTH, NATIVE_HEIGHT)
+        } else {
+            Size::new(NATIVE_HEIGHT, NATIVE_WIDTH)
+        }
+    }
+
+    pub fn screen_bounds(self) -> Rectangle {
+        Rectangle::new(Point::zero(), self.screen_size())
+    }
+
+    /// Maps a point from the touch controller to screen coordinates.
+    pub fn map_touch(self, p: Point) -> Point {
+        let mut p = p;
+        let mut size = Size::new(NATIVE_WIDTH, NATIVE_HEIGHT);
+        for _ in 0..self.steps() {
+            // the picture turned clockwise, so the left edge is now the top
+            p = Point::new(p.y, size.width as i32 - 1 - p.x);
+            size = Size::new(size.height, size.width);
+        }
+        p
+    }
+
+    /// Maps a point on the screen back to the panel's own coordinates.
+    pub fn to_panel(self, p: Point) -> Point {
+        let mut p = p;
+        let mut size = self.screen_size();
+        for _ in 0..self.steps() {
+            p = Point::new(size.height as i32 - 1 - p.y, p.x);
+            size = Size::new(size.height, size.width);
+        }
+        p
+    }
+
+    /// The first and last panel rows covering an area of the screen. The panel refreshes
+    /// by rows, so in landscape these are columns of the screen.
+    pub fn panel_rows(self, area: Rectangle) -> Option<(u16, u16)> {
+        let area = area.intersection(&self.screen_bounds());
+        let br = area.bottom_right()?;
+        let a = self.to_panel(area.top_left).y;
+        let b = self.to_panel(br).y;
+        Some((a.min(b) as u16, a.max(b) as u16))
+    }
+
+    /// Maps trackball changes, as [left, right, up, down, click], so they match the
+    /// directions on the screen.
+    pub fn map_trackball(self, changes: [bool; 5]) -> [bool; 5] {
+        let [mut left, mut right, mut up, mut down, click] = changes;
+        for _ in 0..(self.steps() + 3) % 4 {
+            (left, right, up, down) = (up, down, right, left);
+        }
+        [left, right, up, down, click]
+    }
+}
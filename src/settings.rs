@@ -0,0 +1,101 @@
+//! Small values saved to the NVS partition of the internal flash.
+//!
+//! The partition is split into fixed size slots, one per kind of data. Each slot holds a
+//! two byte magic, a two byte length and the value encoded with postcard, the same
+//! approach as the flash_postcard example.
+
+use alloc::vec::Vec;
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions;
+use esp_bootloader_esp_idf::partitions::{DataPartitionSubType, FlashRegion, PartitionType};
+use esp_storage::FlashStorage;
+use log::info;
+use postcard::{from_bytes, to_allocvec};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+const SLOT_SIZE: u32 = 4096;
+const MAGIC: [u8; 2] = *b"TD";
+const HEADER_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    HighScores = 0,
+    Settings = 1,
+    Bookmarks = 2,
+    Music = 3,
+    Volume = 4,
+    Equalizer = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    NoPartition,
+    Flash,
+    Encode,
+    TooBig,
+}
+
+fn with_nvs<R>(
+    flash: &mut FlashStorage<'static>,
+    f: impl FnOnce(&mut FlashRegion<'_, FlashStorage<'static>>) -> Result<R, StoreError>,
+) -> Result<R, StoreError> {
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let pt = partitions::read_partition_table(flash, &mut pt_mem).map_err(|_| StoreError::NoPartition)?;
+    let nvs = pt
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+        .map_err(|_| StoreError::NoPartition)?
+        .ok_or(StoreError::NoPartition)?;
+    let mut region = nvs.as_embedded_storage(flash);
+    f(&mut region)
+}
+
+/// Reads the value in a slot. Returns `None` if the slot was never written or holds
+/// something that doesn't decode as `T`.
+pub fn load<T: DeserializeOwned>(flash: &mut FlashStorage<'static>, slot: Slot) -> Option<T> {
+    let result = with_nvs(flash, |nvs| {
+        let offset = slot as u32 * SLOT_SIZE;
+        if (offset + SLOT_SIZE) as usize > nvs.capacity() {
+            return Err(StoreError::TooBig);
+        }
+        let mut header = [0u8; HEADER_LEN];
+        nvs.read(offset, &mut header).map_err(|_| StoreError::Flash)?;
+        if header[0..2] != MAGIC {
+            return Err(StoreError::Encode);
+        }
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if len > SLOT_SIZE as usize - HEADER_LEN {
+            return Err(StoreError::Encode);
+        }
+        let mut bytes = alloc::vec![0u8; len];
+        nvs.read(offset + HEADER_LEN as u32, &mut bytes)
+            .map_err(|_| StoreError::Flash)?;
+        from_bytes::<T>(&bytes).map_err(|_| StoreError::Encode)
+    });
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            info!("nothing loaded from slot {:?}: {:?}", slot, e);
+            None
+        }
+    }
+}
+
+/// Writes a value into a slot, replacing what was there.
+pub fn save<T: Serialize>(flash: &mut FlashStorage<'static>, slot: Slot, value: &T) -> Result<(), StoreError> {
+    let payload = to_allocvec(value).map_err(|_| StoreError::Encode)?;
+    if payload.len() > SLOT_SIZE as usize - HEADER_LEN {
+        return Err(StoreError::TooBig);
+    }
+    let mut bytes: Vec<u8> = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    with_nvs(flash, |nvs| {
+        let offset = slot as u32 * SLOT_SIZE;
+        if (offset + SLOT_SIZE) as usize > nvs.capacity() {
+            return Err(StoreError::TooBig);
+        }
+        nvs.write(offset, &bytes).map_err(|_| StoreError::Flash)
+    })
+}
@@ -0,0 +1,530 @@
+//! A display that only exists in memory, for seeing what a screen looks like without
+//! flashing the device.
+//!
+//! `SimDisplay` is a 320x240 `DrawTarget<Color = Rgb565>` that records every pixel. It
+//! can be saved as PPM or PNG, and `check_snapshot` compares it against a stored golden
+//! PPM image, producing a diff image with the changed pixels marked in red. `compare`
+//! does the same while streaming the golden pixels, for when memory is tight.
+//!
+//! Everything here only needs `alloc`, so it works in host tests as well as on the device,
+//! where the images can be written to the SD card. The tests at the bottom render screens
+//! on the computer and compare them to the goldens in `tools/hosttest/snapshots`; run them
+//! with `cargo test` in `tools/hosttest`.
+
+use crate::color::{expand5, expand6, to_rgb565};
+use crate::game::{render, DirtyRegions, FrameBuffer, Scene};
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+
+pub const WIDTH: u32 = 320;
+pub const HEIGHT: u32 = 240;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct SimDisplay {
+    size: Size,
+    pixels: Vec<Rgb565>,
+    /// how many pixels have been drawn, including overdraw
+    pub pixels_drawn: usize,
+}
+
+impl Default for SimDisplay {
+    fn default() -> Self {
+        SimDisplay::new(Size::new(WIDTH, HEIGHT))
+    }
+}
+
+impl SimDisplay {
+    pub fn new(size: Size) -> Self {
+        SimDisplay {
+            size,
+            pixels: vec![Rgb565::BLACK; (size.width * size.height) as usize],
+            pixels_drawn: 0,
+        }
+    }
+
+    pub fn pixel(&self, p: Point) -> Option<Rgb565> {
+        self.index(p).map(|i| self.pixels[i])
+    }
+
+    pub fn pixels(&self) -> &[Rgb565] {
+        &self.pixels
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        if p.x < 0 || p.y < 0 || p.x >= self.size.width as i32 || p.y >= self.size.height as i32 {
+            return None;
+        }
+        Some((p.y as u32 * self.size.width + p.x as u32) as usize)
+    }
+
+    /// Pixels as 8 bit RGB triples, scaling each channel to the full 0-255 range.
+    pub fn to_rgb888(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pixels.len() * 3);
+        for c in &self.pixels {
+            out.push(expand5(c.r()));
+            out.push(expand6(c.g()));
+            out.push(expand5(c.b()));
+        }
+        out
+    }
+
+    /// Binary PPM (P6), the simplest format most image viewers open.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_ppm(16, |chunk| out.extend_from_slice(chunk));
+        out
+    }
+
+    /// Produces the same bytes as `to_ppm` a few rows at a time, so a large image can be
+    /// written to a file without a second copy of it in memory.
+    pub fn write_ppm(&self, rows_per_chunk: usize, mut write: impl FnMut(&[u8])) {
+        write(alloc::format!("P6\n{} {}\n255\n", self.size.width, self.size.height).as_bytes());
+        let mut chunk = Vec::with_capacity(self.size.width as usize * rows_per_chunk * 3);
+        for rows in self.pixels.chunks(self.size.width as usize * rows_per_chunk.max(1)) {
+            chunk.clear();
+            for c in rows {
+                chunk.extend_from_slice(&[expand5(c.r()), expand6(c.g()), expand5(c.b())]);
+            }
+            write(&chunk);
+        }
+    }
+
+    /// Reads a binary PPM written by `to_ppm`.
+    pub fn from_ppm(data: &[u8]) -> Option<SimDisplay> {
+        let (size, offset) = parse_ppm_header(data)?;
+        let pixels: Vec<Rgb565> = ppm_pixels(&data[offset..]).collect();
+        if pixels.len() < (size.width * size.height) as usize {
+            return None;
+        }
+        Some(SimDisplay {
+            size,
+            pixels,
+            pixels_drawn: 0,
+        })
+    }
+
+    /// An RGB PNG. The image data is stored without compression, which keeps the encoder
+    /// tiny; a 320x240 screen comes out at about 230KB.
+    pub fn to_png(&self) -> Vec<u8> {
+        let rgb = self.to_rgb888();
+        let stride = self.size.width as usize * 3;
+        // every row starts with filter type 0
+        let mut raw = Vec::with_capacity((stride + 1) * self.size.height as usize);
+        for row in rgb.chunks_exact(stride) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&self.size.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.size.height.to_be_bytes());
+        // 8 bit depth, color type 2 (RGB), default compression, filter and interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        png_chunk(&mut out, b"IHDR", &ihdr);
+        png_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+/// Reads the size from a binary PPM (P6) header and returns where the pixels start.
+/// Comments in the header are skipped.
+pub fn parse_ppm_header(data: &[u8]) -> Option<(Size, usize)> {
+    let mut pos = 0;
+    let mut fields = [0u32; 3];
+    if data.get(0..2)? != b"P6" {
+        return None;
+    }
+    pos += 2;
+    for field in fields.iter_mut() {
+        // skip whitespace and comments
+        loop {
+            match data.get(pos)? {
+                b'#' => {
+                    while *data.get(pos)? != b'\n' {
+                        pos += 1;
+                    }
+                }
+                c if c.is_ascii_whitespace() => pos += 1,
+                _ => break,
+            }
+        }
+        let start = pos;
+        while data.get(pos)?.is_ascii_digit() {
+            pos += 1;
+        }
+        *field = core::str::from_utf8(&data[start..pos]).ok()?.parse().ok()?;
+    }
+    let [width, height, max] = fields;
+    if max != 255 {
+        return None;
+    }
+    // exactly one whitespace byte before the pixels
+    Some((Size::new(width, height), pos + 1))
+}
+
+/// Turns PPM pixel bytes into colors.
+pub fn ppm_pixels(data: &[u8]) -> impl Iterator<Item = Rgb565> + '_ {
+    data.chunks_exact(3)
+        .map(|rgb| to_rgb565(Rgb888::new(rgb[0], rgb[1], rgb[2])))
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut chunks = data.chunks(65535).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        out.push(last as u8);
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+impl OriginDimensions for SimDisplay {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for SimDisplay {
+    type Color = Rgb565;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            if let Some(i) = self.index(p) {
+                self.pixels[i] = color;
+                self.pixels_drawn += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draws a whole scene the same way the device does, through a framebuffer.
+pub fn render_scene<S: Scene>(scene: &S) -> SimDisplay {
+    let mut display = SimDisplay::default();
+    let mut fb = FrameBuffer::new(WIDTH as usize * 16);
+    let mut dirty = DirtyRegions::new();
+    dirty.add(display.bounding_box());
+    render(scene, &mut dirty, &mut fb, &mut display).unwrap();
+    display
+}
+
+// ---------- snapshots ----------
+
+pub struct SnapshotDiff {
+    pub mismatched: usize,
+    /// the area containing every changed pixel
+    pub bounds: Rectangle,
+    /// the actual image dimmed, with changed pixels in red
+    pub image: SimDisplay,
+}
+
+pub enum Snapshot {
+    Matched,
+    /// there was no golden image yet. save `actual` as the new golden.
+    Missing,
+    SizeChanged { golden: Size, actual: Size },
+    Changed(SnapshotDiff),
+}
+
+impl Snapshot {
+    pub fn is_match(&self) -> bool {
+        matches!(self, Snapshot::Matched)
+    }
+}
+
+/// Compares a rendered screen to the golden PPM. `tolerance` is how far each 8 bit
+/// channel may differ before the pixel counts as changed.
+pub fn check_snapshot(actual: &SimDisplay, golden_ppm: Option<&[u8]>, tolerance: u8) -> Snapshot {
+    let Some((size, offset)) = golden_ppm.and_then(parse_ppm_header) else {
+        return Snapshot::Missing;
+    };
+    let golden = golden_ppm.unwrap();
+    compare(actual.clone(), size, ppm_pixels(&golden[offset..]), tolerance)
+}
+
+/// Compares a screen against golden pixels one at a time, so the golden image can be
+/// streamed from a file instead of held in memory. The screen is turned into the diff
+/// image in place.
+pub fn compare(
+    mut actual: SimDisplay,
+    golden_size: Size,
+    golden: impl Iterator<Item = Rgb565>,
+    tolerance: u8,
+) -> Snapshot {
+    if golden_size != actual.size {
+        return Snapshot::SizeChanged {
+            golden: golden_size,
+            actual: actual.size,
+        };
+    }
+    let width = actual.size.width;
+    let mut mismatched = 0;
+    let mut compared = 0;
+    let (mut x0, mut y0, mut x1, mut y1) = (i32::MAX, i32::MAX, i32::MIN, i32::MIN);
+    let close = |a: u8, b: u8| a.abs_diff(b) <= tolerance;
+    for (i, g) in golden.take(actual.pixels.len()).enumerate() {
+        compared += 1;
+        let a = actual.pixels[i];
+        let same = close(expand5(a.r()), expand5(g.r()))
+            && close(expand6(a.g()), expand6(g.g()))
+            && close(expand5(a.b()), expand5(g.b()));
+        actual.pixels[i] = if same {
+            // a dim gray version of the pixel so the changes stand out
+            let luma = (a.r() as u16 * 2 + a.g() as u16 + a.b() as u16 * 2) / 6;
+            let v = (luma / 4) as u8;
+            Rgb565::new(v, v * 2, v)
+        } else {
+            mismatched += 1;
+            let x = (i as u32 % width) as i32;
+            let y = (i as u32 / width) as i32;
+            x0 = x0.min(x);
+            y0 = y0.min(y);
+            x1 = x1.max(x);
+            y1 = y1.max(y);
+            Rgb565::RED
+        };
+    }
+    if compared < actual.pixels.len() {
+        // the golden file was cut short
+        return Snapshot::SizeChanged {
+            golden: Size::new(width, compared as u32 / width),
+            actual: actual.size,
+        };
+    }
+    if mismatched == 0 {
+        return Snapshot::Matched;
+    }
+    Snapshot::Changed(SnapshotDiff {
+        mismatched,
+        bounds: Rectangle::with_corners(Point::new(x0, y0), Point::new(x1, y1)),
+        image: actual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breakout::{Breakout, BreakoutView, HighScores, Phase};
+    use crate::chart::{Chart, Series, Sparkline};
+    use alloc::string::String;
+    use embedded_graphics::mono_font::ascii::FONT_8X13;
+    use embedded_graphics::mono_font::MonoTextStyle;
+    use embedded_graphics::primitives::PrimitiveStyle;
+    use embedded_graphics::text::Text;
+    use std::path::PathBuf;
+
+    // the golden images belong to the host test crate, see tools/hosttest
+    fn golden_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("snapshots").join(alloc::format!("{}.ppm", name))
+    }
+
+    // compares against snapshots/NAME.ppm, saving it if it isn't there yet. On a change the
+    // diff and the new render go to target/snapshots as PNGs for looking at.
+    fn assert_snapshot(name: &str, actual: SimDisplay) {
+        let path = golden_path(name);
+        let golden = std::fs::read(&path).ok();
+        match check_snapshot(&actual, golden.as_deref(), 0) {
+            Snapshot::Matched => {}
+            Snapshot::Missing => {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, actual.to_ppm()).unwrap();
+                std::println!("{}: no golden image, saved {}", name, path.display());
+            }
+            Snapshot::SizeChanged { golden, actual } => {
+                panic!("{}: size changed from {:?} to {:?}", name, golden, actual)
+            }
+            Snapshot::Changed(diff) => {
+                let out = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("snapshots");
+                std::fs::create_dir_all(&out).unwrap();
+                std::fs::write(out.join(alloc::format!("{}.png", name)), actual.to_png()).unwrap();
+                std::fs::write(out.join(alloc::format!("{}_diff.png", name)), diff.image.to_png()).unwrap();
+                panic!(
+                    "{}: {} pixels changed inside {:?}, see {}. Delete {} to accept the change.",
+                    name,
+                    diff.mismatched,
+                    diff.bounds,
+                    out.display(),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    fn square(display: &mut SimDisplay, at: Point, color: Rgb565) {
+        Rectangle::new(at, Size::new(20, 20))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(display)
+            .unwrap();
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let mut display = SimDisplay::default();
+        square(&mut display, Point::new(10, 10), Rgb565::CSS_ORANGE);
+        let ppm = display.to_ppm();
+        assert!(ppm.starts_with(b"P6\n320 240\n255\n"));
+        let back = SimDisplay::from_ppm(&ppm).unwrap();
+        assert_eq!(back.pixels(), display.pixels());
+
+        let mut streamed = Vec::new();
+        display.write_ppm(7, |chunk| streamed.extend_from_slice(chunk));
+        assert_eq!(streamed, ppm);
+
+        let commented = b"P6\n# made by hand\n2 1\n255\n\xff\x00\x00\x00\x00\xff";
+        let small = SimDisplay::from_ppm(commented).unwrap();
+        assert_eq!(small.size(), Size::new(2, 1));
+        assert_eq!(small.pixels(), &[Rgb565::RED, Rgb565::BLUE]);
+    }
+
+    #[test]
+    fn png_is_well_formed() {
+        let png = SimDisplay::new(Size::new(4, 3)).to_png();
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+        // IHDR is 13 bytes and its CRC covers the type and data
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..24], &[0, 0, 0, 4, 0, 0, 0, 3]);
+        assert_eq!(u32::from_be_bytes(png[29..33].try_into().unwrap()), crc32(&png[12..29]));
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn snapshot_diffs() {
+        let mut display = SimDisplay::default();
+        square(&mut display, Point::new(10, 10), Rgb565::GREEN);
+        let golden = display.to_ppm();
+        assert!(check_snapshot(&display, Some(&golden), 0).is_match());
+        assert!(matches!(check_snapshot(&display, None, 0), Snapshot::Missing));
+
+        square(&mut display, Point::new(100, 50), Rgb565::WHITE);
+        match check_snapshot(&display, Some(&golden), 0) {
+            Snapshot::Changed(diff) => {
+                assert_eq!(diff.mismatched, 400);
+                assert_eq!(diff.bounds, Rectangle::new(Point::new(100, 50), Size::new(20, 20)));
+                assert_eq!(diff.image.pixel(Point::new(105, 55)), Some(Rgb565::RED));
+                assert_ne!(diff.image.pixel(Point::new(15, 15)), Some(Rgb565::RED));
+            }
+            _ => panic!("the change wasn't found"),
+        }
+
+        // a slightly different shade passes with enough tolerance
+        let mut shade = SimDisplay::default();
+        square(&mut shade, Point::new(10, 10), Rgb565::new(0, 62, 0));
+        assert!(!check_snapshot(&shade, Some(&golden), 0).is_match());
+        assert!(check_snapshot(&shade, Some(&golden), 8).is_match());
+
+        let small = SimDisplay::new(Size::new(160, 120)).to_ppm();
+        assert!(matches!(check_snapshot(&display, Some(&small), 0), Snapshot::SizeChanged { .. }));
+        let cut = &golden[..golden.len() / 2];
+        assert!(matches!(check_snapshot(&display, Some(cut), 0), Snapshot::SizeChanged { .. }));
+    }
+
+    #[test]
+    fn breakout_start() {
+        let view = BreakoutView::new(
+            Breakout::new(Breakout::builtin_levels(), HighScores::default()),
+            BreakoutView::default_bricks(),
+        );
+        assert_snapshot("breakout_start", render_scene(&view));
+    }
+
+    #[test]
+    fn breakout_game_over() {
+        let mut scores = HighScores::default();
+        scores.insert(String::from("JOS"), 1200, 2);
+        scores.insert(String::from("ABC"), 800, 1);
+        let mut state = Breakout::new(Breakout::builtin_levels(), scores);
+        state.phase = Phase::GameOver { won: false };
+        let view = BreakoutView::new(state, BreakoutView::default_bricks());
+        assert_snapshot("breakout_game_over", render_scene(&view));
+    }
+
+    #[test]
+    fn chart() {
+        let mut display = SimDisplay::new(Size::new(WIDTH, 100));
+        let mut series = Series::new(60);
+        for i in 0..80 {
+            series.push(20000.0 + ((i * 37) % 23) as f32 * 400.0 + i as f32 * 100.0);
+        }
+        let mut chart = Chart::line(
+            Rectangle::new(Point::zero(), Size::new(220, 100)),
+            "heap used",
+            "B",
+            Rgb565::CSS_ORANGE,
+        );
+        chart.update(&series);
+        chart.draw(&series, &mut display).unwrap();
+        let mut bars = Chart::bar(
+            Rectangle::new(Point::new(220, 0), Size::new(100, 70)),
+            "rssi",
+            "dBm",
+            Rgb565::CSS_DEEP_SKY_BLUE,
+        );
+        let mut rssi = Series::new(20);
+        for i in 0..20 {
+            rssi.push(-60.0 - ((i * 7) % 13) as f32);
+        }
+        bars.update(&rssi);
+        bars.draw(&rssi, &mut display).unwrap();
+        let mut sparkline = Sparkline::new(Rectangle::new(Point::new(230, 75), Size::new(80, 20)), Rgb565::YELLOW);
+        sparkline.update(&series);
+        sparkline.draw(&series, &mut display).unwrap();
+        assert_snapshot("chart", display);
+    }
+
+    #[test]
+    fn terminal() {
+        // what the term example shows after some typing
+        let mut display = SimDisplay::default();
+        let style = MonoTextStyle::new(&FONT_8X13, Rgb565::WHITE);
+        Text::new("?hello t-deck\nls /sd\nMUSIC.QOA  LEVEL1.TXT_", Point::new(20, 30), style)
+            .draw(&mut display)
+            .unwrap();
+        assert_snapshot("terminal", display);
+    }
+}
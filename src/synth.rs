@@ -0,0 +1,704 @@
+//! A small polyphonic synthesizer.
+//!
+//! Each voice is an oscillator, an ADSR envelope and a state variable filter. The
+//! oscillators keep their phase as a 32 bit fraction of a cycle, so the pitch is exact
+//! and they never drift. The saw and square waves have their jumps smoothed with PolyBLEP,
+//! and the triangle its corners with PolyBLAMP, which takes out most of the aliasing a
+//! plain waveform makes at high notes. Measured on the host at 2.6kHz and 44.1kHz, the
+//! aliasing is -27 dB for the saw and -28 dB for the square against -11 and -13 dB plain,
+//! and -47 dB for the triangle against -35 dB. The sine comes from a table with
+//! interpolation and its distortion is at -91 dB.
+//! Everything per sample is integer maths.
+//!
+//! `note_on` picks a free voice, or steals one: first the quietest voice that has been
+//! released, then the oldest. A stolen voice carries on from where its envelope and
+//! waveform were, so stealing doesn't click. `Synth` implements `mixer::Sound` so it can
+//! play through a `Mixer`, or `render` can be called directly.
+
+use crate::mixer::{soft_clip, Sound};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use micromath::F32Ext;
+
+// the sine table has 1 << SINE_BITS entries plus one, so interpolating never wraps
+const SINE_BITS: u32 = 8;
+// envelope levels are Q24
+const ENV_BITS: u32 = 24;
+const ENV_MAX: i32 = 1 << ENV_BITS;
+// filter coefficients are Q14
+const FILTER_BITS: u32 = 14;
+// waveforms are Q15, -1.0 to 1.0
+const ONE: i32 = 1 << 15;
+// note 69, the A above middle C, at 440Hz
+const A4: i32 = 69;
+// 2^(n/12) for each semitone, Q16
+const SEMITONES: [u64; 12] = [
+    65536, 69433, 73562, 77936, 82570, 87480, 92682, 98193, 104032, 110218, 116772, 123715,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Wave {
+    #[default]
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterKind {
+    #[default]
+    Off,
+    LowPass,
+    HighPass,
+    BandPass,
+}
+
+/// How a voice sounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Patch {
+    pub wave: Wave,
+    pub attack_ms: u16,
+    pub decay_ms: u16,
+    /// the level held after the decay, 0.0 to 1.0
+    pub sustain: f32,
+    /// time to fall from full level to silence after note off
+    pub release_ms: u16,
+    pub filter: FilterKind,
+    pub cutoff_hz: u16,
+    /// 0.0 is a gentle filter, near 1.0 it rings
+    pub resonance: f32,
+    /// loudness of one voice at full velocity, leave room for chords
+    pub volume: f32,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        PRESETS[0].1
+    }
+}
+
+/// Some patches to start from.
+pub const PRESETS: [(&str, Patch); 6] = [
+    (
+        "piano",
+        Patch {
+            wave: Wave::Triangle,
+            attack_ms: 2,
+            decay_ms: 900,
+            sustain: 0.0,
+            release_ms: 250,
+            filter: FilterKind::LowPass,
+            cutoff_hz: 3000,
+            resonance: 0.1,
+            volume: 0.35,
+        },
+    ),
+    (
+        "organ",
+        Patch {
+            wave: Wave::Sine,
+            attack_ms: 10,
+            decay_ms: 50,
+            sustain: 0.8,
+            release_ms: 60,
+            filter: FilterKind::Off,
+            cutoff_hz: 0,
+            resonance: 0.0,
+            volume: 0.3,
+        },
+    ),
+    (
+        "lead",
+        Patch {
+            wave: Wave::Saw,
+            attack_ms: 5,
+            decay_ms: 200,
+            sustain: 0.6,
+            release_ms: 150,
+            filter: FilterKind::LowPass,
+            cutoff_hz: 2500,
+            resonance: 0.5,
+            volume: 0.25,
+        },
+    ),
+    (
+        "pad",
+        Patch {
+            wave: Wave::Square,
+            attack_ms: 400,
+            decay_ms: 500,
+            sustain: 0.7,
+            release_ms: 800,
+            filter: FilterKind::LowPass,
+            cutoff_hz: 1200,
+            resonance: 0.2,
+            volume: 0.2,
+        },
+    ),
+    (
+        "bass",
+        Patch {
+            wave: Wave::Saw,
+            attack_ms: 3,
+            decay_ms: 300,
+            sustain: 0.4,
+            release_ms: 80,
+            filter: FilterKind::LowPass,
+            cutoff_hz: 600,
+            resonance: 0.6,
+            volume: 0.4,
+        },
+    ),
+    (
+        "drum",
+        Patch {
+            wave: Wave::Noise,
+            attack_ms: 1,
+            decay_ms: 120,
+            sustain: 0.0,
+            release_ms: 60,
+            filter: FilterKind::BandPass,
+            cutoff_hz: 1800,
+            resonance: 0.3,
+            volume: 0.4,
+        },
+    ),
+];
+
+/// The frequency of a MIDI note number, in Hz.
+pub fn note_hz(note: u8) -> f32 {
+    note_hz_q16(note) as f32 / 65536.0
+}
+
+// Hz in Q16, from the semitone table shifted by octaves
+fn note_hz_q16(note: u8) -> u64 {
+    // counted up from ten octaves below A4 so it's never negative
+    let semis = (note as i32 - A4 + 120) as usize;
+    let hz = 440 * SEMITONES[semis % 12];
+    let octave = semis / 12;
+    if octave >= 10 {
+        hz << (octave - 10)
+    } else {
+        hz >> (10 - octave)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// straight line segments, worked out per sample
+#[derive(Debug, Clone, Copy)]
+struct Envelope {
+    stage: Stage,
+    level: i32,
+    attack: i32,
+    decay: i32,
+    sustain: i32,
+    release: i32,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            stage: Stage::Idle,
+            level: 0,
+            attack: ENV_MAX,
+            decay: ENV_MAX,
+            sustain: 0,
+            release: ENV_MAX,
+        }
+    }
+
+    // starts the attack from wherever the level is now
+    fn start(&mut self, patch: &Patch, sample_rate: u32) {
+        let step = |ms: u16| ENV_MAX / (ms as u64 * sample_rate as u64 / 1000).max(1) as i32;
+        self.attack = step(patch.attack_ms);
+        self.decay = step(patch.decay_ms);
+        self.release = step(patch.release_ms);
+        self.sustain = (patch.sustain.clamp(0.0, 1.0) * ENV_MAX as f32) as i32;
+        self.stage = Stage::Attack;
+    }
+
+    fn release(&mut self) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+        }
+    }
+
+    fn next(&mut self) -> i32 {
+        match self.stage {
+            Stage::Idle | Stage::Sustain => {}
+            Stage::Attack => {
+                self.level += self.attack;
+                if self.level >= ENV_MAX {
+                    self.level = ENV_MAX;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= self.decay;
+                if self.level <= self.sustain {
+                    self.level = self.sustain;
+                    self.stage = if self.sustain > 0 { Stage::Sustain } else { Stage::Idle };
+                }
+            }
+            Stage::Release => {
+                self.level -= self.release;
+                if self.level <= 0 {
+                    self.level = 0;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.level
+    }
+}
+
+// a Chamberlin state variable filter, which gives low, high and band pass at once
+#[derive(Debug, Clone, Copy, Default)]
+struct Filter {
+    kind: FilterKind,
+    f: i32,
+    damping: i32,
+    low: i32,
+    band: i32,
+}
+
+impl Filter {
+    fn set(&mut self, patch: &Patch, sample_rate: u32) {
+        self.kind = patch.filter;
+        // it goes unstable near a quarter of the sample rate, and resonance makes that worse
+        let resonance = patch.resonance.clamp(0.0, 1.0);
+        // no resonance is a Q of 0.7, the most without a bump at the cutoff
+        let damping = (1.0 - resonance) * 1.3 + 0.1;
+        let max = sample_rate as f32 / 8.0;
+        let cutoff = (patch.cutoff_hz as f32).clamp(20.0, max);
+        let f = 2.0 * (PI * cutoff / sample_rate as f32).sin();
+        self.f = (f * (1 << FILTER_BITS) as f32) as i32;
+        self.damping = (damping * (1 << FILTER_BITS) as f32) as i32;
+    }
+
+    fn process(&mut self, input: i32) -> i32 {
+        if self.kind == FilterKind::Off {
+            return input;
+        }
+        self.low += (self.f * self.band) >> FILTER_BITS;
+        let high = input - self.low - ((self.damping * self.band) >> FILTER_BITS);
+        self.band += (self.f * high) >> FILTER_BITS;
+        // ringing at full resonance shouldn't be able to run away
+        self.low = self.low.clamp(-4 * ONE, 4 * ONE);
+        self.band = self.band.clamp(-4 * ONE, 4 * ONE);
+        match self.kind {
+            FilterKind::Off => input,
+            FilterKind::LowPass => self.low,
+            FilterKind::HighPass => high,
+            FilterKind::BandPass => self.band,
+        }
+    }
+}
+
+// the PolyBLEP correction for a jump down by two at phase 0, Q15. `t` and `dt` are
+// fractions of a cycle, as u32.
+fn poly_blep(t: u32, dt: u32) -> i32 {
+    if t < dt {
+        let x = (((t as u64) << 15) / dt as u64) as i32;
+        2 * x - ((x * x) >> 15) - ONE
+    } else if t > dt.wrapping_neg() {
+        let x = -((((t.wrapping_neg() as u64) << 15) / dt as u64) as i32);
+        ((x * x) >> 15) + 2 * x + ONE
+    } else {
+        0
+    }
+}
+
+// the PolyBLAMP correction for a corner at phase 0, Q15, for a change in slope of one
+// per sample
+fn poly_blamp(t: u32, dt: u32) -> i32 {
+    if t < dt {
+        let x = (((t as u64) << 15) / dt as u64) as i32 - ONE;
+        -((((x * x) >> 15) * x) >> 15) / 6
+    } else if t > dt.wrapping_neg() {
+        let x = ONE - (((t.wrapping_neg() as u64) << 15) / dt as u64) as i32;
+        ((((x * x) >> 15) * x) >> 15) / 6
+    } else {
+        0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    channel: u8,
+    note: u8,
+    patch: Patch,
+    // velocity times volume, Q15
+    gain: i32,
+    phase: u32,
+    step: u32,
+    // the step before pitch bend
+    base_step: u32,
+    env: Envelope,
+    filter: Filter,
+    rng: u32,
+    // when the note started, for stealing the oldest
+    started: u32,
+}
+
+impl Voice {
+    fn is_idle(&self) -> bool {
+        self.env.stage == Stage::Idle
+    }
+
+    fn oscillator(&mut self, sine: &[i16]) -> i32 {
+        let t = self.phase;
+        let dt = self.step.max(1);
+        self.phase = self.phase.wrapping_add(self.step);
+        match self.patch.wave {
+            Wave::Sine => {
+                let i = (t >> (32 - SINE_BITS)) as usize;
+                let frac = ((t >> (17 - SINE_BITS)) & 0x7FFF) as i32;
+                let a = sine[i] as i32;
+                let b = sine[i + 1] as i32;
+                a + (((b - a) * frac) >> 15)
+            }
+            Wave::Saw => ((t >> 16) as i32 - ONE) - poly_blep(t, dt),
+            Wave::Square => {
+                let naive = if t < 1 << 31 { ONE - 1 } else { -ONE };
+                naive + poly_blep(t, dt) - poly_blep(t.wrapping_add(1 << 31), dt)
+            }
+            Wave::Triangle => {
+                // up from -1 to 1 over the first half, back down over the second
+                let naive = if t < 1 << 31 {
+                    (t >> 15) as i32 - ONE
+                } else {
+                    3 * ONE - (t >> 15) as i32
+                };
+                // the slope changes by 8 per cycle at the corners, dt cycles per sample
+                let scale = 8 * (dt >> 17) as i64;
+                let corners = poly_blamp(t, dt) - poly_blamp(t.wrapping_add(1 << 31), dt);
+                naive + ((scale * corners as i64) >> 15) as i32
+            }
+            Wave::Noise => {
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 17;
+                self.rng ^= self.rng << 5;
+                (self.rng >> 16) as i16 as i32
+            }
+        }
+    }
+}
+
+pub struct Synth {
+    pub sample_rate: u32,
+    /// the patch `note_on` uses
+    pub patch: Patch,
+    voices: Vec<Voice>,
+    // pitch bend for each of 16 channels, Q16
+    bends: [u64; 16],
+    sine: Vec<i16>,
+    clock: u32,
+    mix: Vec<i32>,
+}
+
+impl Synth {
+    /// A synth with `polyphony` voices.
+    pub fn new(sample_rate: u32, polyphony: usize) -> Self {
+        let voice = Voice {
+            channel: 0,
+            note: 0,
+            patch: Patch::default(),
+            gain: 0,
+            phase: 0,
+            step: 0,
+            base_step: 0,
+            env: Envelope::new(),
+            filter: Filter::default(),
+            rng: 1,
+            started: 0,
+        };
+        Synth {
+            sample_rate: sample_rate.max(1),
+            patch: Patch::default(),
+            voices: vec![voice; polyphony.max(1)],
+            bends: [1 << 16; 16],
+            sine: sine_table(),
+            clock: 0,
+            mix: Vec::new(),
+        }
+    }
+
+    /// Plays a note with the current patch. Velocity is 1 to 127, like MIDI.
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        let patch = self.patch;
+        self.note_on_patch(0, note, velocity, &patch);
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.note_off_channel(0, note);
+    }
+
+    /// Plays a note with its own patch. `channel` keeps notes from different instruments
+    /// apart, so they can be stopped separately.
+    pub fn note_on_patch(&mut self, channel: u8, note: u8, velocity: u8, patch: &Patch) {
+        // MIDI sends note on with no velocity for note off
+        if velocity == 0 {
+            self.note_off_channel(channel, note);
+            return;
+        }
+        let at = self.pick_voice(channel, note);
+        self.clock = self.clock.wrapping_add(1);
+        let sample_rate = self.sample_rate;
+        let step = ((note_hz_q16(note) << 16) / sample_rate as u64) as u32;
+        let bend = self.bends[channel as usize & 15];
+        let voice = &mut self.voices[at];
+        if voice.is_idle() {
+            // a fresh start. a stolen voice keeps its phase and filter so there's no click.
+            voice.phase = 0;
+            voice.filter = Filter::default();
+            voice.env.level = 0;
+        }
+        voice.channel = channel;
+        voice.note = note;
+        voice.patch = *patch;
+        voice.gain = (patch.volume.clamp(0.0, 1.0) * velocity.min(127) as f32 / 127.0 * ONE as f32) as i32;
+        voice.base_step = step;
+        voice.step = ((step as u64 * bend) >> 16) as u32;
+        voice.rng = voice.rng.wrapping_add(self.clock.wrapping_mul(0x9E37_79B9)) | 1;
+        voice.started = self.clock;
+        voice.env.start(patch, sample_rate);
+        voice.filter.set(patch, sample_rate);
+    }
+
+    /// Lets a note fade out through its release.
+    pub fn note_off_channel(&mut self, channel: u8, note: u8) {
+        for voice in self.voices.iter_mut() {
+            if voice.channel == channel && voice.note == note && voice.env.stage != Stage::Release {
+                voice.env.release();
+            }
+        }
+    }
+
+    /// Bends the notes on a channel, and ones played on it after, by `semitones`.
+    pub fn pitch_bend(&mut self, channel: u8, semitones: f32) {
+        let bend = (2f32.powf(semitones / 12.0) * 65536.0) as u64;
+        self.bends[channel as usize & 15] = bend;
+        for voice in self.voices.iter_mut().filter(|v| v.channel == channel) {
+            voice.step = ((voice.base_step as u64 * bend) >> 16) as u32;
+        }
+    }
+
+    /// Releases every note on a channel.
+    pub fn channel_off(&mut self, channel: u8) {
+        for voice in self.voices.iter_mut().filter(|v| v.channel == channel) {
+            voice.env.release();
+        }
+    }
+
+    /// Releases everything that's playing.
+    pub fn all_notes_off(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.env.release();
+        }
+    }
+
+    /// Stops every voice straight away.
+    pub fn silence(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.env = Envelope::new();
+        }
+    }
+
+    /// Voices making a sound, including ones fading out.
+    pub fn active(&self) -> usize {
+        self.voices.iter().filter(|v| !v.is_idle()).count()
+    }
+
+    pub fn polyphony(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Notes that are held down, not counting ones being released.
+    pub fn held_notes(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.voices
+            .iter()
+            .filter(|v| !matches!(v.env.stage, Stage::Idle | Stage::Release))
+            .map(|v| (v.channel, v.note))
+    }
+
+    // the voice to play a note on
+    fn pick_voice(&mut self, channel: u8, note: u8) -> usize {
+        // the same note again restarts it
+        let again = |v: &Voice| !v.is_idle() && v.channel == channel && v.note == note;
+        if let Some(at) = self.voices.iter().position(again) {
+            return at;
+        }
+        if let Some(at) = self.voices.iter().position(|v| v.is_idle()) {
+            return at;
+        }
+        let clock = self.clock;
+        let age = |v: &Voice| clock.wrapping_sub(v.started);
+        let released = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.env.stage == Stage::Release)
+            .min_by_key(|(_, v)| v.env.level);
+        match released {
+            Some((at, _)) => at,
+            None => (0..self.voices.len()).max_by_key(|i| age(&self.voices[*i])).unwrap_or(0),
+        }
+    }
+
+    /// Writes `out.len()` mono samples.
+    pub fn render(&mut self, out: &mut [i16]) {
+        self.mix.clear();
+        self.mix.resize(out.len(), 0);
+        for voice in self.voices.iter_mut().filter(|v| !v.is_idle()) {
+            for m in self.mix.iter_mut() {
+                let level = voice.env.next();
+                let wave = voice.oscillator(&self.sine);
+                // resonance can take it over full scale, but not so far it overflows below
+                let sample = voice.filter.process(wave).clamp(1 - 2 * ONE, 2 * ONE - 1);
+                // Q15 times Q15 times the top 15 bits of the Q24 level
+                let sample = (sample * voice.gain) >> 15;
+                *m += (sample * (level >> (ENV_BITS - 15))) >> 15;
+                if voice.env.stage == Stage::Idle {
+                    break;
+                }
+            }
+        }
+        for (o, m) in out.iter_mut().zip(self.mix.iter()) {
+            *o = soft_clip(*m).0;
+        }
+    }
+}
+
+impl Sound for Synth {
+    fn render(&mut self, out: &mut [i16]) -> bool {
+        Synth::render(self, out);
+        true
+    }
+}
+
+// one cycle of a sine, Q15. Rotating a point round a circle only needs multiplies, so
+// this is as exact as the f64 maths without needing sin for every entry.
+fn sine_table() -> Vec<i16> {
+    let len = 1usize << SINE_BITS;
+    // cos and sin of a 256th of a turn
+    const COS: f64 = 0.999_698_818_696_204_2;
+    const SIN: f64 = 0.024_541_228_522_912_288;
+    let (mut x, mut y) = (1.0f64, 0.0f64);
+    let mut table = Vec::with_capacity(len + 1);
+    for _ in 0..=len {
+        let v = y * (ONE - 1) as f64;
+        table.push(if v < 0.0 { v - 0.5 } else { v + 0.5 } as i16);
+        (x, y) = (x * COS - y * SIN, x * SIN + y * COS);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+    const N: usize = 1 << 15;
+
+    // N samples of a voice's oscillator at `bin` times RATE / N Hz, and the same without
+    // the PolyBLEP and PolyBLAMP corrections. That many samples is a whole number of
+    // cycles, so every harmonic and every alias lands right on a bin of an N point DFT.
+    fn oscillator(wave: Wave, bin: u32) -> (Vec<i32>, Vec<i32>) {
+        let mut synth = Synth::new(RATE, 1);
+        let patch = Patch { wave, filter: FilterKind::Off, ..Patch::default() };
+        synth.note_on_patch(0, 100, 127, &patch);
+        let voice = &mut synth.voices[0];
+        voice.step = bin << (32 - N.trailing_zeros());
+        let (mut smooth, mut plain) = (Vec::new(), Vec::new());
+        for _ in 0..N {
+            let t = voice.phase;
+            plain.push(match wave {
+                Wave::Saw => (t >> 16) as i32 - ONE,
+                Wave::Square if t < 1 << 31 => ONE - 1,
+                Wave::Square => -ONE,
+                Wave::Triangle if t < 1 << 31 => (t >> 15) as i32 - ONE,
+                Wave::Triangle => 3 * ONE - (t >> 15) as i32,
+                _ => 0,
+            });
+            smooth.push(voice.oscillator(&synth.sine));
+        }
+        (smooth, plain)
+    }
+
+    // the power in bin `k` of an N point DFT, by Goertzel's recurrence
+    fn bin_power(samples: &[i32], k: usize) -> f64 {
+        let coef = 2.0 * (2.0 * core::f64::consts::PI * k as f64 / N as f64).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for x in samples {
+            let s = *x as f64 + coef * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        s1 * s1 + s2 * s2 - coef * s1 * s2
+    }
+
+    // the power in the bins that aren't harmonics of `bin` against the power in the ones
+    // that are, in dB. With an odd `bin` the aliases can't land on a harmonic. Only the
+    // harmonics need a DFT, the rest is everything from Parseval's theorem, without DC
+    // and Nyquist, and halved for the bins above Nyquist that mirror the ones below.
+    fn aliasing_db(samples: &[i32], bin: u32, harmonics: usize) -> f64 {
+        let energy: f64 = samples.iter().map(|s| (*s as f64).powi(2)).sum();
+        let dc: f64 = samples.iter().map(|s| *s as f64).sum();
+        let nyquist: f64 = samples
+            .iter()
+            .enumerate()
+            .map(|(i, s)| if i % 2 == 0 { *s as f64 } else { -*s as f64 })
+            .sum();
+        let total = (N as f64 * energy - dc * dc - nyquist * nyquist) / 2.0;
+        let wanted: f64 = (1..=harmonics)
+            .map(|h| h * bin as usize)
+            .take_while(|k| *k < N / 2)
+            .map(|k| bin_power(samples, k))
+            .sum();
+        10.0 * ((total - wanted) / wanted).log10()
+    }
+
+    #[test]
+    fn aliasing() {
+        // about 2.6kHz, the numbers in the module doc
+        let bin = 1959;
+        for (wave, smooth_db, plain_db) in [
+            (Wave::Saw, -27.0, -11.0),
+            (Wave::Square, -28.0, -13.0),
+            (Wave::Triangle, -47.0, -35.0),
+        ] {
+            let (smooth, plain) = oscillator(wave, bin);
+            let measured = (aliasing_db(&smooth, bin, N), aliasing_db(&plain, bin, N));
+            assert!((measured.0 - smooth_db).abs() < 1.0, "{:?} {:.1} dB", wave, measured.0);
+            assert!((measured.1 - plain_db).abs() < 1.0, "{:?} plain {:.1} dB", wave, measured.1);
+        }
+        // everything but the fundamental counts for the sine
+        let (sine, _) = oscillator(Wave::Sine, bin);
+        let measured = aliasing_db(&sine, bin, 1);
+        assert!((measured + 91.0).abs() < 1.0, "sine {:.1} dB", measured);
+    }
+
+    #[test]
+    fn smoothing_helps_at_every_pitch() {
+        // from about 330Hz to 8.4kHz
+        for bin in [245, 491, 981, 1959, 3111, 6223] {
+            for wave in [Wave::Saw, Wave::Square, Wave::Triangle] {
+                let (smooth, plain) = oscillator(wave, bin);
+                let (smooth, plain) = (aliasing_db(&smooth, bin, N), aliasing_db(&plain, bin, N));
+                assert!(smooth < plain - 9.0, "{:?} at bin {}: {:.1} against {:.1}", wave, bin, smooth, plain);
+            }
+        }
+    }
+}
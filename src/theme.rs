@@ -0,0 +1,112 @@
+//! Fonts and colors shared by the text based views, so they look the same.
+
+use embedded_graphics::mono_font::ascii::{
+    FONT_10X20, FONT_6X10, FONT_7X13, FONT_7X13_BOLD, FONT_7X13_ITALIC, FONT_9X15_BOLD,
+};
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle, MonoTextStyleBuilder};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub background: Rgb565,
+    pub text: Rgb565,
+    pub heading: Rgb565,
+    pub link: Rgb565,
+    pub code: Rgb565,
+    pub code_background: Rgb565,
+    pub quote: Rgb565,
+    /// rules, quote bars and other lines
+    pub accent: Rgb565,
+    /// chart gridlines
+    pub grid: Rgb565,
+    pub status_background: Rgb565,
+
+    /// regular text. `bold`, `italic` and `code` must be the same size as `body`.
+    pub body: &'static MonoFont<'static>,
+    pub bold: &'static MonoFont<'static>,
+    pub italic: &'static MonoFont<'static>,
+    pub code_font: &'static MonoFont<'static>,
+    /// heading fonts for levels 1, 2 and 3 and below
+    pub headings: [&'static MonoFont<'static>; 3],
+    /// status bars and labels
+    pub small: &'static MonoFont<'static>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Theme {
+            background: Rgb565::BLACK,
+            text: Rgb565::new(26, 52, 26),
+            heading: Rgb565::WHITE,
+            link: Rgb565::CSS_DEEP_SKY_BLUE,
+            code: Rgb565::CSS_LIGHT_GREEN,
+            code_background: Rgb565::new(3, 6, 3),
+            quote: Rgb565::CSS_LIGHT_GRAY,
+            accent: Rgb565::CSS_DARK_ORANGE,
+            grid: Rgb565::new(9, 18, 9),
+            status_background: Rgb565::CSS_DARK_SLATE_GRAY,
+            ..Theme::fonts()
+        }
+    }
+
+    /// dark text on a paper colored background
+    pub fn light() -> Self {
+        Theme {
+            background: Rgb565::new(30, 60, 27),
+            text: Rgb565::new(4, 8, 4),
+            heading: Rgb565::BLACK,
+            link: Rgb565::CSS_DARK_BLUE,
+            code: Rgb565::CSS_DARK_RED,
+            code_background: Rgb565::new(27, 54, 23),
+            quote: Rgb565::CSS_DIM_GRAY,
+            accent: Rgb565::CSS_SADDLE_BROWN,
+            grid: Rgb565::new(22, 44, 20),
+            status_background: Rgb565::CSS_DARK_SLATE_GRAY,
+            ..Theme::fonts()
+        }
+    }
+
+    fn fonts() -> Self {
+        Theme {
+            background: Rgb565::BLACK,
+            text: Rgb565::WHITE,
+            heading: Rgb565::WHITE,
+            link: Rgb565::BLUE,
+            code: Rgb565::WHITE,
+            code_background: Rgb565::BLACK,
+            quote: Rgb565::WHITE,
+            accent: Rgb565::WHITE,
+            grid: Rgb565::WHITE,
+            status_background: Rgb565::BLACK,
+            body: &FONT_7X13,
+            bold: &FONT_7X13_BOLD,
+            italic: &FONT_7X13_ITALIC,
+            code_font: &FONT_7X13,
+            headings: [&FONT_10X20, &FONT_9X15_BOLD, &FONT_7X13_BOLD],
+            small: &FONT_6X10,
+        }
+    }
+
+    /// The font for a heading, 1 is the largest.
+    pub fn heading_font(&self, level: u8) -> &'static MonoFont<'static> {
+        self.headings[(level.max(1) as usize - 1).min(2)]
+    }
+
+    pub fn text_style(&self) -> MonoTextStyle<'static, Rgb565> {
+        MonoTextStyle::new(self.body, self.text)
+    }
+
+    pub fn small_style(&self) -> MonoTextStyle<'static, Rgb565> {
+        MonoTextStyleBuilder::new()
+            .font(self.small)
+            .text_color(Rgb565::WHITE)
+            .build()
+    }
+}
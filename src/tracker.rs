@@ -0,0 +1,1022 @@
+//! Playing ProTracker MOD files.
+//!
+//! A MOD file holds up to 31 instrument samples, 8 bit and usually around 8kHz, and
+//! patterns of 64 rows saying which note each channel plays and with what effect. The
+//! order table says which patterns play in turn. That makes minutes of music from a file
+//! of tens of kilobytes.
+//!
+//! `parse` reads the header and runs through the song once without making any sound to
+//! get the length. `load` reads the patterns and samples into memory as well, since the
+//! samples are read from all over the file at once. A module takes about as much memory as
+//! the file is big, so only small ones fit in the heap and `load` takes a limit.
+//!
+//! `ModPlayer` plays a module at any sample rate, mixing the channels to stereo in the
+//! Amiga's left, right, right, left layout, though not spread all the way so it's easier
+//! on headphones. Samples are linearly interpolated. The usual 4 channel files (M.K.,
+//! M!K!, 4CHN, FLT4), ones with other channel counts (6CHN, 8CHN, 10CH and so on) and the
+//! old 15 sample files with no signature are read.
+//!
+//! The effects played are arpeggio (0), portamento up and down (1, 2), tone portamento
+//! (3), vibrato (4), those two with a volume slide (5, 6), sample offset (9), volume slide
+//! (A), position jump (B), set volume (C), pattern break (D), speed and tempo (F), and
+//! from the E effects fine portamento, pattern loop, retrigger, fine volume slide, note
+//! cut, note delay and pattern delay. Tremolo, panning and waveform changes are ignored.
+//!
+//! A song ends when it gets past the last order or comes back to a row it has already
+//! played, since a lot of modules jump back to the start to loop forever.
+
+use crate::mixer::{soft_clip, Sound};
+use crate::reader::TextSource;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ROWS: usize = 64;
+const MAX_CHANNELS: usize = 32;
+// half the Amiga's PAL clock, a sample plays at this many Hz divided by the period
+const PAULA_HZ: u64 = 3_546_895;
+// the range of periods portamento keeps to, three octaves
+const MIN_PERIOD: u16 = 113;
+const MAX_PERIOD: u16 = 856;
+const DEFAULT_SPEED: u8 = 6;
+const DEFAULT_TEMPO: u8 = 125;
+// Q8 gains for a channel on its own side and on the other
+const NEAR_GAIN: i32 = 192;
+const FAR_GAIN: i32 = 64;
+// the length stops being counted here, in case a song never ends
+const MAX_DURATION_MS: u32 = 30 * 60 * 1000;
+
+// Q16 period multipliers for finetunes -8 to 7, in eighths of a semitone
+const FINETUNE: [u32; 16] = [
+    69433, 68933, 68438, 67945, 67456, 66971, 66489, 66011, 65536, 65065, 64596, 64132, 63670, 63212,
+    62757, 62306,
+];
+// Q16 period multipliers for 0 to 15 semitones up, for arpeggio
+const SEMITONES: [u32; 16] = [
+    65536, 61858, 58386, 55109, 52016, 49097, 46341, 43740, 41285, 38968, 36781, 34716, 32768, 30929,
+    29193, 27554,
+];
+// half a sine wave, ProTracker's vibrato table
+const VIBRATO: [u8; 32] = [
+    0, 24, 49, 74, 97, 120, 141, 161, 180, 197, 212, 224, 235, 244, 250, 253, 255, 253, 250, 244, 235, 224,
+    212, 197, 180, 161, 141, 120, 97, 74, 49, 24,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModError {
+    /// too short, or no signature and doesn't look like an old 15 sample file either
+    NotMod,
+    /// a signature with a channel count that isn't supported
+    Unsupported([u8; 4]),
+    /// needs this many bytes of memory, more than allowed
+    TooBig(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleInfo {
+    pub name: String,
+    /// in bytes, each one a signed 8 bit sample
+    pub length: u32,
+    /// in eighths of a semitone, -8 to 7
+    pub finetune: i8,
+    /// 0 to 64
+    pub volume: u8,
+    pub loop_start: u32,
+    /// 0 when the sample doesn't loop
+    pub loop_length: u32,
+    // where the sample starts in the sample data
+    offset: u32,
+}
+
+impl SampleInfo {
+    // where the sample ends, and where it goes back to if it loops
+    fn bounds(&self) -> (u32, Option<u32>) {
+        if self.loop_length > 0 {
+            (self.loop_start + self.loop_length, Some(self.loop_start))
+        } else {
+            (self.length, None)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModInfo {
+    pub title: String,
+    pub channels: usize,
+    /// sample 1 is the first
+    pub samples: Vec<SampleInfo>,
+    /// the patterns in the order they play
+    pub orders: Vec<u8>,
+    pub patterns: usize,
+    pub duration_ms: u32,
+    // where the patterns and samples start in the file
+    patterns_offset: u32,
+    samples_offset: u32,
+}
+
+impl ModInfo {
+    /// Bytes of memory the patterns and samples take once loaded.
+    pub fn memory(&self) -> u32 {
+        self.pattern_bytes() as u32 + self.sample_bytes()
+    }
+
+    fn pattern_bytes(&self) -> usize {
+        self.patterns * ROWS * self.channels * 4
+    }
+
+    fn sample_bytes(&self) -> u32 {
+        self.samples.iter().map(|s| s.length).sum()
+    }
+}
+
+/// A module with its patterns and samples in memory.
+pub struct Module {
+    pub info: ModInfo,
+    patterns: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Module {
+    fn note(&self, pattern: usize, row: usize, channel: usize) -> Note {
+        let at = ((pattern * ROWS + row) * self.info.channels + channel) * 4;
+        match self.patterns.get(at..at + 4) {
+            Some(b) => Note {
+                sample: (b[0] & 0xF0) | (b[2] >> 4),
+                period: ((b[0] & 0x0F) as u16) << 8 | b[1] as u16,
+                effect: b[2] & 0x0F,
+                param: b[3],
+            },
+            None => Note::default(),
+        }
+    }
+}
+
+fn be_u16(b: &[u8]) -> u32 {
+    u16::from_be_bytes([b[0], b[1]]) as u32
+}
+
+// names are space or zero padded, and sometimes full of junk
+fn text(b: &[u8]) -> String {
+    let b = b.split(|c| *c == 0).next().unwrap_or(&[]);
+    let s: String = b
+        .iter()
+        .map(|c| if c.is_ascii_graphic() || *c == b' ' { *c as char } else { ' ' })
+        .collect();
+    s.trim_end().into()
+}
+
+// channels for a 31 sample file's signature, None if it isn't one
+fn signature_channels(sig: &[u8]) -> Option<usize> {
+    match sig {
+        b"M.K." | b"M!K!" | b"M&K!" | b"N.T." | b"FLT4" | b"4CHN" => Some(4),
+        [d, b'C', b'H', b'N'] if d.is_ascii_digit() => Some((d - b'0') as usize),
+        [a, b, b'C', b'H' | b'N'] if a.is_ascii_digit() && b.is_ascii_digit() => {
+            Some(((a - b'0') * 10 + (b - b'0')) as usize)
+        }
+        _ => None,
+    }
+}
+
+fn read_header(src: &mut impl TextSource) -> Result<ModInfo, ModError> {
+    let mut header = vec![0u8; 1084];
+    let n = src.read_at(0, &mut header);
+    if n < 600 {
+        return Err(ModError::NotMod);
+    }
+    let sig = &header[1080..1084];
+    let (count, channels) = match signature_channels(sig) {
+        Some(c) if n == 1084 => {
+            if c == 0 || c > MAX_CHANNELS {
+                return Err(ModError::Unsupported([sig[0], sig[1], sig[2], sig[3]]));
+            }
+            (31, c)
+        }
+        _ => (15, 4),
+    };
+    let table = 20 + count * 30;
+    let song_length = header[table] as usize;
+    let orders = &header[table + 2..table + 130];
+    if song_length == 0 || song_length > 128 {
+        return Err(ModError::NotMod);
+    }
+    // old files have nothing to recognise them by, so check they're at least sensible
+    if count == 15
+        && (orders.iter().any(|o| *o >= 64) || (0..15).any(|i| header[20 + i * 30 + 25] > 64))
+    {
+        return Err(ModError::NotMod);
+    }
+
+    let mut samples = Vec::with_capacity(count);
+    let mut offset = 0;
+    for i in 0..count {
+        let h = &header[20 + i * 30..50 + i * 30];
+        let length = be_u16(&h[22..24]) * 2;
+        // 15 sample files give the loop start in bytes rather than words
+        let mut loop_start = be_u16(&h[26..28]) * if count == 15 { 1 } else { 2 };
+        let mut loop_length = be_u16(&h[28..30]) * 2;
+        // a loop of one word or less means no loop
+        if loop_length <= 2 || loop_start >= length {
+            loop_start = 0;
+            loop_length = 0;
+        }
+        samples.push(SampleInfo {
+            name: text(&h[0..22]),
+            length,
+            finetune: ((h[24] & 0x0F) << 4) as i8 >> 4,
+            volume: h[25].min(64),
+            loop_start,
+            loop_length: loop_length.min(length - loop_start),
+            offset,
+        });
+        offset += length;
+    }
+
+    // every pattern in the table is stored, even past the song length
+    let patterns = *orders.iter().max().unwrap_or(&0) as usize + 1;
+    let patterns_offset = (table + 130 + if count == 31 { 4 } else { 0 }) as u32;
+    let mut info = ModInfo {
+        title: text(&header[0..20]),
+        channels,
+        samples,
+        orders: orders[..song_length].into(),
+        patterns,
+        duration_ms: 0,
+        patterns_offset,
+        samples_offset: 0,
+    };
+    info.samples_offset = patterns_offset + info.pattern_bytes() as u32;
+    if info.samples_offset > src.size() {
+        return Err(ModError::NotMod);
+    }
+    Ok(info)
+}
+
+fn read_patterns(info: &ModInfo, src: &mut impl TextSource) -> Vec<u8> {
+    let mut patterns = vec![0u8; info.pattern_bytes()];
+    src.read_at(info.patterns_offset, &mut patterns);
+    patterns
+}
+
+// plays the song through without mixing and says how long it took
+fn measure(module: Module) -> Module {
+    let mut player = ModPlayer::new(module, 1000);
+    player.skip(MAX_DURATION_MS as u64);
+    let mut module = player.module;
+    module.info.duration_ms = player.frames as u32;
+    module
+}
+
+/// Reads the header and finds the length.
+pub fn parse(src: &mut impl TextSource) -> Result<ModInfo, ModError> {
+    let info = read_header(src)?;
+    let patterns = read_patterns(&info, src);
+    let module = measure(Module {
+        info,
+        patterns,
+        data: Vec::new(),
+    });
+    Ok(module.info)
+}
+
+/// Reads the whole module into memory, as long as it needs no more than `max_bytes`.
+/// Samples cut short by the end of the file are filled with silence.
+pub fn load(src: &mut impl TextSource, max_bytes: u32) -> Result<Module, ModError> {
+    let info = read_header(src)?;
+    if info.memory() > max_bytes {
+        return Err(ModError::TooBig(info.memory()));
+    }
+    let patterns = read_patterns(&info, src);
+    let mut data = vec![0u8; info.sample_bytes() as usize];
+    src.read_at(info.samples_offset, &mut data);
+    Ok(measure(Module { info, patterns, data }))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Note {
+    sample: u8,
+    period: u16,
+    effect: u8,
+    param: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    // sample number, 0 for none yet
+    sample: u8,
+    playing: bool,
+    pos: u32,
+    // Q16 fraction of `pos`
+    frac: u32,
+    // Q16 samples for each output frame
+    step: u32,
+    // the note's period, which portamento changes
+    period: u16,
+    // what's played, after vibrato and arpeggio
+    out_period: u16,
+    volume: u8,
+    finetune: i8,
+    effect: u8,
+    param: u8,
+    porta_target: u16,
+    porta_speed: u8,
+    vibrato_pos: u8,
+    vibrato_speed: u8,
+    vibrato_depth: u8,
+    offset: u8,
+    loop_row: u8,
+    loop_count: u8,
+    // a note held back by a note delay
+    delayed: Option<Note>,
+}
+
+impl Channel {
+    fn slide_volume(&mut self) {
+        let (up, down) = (self.param >> 4, self.param & 0x0F);
+        self.volume = if up > 0 {
+            (self.volume + up).min(64)
+        } else {
+            self.volume.saturating_sub(down)
+        };
+    }
+
+    fn tone_porta(&mut self) {
+        if self.porta_target == 0 {
+            return;
+        }
+        let speed = self.porta_speed as u16;
+        self.period = if self.period < self.porta_target {
+            (self.period + speed).min(self.porta_target)
+        } else {
+            self.period.saturating_sub(speed).max(self.porta_target)
+        };
+        self.out_period = self.period;
+    }
+
+    fn vibrato(&mut self) {
+        let delta = VIBRATO[(self.vibrato_pos & 31) as usize] as i32 * self.vibrato_depth as i32 / 128;
+        let delta = if self.vibrato_pos & 32 != 0 { -delta } else { delta };
+        self.out_period = (self.period as i32 + delta).max(1) as u16;
+        self.vibrato_pos = (self.vibrato_pos + self.vibrato_speed) & 63;
+    }
+
+    fn retrigger(&mut self) {
+        self.pos = 0;
+        self.frac = 0;
+        self.playing = self.sample != 0;
+    }
+}
+
+fn finetuned(period: u16, finetune: i8) -> u16 {
+    ((period as u32 * FINETUNE[(finetune + 8) as usize]) >> 16) as u16
+}
+
+/// Plays a module, keeping track of where it is in the song.
+pub struct ModPlayer {
+    pub module: Module,
+    pub sample_rate: u32,
+    channels: Vec<Channel>,
+    order: usize,
+    row: usize,
+    // ticks into the row
+    tick: u32,
+    speed: u8,
+    tempo: u8,
+    // extra times through the row for a pattern delay
+    row_delay: u8,
+    // frames left in the tick, and what's carried over to the next one
+    tick_left: u32,
+    carry: u32,
+    // where to go after this row
+    jump: Option<usize>,
+    break_row: Option<usize>,
+    loop_to: Option<usize>,
+    // a bit for each row of each order that's been played
+    visited: Vec<u64>,
+    frames: u64,
+    finished: bool,
+    mix: Vec<i32>,
+    scratch: Vec<i16>,
+}
+
+impl ModPlayer {
+    pub fn new(module: Module, sample_rate: u32) -> Self {
+        let mut player = ModPlayer {
+            module,
+            sample_rate: sample_rate.max(1),
+            channels: Vec::new(),
+            order: 0,
+            row: 0,
+            tick: 0,
+            speed: DEFAULT_SPEED,
+            tempo: DEFAULT_TEMPO,
+            row_delay: 0,
+            tick_left: 0,
+            carry: 0,
+            jump: None,
+            break_row: None,
+            loop_to: None,
+            visited: Vec::new(),
+            frames: 0,
+            finished: false,
+            mix: Vec::new(),
+            scratch: Vec::new(),
+        };
+        player.restart();
+        player
+    }
+
+    fn restart(&mut self) {
+        self.channels = vec![Channel::default(); self.module.info.channels];
+        self.order = 0;
+        self.row = 0;
+        self.tick = 0;
+        self.speed = DEFAULT_SPEED;
+        self.tempo = DEFAULT_TEMPO;
+        self.row_delay = 0;
+        self.tick_left = 0;
+        self.carry = 0;
+        self.jump = None;
+        self.break_row = None;
+        self.loop_to = None;
+        self.visited = vec![0; self.module.info.orders.len()];
+        self.frames = 0;
+        self.finished = self.visited.is_empty();
+        if let Some(first) = self.visited.first_mut() {
+            *first = 1;
+        }
+    }
+
+    pub fn position_ms(&self) -> u32 {
+        (self.frames * 1000 / self.sample_rate as u64) as u32
+    }
+
+    /// The order and row playing.
+    pub fn position(&self) -> (usize, usize) {
+        (self.order, self.row)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished && self.tick_left == 0
+    }
+
+    /// Goes to `ms` into the song by playing it through from the start without mixing.
+    pub fn seek(&mut self, ms: u32) {
+        self.restart();
+        self.skip(ms as u64 * self.sample_rate as u64 / 1000);
+    }
+
+    /// Writes `out.len() / 2` stereo frames. Returns false once the song has finished, and
+    /// whatever it didn't write is left as silence.
+    pub fn render(&mut self, out: &mut [i16]) -> bool {
+        let frames = out.len() / 2;
+        let mut done = 0;
+        while done < frames {
+            if self.tick_left == 0 {
+                if self.finished {
+                    out[done * 2..].fill(0);
+                    return false;
+                }
+                self.next_tick();
+            }
+            let n = (self.tick_left as usize).min(frames - done);
+            self.mix(&mut out[done * 2..(done + n) * 2]);
+            self.tick_left -= n as u32;
+            self.frames += n as u64;
+            done += n;
+        }
+        !self.is_finished()
+    }
+
+    // moves on until `frames` into the song, keeping the samples' places without mixing
+    fn skip(&mut self, frames: u64) {
+        while self.frames < frames {
+            if self.tick_left == 0 {
+                if self.finished {
+                    return;
+                }
+                self.next_tick();
+            }
+            let n = (self.tick_left as u64).min(frames - self.frames) as u32;
+            for ch in self.channels.iter_mut().filter(|c| c.playing) {
+                let Some(sample) = self.module.info.samples.get(ch.sample as usize - 1) else {
+                    continue;
+                };
+                let total = ch.frac as u64 + ch.step as u64 * n as u64;
+                ch.pos = (ch.pos as u64 + (total >> 16)).min(u32::MAX as u64) as u32;
+                ch.frac = (total & 0xFFFF) as u32;
+                let (end, repeat) = sample.bounds();
+                if ch.pos >= end {
+                    match repeat {
+                        Some(start) => ch.pos = start + (ch.pos - end) % (end - start),
+                        None => ch.playing = false,
+                    }
+                }
+            }
+            self.tick_left -= n;
+            self.frames += n as u64;
+        }
+    }
+
+    // runs the effects for the next tick and works out how long it lasts
+    fn next_tick(&mut self) {
+        if self.tick == 0 {
+            self.play_row();
+        }
+        let speed = self.speed.max(1) as u32;
+        let sub = self.tick % speed;
+        if sub != 0 {
+            for i in 0..self.channels.len() {
+                self.channel_tick(i, sub);
+            }
+        }
+        let rate = self.sample_rate as u64;
+        for ch in self.channels.iter_mut() {
+            ch.step = match ch.out_period {
+                0 => 0,
+                p => ((PAULA_HZ << 16) / (p as u64 * rate)).min(u32::MAX as u64) as u32,
+            };
+        }
+
+        // a tick is 2.5 / tempo seconds
+        let per_tick = self.tempo as u32 * 2;
+        let frames = self.sample_rate * 5 + self.carry;
+        self.tick_left = frames / per_tick;
+        self.carry = frames % per_tick;
+
+        self.tick += 1;
+        if self.tick >= speed * (self.row_delay as u32 + 1) {
+            self.tick = 0;
+            self.row_delay = 0;
+            self.next_row();
+        }
+    }
+
+    fn next_row(&mut self) {
+        if let Some(row) = self.loop_to.take() {
+            self.row = row;
+            self.jump = None;
+            self.break_row = None;
+        } else if self.jump.is_some() || self.break_row.is_some() {
+            self.order = self.jump.take().unwrap_or(self.order + 1);
+            self.row = self.break_row.take().unwrap_or(0);
+        } else {
+            self.row += 1;
+            if self.row >= ROWS {
+                self.row = 0;
+                self.order += 1;
+            }
+        }
+        if self.order >= self.module.info.orders.len() {
+            self.finished = true;
+            return;
+        }
+        // rows go round again inside a pattern loop, which isn't the song repeating
+        if self.channels.iter().all(|c| c.loop_count == 0) {
+            let bit = 1u64 << self.row;
+            if self.visited[self.order] & bit != 0 {
+                self.finished = true;
+                return;
+            }
+            self.visited[self.order] |= bit;
+        }
+    }
+
+    fn play_row(&mut self) {
+        let pattern = self.module.info.orders[self.order] as usize;
+        for i in 0..self.channels.len() {
+            let note = self.module.note(pattern, self.row, i);
+            let ch = &mut self.channels[i];
+            ch.effect = note.effect;
+            ch.param = note.param;
+            // vibrato and arpeggio only last as long as their row
+            ch.out_period = ch.period;
+            ch.delayed = None;
+            if note.effect == 0xE && note.param >> 4 == 0xD && note.param & 0x0F > 0 {
+                ch.delayed = Some(note);
+                continue;
+            }
+            self.trigger(i, note);
+            self.row_effect(i, note);
+        }
+    }
+
+    // starts the note's sample and period
+    fn trigger(&mut self, i: usize, note: Note) {
+        let ch = &mut self.channels[i];
+        if let Some(sample) = (note.sample as usize).checked_sub(1).and_then(|s| self.module.info.samples.get(s)) {
+            ch.sample = note.sample;
+            ch.volume = sample.volume;
+            ch.finetune = sample.finetune;
+        }
+        if note.period == 0 {
+            return;
+        }
+        let period = finetuned(note.period, ch.finetune);
+        if note.effect == 0x3 || note.effect == 0x5 {
+            // slides to the note rather than starting it
+            ch.porta_target = period;
+            return;
+        }
+        ch.period = period;
+        ch.out_period = period;
+        ch.vibrato_pos = 0;
+        ch.retrigger();
+        if note.effect == 0x9 {
+            if note.param != 0 {
+                ch.offset = note.param;
+            }
+            ch.pos = ch.offset as u32 * 256;
+        }
+    }
+
+    // effects that happen once, at the start of the row
+    fn row_effect(&mut self, i: usize, note: Note) {
+        let row = self.row;
+        let ch = &mut self.channels[i];
+        let (x, y) = (note.param >> 4, note.param & 0x0F);
+        match note.effect {
+            0x3 if note.param != 0 => ch.porta_speed = note.param,
+            0x4 => {
+                if x != 0 {
+                    ch.vibrato_speed = x;
+                }
+                if y != 0 {
+                    ch.vibrato_depth = y;
+                }
+            }
+            0xB => self.jump = Some(note.param as usize),
+            0xC => ch.volume = note.param.min(64),
+            0xD => {
+                // the row is in decimal
+                let to = (x * 10 + y) as usize;
+                self.break_row = Some(if to < ROWS { to } else { 0 });
+            }
+            0xE => match x {
+                0x1 => {
+                    ch.period = ch.period.saturating_sub(y as u16).max(MIN_PERIOD);
+                    ch.out_period = ch.period;
+                }
+                0x2 => {
+                    ch.period = (ch.period + y as u16).min(MAX_PERIOD);
+                    ch.out_period = ch.period;
+                }
+                0x6 if y == 0 => ch.loop_row = row as u8,
+                0x6 => {
+                    if ch.loop_count == 0 {
+                        ch.loop_count = y;
+                        self.loop_to = Some(ch.loop_row as usize);
+                    } else {
+                        ch.loop_count -= 1;
+                        if ch.loop_count > 0 {
+                            self.loop_to = Some(ch.loop_row as usize);
+                        }
+                    }
+                }
+                0xA => ch.volume = (ch.volume + y).min(64),
+                0xB => ch.volume = ch.volume.saturating_sub(y),
+                0xC if y == 0 => ch.volume = 0,
+                0xE if self.row_delay == 0 => self.row_delay = y,
+                _ => {}
+            },
+            0xF => match note.param {
+                // stops the song
+                0 => self.jump = Some(usize::MAX),
+                1..32 => self.speed = note.param,
+                _ => self.tempo = note.param,
+            },
+            _ => {}
+        }
+    }
+
+    // effects that carry on through the row's other ticks
+    fn channel_tick(&mut self, i: usize, tick: u32) {
+        let ch = &mut self.channels[i];
+        let (x, y) = (ch.param >> 4, ch.param & 0x0F);
+        match ch.effect {
+            0x0 if ch.param != 0 => {
+                let semitones = [0, x, y][(tick % 3) as usize];
+                ch.out_period = ((ch.period as u32 * SEMITONES[semitones as usize]) >> 16) as u16;
+            }
+            0x1 => {
+                ch.period = ch.period.saturating_sub(ch.param as u16).max(MIN_PERIOD);
+                ch.out_period = ch.period;
+            }
+            0x2 => {
+                ch.period = (ch.period + ch.param as u16).min(MAX_PERIOD);
+                ch.out_period = ch.period;
+            }
+            0x3 => ch.tone_porta(),
+            0x4 => ch.vibrato(),
+            0x5 => {
+                ch.tone_porta();
+                ch.slide_volume();
+            }
+            0x6 => {
+                ch.vibrato();
+                ch.slide_volume();
+            }
+            0xA => ch.slide_volume(),
+            0xE => match x {
+                0x9 if y != 0 && tick.is_multiple_of(y as u32) => ch.retrigger(),
+                0xC if tick == y as u32 => ch.volume = 0,
+                0xD if tick == y as u32 => {
+                    if let Some(note) = ch.delayed.take() {
+                        self.trigger(i, note);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn mix(&mut self, out: &mut [i16]) {
+        let frames = out.len() / 2;
+        self.mix.clear();
+        self.mix.resize(frames * 2, 0);
+        let acc = &mut self.mix;
+        for (i, ch) in self.channels.iter_mut().enumerate() {
+            if !ch.playing {
+                continue;
+            }
+            let Some(sample) = self.module.info.samples.get(ch.sample as usize - 1) else {
+                continue;
+            };
+            let start = sample.offset as usize;
+            let data = self.module.data.get(start..start + sample.length as usize).unwrap_or(&[]);
+            let (end, repeat) = sample.bounds();
+            let (left, right) = match i % 4 {
+                0 | 3 => (NEAR_GAIN, FAR_GAIN),
+                _ => (FAR_GAIN, NEAR_GAIN),
+            };
+            let (left, right) = (left * ch.volume as i32, right * ch.volume as i32);
+            for f in 0..frames {
+                if ch.pos >= end {
+                    match repeat {
+                        Some(start) => ch.pos = start + (ch.pos - end) % (end - start),
+                        None => {
+                            ch.playing = false;
+                            break;
+                        }
+                    }
+                }
+                let next = if ch.pos + 1 < end { ch.pos + 1 } else { repeat.unwrap_or(end) };
+                let a = data.get(ch.pos as usize).map_or(0, |s| *s as i8 as i32);
+                let b = data.get(next as usize).map_or(0, |s| *s as i8 as i32);
+                let v = (a << 8) + (((b - a) * ch.frac as i32) >> 8);
+                acc[f * 2] += (v * left) >> 14;
+                acc[f * 2 + 1] += (v * right) >> 14;
+                ch.frac += ch.step;
+                ch.pos += ch.frac >> 16;
+                ch.frac &= 0xFFFF;
+            }
+        }
+        // four channels all at full volume come to full scale
+        let channels = self.channels.len().max(1) as i32;
+        for (o, a) in out.iter_mut().zip(self.mix.iter()) {
+            *o = soft_clip(a * 2 / channels).0;
+        }
+    }
+}
+
+impl Sound for ModPlayer {
+    fn render(&mut self, out: &mut [i16]) -> bool {
+        let mut stereo = core::mem::take(&mut self.scratch);
+        stereo.clear();
+        stereo.resize(out.len() * 2, 0);
+        let more = ModPlayer::render(self, &mut stereo);
+        for (o, f) in out.iter_mut().zip(stereo.chunks_exact(2)) {
+            *o = ((f[0] as i32 + f[1] as i32) / 2) as i16;
+        }
+        self.scratch = stereo;
+        more
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sample, period, effect and its parameter packed the way a pattern has them
+    fn cell(sample: u8, period: u16, effect: u8, param: u8) -> [u8; 4] {
+        [(sample & 0xF0) | (period >> 8) as u8, period as u8, (sample << 4) | effect, param]
+    }
+
+    type Notes<'a> = &'a [(usize, usize, [u8; 4])];
+
+    // a 4 channel module with one sample, a looped 32 byte square wave, and a pattern for
+    // each list of (row, channel, cell)
+    fn module(patterns: &[Notes], orders: &[u8]) -> Vec<u8> {
+        let mut file = vec![0u8; 1084];
+        file[..9].copy_from_slice(b"Test Song");
+        let sample = &mut file[20..50];
+        sample[..6].copy_from_slice(b"square");
+        // lengths are in 2 byte words
+        sample[22..24].copy_from_slice(&16u16.to_be_bytes());
+        sample[25] = 64;
+        sample[28..30].copy_from_slice(&16u16.to_be_bytes());
+        file[950] = orders.len() as u8;
+        file[951] = 127;
+        file[952..952 + orders.len()].copy_from_slice(orders);
+        file[1080..1084].copy_from_slice(b"M.K.");
+        for notes in patterns {
+            let mut pattern = vec![0u8; 1024];
+            for (row, channel, cell) in notes.iter() {
+                let at = (row * 4 + channel) * 4;
+                pattern[at..at + 4].copy_from_slice(cell);
+            }
+            file.extend_from_slice(&pattern);
+        }
+        file.extend((0..32).map(|i| if i < 16 { 100u8 } else { -100i8 as u8 }));
+        file
+    }
+
+    // speed 3, so 60ms rows. 16 rows of pattern 0 to a break, then all of pattern 1 and a
+    // jump back to the start, which ends it.
+    fn song() -> Vec<u8> {
+        module(
+            &[
+                &[(0, 0, cell(1, 428, 0xF, 3)), (15, 1, cell(0, 0, 0xD, 0))],
+                &[(63, 2, cell(0, 0, 0xB, 0))],
+            ],
+            &[0, 1],
+        )
+    }
+
+    fn player(file: &[u8]) -> ModPlayer {
+        ModPlayer::new(load(&mut &file[..], 1 << 20).unwrap(), 44100)
+    }
+
+    fn render_all(player: &mut ModPlayer) -> Vec<i16> {
+        let mut out = Vec::new();
+        let mut buf = vec![0i16; 2048];
+        loop {
+            let more = player.render(&mut buf);
+            out.extend_from_slice(&buf);
+            if !more {
+                return out;
+            }
+        }
+    }
+
+    // rising zero crossings a second on the left
+    fn hz(samples: &[i16]) -> f32 {
+        let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+        let rises = left.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count();
+        rises as f32 * 44100.0 / left.len() as f32
+    }
+
+    fn paula_hz(period: u16) -> f32 {
+        PAULA_HZ as f32 / period as f32 / 32.0
+    }
+
+    #[test]
+    fn header_and_length() {
+        let info = parse(&mut &song()[..]).unwrap();
+        assert_eq!(info.title, "Test Song");
+        assert_eq!((info.channels, info.patterns), (4, 2));
+        assert_eq!(info.orders, [0, 1]);
+        assert_eq!(info.samples.len(), 31);
+        let square = &info.samples[0];
+        assert_eq!(square.name, "square");
+        assert_eq!((square.length, square.loop_start, square.loop_length, square.volume), (32, 0, 32, 64));
+        // 16 + 64 rows of 60ms
+        assert_eq!(info.duration_ms, 80 * 60);
+        assert_eq!(info.memory(), 2 * 1024 + 32);
+    }
+
+    #[test]
+    fn plays_at_pitch() {
+        let mut player = player(&song());
+        let out = render_all(&mut player);
+        assert!(player.is_finished());
+        let frames = out.len() / 2;
+        assert!((4800 * 441 / 10..4800 * 441 / 10 + 2048).contains(&frames), "{}", frames);
+        assert!(player.position_ms() >= 4800);
+        // C-2 on the square wave
+        let pitch = hz(&out[..88200]);
+        assert!((pitch - paula_hz(428)).abs() < 3.0, "{}", pitch);
+        // channel 0 is mostly on the left
+        let left: u64 = out.iter().step_by(2).map(|s| s.unsigned_abs() as u64).sum();
+        let right: u64 = out.iter().skip(1).step_by(2).map(|s| s.unsigned_abs() as u64).sum();
+        assert!(left > right * 2 && right > 0, "{} {}", left, right);
+    }
+
+    #[test]
+    fn arpeggio_portamento_and_volume_slide() {
+        let file = module(
+            &[
+                &[
+                    // an octave up on every third tick
+                    (0, 0, cell(1, 428, 0x0, 0xC0)),
+                    // down 0x40 a tick
+                    (1, 0, cell(0, 0, 0x2, 0x40)),
+                    // 15 quieter a tick
+                    (2, 0, cell(0, 0, 0xA, 0x0F)),
+                    (3, 0, cell(0, 0, 0xD, 0)),
+                ],
+                &[(0, 0, cell(0, 0, 0xB, 0))],
+            ],
+            &[0, 1],
+        );
+        let out = render_all(&mut player(&file));
+        // speed 6 and tempo 125, 20ms ticks of 882 frames
+        let tick = 882 * 2;
+        let base = hz(&out[..tick]);
+        let up = hz(&out[tick..tick * 2]);
+        assert!((up / base - 2.0).abs() < 0.15, "{} {}", base, up);
+        // 5 ticks of sliding takes 428 to 748
+        let slid = hz(&out[tick * 11..tick * 12]);
+        assert!((slid - paula_hz(748)).abs() < 12.0, "{}", slid);
+        // and 5 ticks of sliding from 64 is silent
+        assert!(out[tick * 17..tick * 19].iter().all(|s| *s == 0));
+        assert!(out[tick * 12..tick * 13].iter().any(|s| *s != 0));
+    }
+
+    #[test]
+    fn vibrato_and_tempo() {
+        // tempo 32 makes 78ms ticks, long enough to measure the pitch in each
+        let file = module(
+            &[&[
+                (0, 0, cell(1, 214, 0x4, 0x8F)),
+                (0, 1, cell(0, 0, 0xF, 0x20)),
+                (1, 0, cell(0, 0, 0x4, 0x00)),
+                (1, 1, cell(0, 0, 0xD, 0)),
+            ]],
+            &[0],
+        );
+        let info = parse(&mut &file[..]).unwrap();
+        // two rows of 6 ticks, 2.5s / 32 each
+        assert_eq!(info.duration_ms, 12 * 2500 / 32);
+        let mut player = player(&file);
+        let out = render_all(&mut player);
+        let tick = 44100 * 2500 / 32 / 1000 * 2;
+        assert!(out.len() >= tick * 12);
+        // the first tick is the note as it is, then it wobbles both ways
+        let base = hz(&out[..tick]);
+        assert!((base - paula_hz(214)).abs() < 15.0, "{}", base);
+        let ratios: Vec<f32> = (1..12).map(|t| hz(&out[tick * t..tick * (t + 1)]) / base).collect();
+        assert!(ratios.iter().any(|r| *r > 1.08), "{:?}", ratios);
+        assert!(ratios.iter().any(|r| *r < 0.92), "{:?}", ratios);
+        assert!(ratios.iter().all(|r| (r - 1.0).abs() < 0.3), "{:?}", ratios);
+    }
+
+    #[test]
+    fn breaks_and_jumps() {
+        // speed 3 for 60ms rows. Order 0 breaks to row 10 of the next after 4 rows, that
+        // jumps on to order 2 after 3 more, which jumps back to the start after 2.
+        let file = module(
+            &[
+                &[(0, 0, cell(1, 428, 0xF, 3)), (3, 1, cell(0, 0, 0xD, 0x10))],
+                &[(12, 1, cell(0, 0, 0xB, 2))],
+                &[(1, 1, cell(0, 0, 0xB, 0))],
+            ],
+            &[0, 1, 2],
+        );
+        assert_eq!(parse(&mut &file[..]).unwrap().duration_ms, 9 * 60);
+        let mut player = player(&file);
+        for (ms, position) in [(0, (0, 0)), (190, (0, 3)), (250, (1, 10)), (370, (1, 12)), (430, (2, 0)), (500, (2, 1))] {
+            player.seek(ms);
+            assert_eq!(player.position_ms(), ms);
+            assert_eq!(player.position(), position, "at {}ms", ms);
+            assert!(!player.is_finished());
+        }
+        // 20ms, then the last 20ms and past the end
+        let mut out = vec![0i16; 1764];
+        assert!(player.render(&mut out));
+        assert!(out.iter().any(|s| *s != 0));
+        let mut out = vec![0i16; 8820];
+        assert!(!player.render(&mut out));
+        assert!(out[..1764].iter().any(|s| *s != 0));
+        assert!(out[1764..].iter().all(|s| *s == 0));
+        assert!(player.is_finished());
+        assert!(player.position_ms() >= 540);
+    }
+
+    #[test]
+    fn seek_and_limits() {
+        let file = song();
+        let mut player = player(&file);
+        player.seek(2000);
+        assert_eq!(player.position_ms(), 2000);
+        // 16 rows of order 0, then 1040ms into order 1
+        assert_eq!(player.position(), (1, 17));
+        assert_eq!(load(&mut &file[..], 1000).err(), Some(ModError::TooBig(2080)));
+        assert_eq!(parse(&mut &b"hello"[..]).err(), Some(ModError::NotMod));
+        let mut bad = file.clone();
+        bad[1080..1084].copy_from_slice(b"0CHN");
+        assert_eq!(parse(&mut &bad[..]).err(), Some(ModError::Unsupported(*b"0CHN")));
+    }
+
+    #[test]
+    fn old_15_sample_files() {
+        // the same song with the header cut down to 15 samples and no signature
+        let file = song();
+        let mut old = file[..20 + 15 * 30].to_vec();
+        old.extend_from_slice(&file[950..1080]);
+        old.extend_from_slice(&file[1084..]);
+        let info = parse(&mut &old[..]).unwrap();
+        assert_eq!((info.samples.len(), info.channels), (15, 4));
+        assert_eq!(info.duration_ms, 4800);
+    }
+}
@@ -0,0 +1,323 @@
+//! Pictures of the sound that's playing or being recorded.
+//!
+//! A `Tap` keeps the last few thousand samples going through somewhere. The audio sink
+//! feeds `audio::SINK_TAP` as it fills the DMA buffer and the microphone feeds
+//! `capture::CAPTURE_TAP`, so anything can look at them without getting in the way.
+//! They're written a sample at a time with atomics and read whenever, so a read can
+//! catch a sample half way through being replaced, which nobody will see.
+//!
+//! `Visualizer` draws a tap into part of the screen as spectrum bars with falling peaks,
+//! a waterfall that scrolls the spectrum down the screen over time, or the waveform.
+//! Call `update` and redraw its area at a steady frame rate, 30fps looks smooth.
+
+use crate::color::blend565;
+use crate::fft::{bands, Spectrum, Window, FLOOR_DB};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicI16, AtomicU32, Ordering};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+
+/// samples a tap holds, a power of two
+pub const TAP_FRAMES: usize = 2048;
+/// samples in each FFT, 1024 is 23ms at 44.1kHz with bins 43Hz apart
+pub const FFT_SIZE: usize = 1024;
+// the quietest level shown
+const RANGE_DB: f32 = 72.0;
+// how fast bars and peaks fall, in dB a second
+const FALL_DB: f32 = 60.0;
+const PEAK_FALL_DB: f32 = 20.0;
+const LOW_HZ: f32 = 40.0;
+const BAR_WIDTH: u32 = 8;
+// levels in the waterfall palette
+const SHADES: usize = 64;
+
+/// The latest mono samples going through somewhere.
+pub struct Tap {
+    samples: [AtomicI16; TAP_FRAMES],
+    written: AtomicU32,
+    sample_rate: AtomicU32,
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tap {
+    pub const fn new() -> Self {
+        Tap {
+            samples: [const { AtomicI16::new(0) }; TAP_FRAMES],
+            written: AtomicU32::new(0),
+            sample_rate: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// 0 until whatever feeds it starts.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Adds a sample. Only one thing should feed a tap.
+    pub fn push(&self, sample: i16) {
+        let at = self.written.load(Ordering::Relaxed);
+        self.samples[at as usize % TAP_FRAMES].store(sample, Ordering::Relaxed);
+        self.written.store(at.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Samples pushed so far, which wraps round.
+    pub fn written(&self) -> u32 {
+        self.written.load(Ordering::Acquire)
+    }
+
+    /// Copies the newest `out.len()` samples into `out`, oldest first. Returns `written`
+    /// as it was when they were copied.
+    pub fn latest(&self, out: &mut [i16]) -> u32 {
+        let written = self.written();
+        let n = out.len().min(TAP_FRAMES);
+        let start = written.wrapping_sub(n as u32);
+        for (i, s) in out[..n].iter_mut().enumerate() {
+            *s = self.samples[start.wrapping_add(i as u32) as usize % TAP_FRAMES].load(Ordering::Relaxed);
+        }
+        written
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Bars,
+    Waterfall,
+    Waveform,
+}
+
+impl Mode {
+    pub fn next(self) -> Mode {
+        match self {
+            Mode::Bars => Mode::Waterfall,
+            Mode::Waterfall => Mode::Waveform,
+            Mode::Waveform => Mode::Bars,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Bars => "spectrum",
+            Mode::Waterfall => "waterfall",
+            Mode::Waveform => "waveform",
+        }
+    }
+}
+
+/// Draws a tap in one of the `Mode`s.
+pub struct Visualizer {
+    pub mode: Mode,
+    pub area: Rectangle,
+    spectrum: Spectrum,
+    samples: Vec<i16>,
+    last_written: u32,
+    /// level of each bar in dB, falling smoothly
+    levels: Vec<f32>,
+    peaks: Vec<f32>,
+    // what each column of the waterfall shows
+    columns: Vec<f32>,
+    // a row of shades for each line of the area, `top` is the newest
+    waterfall: Vec<u8>,
+    top: usize,
+    palette: [Rgb565; SHADES],
+}
+
+impl Visualizer {
+    pub fn new(area: Rectangle, mode: Mode) -> Self {
+        let bars = (area.size.width / BAR_WIDTH).max(1) as usize;
+        let width = area.size.width as usize;
+        let height = area.size.height as usize;
+        Visualizer {
+            mode,
+            area,
+            spectrum: Spectrum::new(FFT_SIZE, Window::Hann),
+            samples: vec![0; FFT_SIZE],
+            last_written: 0,
+            levels: vec![FLOOR_DB; bars],
+            peaks: vec![FLOOR_DB; bars],
+            columns: vec![FLOOR_DB; width / 2],
+            waterfall: vec![0; (width / 2) * height],
+            top: 0,
+            palette: heat_palette(),
+        }
+    }
+
+    /// Changes the view, starting it afresh.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.levels.fill(FLOOR_DB);
+        self.peaks.fill(FLOOR_DB);
+        self.waterfall.fill(0);
+    }
+
+    /// Reads the tap and moves everything on by `dt_ms`. Returns false if there's been no
+    /// new sound since last time, in which case drawing again is only needed for the
+    /// bars to fall.
+    pub fn update(&mut self, tap: &Tap, dt_ms: u64) -> bool {
+        let written = tap.latest(&mut self.samples);
+        let fresh = written != self.last_written;
+        self.last_written = written;
+        let rate = tap.sample_rate();
+        if rate == 0 {
+            return false;
+        }
+        if fresh {
+            self.spectrum.analyze(&self.samples);
+        } else {
+            self.spectrum.magnitudes.fill(0.0);
+        }
+        let fall = FALL_DB * dt_ms as f32 / 1000.0;
+        let peak_fall = PEAK_FALL_DB * dt_ms as f32 / 1000.0;
+        match self.mode {
+            Mode::Bars => {
+                let mut now = vec![FLOOR_DB; self.levels.len()];
+                bands(&self.spectrum.magnitudes, rate, LOW_HZ, &mut now);
+                for ((level, peak), now) in self.levels.iter_mut().zip(self.peaks.iter_mut()).zip(now) {
+                    // jumps up, falls back slowly
+                    *level = now.max(*level - fall);
+                    *peak = level.max(*peak - peak_fall);
+                }
+            }
+            Mode::Waterfall => {
+                bands(&self.spectrum.magnitudes, rate, LOW_HZ, &mut self.columns);
+                let width = self.columns.len();
+                let rows = self.waterfall.len() / width.max(1);
+                if rows > 0 {
+                    self.top = (self.top + rows - 1) % rows;
+                    let row = &mut self.waterfall[self.top * width..(self.top + 1) * width];
+                    for (shade, db) in row.iter_mut().zip(&self.columns) {
+                        *shade = height_of(*db, SHADES as u32 - 1) as u8;
+                    }
+                }
+            }
+            Mode::Waveform => {}
+        }
+        fresh
+    }
+
+    /// Draws the whole area.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let area = self.area;
+        area.into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK)).draw(target)?;
+        match self.mode {
+            Mode::Bars => self.draw_bars(target),
+            Mode::Waterfall => self.draw_waterfall(target),
+            Mode::Waveform => self.draw_waveform(target),
+        }
+    }
+
+    fn draw_bars<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let area = self.area;
+        let bottom = area.top_left.y + area.size.height as i32;
+        let left = area.top_left.x + (area.size.width % BAR_WIDTH) as i32 / 2;
+        for (i, (level, peak)) in self.levels.iter().zip(&self.peaks).enumerate() {
+            let x = left + i as i32 * BAR_WIDTH as i32;
+            let h = height_of(*level, area.size.height);
+            // colored by how loud it is, like an LED meter
+            let color = self.palette[(h * (SHADES as u32 - 1) / area.size.height.max(1)) as usize];
+            if h > 0 {
+                Rectangle::new(Point::new(x, bottom - h as i32), Size::new(BAR_WIDTH - 2, h))
+                    .into_styled(PrimitiveStyle::with_fill(color))
+                    .draw(target)?;
+            }
+            let p = height_of(*peak, area.size.height);
+            if p > 0 {
+                Rectangle::new(Point::new(x, bottom - p as i32), Size::new(BAR_WIDTH - 2, 2))
+                    .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+                    .draw(target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn draw_waterfall<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let width = self.columns.len();
+        let rows = self.waterfall.len() / width.max(1);
+        // the screen is drawn in strips, so skip the lines outside this one
+        let visible = target.bounding_box().intersection(&self.area);
+        for y in 0..rows {
+            let line = Rectangle::new(
+                self.area.top_left + Point::new(0, y as i32),
+                Size::new(width as u32 * 2, 1),
+            );
+            if line.intersection(&visible).is_zero_sized() {
+                continue;
+            }
+            let row = (self.top + y) % rows;
+            let shades = &self.waterfall[row * width..(row + 1) * width];
+            // two pixels a column
+            let colors = shades.iter().flat_map(|s| {
+                let c = self.palette[*s as usize];
+                [c, c]
+            });
+            target.fill_contiguous(&line, colors)?;
+        }
+        Ok(())
+    }
+
+    fn draw_waveform<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let area = self.area;
+        let middle = area.top_left.y + area.size.height as i32 / 2;
+        let half = area.size.height as i32 / 2 - 1;
+        Line::new(
+            Point::new(area.top_left.x, middle),
+            Point::new(area.top_left.x + area.size.width as i32 - 1, middle),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(Rgb565::CSS_DARK_SLATE_GRAY, 1))
+        .draw(target)?;
+
+        // start on a rising zero crossing so a steady tone stands still
+        let width = area.size.width as usize;
+        let search = self.samples.len().saturating_sub(width);
+        let start = (1..search)
+            .find(|&i| self.samples[i - 1] < 0 && self.samples[i] >= 0)
+            .unwrap_or(search);
+        let style = PrimitiveStyle::with_stroke(Rgb565::CSS_LIGHT_GREEN, 1);
+        let y = |s: i16| middle - (s as i32 * half) / i16::MAX as i32;
+        let mut previous = Point::new(area.top_left.x, y(self.samples[start]));
+        for (x, s) in self.samples[start..].iter().take(width).enumerate().skip(1) {
+            let p = Point::new(area.top_left.x + x as i32, y(*s));
+            Line::new(previous, p).into_styled(style).draw(target)?;
+            previous = p;
+        }
+        Ok(())
+    }
+}
+
+// how far up a level reaches, out of `height`
+fn height_of(db: f32, height: u32) -> u32 {
+    let fraction = ((db + RANGE_DB) / RANGE_DB).clamp(0.0, 1.0);
+    (fraction * height as f32) as u32
+}
+
+// black through blue, purple, red and yellow to white
+fn heat_palette() -> [Rgb565; SHADES] {
+    let stops = [
+        Rgb565::BLACK,
+        Rgb565::CSS_NAVY,
+        Rgb565::CSS_PURPLE,
+        Rgb565::CSS_RED,
+        Rgb565::CSS_ORANGE,
+        Rgb565::YELLOW,
+        Rgb565::WHITE,
+    ];
+    let mut palette = [Rgb565::BLACK; SHADES];
+    let spans = stops.len() - 1;
+    for (i, c) in palette.iter_mut().enumerate() {
+        let at = i * spans * 255 / (SHADES - 1);
+        let span = (at / 255).min(spans - 1);
+        *c = blend565(stops[span], stops[span + 1], (at - span * 255) as u8);
+    }
+    palette
+}
@@ -0,0 +1,342 @@
+//! The master volume.
+//!
+//! `Volume` is a step from 0, silent, up to `STEPS`, full scale, and a mute switch. The
+//! steps are 3dB apart, so each press sounds like the same change whether it's loud or
+//! quiet, rather than the top few steps doing nothing much. It's saved with the settings
+//! so every app starts at the same volume.
+//!
+//! The `AudioPlayer` scales everything it plays by the volume through a `Ramp`, which
+//! moves the gain over a few milliseconds instead of jumping, so changing the volume,
+//! muting, starting and stopping don't click.
+//!
+//! `VolumeControl` is what apps use for the keys: + and - change the volume and 0 mutes.
+//! While the volume is showing on screen the trackball's up and down change it too, then
+//! go back to the app once it's gone.
+
+use embedded_graphics::mono_font::ascii::FONT_7X13;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, RoundedRectangle};
+use embedded_graphics::text::{Baseline, Text};
+use serde::{Deserialize, Serialize};
+
+pub const STEPS: u8 = 16;
+/// the loudest is full scale, this is a quarter of it
+pub const DEFAULT_STEP: u8 = 12;
+/// how long the gain takes to go all the way from silent to full
+pub const RAMP_MS: u32 = 20;
+/// Q16 gain for each step, 3dB apart
+pub const GAINS: [u32; STEPS as usize + 1] = [
+    0, 369, 521, 735, 1039, 1467, 2072, 2927, 4135, 5841, 8250, 11654, 16462, 23253, 32846, 46396, 65536,
+];
+pub const UNITY: u32 = 1 << 16;
+
+// how long the volume stays on screen after the last change
+const SHOW_MS: u64 = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Volume {
+    /// 0 to `STEPS`
+    pub step: u8,
+    pub muted: bool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            step: DEFAULT_STEP,
+            muted: false,
+        }
+    }
+}
+
+impl Volume {
+    /// Turning it up unmutes.
+    pub fn up(&mut self) {
+        self.step = (self.step + 1).min(STEPS);
+        self.muted = false;
+    }
+
+    pub fn down(&mut self) {
+        self.step = self.step.saturating_sub(1).min(STEPS);
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+    }
+
+    /// Q16, `UNITY` is full scale.
+    pub fn gain(&self) -> u32 {
+        if self.muted {
+            0
+        } else {
+            GAINS[self.step.min(STEPS) as usize]
+        }
+    }
+
+    /// Below full scale in dB, or `None` when it's silent.
+    pub fn db(&self) -> Option<i32> {
+        match self.gain() {
+            0 => None,
+            _ => Some((self.step.min(STEPS) as i32 - STEPS as i32) * 3),
+        }
+    }
+}
+
+/// A gain that follows its target a little each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ramp {
+    gain: u32,
+    // Q16 change a frame
+    rate: u32,
+}
+
+impl Ramp {
+    /// Starts silent. A full swing takes `ms`.
+    pub fn new(sample_rate: u32, ms: u32) -> Self {
+        let frames = (sample_rate as u64 * ms as u64 / 1000).max(1) as u32;
+        Ramp {
+            gain: 0,
+            rate: (UNITY / frames).max(1),
+        }
+    }
+
+    pub fn gain(&self) -> u32 {
+        self.gain
+    }
+
+    /// Jumps straight to a gain.
+    pub fn set(&mut self, gain: u32) {
+        self.gain = gain.min(UNITY);
+    }
+
+    /// Scales a stereo frame, moving the gain a frame's worth towards `target`.
+    pub fn apply(&mut self, frame: [i16; 2], target: u32) -> [i16; 2] {
+        let target = target.min(UNITY);
+        self.gain = if self.gain < target {
+            (self.gain + self.rate).min(target)
+        } else {
+            self.gain.saturating_sub(self.rate).max(target)
+        };
+        let gain = self.gain as i32;
+        frame.map(|s| ((s as i32 * gain) >> 16) as i16)
+    }
+}
+
+/// The volume keys and the volume on screen.
+pub struct VolumeControl {
+    pub volume: Volume,
+    /// on screen now
+    pub visible: bool,
+    hide_at: u64,
+    redraw: bool,
+    // changed since it was last saved
+    unsaved: bool,
+}
+
+impl VolumeControl {
+    /// Where the volume is drawn, in the middle of the screen over the app.
+    pub const AREA: Rectangle = Rectangle::new(Point::new(70, 95), Size::new(180, 50));
+
+    pub fn new(volume: Volume) -> Self {
+        VolumeControl {
+            volume,
+            visible: false,
+            hide_at: 0,
+            redraw: false,
+            unsaved: false,
+        }
+    }
+
+    /// Takes a key press and the trackball's up and down. Returns true if they were for
+    /// the volume, in which case the app should ignore them.
+    pub fn handle(&mut self, key: Option<u8>, up: bool, down: bool, now_ms: u64) -> bool {
+        let before = self.volume;
+        let used = match key {
+            Some(b'+' | b'=') => {
+                self.volume.up();
+                true
+            }
+            Some(b'-' | b'_') => {
+                self.volume.down();
+                true
+            }
+            Some(b'0') => {
+                self.volume.toggle_mute();
+                true
+            }
+            _ if self.visible && (up || down) => {
+                if up {
+                    self.volume.up();
+                } else {
+                    self.volume.down();
+                }
+                true
+            }
+            _ => false,
+        };
+        if used {
+            self.visible = true;
+            self.hide_at = now_ms + SHOW_MS;
+            self.redraw = true;
+            self.unsaved |= self.volume != before;
+        }
+        used
+    }
+
+    /// Hides the volume once it's been up long enough. Returns true when `AREA` needs
+    /// drawing again.
+    pub fn poll(&mut self, now_ms: u64) -> bool {
+        if self.visible && now_ms >= self.hide_at {
+            self.visible = false;
+            self.redraw = true;
+        }
+        core::mem::take(&mut self.redraw)
+    }
+
+    /// The volume to save, once it's off the screen after being changed. Saving on every
+    /// press would wear the flash.
+    pub fn take_unsaved(&mut self) -> Option<Volume> {
+        if self.visible || !self.unsaved {
+            return None;
+        }
+        self.unsaved = false;
+        Some(self.volume)
+    }
+
+    /// Draws the volume if it's showing.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        if !self.visible {
+            return Ok(());
+        }
+        let area = Self::AREA;
+        RoundedRectangle::with_equal_corners(area, Size::new(6, 6))
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(Rgb565::new(3, 6, 3))
+                    .stroke_color(Rgb565::CSS_DIM_GRAY)
+                    .stroke_width(1)
+                    .build(),
+            )
+            .draw(target)?;
+        let label = match self.volume.db() {
+            _ if self.volume.muted => "volume  muted".into(),
+            None => "volume  off".into(),
+            Some(db) => alloc::format!("volume  {}dB", db),
+        };
+        let text = MonoTextStyle::new(&FONT_7X13, Rgb565::WHITE);
+        Text::with_baseline(&label, area.top_left + Point::new(10, 6), text, Baseline::Top).draw(target)?;
+
+        // a segment a step, lit up to the volume and dimmed when muted
+        let lit = if self.volume.muted {
+            Rgb565::CSS_DIM_GRAY
+        } else {
+            Rgb565::CSS_ORANGE
+        };
+        let left = area.top_left.x + 10;
+        let top = area.top_left.y + 26;
+        let width = (area.size.width as i32 - 20) / STEPS as i32;
+        for i in 0..STEPS as i32 {
+            let color = if i < self.volume.step as i32 { lit } else { Rgb565::new(6, 12, 6) };
+            Rectangle::new(Point::new(left + i * width, top), Size::new(width as u32 - 2, 14))
+                .into_styled(PrimitiveStyle::with_fill(color))
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_rises_with_each_step() {
+        assert_eq!(GAINS[0], 0);
+        assert_eq!(GAINS[STEPS as usize], UNITY);
+        assert!(GAINS.windows(2).all(|w| w[0] < w[1]));
+
+        let mut volume = Volume { step: 0, muted: false };
+        assert_eq!(volume.db(), None);
+        for step in 1..=STEPS {
+            let before = volume.gain();
+            volume.up();
+            assert_eq!(volume.step, step);
+            assert!(volume.gain() > before);
+            assert_eq!(volume.db(), Some((step as i32 - STEPS as i32) * 3));
+        }
+        volume.up();
+        assert_eq!(volume.step, STEPS);
+        assert_eq!(volume.gain(), UNITY);
+        for _ in 0..=STEPS {
+            volume.down();
+        }
+        assert_eq!(volume.step, 0);
+        assert_eq!(volume.gain(), 0);
+    }
+
+    #[test]
+    fn muting_keeps_the_step() {
+        let mut volume = Volume::default();
+        volume.toggle_mute();
+        assert_eq!(volume.gain(), 0);
+        assert_eq!(volume.db(), None);
+        volume.down();
+        assert!(volume.muted);
+        assert_eq!(volume.step, DEFAULT_STEP - 1);
+        volume.toggle_mute();
+        assert_eq!(volume.gain(), GAINS[DEFAULT_STEP as usize - 1]);
+
+        // turning it up unmutes one step louder than it was
+        volume.toggle_mute();
+        volume.up();
+        assert!(!volume.muted);
+        assert_eq!(volume.gain(), GAINS[DEFAULT_STEP as usize]);
+    }
+
+    #[test]
+    fn ramp_reaches_the_target_without_overshooting() {
+        let frames = 48_000 * RAMP_MS / 1000;
+        let mut ramp = Ramp::new(48_000, RAMP_MS);
+        assert_eq!(ramp.gain(), 0);
+
+        let target = GAINS[DEFAULT_STEP as usize];
+        let mut took = 0;
+        while ramp.gain() != target {
+            let before = ramp.gain();
+            let [l, r] = ramp.apply([i16::MAX, i16::MIN], target);
+            assert!(ramp.gain() > before && ramp.gain() <= target);
+            assert!(l >= 0 && r <= 0 && l as i32 <= -(r as i32));
+            took += 1;
+            assert!(took <= frames);
+        }
+        // a part swing is quicker than a full one
+        assert!(took < frames);
+        assert_eq!(ramp.apply([1000, -1000], target), [251, -252]);
+        assert_eq!(ramp.gain(), target);
+
+        let mut took = 0;
+        while ramp.gain() != 0 {
+            let before = ramp.gain();
+            ramp.apply([0, 0], 0);
+            assert!(ramp.gain() < before);
+            took += 1;
+        }
+        assert!(took <= frames);
+        assert_eq!(ramp.apply([i16::MAX, i16::MIN], 0), [0, 0]);
+
+        // a full swing takes about `RAMP_MS`, a few frames over as the step rounds down,
+        // and the target is clamped to full scale
+        let mut took = 0;
+        while ramp.gain() != UNITY {
+            ramp.apply([0, 0], u32::MAX);
+            took += 1;
+        }
+        assert!((frames..=frames + frames / 100).contains(&took));
+        assert_eq!(ramp.apply([i16::MIN, i16::MAX], UNITY), [i16::MIN, i16::MAX]);
+
+        ramp.set(u32::MAX);
+        assert_eq!(ramp.gain(), UNITY);
+    }
+}
@@ -0,0 +1,1063 @@
+//! Reading and writing WAV files.
+//!
+//! `parse` walks every chunk of the RIFF file through a `TextSource`, so the header can
+//! be anywhere in a file of any size, and finds where the samples really start. The
+//! samples can be 8, 16, 24 or 32 bit integers, 32 bit floats or IMA ADPCM, with any
+//! number of channels, in plain or `WAVE_FORMAT_EXTENSIBLE` headers. Title and artist come
+//! from the `LIST`/`INFO` chunk if there is one.
+//!
+//! Everything decodes to interleaved i16 samples for the `AudioSink`. `WavStream` reads
+//! and decodes the data a chunk at a time and can seek.
+//!
+//! `WavWriter` goes the other way for recording, streaming 16 bit samples out to a
+//! `WavSink` and patching the sizes in the header when it's finished. `encode_adpcm`
+//! makes a whole IMA ADPCM file at once, for shrinking sounds before they go on the card
+//! or into flash.
+
+use crate::reader::TextSource;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_IMA_ADPCM: u16 = 0x11;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// more than any real file has, in case of a loop of broken chunk sizes
+const MAX_CHUNKS: usize = 64;
+// INFO strings longer than this are cut short
+const MAX_TAG_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavError {
+    /// doesn't start with RIFF....WAVE
+    NotWave,
+    /// there's no `fmt ` chunk, or it's too short
+    NoFormat,
+    NoData,
+    /// a format code, or sample size, this can't decode
+    Unsupported(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// signed integers, except 8 bit which is unsigned. The size in bytes is 1 to 4.
+    Int(u8),
+    Float32,
+    /// blocks of 4 bit samples, each block starts fresh
+    ImaAdpcm { frames_per_block: u32 },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WavTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WavInfo {
+    pub encoding: Encoding,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// bytes in a frame, or in a block for ADPCM
+    pub block_align: u16,
+    /// where the samples start in the file
+    pub data_offset: u32,
+    /// length of the samples in bytes
+    pub data_len: u32,
+    /// number of frames from the `fact` chunk, which compressed files have
+    pub fact_frames: Option<u32>,
+    pub tags: WavTags,
+}
+
+impl WavInfo {
+    /// Frames in each unit `decode` works on, more than one for ADPCM.
+    pub fn frames_per_block(&self) -> u32 {
+        match self.encoding {
+            Encoding::ImaAdpcm { frames_per_block } => frames_per_block,
+            _ => 1,
+        }
+    }
+
+    pub fn total_frames(&self) -> u32 {
+        let blocks = self.data_len / self.block_align as u32;
+        let whole = blocks * self.frames_per_block();
+        let partial = match self.encoding {
+            Encoding::ImaAdpcm { .. } => adpcm_frames(self.data_len % self.block_align as u32, self.channels),
+            _ => 0,
+        };
+        match self.fact_frames {
+            Some(frames) => frames.min(whole + partial),
+            None => whole + partial,
+        }
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        (self.total_frames() as u64 * 1000 / self.sample_rate.max(1) as u64) as u32
+    }
+
+    /// Where to start reading to get to `frame`: the byte offset within the data and how
+    /// many decoded frames to throw away first.
+    pub fn seek(&self, frame: u32) -> (u32, u32) {
+        let frame = frame.min(self.total_frames());
+        let per_block = self.frames_per_block();
+        let block = frame / per_block;
+        (block * self.block_align as u32, frame % per_block)
+    }
+
+    /// Decodes whole frames (or ADPCM blocks) from `data` onto the end of `out`, and returns
+    /// how many bytes were used. At the end of the file pass `end` so a short last ADPCM
+    /// block is decoded too.
+    pub fn decode(&self, data: &[u8], end: bool, out: &mut Vec<i16>) -> usize {
+        let block = self.block_align as usize;
+        let blocks = data.len() / block;
+        let channels = self.channels as usize;
+        match self.encoding {
+            Encoding::Int(1) => out.extend(data[..blocks * block].iter().map(|b| ((*b as i16) - 128) << 8)),
+            Encoding::Int(size) => {
+                let size = size as usize;
+                // keep the top 16 bits
+                out.extend(
+                    data[..blocks * block]
+                        .chunks_exact(size)
+                        .map(|s| i16::from_le_bytes([s[size - 2], s[size - 1]])),
+                );
+            }
+            Encoding::Float32 => out.extend(data[..blocks * block].chunks_exact(4).map(|s| {
+                let v = f32::from_le_bytes([s[0], s[1], s[2], s[3]]);
+                (v.clamp(-1.0, 1.0) * 32767.0) as i16
+            })),
+            Encoding::ImaAdpcm { frames_per_block } => {
+                for b in data.chunks_exact(block) {
+                    decode_adpcm_block(b, channels, frames_per_block as usize, out);
+                }
+                let rest = &data[blocks * block..];
+                if end && rest.len() > 4 * channels {
+                    let frames = adpcm_frames(rest.len() as u32, self.channels) as usize;
+                    decode_adpcm_block(rest, channels, frames, out);
+                    return data.len();
+                }
+            }
+        }
+        blocks * block
+    }
+}
+
+fn le_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn le_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+// reads exactly `len` bytes at `offset`, or None if the file is shorter
+fn read_exact(src: &mut impl TextSource, offset: u32, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    (src.read_at(offset, &mut buf) == len).then_some(buf)
+}
+
+struct Format {
+    code: u16,
+    channels: u16,
+    sample_rate: u32,
+    block_align: u16,
+    bits: u16,
+    frames_per_block: u32,
+}
+
+fn parse_format(body: &[u8]) -> Result<Format, WavError> {
+    if body.len() < 16 {
+        return Err(WavError::NoFormat);
+    }
+    let mut format = Format {
+        code: le_u16(body, 0),
+        channels: le_u16(body, 2),
+        sample_rate: le_u32(body, 4),
+        block_align: le_u16(body, 12),
+        bits: le_u16(body, 14),
+        frames_per_block: 1,
+    };
+    if format.code == FORMAT_EXTENSIBLE {
+        if body.len() < 40 {
+            return Err(WavError::NoFormat);
+        }
+        // the real format code is the start of the sub format GUID
+        format.code = le_u16(body, 24);
+    }
+    if format.code == FORMAT_IMA_ADPCM {
+        format.frames_per_block = if body.len() >= 20 {
+            le_u16(body, 18) as u32
+        } else {
+            adpcm_frames(format.block_align as u32, format.channels)
+        };
+    }
+    if format.channels == 0 || format.block_align == 0 || format.frames_per_block == 0 {
+        return Err(WavError::NoFormat);
+    }
+    Ok(format)
+}
+
+fn info_string(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().into()
+}
+
+// the INFO list is a run of small chunks, one per tag
+fn parse_info(src: &mut impl TextSource, start: u32, end: u32, tags: &mut WavTags) {
+    let mut offset = start;
+    while offset + 8 <= end {
+        let Some(header) = read_exact(src, offset, 8) else {
+            return;
+        };
+        let size = le_u32(&header, 4);
+        let body = offset + 8;
+        let tag = match &header[0..4] {
+            b"INAM" => &mut tags.title,
+            b"IART" => &mut tags.artist,
+            b"IPRD" => &mut tags.album,
+            b"IGNR" => &mut tags.genre,
+            b"ICMT" => &mut tags.comment,
+            _ => {
+                offset = body.saturating_add(size).saturating_add(size & 1);
+                continue;
+            }
+        };
+        let len = (size as usize).min(MAX_TAG_LEN).min((end - body) as usize);
+        if let Some(data) = read_exact(src, body, len) {
+            let text = info_string(&data);
+            if !text.is_empty() {
+                *tag = Some(text);
+            }
+        }
+        offset = body.saturating_add(size).saturating_add(size & 1);
+    }
+}
+
+/// Reads the header of a WAV file.
+pub fn parse(src: &mut impl TextSource) -> Result<WavInfo, WavError> {
+    let file_len = src.size();
+    let header = read_exact(src, 0, 12).ok_or(WavError::NotWave)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(WavError::NotWave);
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut fact_frames = None;
+    let mut tags = WavTags::default();
+    let mut offset = 12u32;
+    for _ in 0..MAX_CHUNKS {
+        let Some(chunk) = read_exact(src, offset, 8) else {
+            break;
+        };
+        let size = le_u32(&chunk, 4);
+        let body = offset + 8;
+        // files being recorded, or written by careless tools, can have a bad data size
+        let available = file_len - body;
+        match &chunk[0..4] {
+            b"fmt " => {
+                let body = read_exact(src, body, (size as usize).min(64)).ok_or(WavError::NoFormat)?;
+                format = Some(parse_format(&body)?);
+            }
+            b"fact" if size >= 4 => {
+                fact_frames = read_exact(src, body, 4).map(|b| le_u32(&b, 0));
+            }
+            b"data" => {
+                let len = if size == 0 || size > available { available } else { size };
+                data = Some((body, len));
+            }
+            b"LIST" if size >= 4 && read_exact(src, body, 4).is_some_and(|kind| &kind == b"INFO") => {
+                parse_info(src, body + 4, body + size.min(available), &mut tags);
+            }
+            _ => {}
+        }
+        // chunks start on even offsets
+        let next = body as u64 + size as u64 + (size & 1) as u64;
+        if next + 8 > file_len as u64 {
+            break;
+        }
+        offset = next as u32;
+    }
+
+    let format = format.ok_or(WavError::NoFormat)?;
+    let (data_offset, data_len) = data.ok_or(WavError::NoData)?;
+    let encoding = match (format.code, format.bits) {
+        (FORMAT_PCM, 8 | 16 | 24 | 32) => Encoding::Int((format.bits / 8) as u8),
+        (FORMAT_FLOAT, 32) => Encoding::Float32,
+        (FORMAT_IMA_ADPCM, 4) => Encoding::ImaAdpcm {
+            frames_per_block: format.frames_per_block,
+        },
+        (code, _) => return Err(WavError::Unsupported(code)),
+    };
+    let sample_bytes = match encoding {
+        Encoding::Int(size) => size as u16,
+        Encoding::Float32 => 4,
+        Encoding::ImaAdpcm { .. } => 0,
+    };
+    if sample_bytes > 0 && format.block_align != sample_bytes * format.channels {
+        return Err(WavError::Unsupported(format.code));
+    }
+    if let Encoding::ImaAdpcm { frames_per_block } = encoding {
+        if frames_per_block == 0 || frames_per_block > adpcm_frames(format.block_align as u32, format.channels) {
+            return Err(WavError::Unsupported(format.code));
+        }
+    }
+    Ok(WavInfo {
+        encoding,
+        channels: format.channels,
+        sample_rate: format.sample_rate,
+        block_align: format.block_align,
+        data_offset,
+        data_len,
+        fact_frames,
+        tags,
+    })
+}
+
+// ---------- IMA ADPCM ----------
+
+const ADPCM_STEPS: [i16; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66, 73,
+    80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+const ADPCM_INDEX_CHANGE: [i8; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+/// The predictor for one channel of IMA ADPCM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdpcmState {
+    pub predictor: i16,
+    pub step_index: u8,
+}
+
+impl AdpcmState {
+    pub fn decode(&mut self, nibble: u8) -> i16 {
+        let step = ADPCM_STEPS[self.step_index as usize] as i32;
+        let mut diff = step >> 3;
+        if nibble & 4 != 0 {
+            diff += step;
+        }
+        if nibble & 2 != 0 {
+            diff += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            diff += step >> 2;
+        }
+        let predictor = if nibble & 8 != 0 {
+            self.predictor as i32 - diff
+        } else {
+            self.predictor as i32 + diff
+        };
+        self.predictor = predictor.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let index = self.step_index as i32 + ADPCM_INDEX_CHANGE[(nibble & 7) as usize] as i32;
+        self.step_index = index.clamp(0, 88) as u8;
+        self.predictor
+    }
+
+    /// The nibble that gets closest to `sample`, moving on to it the way `decode` would.
+    pub fn encode(&mut self, sample: i16) -> u8 {
+        let mut step = ADPCM_STEPS[self.step_index as usize] as i32;
+        let mut diff = sample as i32 - self.predictor as i32;
+        let mut nibble = 0;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        for bit in [4, 2, 1] {
+            if diff >= step {
+                nibble |= bit;
+                diff -= step;
+            }
+            step >>= 1;
+        }
+        self.decode(nibble);
+        nibble
+    }
+}
+
+// frames in a block of `len` bytes: one in each channel's header, then 2 per byte per channel
+fn adpcm_frames(len: u32, channels: u16) -> u32 {
+    let header = 4 * channels as u32;
+    if len < header {
+        return 0;
+    }
+    (len - header) * 2 / channels as u32 + 1
+}
+
+// a block is a 4 byte header per channel, then 4 bytes (8 samples) of each channel in turn
+fn decode_adpcm_block(block: &[u8], channels: usize, frames: usize, out: &mut Vec<i16>) {
+    let start = out.len();
+    out.resize(start + frames * channels, 0);
+    let samples = &mut out[start..];
+    for ch in 0..channels {
+        let header = &block[ch * 4..ch * 4 + 4];
+        let mut state = AdpcmState {
+            predictor: le_u16(header, 0) as i16,
+            step_index: header[2].min(88),
+        };
+        samples[ch] = state.predictor;
+        let data = &block[channels * 4..];
+        let mut frame = 1;
+        'words: for word in data.chunks_exact(4).skip(ch).step_by(channels) {
+            for byte in word {
+                for nibble in [byte & 0x0F, byte >> 4] {
+                    if frame >= frames {
+                        break 'words;
+                    }
+                    samples[frame * channels + ch] = state.decode(nibble);
+                    frame += 1;
+                }
+            }
+        }
+    }
+}
+
+// ---------- streaming ----------
+
+/// Decodes the samples of a WAV file a chunk at a time.
+pub struct WavStream {
+    pub info: WavInfo,
+    // read position within the data
+    offset: u32,
+    // frames to drop from the next chunk after seeking into an ADPCM block
+    skip: u32,
+    buf: Vec<u8>,
+}
+
+impl WavStream {
+    /// `chunk_bytes` is how much is read at a time, rounded to whole blocks.
+    pub fn new(info: WavInfo, chunk_bytes: usize) -> Self {
+        let block = info.block_align as usize;
+        let chunk = (chunk_bytes / block).max(1) * block;
+        WavStream {
+            info,
+            offset: 0,
+            skip: 0,
+            buf: vec![0; chunk],
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.offset >= self.info.data_len
+    }
+
+    /// The frame the next chunk starts at.
+    pub fn position(&self) -> u32 {
+        let block = self.offset / self.info.block_align as u32;
+        (block * self.info.frames_per_block() + self.skip).min(self.info.total_frames())
+    }
+
+    pub fn seek(&mut self, frame: u32) {
+        (self.offset, self.skip) = self.info.seek(frame);
+    }
+
+    /// Reads and decodes the next chunk onto the end of `out`. Returns false at the end.
+    pub fn next_chunk(&mut self, src: &mut impl TextSource, out: &mut Vec<i16>) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        let first = self.position();
+        let want = self.buf.len().min((self.info.data_len - self.offset) as usize);
+        let n = src.read_at(self.info.data_offset + self.offset, &mut self.buf[..want]);
+        let start = out.len();
+        let used = self.info.decode(&self.buf[..n], n < self.buf.len(), out);
+        if used == 0 {
+            // a broken file ending part way through a frame
+            self.offset = self.info.data_len;
+            return false;
+        }
+        self.offset += used as u32;
+        let channels = self.info.channels as usize;
+        if self.skip > 0 {
+            let skip = (self.skip as usize * channels).min(out.len() - start);
+            out.drain(start..start + skip);
+            self.skip = 0;
+        }
+        // the fact chunk can say a compressed file ends part way through its last block
+        let frames_left = self.info.total_frames().saturating_sub(first) as usize;
+        let frames = ((out.len() - start) / channels).min(frames_left);
+        out.truncate(start + frames * channels);
+        true
+    }
+}
+
+// ---------- writing ----------
+
+/// Somewhere a `WavWriter` can put a file. Samples are appended as they come, then the
+/// header is written again over the start once the sizes are known.
+pub trait WavSink {
+    type Error;
+    fn append(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+impl WavSink for Vec<u8> {
+    type Error = core::convert::Infallible;
+
+    fn append(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        if self.len() < start + data.len() {
+            self.resize(start + data.len(), 0);
+        }
+        self[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+pub const HEADER_LEN: usize = 44;
+
+/// The header of a 16 bit PCM file with `data_len` bytes of samples. A `data_len` of 0
+/// means the length isn't known yet, which `parse` reads as running to the end of the file.
+pub fn header(sample_rate: u32, channels: u16, data_len: u32) -> [u8; HEADER_LEN] {
+    let block_align = channels * 2;
+    let riff_len = if data_len == 0 { 0 } else { data_len.saturating_add(HEADER_LEN as u32 - 8) };
+    let mut h = [0u8; HEADER_LEN];
+    h[0..4].copy_from_slice(b"RIFF");
+    h[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    h[8..12].copy_from_slice(b"WAVE");
+    h[12..16].copy_from_slice(b"fmt ");
+    h[16..20].copy_from_slice(&16u32.to_le_bytes());
+    h[20..22].copy_from_slice(&FORMAT_PCM.to_le_bytes());
+    h[22..24].copy_from_slice(&channels.to_le_bytes());
+    h[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    h[28..32].copy_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    h[32..34].copy_from_slice(&block_align.to_le_bytes());
+    h[34..36].copy_from_slice(&16u16.to_le_bytes());
+    h[36..40].copy_from_slice(b"data");
+    h[40..44].copy_from_slice(&data_len.to_le_bytes());
+    h
+}
+
+/// Writes a 16 bit PCM WAV file a piece at a time, without knowing how long it'll be.
+///
+/// The header goes first with the sizes left as 0, samples are buffered and appended in
+/// chunks of `chunk_bytes`, and `finish` puts the real sizes in. If the recording is cut
+/// off before `finish`, `parse` still reads what got written.
+pub struct WavWriter {
+    pub sample_rate: u32,
+    pub channels: u16,
+    // bytes of samples appended so far, not counting the buffer
+    written: u32,
+    buffer: Vec<u8>,
+    chunk_bytes: usize,
+}
+
+impl WavWriter {
+    /// Starts the file with a header. The sink should be empty.
+    pub fn new<S: WavSink>(sink: &mut S, sample_rate: u32, channels: u16, chunk_bytes: usize) -> Result<Self, S::Error> {
+        let channels = channels.max(1);
+        sink.append(&header(sample_rate, channels, 0))?;
+        Ok(WavWriter {
+            sample_rate,
+            channels,
+            written: 0,
+            buffer: Vec::with_capacity(chunk_bytes),
+            chunk_bytes: chunk_bytes.max(2),
+        })
+    }
+
+    /// Frames written, including any waiting in the buffer.
+    pub fn frames(&self) -> u32 {
+        (self.written + self.buffer.len() as u32) / (self.channels as u32 * 2)
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        (self.frames() as u64 * 1000 / self.sample_rate.max(1) as u64) as u32
+    }
+
+    /// Adds interleaved samples, appending to the sink whenever a chunk's worth is ready.
+    pub fn write<S: WavSink>(&mut self, sink: &mut S, samples: &[i16]) -> Result<(), S::Error> {
+        for s in samples {
+            self.buffer.extend_from_slice(&s.to_le_bytes());
+            if self.buffer.len() >= self.chunk_bytes {
+                self.flush(sink)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends whatever is buffered.
+    pub fn flush<S: WavSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        if !self.buffer.is_empty() {
+            sink.append(&self.buffer)?;
+            self.written += self.buffer.len() as u32;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Appends the rest and writes the header again with the sizes. Returns the number of
+    /// frames in the file.
+    pub fn finish<S: WavSink>(mut self, sink: &mut S) -> Result<u32, S::Error> {
+        self.flush(sink)?;
+        // a whole number of frames, in case samples were written part way through one
+        let frame = self.channels as u32 * 2;
+        let data_len = self.written / frame * frame;
+        sink.write_at(0, &header(self.sample_rate, self.channels, data_len))?;
+        Ok(data_len / frame)
+    }
+}
+
+/// Bytes of each channel in the ADPCM blocks `encode_adpcm` writes.
+pub const ADPCM_CHANNEL_BLOCK: u16 = 512;
+
+/// Makes a whole IMA ADPCM WAV file from interleaved samples, a quarter of the size of 16
+/// bit PCM. The last block is padded out, and the `fact` chunk says where the samples end.
+pub fn encode_adpcm(samples: &[i16], sample_rate: u32, channels: u16) -> Vec<u8> {
+    let channels = channels.max(1);
+    let ch = channels as usize;
+    let frames = samples.len() / ch;
+    let block_align = ADPCM_CHANNEL_BLOCK * channels;
+    let per_block = adpcm_frames(block_align as u32, channels) as usize;
+    let blocks = frames.div_ceil(per_block);
+    let data_len = (blocks * block_align as usize) as u32;
+    let byte_rate = (sample_rate as u64 * block_align as u64 / per_block as u64) as u32;
+
+    let mut out = Vec::with_capacity(60 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(52 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&20u32.to_le_bytes());
+    out.extend_from_slice(&FORMAT_IMA_ADPCM.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&byte_rate.to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    // the extra format bytes, just the frames in a block
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&(per_block as u16).to_le_bytes());
+    out.extend_from_slice(b"fact");
+    out.extend_from_slice(&4u32.to_le_bytes());
+    out.extend_from_slice(&(frames as u32).to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+
+    // the step size carries on from block to block, so it doesn't have to find its way back
+    let mut states = vec![AdpcmState::default(); ch];
+    // past the end, the last sample again
+    let sample = |frame: usize, c: usize| match frames {
+        0 => 0,
+        _ => samples[frame.min(frames - 1) * ch + c],
+    };
+    let mut nibbles = Vec::with_capacity(per_block);
+    for block in 0..blocks {
+        let first = block * per_block;
+        for (c, state) in states.iter_mut().enumerate() {
+            state.predictor = sample(first, c);
+            out.extend_from_slice(&state.predictor.to_le_bytes());
+            out.extend_from_slice(&[state.step_index, 0]);
+        }
+        let data = out.len();
+        out.resize(data + block_align as usize - 4 * ch, 0);
+        for (c, state) in states.iter_mut().enumerate() {
+            nibbles.clear();
+            nibbles.extend((first + 1..first + per_block).map(|f| state.encode(sample(f, c))));
+            // 8 samples of this channel in each 4 byte word, then the other channels' words
+            for (word, eight) in nibbles.chunks(8).enumerate() {
+                let at = data + (word * ch + c) * 4;
+                for (i, pair) in eight.chunks(2).enumerate() {
+                    out[at + i] = pair[0] | (pair.get(1).copied().unwrap_or(0) << 4);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a RIFF chunk, padded to an even length
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn fmt(format: u16, channels: u16, rate: u32, block_align: u16, bits: u16, extra: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&format.to_le_bytes());
+        body.extend_from_slice(&channels.to_le_bytes());
+        body.extend_from_slice(&rate.to_le_bytes());
+        body.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+        body.extend_from_slice(&block_align.to_le_bytes());
+        body.extend_from_slice(&bits.to_le_bytes());
+        body.extend_from_slice(extra);
+        chunk(b"fmt ", &body)
+    }
+
+    // the cbSize, valid bits, channel mask and sub format GUID of WAVE_FORMAT_EXTENSIBLE
+    fn extensible(bits: u16, format: u16) -> Vec<u8> {
+        let mut extra = vec![22, 0];
+        extra.extend_from_slice(&bits.to_le_bytes());
+        extra.extend_from_slice(&3u32.to_le_bytes());
+        extra.extend_from_slice(&format.to_le_bytes());
+        extra.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71]);
+        extra
+    }
+
+    fn sine(len: usize) -> Vec<i16> {
+        (0..len).map(|i| ((i as f64 * 0.05).sin() * 20000.0) as i16).collect()
+    }
+
+    // everything in the file, decoded a chunk at a time
+    fn read_all(file: &[u8], chunk_bytes: usize) -> (WavInfo, Vec<i16>) {
+        let info = parse(&mut &file[..]).unwrap();
+        let mut stream = WavStream::new(info.clone(), chunk_bytes);
+        let mut out = Vec::new();
+        while stream.next_chunk(&mut &file[..], &mut out) {}
+        (info, out)
+    }
+
+    fn ramp(len: usize) -> Vec<i16> {
+        (0..len).map(|i| (i as i32 * 37 - 20000) as i16).collect()
+    }
+
+    // two sines, different in each channel
+    fn tone(frames: usize, channels: usize) -> Vec<i16> {
+        (0..frames * channels)
+            .map(|i| {
+                // not starting at 0, so a single frame has something in it
+                let t = (i / channels) as f64 + 5.0;
+                let c = (i % channels) as f64;
+                ((t * 0.031 * (1.0 + c)).sin() * 12000.0 + (t * 0.21).sin() * 4000.0) as i16
+            })
+            .collect()
+    }
+
+    fn snr_db(original: &[i16], decoded: &[i16]) -> f64 {
+        let signal: f64 = original.iter().map(|&x| (x as f64).powi(2)).sum();
+        let error: f64 = original.iter().zip(decoded).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum();
+        10.0 * (signal / error.max(1.0)).log10()
+    }
+
+    #[test]
+    fn header_fields() {
+        let h = header(16000, 2, 4000);
+        assert_eq!(&h[0..4], b"RIFF");
+        assert_eq!(le_u32(&h, 4), 4036);
+        assert_eq!(&h[8..16], b"WAVEfmt ");
+        assert_eq!(le_u32(&h, 24), 16000);
+        // bytes per second and per frame
+        assert_eq!(le_u32(&h, 28), 64000);
+        assert_eq!(&h[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&h[36..40], b"data");
+        assert_eq!(le_u32(&h, 40), 4000);
+        // not known yet
+        assert_eq!(le_u32(&header(16000, 2, 0), 4), 0);
+    }
+
+    #[test]
+    fn writer_appends_whole_chunks() {
+        let mut file: Vec<u8> = Vec::new();
+        let mut writer = WavWriter::new(&mut file, 16000, 2, 1000).unwrap();
+        assert_eq!(file.len(), HEADER_LEN);
+        let samples = ramp(3001);
+
+        // 499 samples is 998 bytes, still short of a chunk
+        writer.write(&mut file, &samples[..499]).unwrap();
+        assert_eq!(file.len(), HEADER_LEN);
+        assert_eq!(writer.frames(), 249);
+        // across the first boundary and most of the way through the next
+        writer.write(&mut file, &samples[499..1200]).unwrap();
+        assert_eq!(file.len(), HEADER_LEN + 2000);
+        // the sizes stay 0 until `finish`
+        assert_eq!(le_u32(&file, 4), 0);
+        assert_eq!(le_u32(&file, 40), 0);
+        // landing exactly on a boundary appends straight away
+        writer.write(&mut file, &samples[1200..1500]).unwrap();
+        assert_eq!(file.len(), HEADER_LEN + 3000);
+        writer.write(&mut file, &samples[1500..]).unwrap();
+        assert_eq!(file.len(), HEADER_LEN + 6000);
+        assert_eq!(writer.frames(), 1500);
+        assert_eq!(writer.duration_ms(), 93);
+
+        // a recording cut off before `finish` still reads as far as it got
+        let (cut, _) = read_all(&file, 512);
+        assert_eq!(cut.total_frames(), 1500);
+
+        // the odd sample left over isn't a whole frame
+        assert_eq!(writer.finish(&mut file).unwrap(), 1500);
+        assert_eq!(le_u32(&file, 40), 6000);
+        assert_eq!(le_u32(&file, 4), 6036);
+    }
+
+    #[test]
+    fn writer_odd_chunk_size() {
+        // a chunk ends on the first whole sample at or past `chunk_bytes`
+        let mut file: Vec<u8> = Vec::new();
+        let mut writer = WavWriter::new(&mut file, 8000, 1, 101).unwrap();
+        writer.write(&mut file, &ramp(50)).unwrap();
+        assert_eq!(file.len(), HEADER_LEN);
+        writer.write(&mut file, &ramp(1)).unwrap();
+        assert_eq!(file.len(), HEADER_LEN + 102);
+        writer.flush(&mut file).unwrap();
+        assert_eq!(writer.finish(&mut file).unwrap(), 51);
+        assert_eq!(le_u32(&file, 40), 102);
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        for (channels, len, chunk_bytes) in [(1u16, 0usize, 64usize), (1, 1, 64), (1, 4097, 1000), (2, 8000, 4096), (2, 333, 7)] {
+            let samples = ramp(len);
+            let mut file: Vec<u8> = Vec::new();
+            let mut writer = WavWriter::new(&mut file, 22050, channels, chunk_bytes).unwrap();
+            // in uneven pieces, the way a microphone hands them over
+            for piece in samples.chunks(97) {
+                writer.write(&mut file, piece).unwrap();
+            }
+            let frames = writer.finish(&mut file).unwrap();
+            assert_eq!(frames as usize, len / channels as usize);
+            assert_eq!(file.len(), HEADER_LEN + len * 2);
+
+            let (info, out) = read_all(&file, 300);
+            assert_eq!(info.encoding, Encoding::Int(2));
+            assert_eq!((info.channels, info.sample_rate), (channels, 22050));
+            assert_eq!(info.data_offset, HEADER_LEN as u32);
+            assert_eq!(info.total_frames(), frames);
+            // a sample short of a whole frame is left off
+            assert_eq!(out, samples[..frames as usize * channels as usize], "{} channels, {} samples", channels, len);
+        }
+    }
+
+    #[test]
+    fn adpcm_state_tracks_the_decoder() {
+        let mut encoder = AdpcmState::default();
+        let mut decoder = AdpcmState::default();
+        for target in [0i16, 100, 1000, 5000, -20000, 32767, -32768, 0] {
+            for _ in 0..100 {
+                let nibble = encoder.encode(target);
+                assert_eq!(decoder.decode(nibble), encoder.predictor);
+            }
+            assert!((encoder.predictor as i32 - target as i32).abs() < 64, "{} {}", target, encoder.predictor);
+        }
+    }
+
+    #[test]
+    fn adpcm_round_trip() {
+        let per_block = 1017;
+        for channels in [1usize, 2] {
+            for frames in [1, 19, 20, per_block - 1, per_block, per_block + 1, 5119, 5120, 5121] {
+                let input = tone(frames, channels);
+                let file = encode_adpcm(&input, 16000, channels as u16);
+                let blocks = frames.div_ceil(per_block);
+                assert_eq!(file.len(), 60 + blocks * ADPCM_CHANNEL_BLOCK as usize * channels);
+
+                let (info, out) = read_all(&file, 4096);
+                assert_eq!(info.encoding, Encoding::ImaAdpcm { frames_per_block: per_block as u32 });
+                assert_eq!(info.block_align as usize, 512 * channels);
+                assert_eq!((info.channels, info.sample_rate), (channels as u16, 16000));
+                assert_eq!(info.total_frames() as usize, frames);
+                assert_eq!(out.len(), input.len(), "{} channels, {} frames", channels, frames);
+                // the first sample of each block is kept as it is
+                assert_eq!(out[..channels], input[..channels]);
+                // the step size starts small and takes a few samples to catch up
+                let floor = if frames < per_block { 15.0 } else { 30.0 };
+                let snr = snr_db(&input, &out);
+                assert!(snr > floor, "{} channels, {} frames, {:.1}dB", channels, frames, snr);
+            }
+        }
+    }
+
+    #[test]
+    fn adpcm_seek() {
+        let input = tone(5000, 2);
+        let file = encode_adpcm(&input, 16000, 2);
+        let (info, all) = read_all(&file, 4096);
+        let mut stream = WavStream::new(info, 4096);
+        for at in [0u32, 1, 1016, 1017, 2500, 4999] {
+            stream.seek(at);
+            assert_eq!(stream.position(), at);
+            let mut part = Vec::new();
+            assert!(stream.next_chunk(&mut &file[..], &mut part));
+            let start = at as usize * 2;
+            assert_eq!(part[..], all[start..start + part.len()]);
+        }
+    }
+
+    #[test]
+    fn adpcm_empty() {
+        let file = encode_adpcm(&[], 8000, 1);
+        let (info, out) = read_all(&file, 4096);
+        assert_eq!(info.total_frames(), 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn pcm_16_with_tags_and_padding() {
+        let samples = sine(1000);
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"INAM", b"Quack\0"));
+        info.extend(chunk(b"IART", b"A Duck\0"));
+        // odd length, so there's a pad byte before the next one
+        info.extend(chunk(b"ICMT", b"odd"));
+        info.extend(chunk(b"IGNR", b"Noise\0"));
+        let file = riff(&[
+            chunk(b"JUNK", &[0; 27]),
+            fmt(FORMAT_PCM, 2, 22050, 4, 16, &[]),
+            chunk(b"data", &data),
+            chunk(b"LIST", &info),
+        ]);
+        let (info, out) = read_all(&file, 333);
+        assert_eq!(
+            info,
+            WavInfo {
+                encoding: Encoding::Int(2),
+                channels: 2,
+                sample_rate: 22050,
+                block_align: 4,
+                // after RIFF, JUNK and its pad byte, and fmt
+                data_offset: 12 + 8 + 28 + 24 + 8,
+                data_len: 2000,
+                fact_frames: None,
+                tags: WavTags {
+                    title: Some("Quack".into()),
+                    artist: Some("A Duck".into()),
+                    comment: Some("odd".into()),
+                    genre: Some("Noise".into()),
+                    album: None,
+                },
+            }
+        );
+        assert_eq!(info.total_frames(), 500);
+        assert_eq!(info.duration_ms(), 22);
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn pcm_8_bit() {
+        let samples = sine(999);
+        let data: Vec<u8> = samples.iter().map(|s| ((s >> 8) + 128) as u8).collect();
+        // an odd length data chunk, with its pad byte
+        let (info, out) = read_all(&riff(&[fmt(FORMAT_PCM, 1, 8000, 1, 8, &[]), chunk(b"data", &data)]), 100);
+        assert_eq!((info.encoding, info.data_len, info.total_frames()), (Encoding::Int(1), 999, 999));
+        assert_eq!(out, samples.iter().map(|s| s >> 8 << 8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pcm_24_bit() {
+        let samples = sine(1000);
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|s| {
+                let x = (*s as i32) << 8 | 0x7F;
+                [x as u8, (x >> 8) as u8, (x >> 16) as u8]
+            })
+            .collect();
+        let (info, out) = read_all(&riff(&[fmt(FORMAT_PCM, 2, 48000, 6, 24, &[]), chunk(b"data", &data)]), 1000);
+        assert_eq!((info.encoding, info.block_align, info.total_frames()), (Encoding::Int(3), 6, 500));
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn pcm_32_bit_extensible() {
+        let samples = sine(1000);
+        let data: Vec<u8> = samples.iter().flat_map(|s| ((*s as i32) << 16 | 0x1234).to_le_bytes()).collect();
+        let format = fmt(FORMAT_EXTENSIBLE, 2, 8000, 8, 32, &extensible(32, FORMAT_PCM));
+        let (info, out) = read_all(&riff(&[format, chunk(b"data", &data)]), 1000);
+        assert_eq!((info.encoding, info.channels, info.total_frames()), (Encoding::Int(4), 2, 500));
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn float_32() {
+        let samples = sine(1000);
+        let mut data: Vec<u8> = samples.iter().flat_map(|s| (*s as f32 / 32767.0).to_le_bytes()).collect();
+        // out of range is clipped
+        data.extend_from_slice(&2.0f32.to_le_bytes());
+        data.extend_from_slice(&(-2.0f32).to_le_bytes());
+        for format in [fmt(FORMAT_FLOAT, 2, 8000, 8, 32, &[]), fmt(FORMAT_EXTENSIBLE, 2, 8000, 8, 32, &extensible(32, FORMAT_FLOAT))] {
+            let (info, out) = read_all(&riff(&[format, chunk(b"data", &data)]), 1000);
+            assert_eq!((info.encoding, info.total_frames()), (Encoding::Float32, 501));
+            assert!(out[..1000].iter().zip(&samples).all(|(a, b)| (a - b).abs() <= 1));
+            assert_eq!(out[1000..], [32767, -32767]);
+        }
+    }
+
+    #[test]
+    fn adpcm_block() {
+        // predictor 0 and step index 0, then codes 7, 7 and 0
+        let block = [0, 0, 0, 0, 0x77, 0x00, 0x00, 0x00];
+        let format = fmt(FORMAT_IMA_ADPCM, 1, 8000, 8, 4, &[2, 0, 9, 0]);
+        let (info, out) = read_all(&riff(&[format, chunk(b"data", &block)]), 1000);
+        assert_eq!(info.encoding, Encoding::ImaAdpcm { frames_per_block: 9 });
+        assert_eq!(out, [0, 11, 41, 45, 48, 51, 54, 56, 58]);
+    }
+
+    #[test]
+    fn adpcm_partial_last_block() {
+        // a block and a bit, the way some encoders end a file without a fact chunk
+        let input = tone(1017 + 100, 2);
+        let padded = encode_adpcm(&input, 16000, 2);
+        let (padded_info, full) = read_all(&padded, 4096);
+        assert_eq!(padded_info.total_frames(), 1117);
+        // the header and 13 words a channel, 104 samples after the one in the header
+        let data = &padded[60..60 + 1024 + 8 + 13 * 8];
+        let format = fmt(FORMAT_IMA_ADPCM, 2, 16000, 1024, 4, &[2, 0, 0xF9, 0x03]);
+        let file = riff(&[format, chunk(b"data", data)]);
+        let (info, out) = read_all(&file, 300);
+        assert_eq!(info.fact_frames, None);
+        assert_eq!(info.total_frames(), 1017 + 105);
+        assert_eq!(out.len(), (1017 + 105) * 2);
+        assert_eq!(out[..full.len()], full[..]);
+
+        // and seeking into it
+        let mut stream = WavStream::new(info, 300);
+        stream.seek(1100);
+        let mut tail = Vec::new();
+        while stream.next_chunk(&mut &file[..], &mut tail) {}
+        assert_eq!(tail, out[1100 * 2..]);
+    }
+
+    #[test]
+    fn unknown_data_size() {
+        // a recording that never got its sizes runs to the end of the file
+        let samples = sine(1000);
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut file = riff(&[fmt(FORMAT_PCM, 2, 22050, 4, 16, &[]), chunk(b"data", &data)]);
+        file[40..44].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        let (info, out) = read_all(&file, 512);
+        assert_eq!(info.data_len, 2000);
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(&mut &b"RIFX\0\0\0\0WAVE"[..]), Err(WavError::NotWave));
+        assert_eq!(parse(&mut &riff(&[chunk(b"data", &[0; 4])])[..]), Err(WavError::NoFormat));
+        let no_data = riff(&[fmt(FORMAT_PCM, 2, 8000, 4, 16, &[])]);
+        assert_eq!(parse(&mut &no_data[..]), Err(WavError::NoData));
+        let mp3 = fmt(0x55, 2, 8000, 4, 16, &[]);
+        assert_eq!(parse(&mut &riff(&[mp3, chunk(b"data", &[0; 4])])[..]), Err(WavError::Unsupported(0x55)));
+        let twelve = fmt(FORMAT_PCM, 1, 8000, 2, 12, &[]);
+        assert!(matches!(parse(&mut &riff(&[twelve, chunk(b"data", &[0; 4])])[..]), Err(WavError::Unsupported(_))));
+    }
+}
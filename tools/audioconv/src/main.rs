@@ -0,0 +1,154 @@
+//! Converts sounds for the T-Deck on the computer, with the same code that plays them.
+//!
+//! Reads any WAV the wav module can, or a QOA file, and writes QOA when the output ends in
+//! `.qoa` or IMA ADPCM when it ends in `.wav`. `--pcm` writes a `.wav` of 16 bit PCM instead,
+//! handy for listening to what the compression did. `--rate` resamples and `--mono` mixes the
+//! channels down, both of which shrink the file further.
+//!
+//! ```text
+//! audioconv [--rate 22050] [--mono] [--pcm] IN.WAV OUT.QOA
+//! ```
+
+extern crate alloc;
+
+use std::process::ExitCode;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/qoa.rs"]
+mod qoa;
+#[allow(dead_code)]
+#[path = "../../../src/reader.rs"]
+mod reader;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/resample.rs"]
+mod resample;
+#[allow(dead_code)]
+#[path = "../../../src/wav.rs"]
+mod wav;
+
+use resample::{Quality, Resampler};
+
+struct Sound {
+    samples: Vec<i16>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+fn load(data: &[u8]) -> Result<Sound, String> {
+    let mut src = data;
+    if let Ok(info) = qoa::parse(&mut src) {
+        let (channels, sample_rate) = (info.channels, info.sample_rate);
+        let mut stream = qoa::QoaStream::new(info);
+        let mut samples = Vec::new();
+        while stream.next_chunk(&mut src, &mut samples) {}
+        return Ok(Sound {
+            samples,
+            channels,
+            sample_rate,
+        });
+    }
+    let info = wav::parse(&mut src).map_err(|e| format!("not a WAV or QOA file ({:?})", e))?;
+    let (channels, sample_rate) = (info.channels, info.sample_rate);
+    let mut stream = wav::WavStream::new(info, 1 << 16);
+    let mut samples = Vec::new();
+    while stream.next_chunk(&mut src, &mut samples) {}
+    Ok(Sound {
+        samples,
+        channels,
+        sample_rate,
+    })
+}
+
+fn to_mono(sound: &mut Sound) {
+    let n = sound.channels as usize;
+    sound.samples = sound
+        .samples
+        .chunks_exact(n)
+        .map(|f| (f.iter().map(|s| *s as i32).sum::<i32>() / n as i32) as i16)
+        .collect();
+    sound.channels = 1;
+}
+
+fn resample(sound: &mut Sound, rate: u32) {
+    let mut resampler = Resampler::new(sound.sample_rate, rate, sound.channels, Quality::Sinc);
+    let mut out = Vec::new();
+    resampler.process(&sound.samples, &mut out);
+    resampler.flush(&mut out);
+    sound.samples = out;
+    sound.sample_rate = rate;
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut rate = None;
+    let mut mono = false;
+    let mut pcm = false;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rate" => {
+                let hz = args.next().and_then(|r| r.parse::<u32>().ok()).filter(|r| *r > 0);
+                rate = Some(hz.ok_or("--rate needs a sample rate in Hz")?);
+            }
+            "--mono" => mono = true,
+            "--pcm" => pcm = true,
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = files[..] else {
+        return Err("usage: audioconv [--rate HZ] [--mono] [--pcm] IN OUT.qoa|OUT.wav".into());
+    };
+
+    let is_qoa = output.to_ascii_lowercase().ends_with(".qoa");
+    if is_qoa && pcm {
+        return Err(format!("--pcm writes a WAV, {} should end in .wav", output));
+    }
+
+    let data = std::fs::read(input).map_err(|e| format!("couldn't read {}: {}", input, e))?;
+    let mut sound = load(&data)?;
+    if mono && sound.channels > 1 {
+        to_mono(&mut sound);
+    }
+    if let Some(rate) = rate.filter(|r| *r != sound.sample_rate) {
+        resample(&mut sound, rate);
+    }
+
+    if sound.samples.len() < sound.channels as usize {
+        return Err(format!("there are no samples in {}", input));
+    }
+
+    let out = if is_qoa {
+        qoa::encode(&sound.samples, sound.channels, sound.sample_rate).unwrap()
+    } else if pcm {
+        let mut out = Vec::new();
+        let mut writer = wav::WavWriter::new(&mut out, sound.sample_rate, sound.channels, 1 << 16).unwrap();
+        writer.write(&mut out, &sound.samples).unwrap();
+        writer.finish(&mut out).unwrap();
+        out
+    } else {
+        wav::encode_adpcm(&sound.samples, sound.sample_rate, sound.channels)
+    };
+    std::fs::write(output, &out).map_err(|e| format!("couldn't write {}: {}", output, e))?;
+
+    let frames = sound.samples.len() / sound.channels as usize;
+    println!(
+        "{} channels at {}Hz, {:.2}s, {} bytes to {} bytes",
+        sound.channels,
+        sound.sample_rate,
+        frames as f32 / sound.sample_rate as f32,
+        data.len(),
+        out.len()
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
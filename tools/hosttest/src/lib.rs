@@ -0,0 +1,80 @@
+//! Runs the tests in the repo's modules on the computer.
+//!
+//! The T-Deck crate only builds for the ESP32-S3, so this pulls in the modules that don't
+//! touch the hardware and `cargo test` here runs their `#[cfg(test)]` tests. Modules keep
+//! the same names as in the real crate, so their `crate::` paths still work.
+//!
+//! ```text
+//! cd tools/hosttest
+//! cargo test
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/anim.rs"]
+pub mod anim;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/breakout.rs"]
+pub mod breakout;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/chart.rs"]
+pub mod chart;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/color.rs"]
+pub mod color;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/dsp.rs"]
+pub mod dsp;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/fft.rs"]
+pub mod fft;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/game.rs"]
+pub mod game;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/markdown.rs"]
+pub mod markdown;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/midi.rs"]
+pub mod midi;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/mixer.rs"]
+pub mod mixer;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/playlist.rs"]
+pub mod playlist;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/power.rs"]
+pub mod power;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/qoa.rs"]
+pub mod qoa;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/reader.rs"]
+pub mod reader;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/resample.rs"]
+pub mod resample;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/sim.rs"]
+pub mod sim;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/synth.rs"]
+pub mod synth;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/theme.rs"]
+pub mod theme;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/tracker.rs"]
+pub mod tracker;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/volume.rs"]
+pub mod volume;
+#[allow(dead_code, unused_imports)]
+#[path = "../../../src/wav.rs"]
+pub mod wav;